@@ -0,0 +1,40 @@
+//! Cooperative cancellation of requests aborted by the web server.
+//!
+//! A web server may abort a request, e.g. because the client went away,
+//! by sending `FCGI_ABORT_REQUEST` or closing the connection. Transports
+//! which notice this, such as the native transport of the `pure` feature,
+//! set the request's `AbortToken`. Handlers doing long computations can
+//! check `Exchange::is_aborted` or pass the token to other threads and stop
+//! early; output written after the abort is discarded.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A flag shared between a transport and the handler of a request, see the
+/// module documentation. Clones share the flag.
+#[derive(Clone, Default)]
+pub struct AbortToken(Arc<AtomicBool>);
+
+impl AbortToken {
+    /// Creates a token which is not aborted.
+    pub fn new() -> AbortToken {
+        AbortToken::default()
+    }
+
+    /// Marks the request as aborted.
+    pub fn abort(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// True once the request has been aborted.
+    pub fn is_aborted(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl fmt::Debug for AbortToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("AbortToken").field(&self.is_aborted()).finish()
+    }
+}
@@ -0,0 +1,552 @@
+//! The Apache JServ Protocol version 1.3, as an alternative transport to
+//! FastCGI, for front ends such as Apache's `mod_proxy_ajp`.
+//!
+//! The web server sends a request as a binary forward request packet: the
+//! method, URI, addresses and headers, some of them coded as numbers,
+//! followed by attributes such as the query string or the remote user.
+//! `AjpRequest` turns it into the usual CGI parameters, `REQUEST_METHOD`,
+//! `REQUEST_URI`, `QUERY_STRING`, `HTTP_*` and so on, so handlers run
+//! unchanged. Further attributes the front end sets with names starting with
+//! `AJP_`, like `AJP_REMOTE_PORT`, are passed as parameters of the same
+//! name; others are ignored.
+//!
+//! The input arrives in body packets which are asked for as the handler
+//! reads it. The CGI response the handler writes is translated into a
+//! headers packet with the status from the `Status` header, body chunks and
+//! the end of the response. CPing probes are answered with CPong.
+//!
+//! Connections are not reused: the end of each response tells the front
+//! end to close it, so that an idle connection never holds a worker, and
+//! connections which stall before the forward request or in the middle of
+//! the body time out. With
+//! a secret set, requests must carry the same `secret` attribute as
+//! configured in the front end, e.g. `ProxyPass / ajp://localhost:8009/
+//! secret=...`; others are rejected.
+
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+use crate::conn::{self, Stream};
+use crate::headers::{cgi_head_len, parse_cgi_head, reason_phrase};
+use crate::{Request, StreamType};
+
+/// The magic bytes starting packets from the web server.
+const SERVER_MAGIC: [u8; 2] = [0x12, 0x34];
+/// The magic bytes starting packets to the web server.
+const CONTAINER_MAGIC: [u8; 2] = *b"AB";
+
+const FORWARD_REQUEST: u8 = 2;
+const SEND_BODY_CHUNK: u8 = 3;
+const SEND_HEADERS: u8 = 4;
+const END_RESPONSE: u8 = 5;
+const GET_BODY_CHUNK: u8 = 6;
+const SHUTDOWN: u8 = 7;
+const CPONG_REPLY: u8 = 9;
+const CPING: u8 = 10;
+
+/// The largest packet sent, the default packet size of the front ends.
+const MAX_PACKET_LEN: usize = 8192;
+/// The most body data in a packet sent: the packet header, prefix, length
+/// and terminating NUL leave the rest.
+const MAX_CHUNK_LEN: usize = MAX_PACKET_LEN - 8;
+/// The most body data asked for at once.
+const MAX_REQUEST_CHUNK_LEN: usize = MAX_PACKET_LEN - 6;
+/// How long a new connection may take to send the forward request. Front
+/// ends send it right after connecting, and the worker accepting the
+/// connection waits for nothing else meanwhile.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the front end may take to send a body chunk asked for.
+const BODY_TIMEOUT: Duration = Duration::from_secs(30);
+/// The longest name of a request attribute passed on as a parameter.
+const MAX_ATTRIBUTE_NAME_LEN: usize = 64;
+
+/// Methods by their code in the forward request.
+const METHODS: &[&str] = &[
+    "OPTIONS", "GET", "HEAD", "POST", "PUT", "DELETE", "TRACE", "PROPFIND", "PROPPATCH", "MKCOL", "COPY", "MOVE",
+    "LOCK", "UNLOCK", "ACL", "REPORT", "VERSION-CONTROL", "CHECKIN", "CHECKOUT", "UNCHECKOUT", "SEARCH",
+    "MKWORKSPACE", "UPDATE", "LABEL", "MERGE", "BASELINE-CONTROL", "MKACTIVITY",
+];
+
+/// Request headers by their code, starting at 0xA001.
+const HEADERS: &[&str] = &[
+    "Accept", "Accept-Charset", "Accept-Encoding", "Accept-Language", "Authorization", "Connection",
+    "Content-Type", "Content-Length", "Cookie", "Cookie2", "Host", "Pragma", "Referer", "User-Agent",
+];
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid AJP request: {}", message))
+}
+
+/// Whether a request attribute is passed on as a parameter: only `AJP_*`
+/// names, which cannot shadow the CGI parameters.
+fn is_passed_attribute(name: &str) -> bool {
+    name.len() <= MAX_ATTRIBUTE_NAME_LEN
+        && name.starts_with("AJP_")
+        && name.bytes().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'_')
+}
+
+/// Compares secrets in a time independent of where they differ.
+fn secret_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Reads a packet from the web server, returning its payload.
+fn read_packet<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut header = [0; 4];
+    reader.read_exact(&mut header)?;
+    if header[..2] != SERVER_MAGIC {
+        return Err(invalid("bad packet magic"));
+    }
+    let mut payload = vec![0; u16::from_be_bytes([header[2], header[3]]) as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+fn write_packet<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    writer.write_all(&CONTAINER_MAGIC)?;
+    writer.write_all(&(payload.len() as u16).to_be_bytes())?;
+    writer.write_all(payload)
+}
+
+/// Appends a string in AJP form: its length, the bytes and a NUL.
+fn put_string(packet: &mut Vec<u8>, s: &str) {
+    let bytes = &s.as_bytes()[..s.len().min(MAX_CHUNK_LEN)];
+    packet.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    packet.extend_from_slice(bytes);
+    packet.push(0);
+}
+
+/// Reads the fields of a packet.
+struct Fields<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn byte(&mut self) -> io::Result<u8> {
+        let b = *self.data.get(self.pos).ok_or_else(|| invalid("truncated packet"))?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn int(&mut self) -> io::Result<u16> {
+        Ok(u16::from_be_bytes([self.byte()?, self.byte()?]))
+    }
+
+    /// A string, None for the null string.
+    fn string(&mut self) -> io::Result<Option<String>> {
+        let len = self.int()?;
+        if len == 0xFFFF {
+            return Ok(None);
+        }
+        let end = self.pos + len as usize;
+        let bytes = self.data.get(self.pos..end).ok_or_else(|| invalid("truncated packet"))?;
+        self.pos = end + 1;
+        Ok(Some(String::from_utf8_lossy(bytes).into_owned()))
+    }
+}
+
+/// Turns a forward request into CGI parameters. Checks the `secret`
+/// attribute if one is required.
+pub fn read_forward_request(packet: &[u8], secret: Option<&str>) -> io::Result<Vec<(String, String)>> {
+    let mut fields = Fields { data: packet, pos: 0 };
+    if fields.byte()? != FORWARD_REQUEST {
+        return Err(invalid("expected a forward request"));
+    }
+    let method = fields.byte()?;
+    let protocol = fields.string()?.unwrap_or_default();
+    let uri = fields.string()?.unwrap_or_default();
+    let remote_addr = fields.string()?.unwrap_or_default();
+    let remote_host = fields.string()?.unwrap_or_default();
+    let server_name = fields.string()?.unwrap_or_default();
+    let server_port = fields.int()?;
+    let is_ssl = fields.byte()? != 0;
+    let mut params = vec![
+        (String::from("GATEWAY_INTERFACE"), String::from("CGI/1.1")),
+        (String::from("SERVER_PROTOCOL"), protocol),
+        (String::from("REMOTE_ADDR"), remote_addr),
+        (String::from("REMOTE_HOST"), remote_host),
+        (String::from("SERVER_NAME"), server_name),
+        (String::from("SERVER_PORT"), server_port.to_string()),
+        (String::from("SCRIPT_NAME"), String::new()),
+        (String::from("PATH_INFO"), uri.clone()),
+    ];
+    if is_ssl {
+        params.push((String::from("HTTPS"), String::from("on")));
+    }
+    for _ in 0..fields.int()? {
+        let code = fields.int()?;
+        let name = if code & 0xFF00 == 0xA000 {
+            let name = HEADERS.get(((code & 0xFF) as usize).wrapping_sub(1)).ok_or_else(|| invalid("unknown header code"))?;
+            String::from(*name)
+        } else {
+            fields.pos -= 2;
+            fields.string()?.unwrap_or_default()
+        };
+        let value = fields.string()?.unwrap_or_default();
+        let name = name.to_ascii_uppercase().replace('-', "_");
+        let name = match name.as_str() {
+            "CONTENT_TYPE" | "CONTENT_LENGTH" => name,
+            _ => format!("HTTP_{}", name),
+        };
+        params.push((name, value));
+    }
+    let mut method = METHODS.get((method as usize).wrapping_sub(1)).map(|&m| String::from(m));
+    let mut query = String::new();
+    let mut secret_ok = secret.is_none();
+    loop {
+        let attribute = fields.byte()?;
+        let name = match attribute {
+            0xFF => break,
+            0x01 => "AJP_CONTEXT",
+            0x02 => "AJP_SERVLET_PATH",
+            0x03 => "REMOTE_USER",
+            0x04 => "AUTH_TYPE",
+            0x05 => {
+                query = fields.string()?.unwrap_or_default();
+                continue;
+            }
+            0x06 => "AJP_JVM_ROUTE",
+            0x07 => "SSL_CLIENT_CERT",
+            0x08 => "SSL_CIPHER",
+            0x09 => "SSL_SESSION_ID",
+            0x0A => {
+                let name = fields.string()?.unwrap_or_default();
+                let value = fields.string()?.unwrap_or_default();
+                if !is_passed_attribute(&name) {
+                    continue;
+                }
+                if name == "AJP_REMOTE_PORT" {
+                    params.push((String::from("REMOTE_PORT"), value.clone()));
+                }
+                params.push((name, value));
+                continue;
+            }
+            0x0B => {
+                let size = fields.int()?;
+                params.push((String::from("SSL_CIPHER_USEKEYSIZE"), size.to_string()));
+                continue;
+            }
+            0x0C => {
+                let value = fields.string()?.unwrap_or_default();
+                // A secret sent without one configured is ignored.
+                secret_ok = secret.is_none_or(|secret| secret_eq(secret, &value));
+                continue;
+            }
+            0x0D => {
+                method = fields.string()?;
+                continue;
+            }
+            _ => return Err(invalid("unknown attribute")),
+        };
+        params.push((String::from(name), fields.string()?.unwrap_or_default()));
+    }
+    if !secret_ok {
+        return Err(invalid("wrong or missing secret"));
+    }
+    let method = method.ok_or_else(|| invalid("unknown method"))?;
+    let request_uri = if query.is_empty() { uri } else { format!("{}?{}", uri, query) };
+    params.push((String::from("REQUEST_METHOD"), method));
+    params.push((String::from("REQUEST_URI"), request_uri));
+    params.push((String::from("QUERY_STRING"), query));
+    Ok(params)
+}
+
+/// The request being handled.
+struct Current {
+    params: Vec<(String, String)>,
+    peer: Option<SocketAddr>,
+    reader: BufReader<Stream>,
+    writer: BufWriter<Stream>,
+    /// The body chunk being read.
+    chunk: Vec<u8>,
+    chunk_pos: usize,
+    /// Input left according to `CONTENT_LENGTH`, None if it is unknown.
+    remaining: Option<u64>,
+    /// Set once all input has arrived.
+    input_done: bool,
+    /// Set while the web server sends the first chunk of input by itself.
+    first_chunk: bool,
+    /// Output collected until the end of the CGI head, None once the
+    /// headers have been sent.
+    head: Option<Vec<u8>>,
+    /// Body output not yet sent.
+    body: Vec<u8>,
+}
+
+impl Current {
+    /// Waits for a forward request on a new connection, answering pings.
+    fn start(stream: Stream, secret: Option<&str>) -> io::Result<Current> {
+        let peer = stream.peer_addr();
+        let mut writer = BufWriter::new(stream.try_clone()?);
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        let mut reader = BufReader::new(stream);
+        let mut current = loop {
+            let packet = read_packet(&mut reader)?;
+            match packet.first() {
+                Some(&FORWARD_REQUEST) => {
+                    let params = read_forward_request(&packet, secret)?;
+                    break Current {
+                        params,
+                        peer,
+                        reader,
+                        writer,
+                        chunk: Vec::new(),
+                        chunk_pos: 0,
+                        remaining: None,
+                        input_done: false,
+                        first_chunk: false,
+                        head: Some(Vec::new()),
+                        body: Vec::new(),
+                    };
+                }
+                Some(&CPING) => {
+                    write_packet(&mut writer, &[CPONG_REPLY])?;
+                    writer.flush()?;
+                }
+                Some(&SHUTDOWN) => return Err(invalid("shutdown requested")),
+                _ => return Err(invalid("unexpected packet")),
+            }
+        };
+        current.reader.get_ref().set_read_timeout(Some(BODY_TIMEOUT))?;
+        current.remaining = current.param("CONTENT_LENGTH").and_then(|len| len.parse().ok());
+        // The web server sends the first chunk of a body right away.
+        let has_body = current.remaining.map_or(current.param("HTTP_TRANSFER_ENCODING").is_some(), |len| len > 0);
+        current.input_done = !has_body;
+        current.first_chunk = has_body;
+        Ok(current)
+    }
+
+    fn param(&self, name: &str) -> Option<String> {
+        self.params.iter().find(|&(n, _)| n == name).map(|(_, value)| value.clone())
+    }
+
+    /// Gets the next chunk of input, false at its end.
+    fn next_chunk(&mut self) -> io::Result<bool> {
+        while self.chunk_pos == self.chunk.len() {
+            if self.input_done {
+                return Ok(false);
+            }
+            if !self.first_chunk {
+                let len = self.remaining.map_or(MAX_REQUEST_CHUNK_LEN, |r| r.min(MAX_REQUEST_CHUNK_LEN as u64) as usize);
+                let mut request = vec![GET_BODY_CHUNK];
+                request.extend_from_slice(&(len as u16).to_be_bytes());
+                write_packet(&mut self.writer, &request)?;
+                self.writer.flush()?;
+            }
+            self.first_chunk = false;
+            let packet = read_packet(&mut self.reader)?;
+            // An empty packet ends input of unknown length.
+            self.chunk = match packet.get(..2) {
+                Some(len) => {
+                    let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+                    packet.get(2..2 + len).ok_or_else(|| invalid("truncated body chunk"))?.to_vec()
+                }
+                None => Vec::new(),
+            };
+            let len = self.chunk.len();
+            self.chunk_pos = 0;
+            if let Some(ref mut remaining) = self.remaining {
+                *remaining = remaining.saturating_sub(len as u64);
+            }
+            self.input_done = len == 0 || self.remaining == Some(0);
+        }
+        Ok(true)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut count = 0;
+        while count < buf.len() && self.next_chunk()? {
+            let n = (buf.len() - count).min(self.chunk.len() - self.chunk_pos);
+            buf[count..count + n].copy_from_slice(&self.chunk[self.chunk_pos..self.chunk_pos + n]);
+            self.chunk_pos += n;
+            count += n;
+        }
+        Ok(count)
+    }
+
+    /// Sends the headers packet made from the CGI head.
+    fn send_headers(&mut self, head: &[u8]) -> io::Result<()> {
+        let (status, headers) = parse_cgi_head(head);
+        let mut packet = vec![SEND_HEADERS];
+        packet.extend_from_slice(&status.to_be_bytes());
+        put_string(&mut packet, reason_phrase(status));
+        packet.extend_from_slice(&(headers.len() as u16).to_be_bytes());
+        for (name, value) in headers.iter() {
+            put_string(&mut packet, name);
+            put_string(&mut packet, value);
+        }
+        if packet.len() > MAX_PACKET_LEN - 4 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "response headers too large for AJP"));
+        }
+        write_packet(&mut self.writer, &packet)
+    }
+
+    /// Sends the body output in chunks, all of it if `all`, otherwise
+    /// only full chunks.
+    fn send_body(&mut self, all: bool) -> io::Result<()> {
+        let mut start = 0;
+        while self.body.len() - start >= MAX_CHUNK_LEN || (all && start < self.body.len()) {
+            let end = (start + MAX_CHUNK_LEN).min(self.body.len());
+            let mut packet = Vec::with_capacity(end - start + 4);
+            packet.push(SEND_BODY_CHUNK);
+            packet.extend_from_slice(&((end - start) as u16).to_be_bytes());
+            packet.extend_from_slice(&self.body[start..end]);
+            packet.push(0);
+            write_packet(&mut self.writer, &packet)?;
+            start = end;
+        }
+        self.body.drain(..start);
+        Ok(())
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self.head.take() {
+            Some(mut head) => {
+                head.extend_from_slice(buf);
+                match cgi_head_len(&head) {
+                    Some(len) => {
+                        self.send_headers(&head[..len])?;
+                        self.body.extend_from_slice(&head[len..]);
+                    }
+                    None => {
+                        self.head = Some(head);
+                        return Ok(());
+                    }
+                }
+            }
+            None => self.body.extend_from_slice(buf),
+        }
+        self.send_body(false)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.head.is_none() {
+            self.send_body(true)?;
+        }
+        self.writer.flush()
+    }
+
+    /// Ends the response and closes the connection.
+    fn end(&mut self) -> io::Result<()> {
+        if let Some(head) = self.head.take() {
+            self.send_headers(&head)?;
+        }
+        self.send_body(true)?;
+        write_packet(&mut self.writer, &[END_RESPONSE, 0])?;
+        self.writer.flush()
+    }
+}
+
+/// An AJP13 request, accepted from a listen socket.
+pub struct AjpRequest {
+    listen_fd: RawFd,
+    secret: Option<String>,
+    current: Option<Current>,
+    /// Set when writing the response failed, e.g. because the web server
+    /// closed the connection.
+    failed: bool,
+}
+
+impl AjpRequest {
+    /// Requires the given secret in every request, as set in the front end.
+    pub fn set_secret(&mut self, secret: Option<String>) {
+        self.secret = secret;
+    }
+}
+
+impl Request for AjpRequest {
+    /// Accepts requests from the socket passed as stdin.
+    fn new() -> Option<AjpRequest> {
+        AjpRequest::new_with_fd(0)
+    }
+
+    fn new_with_fd(fd: RawFd) -> Option<AjpRequest> {
+        Some(AjpRequest { listen_fd: fd, secret: None, current: None, failed: false })
+    }
+
+    /// Finishes the previous request and accepts the next. Connections
+    /// which do not start with a valid forward request are closed.
+    fn accept(&mut self) -> bool {
+        self.finish();
+        loop {
+            let stream = match conn::accept(self.listen_fd) {
+                Ok(stream) => stream,
+                Err(_) => return false,
+            };
+            match Current::start(stream, self.secret.as_deref()) {
+                Ok(current) => {
+                    self.current = Some(current);
+                    self.failed = false;
+                    return true;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+                Err(e) => server_log!(warn, "{}", e),
+            }
+        }
+    }
+
+    /// Ends the response and closes the connection.
+    fn finish(&mut self) {
+        if let Some(mut current) = self.current.take() {
+            if !self.failed {
+                let _ = current.end();
+            }
+            current.writer.get_ref().shutdown();
+        }
+    }
+
+    fn get_param(&self, name: &str) -> Option<String> {
+        self.current.as_ref()?.param(name)
+    }
+
+    fn params(&self) -> Vec<(String, String)> {
+        self.current.as_ref().map(|current| current.params.clone()).unwrap_or_default()
+    }
+
+    fn write(&mut self, msg: &str) -> i32 {
+        self.write_bytes(msg.as_bytes())
+    }
+
+    fn error(&mut self, msg: &str) -> i32 {
+        conn::write_stderr(msg)
+    }
+
+    fn write_bytes(&mut self, buf: &[u8]) -> i32 {
+        match self.current {
+            Some(ref mut current) if !self.failed => match current.write(buf) {
+                Ok(()) => buf.len() as i32,
+                Err(_) => {
+                    self.failed = true;
+                    -1
+                }
+            },
+            _ => -1,
+        }
+    }
+
+    fn read_bytes(&mut self, buf: &mut [u8]) -> i32 {
+        match self.current.as_mut().map(|current| current.read(buf)) {
+            Some(Ok(n)) => n as i32,
+            _ => -1,
+        }
+    }
+
+    fn read(&mut self, n: i32) -> (String, i32) {
+        crate::read_lossy(self, n)
+    }
+
+    fn flush(&mut self, stream_type: StreamType) {
+        if let (StreamType::OutStream, Some(current)) = (stream_type, self.current.as_mut()) {
+            if !self.failed && current.flush().is_err() {
+                self.failed = true;
+            }
+        }
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.current.as_ref().and_then(|current| current.peer)
+    }
+}
@@ -0,0 +1,229 @@
+//! Reading the records of a connection and writing the output of its
+//! requests.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc;
+
+use crate::abort::AbortToken;
+use crate::native::{self, Capabilities};
+use crate::protocol::{self, EndRequest, Header, ProtocolStatus, Record, RecordType, Role, UnknownType, HEADER_LEN};
+
+use super::{AsyncRequest, Spawn};
+
+/// Chunks of input buffered for a request whose handler does not read it.
+/// Reading the connection pauses when they are reached.
+const INPUT_CHUNKS: usize = 16;
+
+type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// A request which has begun and whose parameters are still arriving, or
+/// which was handed out and still receives input.
+enum Open {
+    Building { role: Role, params: Vec<u8> },
+    Started { input: Option<mpsc::Sender<Vec<u8>>>, aborted: AbortToken },
+}
+
+#[derive(Default)]
+struct State {
+    /// Requests begun and not yet ended.
+    requests: HashMap<u16, Open>,
+    /// Set by a request without `FCGI_KEEP_CONN`: the connection is closed
+    /// once no request is open.
+    close_when_idle: bool,
+    /// Set once the web server closed the connection.
+    closed: bool,
+}
+
+/// A connection from the web server, shared by its reading task and the
+/// requests handled on it.
+pub(super) struct Connection {
+    writer: tokio::sync::Mutex<Writer>,
+    state: Mutex<State>,
+    /// Runs the tasks ending dropped requests.
+    pub(super) spawner: Arc<dyn Spawn>,
+}
+
+impl Connection {
+    /// Writes complete records.
+    pub(super) async fn send(&self, records: &[u8]) -> io::Result<()> {
+        let mut writer = self.writer.lock().await;
+        writer.write_all(records).await?;
+        writer.flush().await
+    }
+
+    /// Sends the final records of a request, closing the connection if it
+    /// is not kept open.
+    pub(super) async fn end(&self, request_id: u16, records: &[u8]) -> io::Result<()> {
+        let mut writer = self.writer.lock().await;
+        let mut result = writer.write_all(records).await;
+        if result.is_ok() {
+            result = writer.flush().await;
+        }
+        let close = {
+            let mut state = lock(&self.state);
+            state.requests.remove(&request_id);
+            state.requests.is_empty() && (state.close_when_idle || state.closed || result.is_err())
+        };
+        if close {
+            let _ = writer.shutdown().await;
+        }
+        result
+    }
+}
+
+/// Reads a record, `None` at the end of the input.
+async fn read_record<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Record>> {
+    let mut header = [0u8; HEADER_LEN];
+    if reader.read(&mut header[..1]).await? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut header[1..]).await?;
+    let header = Header::decode(&header)?.expect("complete header");
+    let mut content = vec![0; header.record_len() - HEADER_LEN];
+    reader.read_exact(&mut content).await?;
+    content.truncate(header.content_length as usize);
+    Ok(Some(Record::new(header.record_type, header.request_id, content)))
+}
+
+/// Serves a connection: reads its records until it is closed and hands the
+/// requests to `requests` once their parameters are complete.
+pub(super) async fn serve<R, W>(reader: R, writer: W, peer: Option<SocketAddr>, capabilities: Capabilities,
+                                spawner: Arc<dyn Spawn>, requests: mpsc::Sender<AsyncRequest>)
+    where R: AsyncRead + Unpin, W: AsyncWrite + Send + Unpin + 'static
+{
+    let connection = Arc::new(Connection {
+        writer: tokio::sync::Mutex::new(Box::new(writer)),
+        state: Mutex::new(State::default()),
+        spawner,
+    });
+    let _ = read_connection(reader, &connection, peer, capabilities, &requests).await;
+    let idle = {
+        let mut state = lock(&connection.state);
+        state.closed = true;
+        // Requests still building never reach a handler, the others are
+        // ended by theirs.
+        state.requests.retain(|_, request| match *request {
+            Open::Building { .. } => false,
+            Open::Started { ref aborted, .. } => {
+                aborted.abort();
+                true
+            }
+        });
+        state.requests.is_empty()
+    };
+    if idle {
+        let _ = connection.writer.lock().await.shutdown().await;
+    }
+}
+
+async fn read_connection<R: AsyncRead + Unpin>(reader: R, connection: &Arc<Connection>, peer: Option<SocketAddr>,
+                                               capabilities: Capabilities, requests: &mpsc::Sender<AsyncRequest>)
+                                               -> io::Result<()> {
+    let mut reader = BufReader::new(reader);
+    while let Some(record) = read_record(&mut reader).await? {
+        let id = record.request_id;
+        if id == protocol::NULL_REQUEST_ID {
+            let mut buf = Vec::new();
+            if record.record_type == RecordType::GetValues {
+                let result = capabilities.get_values_result(&record.content);
+                protocol::encode_record(&mut buf, RecordType::GetValuesResult, id, &result);
+            } else {
+                UnknownType { record_type: record.record_type.as_u8() }.to_record().encode(&mut buf);
+            }
+            connection.send(&buf).await?;
+            continue;
+        }
+        match record.record_type {
+            RecordType::BeginRequest => {
+                let begin = record.begin_request()?;
+                let rejected = {
+                    let mut state = lock(&connection.state);
+                    if state.requests.contains_key(&id) {
+                        continue;
+                    }
+                    if !state.requests.is_empty() && !capabilities.mpxs_conns {
+                        Some(ProtocolStatus::CantMpxConn)
+                    } else if begin.role != Role::Responder && begin.role != Role::Authorizer {
+                        Some(ProtocolStatus::UnknownRole)
+                    } else {
+                        state.requests.insert(id, Open::Building { role: begin.role, params: Vec::new() });
+                        state.close_when_idle |= !begin.keep_conn();
+                        None
+                    }
+                };
+                if let Some(protocol_status) = rejected {
+                    let mut buf = Vec::with_capacity(16);
+                    EndRequest { app_status: 0, protocol_status }.to_record(id).encode(&mut buf);
+                    connection.send(&buf).await?;
+                }
+            }
+            RecordType::Params => {
+                let request = {
+                    let mut state = lock(&connection.state);
+                    let (role, params) = match state.requests.get_mut(&id) {
+                        Some(Open::Building { params, .. }) if !record.content.is_empty() => {
+                            params.extend_from_slice(&record.content);
+                            continue;
+                        }
+                        Some(Open::Building { role, params }) => (*role, native::decode_params(params)?),
+                        _ => continue,
+                    };
+                    let mut params = params;
+                    params.push((String::from("FCGI_ROLE"), String::from(native::role_name(role))));
+                    let (sender, receiver) = mpsc::channel(INPUT_CHUNKS);
+                    let aborted = AbortToken::new();
+                    // Authorizers receive no input.
+                    let input = if role == Role::Authorizer { None } else { Some(sender) };
+                    state.requests.insert(id, Open::Started { input, aborted: aborted.clone() });
+                    AsyncRequest::new(connection.clone(), id, role, params, peer, receiver, aborted)
+                };
+                if requests.send(request).await.is_err() {
+                    return Ok(());
+                }
+            }
+            RecordType::Stdin => {
+                let sender = match lock(&connection.state).requests.get_mut(&id) {
+                    Some(Open::Started { input, .. }) if record.content.is_empty() => {
+                        *input = None;
+                        continue;
+                    }
+                    Some(Open::Started { input: Some(sender), .. }) => sender.clone(),
+                    _ => continue,
+                };
+                // A request whose handler dropped it takes no more input.
+                if sender.send(record.content).await.is_err() {
+                    if let Some(Open::Started { input, .. }) = lock(&connection.state).requests.get_mut(&id) {
+                        *input = None;
+                    }
+                }
+            }
+            RecordType::AbortRequest => {
+                let building = match lock(&connection.state).requests.get(&id) {
+                    Some(Open::Building { .. }) => true,
+                    Some(Open::Started { aborted, .. }) => {
+                        aborted.abort();
+                        false
+                    }
+                    None => false,
+                };
+                if building {
+                    let mut buf = Vec::with_capacity(16);
+                    EndRequest { app_status: 0, protocol_status: ProtocolStatus::RequestComplete }
+                        .to_record(id).encode(&mut buf);
+                    connection.end(id, &buf).await?;
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
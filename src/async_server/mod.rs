@@ -0,0 +1,352 @@
+//! An async FastCGI server, with the `async` feature.
+//!
+//! The blocking server gives each request a worker thread for as long as
+//! its handler runs, so handlers which wait for databases or upstream HTTP
+//! services need as many workers as requests waiting. An `AsyncListener`
+//! instead reads connections in async tasks and hands out `AsyncRequest`s,
+//! whose input and output are `AsyncRead` and `AsyncWrite`, so handlers
+//! can `.await` while other requests go on.
+//!
+//! With the `async-tokio` feature the listener accepts connections itself:
+//!
+//! ```ignore
+//! let listener = AsyncListener::bind(&"127.0.0.1:9000".parse()?)?;
+//! async_server::serve(listener, |mut request| async move {
+//!     let _ = request.write_all(b"Content-Type: text/plain\r\n\r\nHello").await;
+//!     let _ = request.finish(0).await;
+//! }).await
+//! ```
+//!
+//! Other runtimes, such as async-std or smol, create the listener with a
+//! `Spawn` for their executor and pass the connections they accept to the
+//! `Connector` returned with it. With the `async-futures` feature the
+//! connector takes streams implementing the `futures-io` traits, and
+//! `AsyncRequest` implements them too:
+//!
+//! ```ignore
+//! let (listener, connector) = AsyncListener::new(|task| { async_std::task::spawn(task); });
+//! async_std::task::spawn(async move {
+//!     let socket = async_std::net::TcpListener::bind("127.0.0.1:9000").await?;
+//!     loop {
+//!         let (stream, peer) = socket.accept().await?;
+//!         connector.connect_futures(stream, Some(peer));
+//!     }
+//! });
+//! ```
+//!
+//! The protocol is the one of the `native` module: requests are multiplexed
+//! over a connection with `Capabilities::mpxs_conns`, `FCGI_GET_VALUES` is
+//! answered with the capabilities, aborted requests have their `AbortToken`
+//! set and connections without `FCGI_KEEP_CONN` are closed once their
+//! requests have ended. Responder and authorizer requests are supported,
+//! filter requests are rejected with `FCGI_UNKNOWN_ROLE`.
+//!
+//! The listener is also a `Stream` of requests, so the combinators of
+//! `futures::StreamExt` can limit how many are handled at once or stop
+//! taking new ones on shutdown:
+//!
+//! ```ignore
+//! listener
+//!     .take_until(shutdown_signal())
+//!     .for_each_concurrent(100, |request| handle(request))
+//!     .await;
+//! ```
+//!
+//! A listener made from a socket is taken over by Tokio on the first
+//! `accept`, which must run inside a Tokio runtime. Shutting down is up to
+//! the application, e.g. by selecting between `serve` and a signal.
+
+mod connection;
+mod request;
+#[cfg(feature = "tower")]
+pub mod tower;
+
+use std::future::{self, Future};
+use std::io;
+use std::net::SocketAddr;
+#[cfg(feature = "async-tokio")]
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+#[cfg(feature = "async-tokio")]
+use std::time::Duration;
+#[cfg(feature = "async-tokio")]
+use std::{mem, net};
+#[cfg(feature = "async-tokio")]
+use std::os::unix::net as unix;
+
+#[cfg(feature = "async-tokio")]
+use libc;
+use futures_core::Stream;
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(feature = "async-tokio")]
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::mpsc;
+#[cfg(feature = "async-tokio")]
+use tokio::task::JoinHandle;
+
+#[cfg(feature = "async-tokio")]
+use crate::listen::ListenAddr;
+use crate::native::Capabilities;
+
+pub use self::request::{AsyncRequest, Output, Stdin};
+
+/// How long accepting pauses when the process runs out of file descriptors.
+#[cfg(feature = "async-tokio")]
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+/// Complete requests waiting for `accept`. Reading connections pauses when
+/// they are reached.
+const QUEUE_LEN: usize = 64;
+
+/// A task of the async server: reading a connection, handling a request or
+/// ending a dropped one.
+pub type Task = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Runs the tasks of the async server on an executor. Implemented for
+/// closures, e.g. `|task| { smol::spawn(task).detach(); }`.
+pub trait Spawn: Send + Sync + 'static {
+    fn spawn(&self, task: Task);
+}
+
+impl<F: Fn(Task) + Send + Sync + 'static> Spawn for F {
+    fn spawn(&self, task: Task) {
+        self(task)
+    }
+}
+
+/// Passes connections accepted by the application to an `AsyncListener`.
+/// Clones feed the same listener.
+#[derive(Clone)]
+pub struct Connector {
+    spawner: Arc<dyn Spawn>,
+    capabilities: Arc<Mutex<Capabilities>>,
+    requests: mpsc::Sender<AsyncRequest>,
+}
+
+impl Connector {
+    /// Reads requests from a connection of a web server in a task of its
+    /// own. `peer` is the web server's address, see
+    /// `AsyncRequest::peer_addr`.
+    pub fn connect<S>(&self, stream: S, peer: Option<SocketAddr>)
+        where S: AsyncRead + AsyncWrite + Send + 'static
+    {
+        let (reader, writer) = tokio::io::split(stream);
+        self.connect_split(reader, writer, peer);
+    }
+
+    /// Like `connect`, for a stream implementing the `futures-io` traits.
+    #[cfg(feature = "async-futures")]
+    pub fn connect_futures<S>(&self, stream: S, peer: Option<SocketAddr>)
+        where S: futures_io::AsyncRead + futures_io::AsyncWrite + Send + 'static
+    {
+        use tokio_util::compat::FuturesAsyncReadCompatExt;
+        self.connect(stream.compat(), peer);
+    }
+
+    fn connect_split<R, W>(&self, reader: R, writer: W, peer: Option<SocketAddr>)
+        where R: AsyncRead + Send + Unpin + 'static, W: AsyncWrite + Send + Unpin + 'static
+    {
+        let capabilities = *self.capabilities.lock().unwrap_or_else(|e| e.into_inner());
+        let task = connection::serve(reader, writer, peer, capabilities, self.spawner.clone(), self.requests.clone());
+        self.spawner.spawn(Box::pin(task));
+    }
+}
+
+#[cfg(feature = "async-tokio")]
+enum Socket {
+    Tcp(net::TcpListener),
+    Unix(unix::UnixListener),
+}
+
+/// Hands out the requests of the connections of a web server, see the
+/// module documentation.
+pub struct AsyncListener {
+    spawner: Arc<dyn Spawn>,
+    capabilities: Arc<Mutex<Capabilities>>,
+    requests: mpsc::Receiver<AsyncRequest>,
+    /// Set once the `Stream` of requests has ended, with its error.
+    done: bool,
+    error: Option<io::Error>,
+    /// The listen socket and the connector for it until the first `accept`.
+    #[cfg(feature = "async-tokio")]
+    socket: Option<(Socket, Connector)>,
+    #[cfg(feature = "async-tokio")]
+    accepting: Option<JoinHandle<io::Error>>,
+}
+
+impl AsyncListener {
+    /// Creates a listener for connections passed to the returned
+    /// `Connector`, whose tasks run on `spawner`.
+    pub fn new<S: Spawn>(spawner: S) -> (AsyncListener, Connector) {
+        let spawner: Arc<dyn Spawn> = Arc::new(spawner);
+        let capabilities = Arc::new(Mutex::new(Capabilities::default()));
+        let (sender, requests) = mpsc::channel(QUEUE_LEN);
+        let connector = Connector { spawner: spawner.clone(), capabilities: capabilities.clone(), requests: sender };
+        let listener = AsyncListener {
+            spawner,
+            capabilities,
+            requests,
+            done: false,
+            error: None,
+            #[cfg(feature = "async-tokio")]
+            socket: None,
+            #[cfg(feature = "async-tokio")]
+            accepting: None,
+        };
+        (listener, connector)
+    }
+
+    /// Creates a listener on a bound listen socket, of which it takes
+    /// ownership, with the `async-tokio` feature.
+    #[cfg(feature = "async-tokio")]
+    pub fn from_fd(fd: RawFd) -> io::Result<AsyncListener> {
+        let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut len = mem::size_of_val(&addr) as libc::socklen_t;
+        if unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = if addr.ss_family as libc::c_int == libc::AF_UNIX {
+            let listener = unsafe { unix::UnixListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            Socket::Unix(listener)
+        } else {
+            let listener = unsafe { net::TcpListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            Socket::Tcp(listener)
+        };
+        let (mut listener, connector) = AsyncListener::new(|task| {
+            tokio::spawn(task);
+        });
+        listener.socket = Some((socket, connector));
+        Ok(listener)
+    }
+
+    /// Binds a listen socket to `addr`, with the `async-tokio` feature.
+    #[cfg(feature = "async-tokio")]
+    pub fn bind(addr: &ListenAddr) -> io::Result<AsyncListener> {
+        AsyncListener::from_fd(addr.bind()?)
+    }
+
+    /// Sets the values reported to `FCGI_GET_VALUES` queries. With
+    /// `mpxs_conns` requests are multiplexed over connections. Takes effect
+    /// for connections accepted afterwards.
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        *self.capabilities.lock().unwrap_or_else(|e| e.into_inner()) = capabilities;
+    }
+
+    /// Waits for the next request whose parameters are complete. Fails if
+    /// the listen socket does, or once all connectors and the connections
+    /// passed to them are gone.
+    pub async fn accept(&mut self) -> io::Result<AsyncRequest> {
+        future::poll_fn(|cx| self.poll_accept(cx)).await
+    }
+
+    /// The error which ended the `Stream` of requests, if any.
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<AsyncRequest>> {
+        #[cfg(feature = "async-tokio")]
+        {
+            if let Some((socket, connector)) = self.socket.take() {
+                let socket = match socket {
+                    Socket::Tcp(listener) => TcpListener::from_std(listener).map(Listening::Tcp),
+                    Socket::Unix(listener) => UnixListener::from_std(listener).map(Listening::Unix),
+                };
+                match socket {
+                    Ok(socket) => self.accepting = Some(tokio::spawn(accept_loop(socket, connector))),
+                    Err(e) => return Poll::Ready(Err(e)),
+                }
+            }
+        }
+        if let Some(request) = ready!(self.requests.poll_recv(cx)) {
+            return Poll::Ready(Ok(request));
+        }
+        #[cfg(feature = "async-tokio")]
+        {
+            if let Some(accepting) = self.accepting.as_mut() {
+                let result = ready!(Pin::new(accepting).poll(cx));
+                self.accepting = None;
+                return Poll::Ready(Err(result.unwrap_or_else(io::Error::other)));
+            }
+        }
+        Poll::Ready(Err(io::Error::new(io::ErrorKind::NotConnected, "no more connections")))
+    }
+}
+
+/// The requests of the listener, as `accept` returns them. The stream ends
+/// when accepting fails; `take_error` then returns the error.
+impl Stream for AsyncListener {
+    type Item = AsyncRequest;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<AsyncRequest>> {
+        let this = self.get_mut();
+        if this.done {
+            return Poll::Ready(None);
+        }
+        match ready!(this.poll_accept(cx)) {
+            Ok(request) => Poll::Ready(Some(request)),
+            Err(e) => {
+                this.done = true;
+                this.error = Some(e);
+                Poll::Ready(None)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "async-tokio")]
+impl Drop for AsyncListener {
+    fn drop(&mut self) {
+        if let Some(ref accepting) = self.accepting {
+            accepting.abort();
+        }
+    }
+}
+
+#[cfg(feature = "async-tokio")]
+enum Listening {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Accepts connections and passes them to `connector`. Returns the error
+/// the listen socket failed with.
+#[cfg(feature = "async-tokio")]
+async fn accept_loop(socket: Listening, connector: Connector) -> io::Error {
+    loop {
+        let result = match socket {
+            Listening::Tcp(ref listener) => listener.accept().await.map(|(stream, peer)| {
+                let (reader, writer) = stream.into_split();
+                connector.connect_split(reader, writer, Some(peer));
+            }),
+            Listening::Unix(ref listener) => listener.accept().await.map(|(stream, _)| {
+                let (reader, writer) = stream.into_split();
+                connector.connect_split(reader, writer, None);
+            }),
+        };
+        if let Err(e) = result {
+            match e.raw_os_error() {
+                Some(libc::EINTR) | Some(libc::ECONNABORTED) => {}
+                Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::ENOBUFS) | Some(libc::ENOMEM) => {
+                    tokio::time::sleep(ACCEPT_RETRY).await;
+                }
+                _ => return e,
+            }
+        }
+    }
+}
+
+/// Accepts requests from `listener` and runs `handler` for each in a task
+/// of its own. The handler ends the request with `AsyncRequest::finish`.
+/// Returns when the listener fails.
+pub async fn serve<F, Fut>(mut listener: AsyncListener, handler: F) -> io::Result<()>
+    where F: Fn(AsyncRequest) -> Fut, Fut: Future<Output = ()> + Send + 'static
+{
+    loop {
+        let request = listener.accept().await?;
+        listener.spawner.spawn(Box::pin(handler(request)));
+    }
+}
@@ -0,0 +1,375 @@
+//! The request type handed to async handlers and its streams.
+
+use std::future::Future;
+use std::io;
+#[cfg(feature = "tower")]
+use std::mem;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::mpsc;
+
+use crate::abort::AbortToken;
+use crate::protocol::{self, EndRequest, ProtocolStatus, RecordType, Role, MAX_ALIGNED_CONTENT_LEN};
+
+use super::connection::Connection;
+
+type Sending = Pin<Box<dyn Future<Output = io::Result<()>> + Send>>;
+
+/// The `FCGI_STDIN` stream as it arrives from the connection.
+pub(super) struct Input {
+    receiver: mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Input {
+    /// The rest of the current chunk or the next one, `None` at the end of
+    /// the stream.
+    #[cfg(feature = "tower")]
+    pub(super) fn poll_chunk(&mut self, cx: &mut Context<'_>) -> Poll<Option<Vec<u8>>> {
+        if self.pos < self.chunk.len() {
+            let mut chunk = mem::take(&mut self.chunk);
+            chunk.drain(..self.pos);
+            self.pos = 0;
+            return Poll::Ready(Some(chunk));
+        }
+        self.receiver.poll_recv(cx)
+    }
+
+    fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        while self.pos == self.chunk.len() {
+            match ready!(self.receiver.poll_recv(cx)) {
+                Some(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let len = buf.remaining().min(self.chunk.len() - self.pos);
+        buf.put_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// An output stream of a request, collected and sent in records of up to
+/// `MAX_ALIGNED_CONTENT_LEN` bytes.
+struct Buffer {
+    connection: Arc<Connection>,
+    record_type: RecordType,
+    request_id: u16,
+    aborted: AbortToken,
+    data: Vec<u8>,
+    /// Records being written to the connection.
+    sending: Option<Sending>,
+    /// Set once anything was sent, so the stream needs an empty record
+    /// ending it.
+    used: bool,
+}
+
+impl Buffer {
+    fn new(connection: Arc<Connection>, record_type: RecordType, request_id: u16, aborted: AbortToken) -> Buffer {
+        Buffer { connection, record_type, request_id, aborted, data: Vec::new(), sending: None, used: false }
+    }
+
+    /// Starts sending the collected data.
+    fn send(&mut self) {
+        if self.data.is_empty() {
+            return;
+        }
+        if self.aborted.is_aborted() {
+            self.data.clear();
+            return;
+        }
+        let mut buf = Vec::with_capacity(self.data.len() + 16);
+        protocol::encode_stream_sized(&mut buf, self.record_type, self.request_id, &self.data,
+                                      MAX_ALIGNED_CONTENT_LEN, 8);
+        self.data.clear();
+        self.used = true;
+        let connection = self.connection.clone();
+        self.sending = Some(Box::pin(async move { connection.send(&buf).await }));
+    }
+
+    /// Waits for data being sent.
+    fn poll_sending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(sending) = self.sending.as_mut() {
+            let result = ready!(sending.as_mut().poll(cx));
+            self.sending = None;
+            return Poll::Ready(result);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        ready!(self.poll_sending(cx))?;
+        if self.data.len() >= MAX_ALIGNED_CONTENT_LEN {
+            self.send();
+            // The data has been taken, so buf is accepted next time.
+            ready!(self.poll_sending(cx))?;
+        }
+        let len = buf.len().min(MAX_ALIGNED_CONTENT_LEN - self.data.len());
+        self.data.extend_from_slice(&buf[..len]);
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_sending(cx))?;
+        self.send();
+        self.poll_sending(cx)
+    }
+
+    /// Takes what is still to be sent, for a dropped request.
+    fn take(&mut self) -> impl Iterator<Item = Sending> {
+        let sending = self.sending.take();
+        self.send();
+        sending.into_iter().chain(self.sending.take())
+    }
+}
+
+/// A request received by an `AsyncListener`.
+///
+/// The input of the request, `FCGI_STDIN`, is read through `AsyncRead` and
+/// the response written through `AsyncWrite`, both without blocking the
+/// thread; use them with `tokio::io::AsyncReadExt` and `AsyncWriteExt`.
+/// `split` gives the input, the output and `FCGI_STDERR` as separate
+/// streams. Output is collected and sent in records of up to 65528 bytes,
+/// or when flushed. With the `async-futures` feature the `futures-io`
+/// traits are implemented as well.
+///
+/// The request ends with `finish`. A request dropped before is ended with
+/// status 0 in a task of its own.
+pub struct AsyncRequest {
+    connection: Arc<Connection>,
+    request_id: u16,
+    role: Role,
+    params: Vec<(String, String)>,
+    peer: Option<SocketAddr>,
+    input: Input,
+    stdout: Buffer,
+    stderr: Buffer,
+    aborted: AbortToken,
+    finished: bool,
+}
+
+impl AsyncRequest {
+    pub(super) fn new(connection: Arc<Connection>, request_id: u16, role: Role, params: Vec<(String, String)>,
+                      peer: Option<SocketAddr>, input: mpsc::Receiver<Vec<u8>>, aborted: AbortToken)
+                      -> AsyncRequest {
+        AsyncRequest {
+            stdout: Buffer::new(connection.clone(), RecordType::Stdout, request_id, aborted.clone()),
+            stderr: Buffer::new(connection.clone(), RecordType::Stderr, request_id, aborted.clone()),
+            connection,
+            request_id,
+            role,
+            params,
+            peer,
+            input: Input { receiver: input, chunk: Vec::new(), pos: 0 },
+            aborted,
+            finished: false,
+        }
+    }
+
+    /// The id the web server gave the request on its connection.
+    pub fn request_id(&self) -> u16 {
+        self.request_id
+    }
+
+    /// The role the request was started with.
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Gets the value of a FastCGI parameter.
+    pub fn param(&self, name: &str) -> Option<String> {
+        self.params.iter().find(|&(n, _)| n == name).map(|(_, value)| value.clone())
+    }
+
+    /// All FastCGI parameters of the request.
+    pub fn params(&self) -> &[(String, String)] {
+        &self.params
+    }
+
+    /// The address of the web server, for TCP connections.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    /// The token set when the web server aborts the request or closes the
+    /// connection. Output written after that is discarded.
+    pub fn abort_token(&self) -> AbortToken {
+        self.aborted.clone()
+    }
+
+    /// True once the web server aborted the request.
+    pub fn is_aborted(&self) -> bool {
+        self.aborted.is_aborted()
+    }
+
+    /// Takes the input out of the request, which then reads as empty.
+    #[cfg(feature = "tower")]
+    pub(super) fn take_input(&mut self) -> Input {
+        let (_, closed) = mpsc::channel(1);
+        let receiver = mem::replace(&mut self.input.receiver, closed);
+        Input { receiver, chunk: mem::take(&mut self.input.chunk), pos: mem::replace(&mut self.input.pos, 0) }
+    }
+
+    /// The input, `FCGI_STDIN`.
+    pub fn stdin(&mut self) -> Stdin<'_> {
+        Stdin(&mut self.input)
+    }
+
+    /// The response, `FCGI_STDOUT`.
+    pub fn stdout(&mut self) -> Output<'_> {
+        Output(&mut self.stdout)
+    }
+
+    /// The web server's error log, `FCGI_STDERR`.
+    pub fn stderr(&mut self) -> Output<'_> {
+        Output(&mut self.stderr)
+    }
+
+    /// The three streams at once, e.g. to copy the input to the output
+    /// with `tokio::io::copy` while logging to stderr.
+    pub fn split(&mut self) -> (Stdin<'_>, Output<'_>, Output<'_>) {
+        (Stdin(&mut self.input), Output(&mut self.stdout), Output(&mut self.stderr))
+    }
+
+    /// Writes a message to the web server's error log, `FCGI_STDERR`.
+    pub async fn write_stderr(&mut self, msg: &str) -> io::Result<()> {
+        let mut stderr = self.stderr();
+        stderr.write_all(msg.as_bytes()).await?;
+        stderr.flush().await
+    }
+
+    /// Sends the remaining output and ends the request with `app_status`.
+    pub async fn finish(mut self, app_status: u32) -> io::Result<()> {
+        self.finished = true;
+        let flushed = self.stdout().flush().await;
+        let flushed = flushed.and(self.stderr().flush().await);
+        let records = self.end_records(app_status);
+        let ended = self.connection.end(self.request_id, &records).await;
+        flushed.and(ended)
+    }
+
+    fn end_records(&self, app_status: u32) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(32);
+        if !self.aborted.is_aborted() {
+            protocol::encode_record(&mut buf, RecordType::Stdout, self.request_id, &[]);
+            if self.stderr.used {
+                protocol::encode_record(&mut buf, RecordType::Stderr, self.request_id, &[]);
+            }
+        }
+        EndRequest { app_status, protocol_status: ProtocolStatus::RequestComplete }
+            .to_record(self.request_id).encode(&mut buf);
+        buf
+    }
+}
+
+impl Drop for AsyncRequest {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        let sending: Vec<Sending> = self.stdout.take().chain(self.stderr.take()).collect();
+        let records = self.end_records(0);
+        let connection = self.connection.clone();
+        let request_id = self.request_id;
+        self.connection.spawner.spawn(Box::pin(async move {
+            for sending in sending {
+                if sending.await.is_err() {
+                    break;
+                }
+            }
+            let _ = connection.end(request_id, &records).await;
+        }));
+    }
+}
+
+/// The input of an `AsyncRequest`, see `AsyncRequest::stdin`.
+pub struct Stdin<'a>(&'a mut Input);
+
+/// An output stream of an `AsyncRequest`, see `AsyncRequest::stdout` and
+/// `AsyncRequest::stderr`.
+pub struct Output<'a>(&'a mut Buffer);
+
+impl AsyncRead for AsyncRequest {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().input.poll_read(cx, buf)
+    }
+}
+
+impl AsyncRead for Stdin<'_> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().0.poll_read(cx, buf)
+    }
+}
+
+/// Writing an `AsyncRequest` writes its `stdout`. Shutting it down only
+/// flushes the output; the request ends with `finish`.
+impl AsyncWrite for AsyncRequest {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().stdout.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().stdout.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().stdout.poll_flush(cx)
+    }
+}
+
+/// Shutting an output stream down only flushes it; the request ends with
+/// `AsyncRequest::finish`.
+impl AsyncWrite for Output<'_> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().0.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().0.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().0.poll_flush(cx)
+    }
+}
+
+/// Implements the `futures-io` traits through the Tokio ones.
+#[cfg(feature = "async-futures")]
+macro_rules! futures_io {
+    ($reader:ty, $writer:ty) => {
+        impl futures_io::AsyncRead for $reader {
+            fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+                let mut buf = ReadBuf::new(buf);
+                ready!(AsyncRead::poll_read(self, cx, &mut buf))?;
+                Poll::Ready(Ok(buf.filled().len()))
+            }
+        }
+
+        impl futures_io::AsyncWrite for $writer {
+            fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+                AsyncWrite::poll_write(self, cx, buf)
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                AsyncWrite::poll_flush(self, cx)
+            }
+
+            fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                AsyncWrite::poll_shutdown(self, cx)
+            }
+        }
+    };
+}
+
+#[cfg(feature = "async-futures")]
+futures_io!(AsyncRequest, AsyncRequest);
+#[cfg(feature = "async-futures")]
+futures_io!(Stdin<'_>, Output<'_>);
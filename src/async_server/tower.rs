@@ -0,0 +1,217 @@
+//! Serving a `tower::Service` over FastCGI, with the `tower` feature.
+//!
+//! Applications built from `http::Request` and `http::Response`, such as
+//! an axum `Router` or a hyper service, run behind a web server's FastCGI
+//! support with `serve_service` instead of speaking HTTP themselves. The
+//! request is rebuilt from the CGI parameters as described in the
+//! `http_compat` module, with the parameters attached as a `Params`
+//! extension. Its `Body` streams the input as the web server sends it. The
+//! response is written back with a `Status` header, as CGI
+//! expects.
+//!
+//! ```ignore
+//! let app = axum::Router::new().route("/", axum::routing::get(|| async { "Hello" }));
+//! let listener = AsyncListener::bind(&"127.0.0.1:9000".parse()?)?;
+//! fcgi::async_server::tower::serve_service(listener, app).await?;
+//! ```
+//!
+//! A service failing, or a request which cannot be represented, is
+//! answered with 500 and the error written to the web server's error log.
+
+use std::error::Error;
+use std::fmt;
+use std::future;
+use std::io;
+use std::mem;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use bytes::{Buf, Bytes};
+use http::{Request, Response};
+use http_body::{Frame, SizeHint};
+use tokio::io::AsyncWriteExt;
+use tower_service::Service;
+
+use crate::headers::reason_phrase;
+use crate::http_compat::request_from_params;
+
+use super::request::Input;
+use super::{serve, AsyncListener, AsyncRequest};
+
+pub use crate::http_compat::Params;
+
+/// The body of a request passed to the service. Made from a FastCGI
+/// request, it streams the input as it arrives, so large uploads are not
+/// held in memory.
+#[derive(Default)]
+pub struct Body {
+    kind: Kind,
+}
+
+#[derive(Default)]
+enum Kind {
+    #[default]
+    Empty,
+    Full(Bytes),
+    Input { input: Input, remaining: Option<u64> },
+}
+
+impl Body {
+    /// An empty body.
+    pub fn empty() -> Body {
+        Body::default()
+    }
+}
+
+impl From<Bytes> for Body {
+    fn from(data: Bytes) -> Body {
+        Body { kind: if data.is_empty() { Kind::Empty } else { Kind::Full(data) } }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(data: Vec<u8>) -> Body {
+        Body::from(Bytes::from(data))
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            Kind::Empty => f.write_str("Body::Empty"),
+            Kind::Full(ref data) => f.debug_tuple("Body::Full").field(&data.len()).finish(),
+            Kind::Input { remaining, .. } => f.debug_struct("Body::Input").field("remaining", &remaining).finish(),
+        }
+    }
+}
+
+impl http_body::Body for Body {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, io::Error>>> {
+        let this = self.get_mut();
+        match this.kind {
+            Kind::Empty => Poll::Ready(None),
+            Kind::Full(_) => match mem::take(&mut this.kind) {
+                Kind::Full(data) => Poll::Ready(Some(Ok(Frame::data(data)))),
+                _ => unreachable!(),
+            },
+            Kind::Input { ref mut input, ref mut remaining } => match ready!(input.poll_chunk(cx)) {
+                Some(chunk) => {
+                    if let Some(remaining) = remaining.as_mut() {
+                        *remaining = remaining.saturating_sub(chunk.len() as u64);
+                    }
+                    Poll::Ready(Some(Ok(Frame::data(Bytes::from(chunk)))))
+                }
+                None => {
+                    this.kind = Kind::Empty;
+                    Poll::Ready(None)
+                }
+            },
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        matches!(self.kind, Kind::Empty)
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self.kind {
+            Kind::Empty => SizeHint::with_exact(0),
+            Kind::Full(ref data) => SizeHint::with_exact(data.len() as u64),
+            Kind::Input { remaining: Some(remaining), .. } => SizeHint::with_exact(remaining),
+            Kind::Input { remaining: None, .. } => SizeHint::default(),
+        }
+    }
+}
+
+/// Rebuilds the HTTP request of `request`, whose input becomes the body.
+/// The input of `request` reads as empty afterwards.
+pub fn http_request(request: &mut AsyncRequest) -> io::Result<Request<Body>> {
+    let remaining = request.param("CONTENT_LENGTH").and_then(|len| len.parse().ok());
+    let body = Body { kind: Kind::Input { input: request.take_input(), remaining } };
+    request_from_params(request.params(), body)
+}
+
+/// Writes the head of the response in CGI form.
+fn response_head<B>(response: &Response<B>) -> Vec<u8> {
+    let status = response.status().as_u16();
+    let mut head = format!("Status: {} {}\r\n", status, reason_phrase(status)).into_bytes();
+    for (name, value) in response.headers() {
+        head.extend_from_slice(name.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+    head
+}
+
+/// Answers a request which the service could not handle.
+async fn fail(mut request: AsyncRequest, error: String) -> io::Result<()> {
+    let _ = request.write_stderr(&format!("{}\n", error)).await;
+    request.write_all(b"Status: 500 Internal Server Error\r\nContent-Type: text/plain\r\n\r\n").await?;
+    request.write_all(reason_phrase(500).as_bytes()).await?;
+    request.finish(0).await
+}
+
+/// Handles one request with `service`, which must be ready.
+pub async fn call<S, B>(service: &mut S, mut request: AsyncRequest) -> io::Result<()>
+    where S: Service<Request<Body>, Response = Response<B>>,
+          S::Error: Into<Box<dyn Error + Send + Sync>>,
+          B: http_body::Body,
+          B::Error: Into<Box<dyn Error + Send + Sync>>
+{
+    let http = match http_request(&mut request) {
+        Ok(http) => http,
+        Err(e) => return fail(request, e.to_string()).await,
+    };
+    let response = match service.call(http).await.map_err(|e| e.into().to_string()) {
+        Ok(response) => response,
+        Err(e) => return fail(request, e).await,
+    };
+    request.write_all(&response_head(&response)).await?;
+    let mut body = Box::pin(response.into_body());
+    loop {
+        let frame = match future::poll_fn(|cx| body.as_mut().poll_frame(cx)).await {
+            Some(frame) => frame.map_err(|e| e.into().to_string()),
+            None => break,
+        };
+        match frame {
+            Ok(frame) => {
+                if let Ok(mut data) = frame.into_data() {
+                    let data = data.copy_to_bytes(data.remaining());
+                    request.write_all(&data).await?;
+                }
+            }
+            // The head is out already, the response can only be cut short.
+            Err(e) => {
+                let _ = request.write_stderr(&format!("{}\n", e)).await;
+                break;
+            }
+        }
+    }
+    request.finish(0).await
+}
+
+/// Serves the requests of `listener` with clones of `service`, each in a
+/// task of its own. Returns when the listener fails.
+pub async fn serve_service<S, B>(listener: AsyncListener, service: S) -> io::Result<()>
+    where S: Service<Request<Body>, Response = Response<B>> + Clone + Send + 'static,
+          S::Future: Send,
+          S::Error: Into<Box<dyn Error + Send + Sync>>,
+          B: http_body::Body + Send + 'static,
+          B::Data: Send,
+          B::Error: Into<Box<dyn Error + Send + Sync>>
+{
+    serve(listener, move |request| {
+        let mut service = service.clone();
+        async move {
+            if future::poll_fn(|cx| service.poll_ready(cx)).await.is_err() {
+                return;
+            }
+            let _ = call(&mut service, request).await;
+        }
+    }).await
+}
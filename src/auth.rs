@@ -0,0 +1,257 @@
+//! Authentication shared by responders and authorizers.
+//!
+//! An `Authenticator` looks at the parameters and headers of a request and
+//! either names the `Principal` making it or answers with a `Challenge`.
+//! The `Authenticate` middleware runs one for every request and works in
+//! both roles a web server may give the application:
+//!
+//! * As a responder, the principal is stored in the exchange's extensions
+//!   and the request goes on to the handler; on a challenge the handler
+//!   is not called.
+//! * As an authorizer, e.g. for Apache's `mod_authnz_fcgi`, the request
+//!   is answered right away: with 200 and the principal as the variables
+//!   `REMOTE_USER` and `AUTH_<NAME>` for its attributes named with
+//!   letters, digits and `_`, or with the challenge, which the web server
+//!   forwards to the client.
+//!
+//! ```ignore
+//! let auth = |exchange: &Exchange| match exchange.header("X-Api-Key") {
+//!     Some(key) if keys.contains(&key) => Ok(Principal::new("service")),
+//!     _ => Err(Challenge::unauthorized("ApiKey")),
+//! };
+//! let server = ServerBuilder::new().layer(Authenticate::new(auth));
+//! ```
+//!
+//! A responder behind an authorizer reads the principal it passed on with
+//! `RemoteUser`.
+
+use crate::exchange::Exchange;
+use crate::middleware::{Middleware, Next};
+use crate::protocol::Role;
+
+/// Who makes a request, with attributes such as roles or a tenant.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Principal {
+    name: String,
+    attributes: Vec<(String, String)>,
+}
+
+impl Principal {
+    /// A principal without attributes.
+    pub fn new(name: &str) -> Principal {
+        Principal { name: String::from(name), attributes: Vec::new() }
+    }
+
+    /// Adds an attribute.
+    pub fn attribute(mut self, name: &str, value: &str) -> Principal {
+        self.attributes.push((String::from(name), String::from(value)));
+        self
+    }
+
+    /// The name, e.g. a user name or the subject of a token.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the value of an attribute.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.attributes.iter().find(|(n, _)| n == name).map(|(_, value)| value.as_str())
+    }
+
+    /// All attributes.
+    pub fn attributes(&self) -> &[(String, String)] {
+        &self.attributes
+    }
+}
+
+/// The answer to a request which is not authenticated.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Challenge {
+    status: u16,
+    headers: Vec<(String, String)>,
+}
+
+impl Challenge {
+    /// Answers with `status` and no further headers.
+    pub fn new(status: u16) -> Challenge {
+        Challenge { status, headers: Vec::new() }
+    }
+
+    /// Asks for credentials: 401 with the given `WWW-Authenticate`, e.g.
+    /// `Basic realm="admin"`.
+    pub fn unauthorized(www_authenticate: &str) -> Challenge {
+        Challenge::new(401).header("WWW-Authenticate", www_authenticate)
+    }
+
+    /// Refuses valid credentials without access: 403.
+    pub fn forbidden() -> Challenge {
+        Challenge::new(403)
+    }
+
+    /// Adds a header to the answer.
+    pub fn header(mut self, name: &str, value: &str) -> Challenge {
+        self.headers.push((String::from(name), String::from(value)));
+        self
+    }
+
+    /// The status of the answer.
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// Answers the request with the challenge, through the error pages.
+    pub fn respond(&self, exchange: &mut Exchange) {
+        for (name, value) in &self.headers {
+            exchange.headers_mut().append(name, value);
+        }
+        exchange.respond_error(self.status);
+    }
+}
+
+/// Decides who makes a request, see the module documentation. Closures
+/// taking an `&Exchange` implement this trait.
+pub trait Authenticator: Send + Sync + 'static {
+    /// Returns the principal of the request, or the challenge to answer
+    /// it with.
+    fn authenticate(&self, exchange: &Exchange) -> Result<Principal, Challenge>;
+}
+
+impl<F> Authenticator for F where F: Fn(&Exchange) -> Result<Principal, Challenge> + Send + Sync + 'static {
+    fn authenticate(&self, exchange: &Exchange) -> Result<Principal, Challenge> {
+        self(exchange)
+    }
+}
+
+/// Trusts the user the web server or an authorizer before the application
+/// authenticated, from the `REMOTE_USER` parameter, with the `AUTH_<NAME>`
+/// variables an `Authenticate` authorizer passes on as attributes, named
+/// in lower case. Requests without one are answered with 401.
+///
+/// The parameters are trusted as they come: clients cannot set them with
+/// headers, which arrive as `HTTP_*`, but the web server must not let
+/// anything else set `REMOTE_USER` or `AUTH_*` either, e.g. `SetEnv`
+/// rules or an authorizer for another application. `AUTH_TYPE`, set by
+/// the web server, and names an authorizer could not have emitted are
+/// ignored.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RemoteUser;
+
+impl Authenticator for RemoteUser {
+    fn authenticate(&self, exchange: &Exchange) -> Result<Principal, Challenge> {
+        let name = exchange.param("REMOTE_USER").filter(|name| !name.is_empty()).ok_or_else(|| Challenge::new(401))?;
+        let principal = exchange.params().into_iter()
+            .filter_map(|(param, value)| param.strip_prefix("AUTH_").filter(|&name| is_attribute(name)).map(|name| (name.to_ascii_lowercase(), value)))
+            .fold(Principal::new(&name), |principal, (name, value)| principal.attribute(&name, &value));
+        Ok(principal)
+    }
+}
+
+/// Whether `AUTH_<name>` is a variable `Authenticate` emits for an
+/// attribute, not `AUTH_TYPE`.
+fn is_attribute(name: &str) -> bool {
+    name != "TYPE" && !name.is_empty()
+        && name.bytes().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'_')
+}
+
+/// Runs an `Authenticator` for every request, as a responder or an
+/// authorizer, see the module documentation.
+pub struct Authenticate<A> {
+    authenticator: A,
+}
+
+impl<A: Authenticator> Authenticate<A> {
+    /// Wraps `authenticator`.
+    pub fn new(authenticator: A) -> Authenticate<A> {
+        Authenticate { authenticator }
+    }
+}
+
+impl<A: Authenticator> Middleware for Authenticate<A> {
+    fn call(&self, exchange: &mut Exchange, next: Next) {
+        let principal = match self.authenticator.authenticate(exchange) {
+            Ok(principal) => principal,
+            Err(challenge) => return challenge.respond(exchange),
+        };
+        if exchange.role() == Role::Authorizer {
+            exchange.set_status(200);
+            exchange.set_variable("REMOTE_USER", principal.name());
+            for (name, value) in principal.attributes() {
+                let name = name.to_ascii_uppercase();
+                if is_attribute(&name) {
+                    exchange.set_variable(&format!("AUTH_{}", name), value);
+                }
+            }
+            return;
+        }
+        exchange.extensions_mut().insert(principal);
+        next.run(exchange);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Authenticate, Challenge, Principal, RemoteUser};
+    use crate::exchange::Exchange;
+    use crate::middleware::Stack;
+    use crate::protocol::Role;
+    use crate::testing::TestRequest;
+
+    /// Authenticates requests with the key `secret` as `alice`.
+    fn stack() -> Stack {
+        Stack::new(|exchange: &mut Exchange| {
+            let name = exchange.extensions().get::<Principal>().map(|principal| String::from(principal.name()));
+            exchange.write_body(name.unwrap_or_default().as_bytes()).unwrap();
+        }).layer(Authenticate::new(|exchange: &Exchange| match exchange.header("X-Api-Key") {
+            Some(ref key) if key == "secret" => Ok(Principal::new("alice").attribute("role", "admin").attribute("x.y", "z")),
+            _ => Err(Challenge::unauthorized("ApiKey")),
+        }))
+    }
+
+    #[test]
+    fn responder() {
+        let response = TestRequest::get("/").header("X-Api-Key", "secret").run(&stack());
+        assert_eq!((response.status, &response.body[..]), (200, &b"alice"[..]));
+        assert_eq!(response.header("Variable-REMOTE_USER"), None);
+    }
+
+    #[test]
+    fn challenge() {
+        for request in [TestRequest::get("/"), TestRequest::get("/").role(Role::Authorizer)] {
+            let response = request.header("X-Api-Key", "wrong").run(&stack());
+            assert_eq!((response.status, response.header("WWW-Authenticate")), (401, Some("ApiKey")));
+            assert!(!response.body.ends_with(b"alice"));
+        }
+    }
+
+    #[test]
+    fn authorizer() {
+        let response = TestRequest::get("/").role(Role::Authorizer).header("X-Api-Key", "secret").run(&stack());
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Variable-REMOTE_USER"), Some("alice"));
+        assert_eq!(response.header("Variable-AUTH_ROLE"), Some("admin"));
+        assert_eq!(response.header("Variable-AUTH_X.Y"), None);
+        assert!(response.body.is_empty());
+    }
+
+    #[test]
+    fn remote_user() {
+        let call = |request: TestRequest| {
+            let stack = Stack::new(|exchange: &mut Exchange| {
+                let principal = exchange.extensions().get::<Principal>().unwrap().clone();
+                let attributes: Vec<String> = principal.attributes().iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+                exchange.write_body(format!("{} {}", principal.name(), attributes.join(",")).as_bytes()).unwrap();
+            }).layer(Authenticate::new(RemoteUser));
+            let mut request = request;
+            request.run(&stack)
+        };
+        let response = call(TestRequest::get("/")
+            .param("REMOTE_USER", "alice")
+            .param("AUTH_TYPE", "Basic")
+            .param("AUTH_ROLE", "admin")
+            .param("AUTH_tenant", "other")
+            .param("AUTH_", "empty"));
+        assert_eq!((response.status, &response.body[..]), (200, &b"alice role=admin"[..]));
+        assert_eq!(call(TestRequest::get("/").param("AUTH_ROLE", "admin")).status, 401);
+        assert_eq!(call(TestRequest::get("/").param("REMOTE_USER", "")).status, 401);
+    }
+}
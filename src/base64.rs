@@ -0,0 +1,54 @@
+//! Base64 as used in headers and tokens: the standard alphabet with
+//! padding, and the URL-safe one without.
+
+#[cfg(feature = "digest")]
+const STANDARD: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+#[cfg(feature = "signed-urls")]
+const URL_SAFE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+fn encode_with(data: &[u8], alphabet: &[u8; 64], pad: bool) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            encoded.push(alphabet[(n >> (18 - 6 * i) & 63) as usize] as char);
+        }
+        if pad {
+            for _ in chunk.len()..3 {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Encodes with the standard alphabet and padding.
+#[cfg(feature = "digest")]
+pub fn encode(data: &[u8]) -> String {
+    encode_with(data, STANDARD, true)
+}
+
+/// Encodes with the URL-safe alphabet, without padding.
+#[cfg(feature = "signed-urls")]
+pub fn encode_url(data: &[u8]) -> String {
+    encode_with(data, URL_SAFE, false)
+}
+
+/// Decodes the URL-safe alphabet without padding, None if `input` is not
+/// such base64.
+#[cfg(feature = "signed-urls")]
+pub fn decode_url(input: &str) -> Option<Vec<u8>> {
+    if input.len() % 4 == 1 {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(input.len() / 4 * 3 + 2);
+    for chunk in input.as_bytes().chunks(4) {
+        let mut n = 0u32;
+        for (i, &c) in chunk.iter().enumerate() {
+            let value = URL_SAFE.iter().position(|&a| a == c)? as u32;
+            n |= value << (18 - 6 * i);
+        }
+        decoded.extend_from_slice(&n.to_be_bytes()[1..chunk.len()]);
+    }
+    Some(decoded)
+}
@@ -0,0 +1,53 @@
+//! Readers for request bodies.
+
+use std::io;
+use std::io::Read;
+
+#[cfg(feature = "compression")]
+use flate2::read::{GzDecoder, ZlibDecoder};
+
+/// Wraps a reader and fails with `InvalidData` once more than `limit`
+/// bytes have been read, instead of silently truncating.
+pub struct LimitedReader<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R: Read> LimitedReader<R> {
+    /// Limits the reader to `limit` bytes.
+    pub fn new(inner: R, limit: u64) -> LimitedReader<R> {
+        LimitedReader { inner, remaining: limit }
+    }
+
+    /// Returns the wrapped reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Read one byte more than allowed to detect oversized input.
+        let max = (self.remaining + 1).min(buf.len() as u64) as usize;
+        let n = self.inner.read(&mut buf[..max])?;
+        if n as u64 > self.remaining {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "request body exceeds the size limit"));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Wraps a body reader in a decoder for the given Content-Encoding.
+/// Identity and missing encodings pass through; unsupported encodings fail
+/// with `InvalidInput`.
+#[cfg(feature = "compression")]
+pub fn decode<'r, R: Read + 'r>(encoding: Option<&str>, body: R) -> io::Result<Box<dyn Read + 'r>> {
+    match encoding.map(|e| e.trim().to_ascii_lowercase()) {
+        None => Ok(Box::new(body)),
+        Some(ref e) if e.is_empty() || e == "identity" => Ok(Box::new(body)),
+        Some(ref e) if e == "gzip" || e == "x-gzip" => Ok(Box::new(GzDecoder::new(body))),
+        Some(ref e) if e == "deflate" => Ok(Box::new(ZlibDecoder::new(body))),
+        Some(e) => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unsupported Content-Encoding {:?}", e))),
+    }
+}
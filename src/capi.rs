@@ -53,10 +53,13 @@ extern {
     pub fn FCGX_InitRequest(request: *mut FCGX_Request, sock: libc::c_int, flags: libc::c_int) -> libc::c_int;
     pub fn FCGX_Accept_r(request: *mut FCGX_Request) -> libc::c_int;
     pub fn FCGX_Finish_r(request: *mut FCGX_Request) -> libc::c_int;
+    pub fn FCGX_ShutdownPending();
     pub fn FCGX_GetParam(name: *const libc::c_char, envp: *mut libc::c_void) -> *mut libc::c_char;
     pub fn FCGX_FPrintF(stream: *mut libc::c_void, format: *const libc::c_char) -> libc::c_int;
     pub fn FCGX_PutS(format: *const libc::c_char, stream: *mut libc::c_void) -> libc::c_int;
+    pub fn FCGX_PutStr(input: *const libc::c_char, n: libc::c_int, stream: *mut libc::c_void) -> libc::c_int;
     pub fn FCGX_GetStr(input: *mut libc::c_char, n: libc::c_int, stream: *mut libc::c_void) -> libc::c_int;
     pub fn FCGX_FFlush(stream: *mut libc::c_void);
+    pub fn FCGX_StartFilterData(stream: *mut libc::c_void) -> libc::c_int;
 }
 
@@ -0,0 +1,126 @@
+//! Plain CGI as a transport: the single request of a process started by
+//! the web server for it, with the parameters in the environment, the
+//! input on stdin and the response on stdout.
+//!
+//! `server::run` picks it when the process is not running under a FastCGI
+//! socket, see `is_cgi`, so that the same binary can be deployed as CGI or
+//! tried from the command line:
+//!
+//! ```text
+//! REQUEST_METHOD=POST REQUEST_URI=/hello CONTENT_LENGTH=4 ./app <<< Test
+//! ```
+
+use std::env;
+use std::io::{self, Read, Write};
+use std::os::unix::io::RawFd;
+
+use crate::{Request, StreamType};
+
+/// The request of a CGI process. It can be accepted once.
+pub struct CgiRequest {
+    accepted: bool,
+    /// Bytes of input left according to `CONTENT_LENGTH`, if it was set.
+    remaining: Option<u64>,
+    stdout: io::BufWriter<io::Stdout>,
+}
+
+impl CgiRequest {
+    fn read_input(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = match self.remaining {
+            Some(remaining) => (buf.len() as u64).min(remaining) as usize,
+            None => buf.len(),
+        };
+        if len == 0 {
+            return Ok(0);
+        }
+        let n = io::stdin().read(&mut buf[..len])?;
+        if let Some(ref mut remaining) = self.remaining {
+            *remaining = if n == 0 { 0 } else { *remaining - n as u64 };
+        }
+        Ok(n)
+    }
+}
+
+impl Request for CgiRequest {
+    fn new() -> Option<CgiRequest> {
+        Some(CgiRequest {
+            accepted: false,
+            remaining: None,
+            stdout: io::BufWriter::new(io::stdout()),
+        })
+    }
+
+    /// Same as `new`, a CGI request always comes on stdin.
+    fn new_with_fd(_fd: RawFd) -> Option<CgiRequest> {
+        CgiRequest::new()
+    }
+
+    fn accept(&mut self) -> bool {
+        if self.accepted {
+            return false;
+        }
+        self.accepted = true;
+        // The web server may leave stdin open past the body.
+        self.remaining = self.get_param("CONTENT_LENGTH").and_then(|len| len.trim().parse().ok());
+        true
+    }
+
+    fn finish(&mut self) {
+        let _ = self.stdout.flush();
+    }
+
+    /// Values which are not valid UTF-8, e.g. raw header bytes passed on
+    /// as `HTTP_*` variables, are decoded lossily.
+    fn get_param(&self, name: &str) -> Option<String> {
+        env::var_os(name).map(|value| value.to_string_lossy().into_owned())
+    }
+
+    fn params(&self) -> Vec<(String, String)> {
+        env::vars_os()
+            .map(|(name, value)| (name.to_string_lossy().into_owned(), value.to_string_lossy().into_owned()))
+            .collect()
+    }
+
+    fn write(&mut self, msg: &str) -> i32 {
+        self.write_bytes(msg.as_bytes())
+    }
+
+    fn error(&mut self, msg: &str) -> i32 {
+        match io::stderr().write_all(msg.as_bytes()) {
+            Ok(()) => msg.len() as i32,
+            Err(_) => -1,
+        }
+    }
+
+    fn write_bytes(&mut self, buf: &[u8]) -> i32 {
+        match self.stdout.write_all(buf) {
+            Ok(()) => buf.len() as i32,
+            Err(_) => -1,
+        }
+    }
+
+    fn read_bytes(&mut self, buf: &mut [u8]) -> i32 {
+        let mut count = 0;
+        while count < buf.len() {
+            match self.read_input(&mut buf[count..]) {
+                Ok(0) => break,
+                Ok(n) => count += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return -1,
+            }
+        }
+        count as i32
+    }
+
+    fn read(&mut self, n: i32) -> (String, i32) {
+        crate::read_lossy(self, n)
+    }
+
+    fn flush(&mut self, stream_type: StreamType) {
+        match stream_type {
+            StreamType::OutStream => { let _ = self.stdout.flush(); }
+            StreamType::ErrStream => { let _ = io::stderr().flush(); }
+            StreamType::InStream => {}
+        }
+    }
+}
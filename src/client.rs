@@ -0,0 +1,332 @@
+//! A FastCGI client, speaking the protocol from the web server side.
+//!
+//! A `Client` sends requests to an application over a connection and reads
+//! back its output, for testing applications end to end and for writing
+//! gateways in Rust. Requests are sent one at a time with request id 1;
+//! the connection is kept open between them unless `set_keep_conn(false)`
+//! is used, in which case the application closes it after one request.
+//!
+//! `PhpParams` builds the parameters PHP-FPM expects for running a script,
+//! and `Response::parts` splits the CGI response the application writes
+//! into status, headers and body.
+//!
+//! ```no_run
+//! use fcgi::client::Client;
+//!
+//! let mut client = Client::connect(&"127.0.0.1:9000".parse().unwrap()).unwrap();
+//! let params = [("REQUEST_METHOD", "GET"), ("SCRIPT_NAME", "/index")];
+//! let response = client.responder(params.iter().cloned(), b"").unwrap();
+//! println!("{}", String::from_utf8_lossy(&response.stdout));
+//! ```
+
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{self, UnixStream};
+
+use crate::headers::{cgi_head_len, parse_cgi_head, Headers};
+use crate::listen::ListenAddr;
+use crate::protocol::{self, BeginRequest, EndRequest, Record, RecordType, Role};
+
+/// The request id used for all requests sent by a client.
+const REQUEST_ID: u16 = 1;
+
+/// A connection to an application, see `Client::connect`.
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn try_clone(&self) -> io::Result<Stream> {
+        match *self {
+            Stream::Tcp(ref stream) => stream.try_clone().map(Stream::Tcp),
+            Stream::Unix(ref stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.read(buf),
+            Stream::Unix(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.write(buf),
+            Stream::Unix(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.flush(),
+            Stream::Unix(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+/// A piece of the output of a request, returned by `Client::next_output`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Output {
+    /// Data written to the output stream.
+    Stdout(Vec<u8>),
+    /// Data written to the error stream.
+    Stderr(Vec<u8>),
+    /// The end of the request.
+    End(EndRequest),
+}
+
+/// The complete output of a request, returned by `Client::responder`.
+#[derive(Debug)]
+pub struct Response {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub end: EndRequest,
+}
+
+impl Response {
+    /// Splits the output into the status, the headers and the body. The
+    /// status comes from the `Status` header, 200 if there is none, and
+    /// the body starts after the first empty line.
+    pub fn parts(&self) -> (u16, Headers, &[u8]) {
+        let len = cgi_head_len(&self.stdout).unwrap_or(self.stdout.len());
+        let (status, headers) = parse_cgi_head(&self.stdout[..len]);
+        (status, headers, &self.stdout[len..])
+    }
+}
+
+/// The parameters PHP-FPM expects for running a script, as a web server
+/// would pass them. `SCRIPT_FILENAME` selects the script to run and
+/// `REDIRECT_STATUS` satisfies `cgi.force_redirect`.
+#[derive(Clone, Debug)]
+pub struct PhpParams {
+    params: Vec<(String, String)>,
+}
+
+impl PhpParams {
+    /// Parameters for a GET request of the script at `script_name` below
+    /// the document root, e.g. `PhpParams::new("/var/www", "/index.php")`.
+    pub fn new(document_root: &str, script_name: &str) -> PhpParams {
+        let root = document_root.trim_end_matches('/');
+        let params = [
+            ("GATEWAY_INTERFACE", "CGI/1.1"),
+            ("SERVER_SOFTWARE", "rust-fcgi"),
+            ("SERVER_PROTOCOL", "HTTP/1.1"),
+            ("REQUEST_METHOD", "GET"),
+            ("DOCUMENT_ROOT", root),
+            ("SCRIPT_NAME", script_name),
+            ("SCRIPT_FILENAME", &format!("{}{}", root, script_name)),
+            ("REQUEST_URI", script_name),
+            ("QUERY_STRING", ""),
+            ("REDIRECT_STATUS", "200"),
+        ];
+        PhpParams {
+            params: params.iter().map(|&(name, value)| (String::from(name), String::from(value))).collect(),
+        }
+    }
+
+    /// Sets a parameter, replacing an earlier value.
+    pub fn param(mut self, name: &str, value: &str) -> PhpParams {
+        match self.params.iter_mut().find(|param| param.0 == name) {
+            Some(param) => param.1 = String::from(value),
+            None => self.params.push((String::from(name), String::from(value))),
+        }
+        self
+    }
+
+    /// The value of a parameter.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params.iter().find(|param| param.0 == name).map(|param| param.1.as_str())
+    }
+
+    /// Sets the request method.
+    pub fn method(self, method: &str) -> PhpParams {
+        self.param("REQUEST_METHOD", method)
+    }
+
+    /// Sets the query string, without the leading '?', and adds it to
+    /// `REQUEST_URI`.
+    pub fn query_string(self, query: &str) -> PhpParams {
+        let script_name = String::from(self.get("SCRIPT_NAME").unwrap_or_default());
+        let uri = if query.is_empty() { script_name } else { format!("{}?{}", script_name, query) };
+        self.param("QUERY_STRING", query).param("REQUEST_URI", &uri)
+    }
+
+    /// Sets `PATH_INFO`, the path after the script name, and
+    /// `PATH_TRANSLATED`.
+    pub fn path_info(self, path_info: &str) -> PhpParams {
+        let translated = format!("{}{}", self.get("DOCUMENT_ROOT").unwrap_or_default(), path_info);
+        self.param("PATH_INFO", path_info).param("PATH_TRANSLATED", &translated)
+    }
+
+    /// Sets the content type of the request body.
+    pub fn content_type(self, content_type: &str) -> PhpParams {
+        self.param("CONTENT_TYPE", content_type)
+    }
+
+    /// Adds a request header, e.g. `header("Accept-Language", "de")` as
+    /// `HTTP_ACCEPT_LANGUAGE`.
+    pub fn header(self, name: &str, value: &str) -> PhpParams {
+        let name = format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_"));
+        self.param(&name, value)
+    }
+
+    /// Sets the server name and port, `SERVER_NAME` and `SERVER_PORT`.
+    pub fn server(self, name: &str, port: u16) -> PhpParams {
+        self.param("SERVER_NAME", name).param("SERVER_PORT", &port.to_string())
+    }
+
+    /// Sets the address of the client, `REMOTE_ADDR` and `REMOTE_PORT`.
+    pub fn remote(self, addr: &str, port: u16) -> PhpParams {
+        self.param("REMOTE_ADDR", addr).param("REMOTE_PORT", &port.to_string())
+    }
+
+    /// The parameters as name-value pairs.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.params.iter().map(|param| (param.0.as_str(), param.1.as_str()))
+    }
+}
+
+/// Sends requests to a FastCGI application, see the module documentation.
+pub struct Client<R: Read, W: Write> {
+    reader: BufReader<R>,
+    writer: BufWriter<W>,
+    keep_conn: bool,
+}
+
+impl Client<Stream, Stream> {
+    /// Connects to an application listening on the address.
+    pub fn connect(addr: &ListenAddr) -> io::Result<Client<Stream, Stream>> {
+        let stream = match *addr {
+            ListenAddr::Tcp(ref addr) => Stream::Tcp(TcpStream::connect(addr)?),
+            ListenAddr::Unix(ref path) => Stream::Unix(UnixStream::connect(path)?),
+            ListenAddr::Abstract(ref name) => {
+                let addr = net::SocketAddr::from_abstract_name(name.as_bytes())?;
+                Stream::Unix(UnixStream::connect_addr(&addr)?)
+            }
+            ListenAddr::Inherited(_) | ListenAddr::Systemd => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "cannot connect to an inherited socket"));
+            }
+        };
+        Ok(Client::new(stream.try_clone()?, stream))
+    }
+}
+
+impl<R: Read, W: Write> Client<R, W> {
+    /// Creates a client talking over the two halves of a connection.
+    pub fn new(reader: R, writer: W) -> Client<R, W> {
+        Client { reader: BufReader::new(reader), writer: BufWriter::new(writer), keep_conn: true }
+    }
+
+    /// Sets whether the application keeps the connection open after a
+    /// request, `FCGI_KEEP_CONN`. On by default.
+    pub fn set_keep_conn(&mut self, keep_conn: bool) {
+        self.keep_conn = keep_conn;
+    }
+
+    /// Sends a request: its parameters, then the standard input read from
+    /// `stdin` until its end. Read the output with `next_output`.
+    pub fn send_request<'a, I, S>(&mut self, role: Role, params: I, stdin: &mut S) -> io::Result<()>
+        where I: IntoIterator<Item = (&'a str, &'a str)>, S: Read + ?Sized
+    {
+        let flags = if self.keep_conn { protocol::KEEP_CONN } else { 0 };
+        let mut buf = Vec::new();
+        BeginRequest { role, flags }.to_record(REQUEST_ID).encode(&mut buf);
+        let params = protocol::encode_name_values(params.into_iter().map(|(name, value)| {
+            (name.as_bytes(), value.as_bytes())
+        }));
+        protocol::encode_stream(&mut buf, RecordType::Params, REQUEST_ID, &params);
+        protocol::encode_record(&mut buf, RecordType::Params, REQUEST_ID, &[]);
+        self.writer.write_all(&buf)?;
+        let mut chunk = vec![0; protocol::MAX_CONTENT_LEN];
+        loop {
+            let n = stdin.read(&mut chunk)?;
+            buf.clear();
+            protocol::encode_record(&mut buf, RecordType::Stdin, REQUEST_ID, &chunk[..n]);
+            self.writer.write_all(&buf)?;
+            if n == 0 {
+                break;
+            }
+        }
+        self.writer.flush()
+    }
+
+    /// Reads the next piece of output of the request sent last. Records for
+    /// other requests and management records are skipped.
+    pub fn next_output(&mut self) -> io::Result<Output> {
+        loop {
+            let record = match Record::read_from(&mut self.reader)? {
+                Some(record) => record,
+                None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by the application")),
+            };
+            if record.request_id != REQUEST_ID {
+                continue;
+            }
+            match record.record_type {
+                RecordType::Stdout if !record.content.is_empty() => return Ok(Output::Stdout(record.content)),
+                RecordType::Stderr if !record.content.is_empty() => return Ok(Output::Stderr(record.content)),
+                RecordType::EndRequest => return Ok(Output::End(record.end_request()?)),
+                _ => {}
+            }
+        }
+    }
+
+    /// Sends a responder request and collects its output.
+    pub fn responder<'a, I>(&mut self, params: I, body: &[u8]) -> io::Result<Response>
+        where I: IntoIterator<Item = (&'a str, &'a str)>
+    {
+        self.send_request(Role::Responder, params, &mut io::Cursor::new(body))?;
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        loop {
+            match self.next_output()? {
+                Output::Stdout(data) => stdout.extend_from_slice(&data),
+                Output::Stderr(data) => stderr.extend_from_slice(&data),
+                Output::End(end) => return Ok(Response { stdout, stderr, end }),
+            }
+        }
+    }
+
+    /// Runs a PHP script with the body as its input, setting
+    /// `CONTENT_LENGTH` to the length of the body.
+    pub fn php(&mut self, params: &PhpParams, body: &[u8]) -> io::Result<Response> {
+        let length = body.len().to_string();
+        let params = params.iter()
+            .filter(|param| param.0 != "CONTENT_LENGTH")
+            .chain(Some(("CONTENT_LENGTH", length.as_str())));
+        self.responder(params, body)
+    }
+
+    /// Asks the application for the values of variables such as
+    /// `FCGI_MAX_CONNS` with `FCGI_GET_VALUES`. Unknown variables are left
+    /// out of the result.
+    pub fn get_values(&mut self, names: &[&str]) -> io::Result<Vec<(String, String)>> {
+        let query = protocol::encode_name_values(names.iter().map(|name| (name.as_bytes(), &b""[..])));
+        let mut buf = Vec::new();
+        protocol::encode_record(&mut buf, RecordType::GetValues, protocol::NULL_REQUEST_ID, &query);
+        self.writer.write_all(&buf)?;
+        self.writer.flush()?;
+        loop {
+            let record = match Record::read_from(&mut self.reader)? {
+                Some(record) => record,
+                None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by the application")),
+            };
+            if record.record_type != RecordType::GetValuesResult {
+                continue;
+            }
+            return protocol::name_values(&record.content)
+                .map(|pair| {
+                    let (name, value) = pair?;
+                    Ok((String::from_utf8_lossy(name).into_owned(), String::from_utf8_lossy(value).into_owned()))
+                })
+                .collect();
+        }
+    }
+}
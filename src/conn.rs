@@ -0,0 +1,217 @@
+//! Connections accepted from a listen socket by the transports which handle
+//! one request per connection, such as SCGI.
+
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::mem;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait before accepting again when out of file descriptors.
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+/// How long a new connection may take to send the parameters, so that a
+/// stalled peer does not hold a worker.
+const PARAMS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Set by `shutdown_pending`.
+static SHUTDOWN_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Makes `accept` give up when interrupted by a signal, instead of
+/// retrying.
+pub(crate) fn shutdown_pending() {
+    SHUTDOWN_PENDING.store(true, Ordering::SeqCst);
+}
+
+/// An accepted connection, from TCP or a Unix socket.
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub(crate) fn try_clone(&self) -> io::Result<Stream> {
+        match *self {
+            Stream::Tcp(ref stream) => stream.try_clone().map(Stream::Tcp),
+            Stream::Unix(ref stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+
+    /// The address of the peer, None for Unix sockets.
+    pub(crate) fn peer_addr(&self) -> Option<SocketAddr> {
+        match *self {
+            Stream::Tcp(ref stream) => stream.peer_addr().ok(),
+            Stream::Unix(_) => None,
+        }
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref stream) => stream.set_read_timeout(timeout),
+            Stream::Unix(ref stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub(crate) fn shutdown(&self) {
+        let _ = match *self {
+            Stream::Tcp(ref stream) => stream.shutdown(Shutdown::Both),
+            Stream::Unix(ref stream) => stream.shutdown(Shutdown::Both),
+        };
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.read(buf),
+            Stream::Unix(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.write(buf),
+            Stream::Unix(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.flush(),
+            Stream::Unix(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+/// Accepts a connection from a listen socket, retrying after aborted
+/// connections, while out of file descriptors and after interrupts unless
+/// a shutdown is pending. Fails once the socket does, e.g. when it is shut
+/// down on shutdown of the server.
+pub(crate) fn accept(listen_fd: RawFd) -> io::Result<Stream> {
+    loop {
+        let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut len = mem::size_of_val(&addr) as libc::socklen_t;
+        let fd = unsafe { libc::accept(listen_fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) };
+        if fd >= 0 {
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+            return Ok(if addr.ss_family as libc::c_int == libc::AF_UNIX {
+                Stream::Unix(unsafe { UnixStream::from_raw_fd(fd) })
+            } else {
+                Stream::Tcp(unsafe { TcpStream::from_raw_fd(fd) })
+            });
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::EINTR) if SHUTDOWN_PENDING.load(Ordering::SeqCst) => return Err(e),
+            Some(libc::EINTR) | Some(libc::ECONNABORTED) => {}
+            Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::ENOBUFS) | Some(libc::ENOMEM) => {
+                thread::sleep(ACCEPT_RETRY)
+            }
+            _ => return Err(e),
+        }
+    }
+}
+
+/// Reads the parameters at the start of a connection.
+pub(crate) type ReadParams = fn(&mut BufReader<Stream>) -> io::Result<Vec<(String, String)>>;
+
+/// A request on a connection of its own: the parameters, then
+/// `CONTENT_LENGTH` bytes of input. The output is written back until the
+/// connection is closed.
+pub(crate) struct Connection {
+    params: Vec<(String, String)>,
+    input: io::Take<BufReader<Stream>>,
+    output: BufWriter<Stream>,
+    peer: Option<SocketAddr>,
+}
+
+impl Connection {
+    fn start(stream: Stream, read_params: ReadParams) -> io::Result<Connection> {
+        let peer = stream.peer_addr();
+        let output = BufWriter::new(stream.try_clone()?);
+        stream.set_read_timeout(Some(PARAMS_TIMEOUT))?;
+        let mut reader = BufReader::new(stream);
+        let params = read_params(&mut reader).map_err(|e| match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+                io::Error::new(io::ErrorKind::TimedOut, "timed out reading the request parameters")
+            }
+            _ => e,
+        })?;
+        reader.get_ref().set_read_timeout(None)?;
+        let content_length = match params.iter().find(|&(name, _)| name == "CONTENT_LENGTH") {
+            Some((_, len)) if !len.is_empty() => {
+                len.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad CONTENT_LENGTH"))?
+            }
+            _ => 0,
+        };
+        Ok(Connection { params, input: reader.take(content_length), output, peer })
+    }
+
+    pub(crate) fn param(&self, name: &str) -> Option<String> {
+        self.params.iter().find(|&(n, _)| n == name).map(|(_, value)| value.clone())
+    }
+
+    pub(crate) fn params(&self) -> &[(String, String)] {
+        &self.params
+    }
+
+    pub(crate) fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    /// Fills `buf` from the input, returning less only at its end, or -1
+    /// on errors.
+    pub(crate) fn read(&mut self, buf: &mut [u8]) -> i32 {
+        let mut count = 0;
+        while count < buf.len() {
+            match self.input.read(&mut buf[count..]) {
+                Ok(0) => break,
+                Ok(n) => count += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return -1,
+            }
+        }
+        count as i32
+    }
+
+    pub(crate) fn write(&mut self, buf: &[u8]) -> i32 {
+        match self.output.write_all(buf) {
+            Ok(()) => buf.len() as i32,
+            Err(_) => -1,
+        }
+    }
+
+    pub(crate) fn flush(&mut self) {
+        let _ = self.output.flush();
+    }
+
+    /// Sends the remaining output and closes the connection.
+    pub(crate) fn close(mut self) {
+        let _ = self.output.flush();
+        self.output.get_ref().shutdown();
+    }
+}
+
+/// Accepts connections until one starts with valid parameters, closing the
+/// others. Returns None once the listen socket fails.
+pub(crate) fn accept_request(listen_fd: RawFd, read_params: ReadParams) -> Option<Connection> {
+    loop {
+        let stream = accept(listen_fd).ok()?;
+        match Connection::start(stream, read_params) {
+            Ok(connection) => return Some(connection),
+            Err(e) => server_log!(warn, "{}", e),
+        }
+    }
+}
+
+/// Writes to stderr, for transports without an error stream.
+pub(crate) fn write_stderr(msg: &str) -> i32 {
+    match io::stderr().write_all(msg.as_bytes()) {
+        Ok(()) => msg.len() as i32,
+        Err(_) => -1,
+    }
+}
@@ -0,0 +1,125 @@
+//! Streaming CSV, for report downloads.
+//!
+//! Fields are quoted as RFC 4180 describes: those containing the
+//! delimiter, a quote or a line break are put in quotes, with quotes
+//! doubled. Records end with CRLF. Like `NdjsonWriter`, the output is
+//! passed on in blocks and flushed at least once per flush interval:
+//!
+//! ```ignore
+//! exchange.set_attachment("report.csv");
+//! let mut csv = exchange.csv().delimiter(';');
+//! csv.header(&["id", "name"])?;
+//! for user in users {
+//!     csv.write_record(&[user.id.to_string(), user.name])?;
+//! }
+//! csv.finish()?;
+//! ```
+
+use std::io::{self, BufWriter, Write};
+use std::time::{Duration, Instant};
+
+/// The Content-Type of CSV responses.
+pub const CONTENT_TYPE: &str = "text/csv; charset=utf-8";
+
+/// How much output is collected before it is written.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Writes records as CSV, see the module documentation.
+pub struct CsvWriter<W: Write> {
+    out: BufWriter<W>,
+    delimiter: char,
+    flush_interval: Duration,
+    last_flush: Instant,
+    records: u64,
+}
+
+impl<W: Write> CsvWriter<W> {
+    /// Writes comma-separated records to `out`, flushing at least once a
+    /// second.
+    pub fn new(out: W) -> CsvWriter<W> {
+        CsvWriter {
+            out: BufWriter::with_capacity(BUFFER_SIZE, out),
+            delimiter: ',',
+            flush_interval: Duration::from_secs(1),
+            last_flush: Instant::now(),
+            records: 0,
+        }
+    }
+
+    /// Sets the field delimiter, e.g. `;` as spreadsheets in many locales
+    /// expect, or a tab.
+    ///
+    /// # Panics
+    ///
+    /// If the delimiter is a quote or a line break.
+    pub fn delimiter(mut self, delimiter: char) -> CsvWriter<W> {
+        assert!(!matches!(delimiter, '"' | '\r' | '\n'), "invalid CSV delimiter {:?}", delimiter);
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Sets how long written records may wait in the buffer.
+    pub fn flush_interval(mut self, interval: Duration) -> CsvWriter<W> {
+        self.flush_interval = interval;
+        self
+    }
+
+    /// Writes the header row. This is an ordinary record, which is not
+    /// counted.
+    pub fn header<I>(&mut self, names: I) -> io::Result<()>
+        where I: IntoIterator, I::Item: AsRef<str>
+    {
+        self.write_fields(names)
+    }
+
+    /// Writes a record.
+    pub fn write_record<I>(&mut self, fields: I) -> io::Result<()>
+        where I: IntoIterator, I::Item: AsRef<str>
+    {
+        self.write_fields(fields)?;
+        self.records += 1;
+        if self.last_flush.elapsed() >= self.flush_interval {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Sends the buffered records to the client.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.last_flush = Instant::now();
+        self.out.flush()
+    }
+
+    /// The number of records written so far, without the header.
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    /// Flushes the rest of the output and returns the number of records
+    /// written.
+    pub fn finish(mut self) -> io::Result<u64> {
+        self.flush()?;
+        Ok(self.records)
+    }
+
+    fn write_fields<I>(&mut self, fields: I) -> io::Result<()>
+        where I: IntoIterator, I::Item: AsRef<str>
+    {
+        let mut delimiter = [0; 4];
+        let delimiter = self.delimiter.encode_utf8(&mut delimiter).as_bytes();
+        for (i, field) in fields.into_iter().enumerate() {
+            if i > 0 {
+                self.out.write_all(delimiter)?;
+            }
+            let field = field.as_ref();
+            if field.contains([self.delimiter, '"', '\r', '\n']) {
+                self.out.write_all(b"\"")?;
+                self.out.write_all(field.replace('"', "\"\"").as_bytes())?;
+                self.out.write_all(b"\"")?;
+            } else {
+                self.out.write_all(field.as_bytes())?;
+            }
+        }
+        self.out.write_all(b"\r\n")
+    }
+}
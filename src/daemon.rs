@@ -0,0 +1,244 @@
+//! Process setup for servers run as classic Unix daemons.
+//!
+//! A server started as root, e.g. to bind a privileged port or create a
+//! socket in a root-owned directory, should give up root before it serves
+//! requests. `drop_privileges` switches to an unprivileged user and group
+//! and clears the supplementary groups inherited from root.
+//!
+//! For defense in depth, `confine` changes the root directory to a jail and
+//! the working directory within it. User and group names are resolved
+//! before, as the jail usually lacks the user database.
+//!
+//! A `PidFile` records the process id for init scripts and monitoring
+//! tools. It stays locked while the server runs, so a second instance
+//! refuses to start, and a file left behind by a crash is taken over.
+//!
+//! Without systemd or a supervisor, `daemonize` moves the process into the
+//! background.
+
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use libc;
+
+/// Buffer size for the reentrant passwd and group lookups.
+const LOOKUP_BUFFER_SIZE: usize = 16 * 1024;
+
+fn to_c_string(name: &str) -> io::Result<CString> {
+    CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Looks up a user by name or numeric id, returning the user id and the
+/// primary group id. The group is None for a numeric id without an entry
+/// in the user database.
+pub fn lookup_user(user: &str) -> io::Result<(u32, Option<u32>)> {
+    let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; LOOKUP_BUFFER_SIZE];
+    let mut result = ptr::null_mut();
+    let numeric = user.parse::<u32>().ok();
+    let rc = match numeric {
+        Some(uid) => unsafe {
+            libc::getpwuid_r(uid as libc::uid_t, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result)
+        },
+        None => {
+            let name = to_c_string(user)?;
+            unsafe { libc::getpwnam_r(name.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) }
+        }
+    };
+    if rc != 0 {
+        return Err(io::Error::from_raw_os_error(rc));
+    }
+    match (result.is_null(), numeric) {
+        (false, _) => Ok((pwd.pw_uid as u32, Some(pwd.pw_gid as u32))),
+        (true, Some(uid)) => Ok((uid, None)),
+        (true, None) => Err(io::Error::new(io::ErrorKind::NotFound, format!("unknown user {:?}", user))),
+    }
+}
+
+/// Looks up a group by name or numeric id.
+pub fn lookup_group(group: &str) -> io::Result<u32> {
+    if let Ok(gid) = group.parse::<u32>() {
+        return Ok(gid);
+    }
+    let name = to_c_string(group)?;
+    let mut grp: libc::group = unsafe { mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; LOOKUP_BUFFER_SIZE];
+    let mut result = ptr::null_mut();
+    let rc = unsafe { libc::getgrnam_r(name.as_ptr(), &mut grp, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if rc != 0 {
+        return Err(io::Error::from_raw_os_error(rc));
+    }
+    if result.is_null() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("unknown group {:?}", group)));
+    }
+    Ok(grp.gr_gid as u32)
+}
+
+/// A resolved user and group to switch to, see `drop_privileges`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Credentials {
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+impl Credentials {
+    /// Resolves the user and group, each given by name or numeric id. The
+    /// group defaults to the user's primary group.
+    pub fn resolve(user: Option<&str>, group: Option<&str>) -> io::Result<Credentials> {
+        let user = match user {
+            Some(user) => Some(lookup_user(user)?),
+            None => None,
+        };
+        let gid = match group {
+            Some(group) => Some(lookup_group(group)?),
+            None => user.and_then(|(_, gid)| gid),
+        };
+        if user.is_some() && gid.is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no group given for a user id without a user entry"));
+        }
+        Ok(Credentials { uid: user.map(|(uid, _)| uid), gid })
+    }
+
+    /// Switches the process to the resolved user and group. The
+    /// supplementary groups are replaced by the new group. Fails if root
+    /// privileges could be regained afterwards.
+    pub fn apply(&self) -> io::Result<()> {
+        if let Some(gid) = self.gid {
+            let gid = gid as libc::gid_t;
+            unsafe {
+                if libc::setgroups(1, &gid) != 0 || libc::setgid(gid) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+        }
+        if let Some(uid) = self.uid {
+            unsafe {
+                if libc::setuid(uid as libc::uid_t) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                if uid != 0 && libc::setuid(0) == 0 {
+                    return Err(io::Error::new(io::ErrorKind::PermissionDenied, "root privileges could be regained"));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Switches the process to the given user and group, each given by name
+/// or numeric id, see `Credentials`.
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> io::Result<()> {
+    Credentials::resolve(user, group)?.apply()
+}
+
+/// Changes the root directory to `root` if given, then the working
+/// directory to `dir`, which is taken relative to the new root, or to the
+/// new root itself. Changing the root requires root privileges.
+pub fn confine(root: Option<&Path>, dir: Option<&Path>) -> io::Result<()> {
+    if let Some(root) = root {
+        let c_root = CString::new(root.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        if unsafe { libc::chroot(c_root.as_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    match dir {
+        Some(dir) => std::env::set_current_dir(dir),
+        None if root.is_some() => std::env::set_current_dir("/"),
+        None => Ok(()),
+    }
+}
+
+/// Detaches the process from its terminal: forks twice with a new session
+/// in between, so the daemon is not a session leader and cannot acquire a
+/// controlling terminal again. Only the grandchild returns; the original
+/// process exits with status 0.
+///
+/// Stdout and stderr are redirected to `error_log`, opened for appending,
+/// or to /dev/null. Stdin is redirected to /dev/null unless it is a socket,
+/// which is taken to be the FastCGI listen socket and kept. The working
+/// directory is not changed.
+///
+/// Must be called before any threads are started, as only the calling
+/// thread survives a fork.
+pub fn daemonize(error_log: Option<&Path>) -> io::Result<()> {
+    let null = OpenOptions::new().read(true).write(true).open("/dev/null")?;
+    let log = match error_log {
+        Some(path) => OpenOptions::new().create(true).append(true).open(path)?,
+        None => null.try_clone()?,
+    };
+    fork_and_exit_parent()?;
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    fork_and_exit_parent()?;
+    unsafe {
+        if !is_socket(0) && libc::dup2(null.as_raw_fd(), 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::dup2(log.as_raw_fd(), 1) < 0 || libc::dup2(log.as_raw_fd(), 2) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn fork_and_exit_parent() -> io::Result<()> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+fn is_socket(fd: libc::c_int) -> bool {
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    unsafe { libc::fstat(fd, &mut stat) == 0 && (stat.st_mode & libc::S_IFMT) == libc::S_IFSOCK }
+}
+
+/// A locked file containing the process id. The file is removed when the
+/// `PidFile` is dropped.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl PidFile {
+    /// Creates or takes over the PID file and writes the current process
+    /// id to it. Fails with `AlreadyExists` if another process holds it.
+    pub fn create<P: Into<PathBuf>>(path: P) -> io::Result<PidFile> {
+        let path = path.into();
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::WouldBlock {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                    format!("PID file {} is locked by a running process", path.display())));
+            }
+            return Err(e);
+        }
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+        file.sync_all()?;
+        Ok(PidFile { path, file })
+    }
+
+    /// The path of the PID file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+        unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
@@ -0,0 +1,421 @@
+//! A plain HTTP/1.1 transport for development, so handlers can be tried
+//! with a browser or curl without setting up a web server.
+//!
+//! `DevRequest` reads HTTP requests and synthesizes the parameters a web
+//! server would pass over FastCGI: `REQUEST_METHOD`, `REQUEST_URI`,
+//! `PATH_INFO` (decoded), `QUERY_STRING`, `SERVER_NAME` and `SERVER_PORT`
+//! from the `Host` header, `REMOTE_ADDR`, `CONTENT_TYPE`, `CONTENT_LENGTH`
+//! and `HTTP_*` for the other headers. Bodies with a `Content-Length` or
+//! chunked transfer coding are supported, and `Expect: 100-continue` is
+//! answered. The CGI response of the handler is turned into an HTTP
+//! response, with the status line taken from the `Status` header. When
+//! sending fails because the client went away, the request's
+//! `AbortToken` is set.
+//!
+//! Every connection serves one request and is then closed. Start the
+//! server with `protocol = "http"`:
+//!
+//! ```text
+//! FCGI_PROTOCOL=http FCGI_LISTEN=127.0.0.1:8080 ./target/debug/app
+//! curl http://127.0.0.1:8080/hello
+//! ```
+//!
+//! This is meant for local development only: it is not hardened against
+//! hostile clients, and it does not support keep-alive or TLS.
+
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+use crate::abort::AbortToken;
+use crate::conn::{self, Stream};
+use crate::headers::{cgi_head_len, parse_cgi_head, reason_phrase};
+use crate::urlencoding::decode_path;
+use crate::{Request, StreamType};
+
+/// The longest request head accepted.
+const MAX_HEAD_LEN: usize = 64 * 1024;
+/// How long a new connection may take to send the request head.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid HTTP request: {}", message))
+}
+
+/// Reads a line of the request head without the line break.
+fn read_line<R: BufRead>(reader: &mut R, head_len: &mut usize) -> io::Result<String> {
+    let mut line = Vec::new();
+    reader.take((MAX_HEAD_LEN - *head_len) as u64).read_until(b'\n', &mut line)?;
+    *head_len += line.len();
+    if line.pop() != Some(b'\n') {
+        return Err(invalid("request head too large or cut short"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).map_err(|_| invalid("request head is not UTF-8"))
+}
+
+/// Reads the request line and headers and turns them into CGI
+/// parameters. `local` is the address the connection came in on, for
+/// requests without a `Host` header.
+pub fn read_params<R: BufRead>(reader: &mut R, peer: Option<SocketAddr>, local: Option<SocketAddr>)
+                               -> io::Result<Vec<(String, String)>> {
+    let mut head_len = 0;
+    let mut line = read_line(reader, &mut head_len)?;
+    // Clients may send empty lines between requests.
+    while line.is_empty() {
+        line = read_line(reader, &mut head_len)?;
+    }
+    let mut parts = line.split(' ');
+    let (method, uri, protocol) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(uri), Some(protocol), None) if protocol.starts_with("HTTP/1.") => (method, uri, protocol),
+        _ => return Err(invalid("bad request line")),
+    };
+    let (path, query) = match uri.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (uri, ""),
+    };
+    if !path.starts_with('/') {
+        return Err(invalid("the request target must be a path"));
+    }
+    let path_info = decode_path(path).ok_or_else(|| invalid("bad escape in the path"))?;
+    let mut params = vec![
+        (String::from("GATEWAY_INTERFACE"), String::from("CGI/1.1")),
+        (String::from("SERVER_SOFTWARE"), format!("fcgi-dev-server/{}", env!("CARGO_PKG_VERSION"))),
+        (String::from("SERVER_PROTOCOL"), String::from(protocol)),
+        (String::from("REQUEST_METHOD"), String::from(method)),
+        (String::from("REQUEST_URI"), String::from(uri)),
+        (String::from("SCRIPT_NAME"), String::new()),
+        (String::from("PATH_INFO"), path_info),
+        (String::from("QUERY_STRING"), String::from(query)),
+    ];
+    if let Some(peer) = peer {
+        params.push((String::from("REMOTE_ADDR"), peer.ip().to_string()));
+        params.push((String::from("REMOTE_PORT"), peer.port().to_string()));
+    }
+    let mut host = None;
+    loop {
+        let line = read_line(reader, &mut head_len)?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').ok_or_else(|| invalid("bad header line"))?;
+        let (name, value) = (name.trim(), value.trim());
+        if name.eq_ignore_ascii_case("Host") {
+            host = Some(String::from(value));
+        }
+        let name = name.to_ascii_uppercase().replace('-', "_");
+        let name = match name.as_str() {
+            "CONTENT_TYPE" | "CONTENT_LENGTH" => name,
+            _ => format!("HTTP_{}", name),
+        };
+        match params.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, existing)) => {
+                existing.push_str(", ");
+                existing.push_str(value);
+            }
+            None => params.push((name, String::from(value))),
+        }
+    }
+    let (server_name, server_port) = match host {
+        Some(ref host) => match host.rsplit_once(':') {
+            Some((name, port)) if !port.contains(']') => (String::from(name), String::from(port)),
+            _ => (host.clone(), String::from("80")),
+        },
+        None => match local {
+            Some(local) => (local.ip().to_string(), local.port().to_string()),
+            None => (String::from("localhost"), String::from("80")),
+        },
+    };
+    params.push((String::from("SERVER_NAME"), server_name));
+    params.push((String::from("SERVER_PORT"), server_port));
+    Ok(params)
+}
+
+/// How the body of the request is delimited.
+enum Body {
+    Length(u64),
+    /// Chunked, with the bytes left in the current chunk, None before the
+    /// first chunk.
+    Chunked(Option<u64>),
+    Done,
+}
+
+/// The request being handled.
+struct Current {
+    params: Vec<(String, String)>,
+    peer: Option<SocketAddr>,
+    reader: BufReader<Stream>,
+    writer: BufWriter<Stream>,
+    body: Body,
+    /// Set if the client waits for `100 Continue` before sending the body.
+    expects_continue: bool,
+    /// Output collected until the end of the CGI head, None once the
+    /// status line and headers have been sent.
+    head: Option<Vec<u8>>,
+    aborted: AbortToken,
+}
+
+impl Current {
+    fn start(stream: Stream) -> io::Result<Current> {
+        let peer = stream.peer_addr();
+        let local = match stream {
+            Stream::Tcp(ref stream) => stream.local_addr().ok(),
+            Stream::Unix(_) => None,
+        };
+        let writer = BufWriter::new(stream.try_clone()?);
+        stream.set_read_timeout(Some(HEAD_TIMEOUT))?;
+        let mut reader = BufReader::new(stream);
+        let params = read_params(&mut reader, peer, local).map_err(|e| match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => invalid("timed out reading the request head"),
+            _ => e,
+        })?;
+        reader.get_ref().set_read_timeout(None)?;
+        let param = |name: &str| params.iter().find(|&(n, _)| n == name).map(|(_, value)| value.as_str());
+        let chunked = param("HTTP_TRANSFER_ENCODING").is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"));
+        let body = match param("CONTENT_LENGTH") {
+            _ if chunked => Body::Chunked(None),
+            Some(len) => Body::Length(len.parse().map_err(|_| invalid("bad Content-Length"))?),
+            None => Body::Done,
+        };
+        let expects_continue = param("HTTP_EXPECT").is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"));
+        Ok(Current { params, peer, reader, writer, body, expects_continue, head: Some(Vec::new()),
+                     aborted: AbortToken::new() })
+    }
+
+    fn param(&self, name: &str) -> Option<String> {
+        self.params.iter().find(|&(n, _)| n == name).map(|(_, value)| value.clone())
+    }
+
+    /// Reads the size line of the next chunk, skipping extensions.
+    fn read_chunk_size(&mut self) -> io::Result<u64> {
+        let mut head_len = 0;
+        let line = read_line(&mut self.reader, &mut head_len)?;
+        let size = line.split(';').next().unwrap_or("").trim();
+        u64::from_str_radix(size, 16).map_err(|_| invalid("bad chunk size"))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.expects_continue {
+            self.expects_continue = false;
+            if self.head.is_some() {
+                self.writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+                self.writer.flush()?;
+            }
+        }
+        loop {
+            match self.body {
+                Body::Done => return Ok(0),
+                Body::Length(0) => self.body = Body::Done,
+                Body::Length(remaining) => {
+                    let len = (buf.len() as u64).min(remaining) as usize;
+                    let n = self.reader.read(&mut buf[..len])?;
+                    if n == 0 {
+                        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                    }
+                    self.body = Body::Length(remaining - n as u64);
+                    return Ok(n);
+                }
+                Body::Chunked(None) | Body::Chunked(Some(0)) => {
+                    if let Body::Chunked(Some(0)) = self.body {
+                        let mut head_len = 0;
+                        read_line(&mut self.reader, &mut head_len)?;
+                    }
+                    let size = self.read_chunk_size()?;
+                    if size == 0 {
+                        // Skip the trailer.
+                        let mut head_len = 0;
+                        while !read_line(&mut self.reader, &mut head_len)?.is_empty() {}
+                        self.body = Body::Done;
+                    } else {
+                        self.body = Body::Chunked(Some(size));
+                    }
+                }
+                Body::Chunked(Some(remaining)) => {
+                    let len = (buf.len() as u64).min(remaining) as usize;
+                    let n = self.reader.read(&mut buf[..len])?;
+                    if n == 0 {
+                        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                    }
+                    self.body = Body::Chunked(Some(remaining - n as u64));
+                    return Ok(n);
+                }
+            }
+        }
+    }
+
+    /// Sends the status line and headers made from the CGI head.
+    fn send_head(&mut self, head: &[u8]) -> io::Result<()> {
+        let (status, headers) = parse_cgi_head(head);
+        write!(self.writer, "HTTP/1.1 {} {}\r\n", status, reason_phrase(status))?;
+        for (name, value) in headers.iter() {
+            if !name.eq_ignore_ascii_case("Connection") {
+                write!(self.writer, "{}: {}\r\n", name, value)?;
+            }
+        }
+        self.writer.write_all(b"Connection: close\r\n\r\n")
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self.head.take() {
+            Some(mut head) => {
+                head.extend_from_slice(buf);
+                match cgi_head_len(&head) {
+                    Some(len) => {
+                        self.send_head(&head[..len])?;
+                        self.writer.write_all(&head[len..])
+                    }
+                    None => {
+                        self.head = Some(head);
+                        Ok(())
+                    }
+                }
+            }
+            None => self.writer.write_all(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.head.is_none() {
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Sends the rest of the response.
+    fn end(&mut self) -> io::Result<()> {
+        if let Some(head) = self.head.take() {
+            self.send_head(&head)?;
+        }
+        self.writer.flush()
+    }
+}
+
+/// An HTTP request, accepted from a listen socket, see the module
+/// documentation.
+pub struct DevRequest {
+    listen_fd: RawFd,
+    current: Option<Current>,
+}
+
+impl DevRequest {
+    /// Answers a request which could not be parsed.
+    fn reject(mut stream: Stream, error: &io::Error) {
+        let body = format!("{}\n", error);
+        let _ = write!(stream, "HTTP/1.1 400 Bad Request\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\
+                                Connection: close\r\n\r\n{}", body.len(), body);
+        stream.shutdown();
+    }
+}
+
+impl Request for DevRequest {
+    /// Accepts requests from the socket passed as stdin.
+    fn new() -> Option<DevRequest> {
+        DevRequest::new_with_fd(0)
+    }
+
+    fn new_with_fd(fd: RawFd) -> Option<DevRequest> {
+        Some(DevRequest { listen_fd: fd, current: None })
+    }
+
+    /// Finishes the previous request and accepts the next. Requests which
+    /// cannot be parsed are answered with 400.
+    fn accept(&mut self) -> bool {
+        self.finish();
+        loop {
+            let stream = match conn::accept(self.listen_fd) {
+                Ok(stream) => stream,
+                Err(_) => return false,
+            };
+            let reply = match stream.try_clone() {
+                Ok(reply) => reply,
+                Err(_) => continue,
+            };
+            match Current::start(stream) {
+                Ok(current) => {
+                    self.current = Some(current);
+                    return true;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+                Err(e) => DevRequest::reject(reply, &e),
+            }
+        }
+    }
+
+    /// Sends the rest of the response and closes the connection.
+    fn finish(&mut self) {
+        if let Some(mut current) = self.current.take() {
+            let _ = current.end();
+            current.writer.get_ref().shutdown();
+        }
+    }
+
+    fn get_param(&self, name: &str) -> Option<String> {
+        self.current.as_ref()?.param(name)
+    }
+
+    fn params(&self) -> Vec<(String, String)> {
+        self.current.as_ref().map(|current| current.params.clone()).unwrap_or_default()
+    }
+
+    fn write(&mut self, msg: &str) -> i32 {
+        self.write_bytes(msg.as_bytes())
+    }
+
+    fn error(&mut self, msg: &str) -> i32 {
+        conn::write_stderr(msg)
+    }
+
+    fn write_bytes(&mut self, buf: &[u8]) -> i32 {
+        let current = match self.current {
+            Some(ref mut current) if !current.aborted.is_aborted() => current,
+            _ => return -1,
+        };
+        match current.write(buf) {
+            Ok(()) => buf.len() as i32,
+            Err(_) => {
+                current.aborted.abort();
+                -1
+            }
+        }
+    }
+
+    fn read_bytes(&mut self, buf: &mut [u8]) -> i32 {
+        let current = match self.current {
+            Some(ref mut current) => current,
+            None => return -1,
+        };
+        let mut count = 0;
+        while count < buf.len() {
+            match current.read(&mut buf[count..]) {
+                Ok(0) => break,
+                Ok(n) => count += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return -1,
+            }
+        }
+        count as i32
+    }
+
+    fn read(&mut self, n: i32) -> (String, i32) {
+        crate::read_lossy(self, n)
+    }
+
+    fn flush(&mut self, stream_type: StreamType) {
+        if let (StreamType::OutStream, Some(current)) = (stream_type, self.current.as_mut()) {
+            if current.flush().is_err() {
+                current.aborted.abort();
+            }
+        }
+    }
+
+    fn abort_token(&self) -> AbortToken {
+        self.current.as_ref().map(|current| current.aborted.clone()).unwrap_or_default()
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.current.as_ref().and_then(|current| current.peer)
+    }
+}
@@ -0,0 +1,115 @@
+//! Writing messages sent with `Exchange::error` to a log file.
+//!
+//! Some web servers throttle or drop what an application writes to the
+//! FastCGI error stream. An `ErrorLog` copies every message into a file, or
+//! takes the messages instead of the stream, with each message prefixed by
+//! a timestamp. The file is rotated by size: `app.log` becomes
+//! `app.log.1`, older files move up by one and the oldest beyond the number
+//! to keep is removed.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::httpdate::format_rfc3339;
+
+struct Output {
+    file: File,
+    size: u64,
+}
+
+/// A size-rotated log file for the error stream, see the module
+/// documentation.
+pub struct ErrorLog {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    redirect: bool,
+    output: Mutex<Output>,
+}
+
+impl ErrorLog {
+    /// Opens the file for appending. Messages are still sent to the error
+    /// stream as well. The file is rotated at 10 MiB, keeping 5 old files.
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<ErrorLog> {
+        let path = path.into();
+        let output = open_output(&path)?;
+        Ok(ErrorLog {
+            path,
+            max_size: 10 * 1024 * 1024,
+            keep: 5,
+            redirect: false,
+            output: Mutex::new(output),
+        })
+    }
+
+    /// Rotates the file once it has grown to `bytes`, never if 0.
+    pub fn max_size(mut self, bytes: u64) -> ErrorLog {
+        self.max_size = bytes;
+        self
+    }
+
+    /// Sets how many rotated files are kept.
+    pub fn keep(mut self, files: usize) -> ErrorLog {
+        self.keep = files;
+        self
+    }
+
+    /// Writes messages only to the file, not to the error stream.
+    pub fn redirect(mut self) -> ErrorLog {
+        self.redirect = true;
+        self
+    }
+
+    /// True if messages should not be sent to the error stream as well.
+    pub fn is_redirect(&self) -> bool {
+        self.redirect
+    }
+
+    /// The path of the current file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends a message, prefixed with the current time and terminated by
+    /// a newline.
+    pub fn write(&self, msg: &str) -> io::Result<()> {
+        let mut line = format!("[{}] {}", format_rfc3339(SystemTime::now()), msg);
+        if !line.ends_with('\n') {
+            line.push('\n');
+        }
+        let mut output = self.output.lock().unwrap_or_else(|e| e.into_inner());
+        if self.max_size > 0 && output.size > 0 && output.size + line.len() as u64 > self.max_size {
+            *output = self.rotate()?;
+        }
+        output.file.write_all(line.as_bytes())?;
+        output.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&self) -> io::Result<Output> {
+        let numbered = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(numbered(self.keep));
+            for n in (1..self.keep).rev() {
+                let _ = fs::rename(numbered(n), numbered(n + 1));
+            }
+            fs::rename(&self.path, numbered(1))?;
+        }
+        open_output(&self.path)
+    }
+}
+
+fn open_output(path: &Path) -> io::Result<Output> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok(Output { file, size })
+}
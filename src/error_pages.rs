@@ -0,0 +1,135 @@
+//! Responses sent for errors detected by the server and the built-in
+//! handlers, such as unknown paths (404), wrong methods (405) or panicking
+//! handlers (500).
+//!
+//! Without configuration a plain-text body with the reason phrase is sent.
+//! `ErrorPages` replaces it per status with a handler or a template, and
+//! can use one template for all 5xx statuses.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::exchange::Exchange;
+use crate::handler::Handler;
+use crate::headers::reason_phrase;
+use crate::httpdate::format_rfc3339;
+
+/// Parameters a web server may pass with a unique request id, e.g. from
+/// nginx's `$request_id`, an X-Request-Id header or Apache's mod_unique_id.
+const REQUEST_ID_PARAMS: [&str; 3] = ["REQUEST_ID", "HTTP_X_REQUEST_ID", "UNIQUE_ID"];
+
+/// Longest request id taken from a parameter.
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+static NEXT_REQUEST_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A body template. `{status}` and `{reason}` are replaced with the status
+/// code and its reason phrase, `{timestamp}` with the current time in RFC
+/// 3339 format and `{request_id}` with the request id, see `request_id`.
+struct Template {
+    content_type: String,
+    body: String,
+}
+
+impl Handler for Template {
+    fn handle(&self, exchange: &mut Exchange) {
+        let status = exchange.status();
+        let mut body = self.body
+            .replace("{status}", &status.to_string())
+            .replace("{reason}", reason_phrase(status))
+            .replace("{timestamp}", &format_rfc3339(SystemTime::now()));
+        if body.contains("{request_id}") {
+            let id = request_id(exchange);
+            exchange.set_header("X-Request-Id", &id);
+            body = body.replace("{request_id}", &id);
+        }
+        exchange.set_header("Content-Type", &self.content_type);
+        let _ = exchange.write_body(body.as_bytes());
+    }
+}
+
+/// The id of the request as passed by the web server, or a new id unique
+/// within this process. Only letters, digits and `-_.:@` are kept from a
+/// passed id, so it can be inserted into HTML as is.
+pub fn request_id(exchange: &Exchange) -> String {
+    let passed = REQUEST_ID_PARAMS.iter()
+        .filter_map(|name| exchange.param(name))
+        .map(|id| id.chars()
+            .filter(|c| c.is_ascii_alphanumeric() || "-_.:@".contains(*c))
+            .take(MAX_REQUEST_ID_LEN)
+            .collect::<String>())
+        .find(|id| !id.is_empty());
+    passed.unwrap_or_else(|| {
+        let start = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        format!("{:x}-{:x}-{:x}", start, std::process::id(), NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed))
+    })
+}
+
+/// User-provided responses for error statuses.
+#[derive(Clone, Default)]
+pub struct ErrorPages {
+    handlers: HashMap<u16, Arc<dyn Handler>>,
+    server_errors: Option<Arc<dyn Handler>>,
+}
+
+impl ErrorPages {
+    /// Creates an empty set, using the plain-text default for all statuses.
+    pub fn new() -> ErrorPages {
+        ErrorPages { handlers: HashMap::new(), server_errors: None }
+    }
+
+    /// Uses the handler to produce responses with the given status. The
+    /// status is already set when the handler is called.
+    pub fn handler<H: Handler>(mut self, status: u16, handler: H) -> ErrorPages {
+        self.handlers.insert(status, Arc::new(handler));
+        self
+    }
+
+    /// Uses a template body of the given content type for the status.
+    pub fn template(self, status: u16, content_type: &str, body: &str) -> ErrorPages {
+        self.handler(status, Template {
+            content_type: String::from(content_type),
+            body: String::from(body),
+        })
+    }
+
+    /// Uses a template body of the given content type for all 5xx
+    /// statuses without a handler of their own, e.g. an HTML page showing
+    /// `{request_id}` and `{timestamp}` for support requests.
+    pub fn server_error_template(mut self, content_type: &str, body: &str) -> ErrorPages {
+        self.server_errors = Some(Arc::new(Template {
+            content_type: String::from(content_type),
+            body: String::from(body),
+        }));
+        self
+    }
+
+    /// Reads the template for all 5xx statuses from an HTML file, see
+    /// `server_error_template`.
+    pub fn server_error_template_file<P: AsRef<Path>>(self, path: P) -> io::Result<ErrorPages> {
+        let body = fs::read_to_string(path)?;
+        Ok(self.server_error_template("text/html; charset=utf-8", &body))
+    }
+
+    /// Writes the response for the status, which must already be set.
+    pub fn respond(&self, exchange: &mut Exchange) {
+        let status = exchange.status();
+        let fallback = self.server_errors.as_ref().filter(|_| (500..600).contains(&status));
+        match self.handlers.get(&status).or(fallback) {
+            Some(handler) => handler.handle(exchange),
+            None => default_response(exchange),
+        }
+    }
+}
+
+/// The plain-text response used when no error page is configured.
+pub fn default_response(exchange: &mut Exchange) {
+    let reason = reason_phrase(exchange.status());
+    exchange.set_header("Content-Type", "text/plain");
+    let _ = exchange.write_body(reason.as_bytes());
+}
@@ -0,0 +1,764 @@
+//! The per-request object handed to high-level handlers.
+
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::abort::AbortToken;
+#[cfg(feature = "compression")]
+use crate::body::{self, LimitedReader};
+use crate::csv::{self, CsvWriter};
+use crate::error_log::ErrorLog;
+use crate::error_pages::{default_response, ErrorPages};
+use crate::extensions::Extensions;
+#[cfg(any(feature = "urlencoded", feature = "json", feature = "protobuf", feature = "msgpack"))]
+use crate::extract::{self, ExtractError};
+use crate::headers::{reason_phrase, Headers};
+use crate::httpdate;
+use crate::mime;
+use crate::multipart::Multipart;
+#[cfg(feature = "json")]
+use crate::ndjson::{self, NdjsonWriter};
+use crate::protocol::Role;
+#[cfg(any(feature = "askama", feature = "tera", feature = "handlebars"))]
+use crate::render::RenderResponse;
+use crate::sse::EventStream;
+use crate::uri::UriBuilder;
+use crate::urlencoding;
+use crate::xml::{self, XmlWriter};
+use crate::{Request, StreamType};
+
+/// Transforms the response body on its way to the output stream, e.g. to
+/// compress it. Filters are added to an exchange with `add_filter`.
+///
+/// All methods append their output to `out`; a filter may hold data back
+/// and emit it later. The headers are sent when the first output leaves
+/// the filter chain, so changes to the headers only take effect until then.
+pub trait BodyFilter: Send {
+    /// Called once before the first body chunk, or when the headers are
+    /// sent or the response finished without a body.
+    fn begin(&mut self, _status: u16, _headers: &mut Headers) {}
+
+    /// Transforms a chunk of the body.
+    fn write(&mut self, data: &[u8], out: &mut Vec<u8>) -> io::Result<()>;
+
+    /// Emits as much buffered data as possible, called when the handler
+    /// flushes the exchange.
+    fn flush(&mut self, _out: &mut Vec<u8>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Emits all remaining data at the end of the body.
+    fn finish(&mut self, _headers: &mut Headers, _out: &mut Vec<u8>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+enum FilterOp {
+    Write,
+    Flush,
+    Finish,
+}
+
+/// A single request/response exchange served by the high-level server.
+///
+/// The exchange gives access to the FCGI parameters and the request body
+/// and collects the response status and headers. Headers are sent
+/// automatically before the first body byte is written, or when the
+/// exchange is finished without a body.
+pub struct Exchange<'a> {
+    request: &'a mut dyn Request,
+    status: u16,
+    headers: Headers,
+    headers_sent: bool,
+    bytes_written: u64,
+    filters: Vec<Box<dyn BodyFilter>>,
+    filters_begun: bool,
+    finished: bool,
+    path_params: Vec<(String, String)>,
+    extensions: Extensions,
+    error_pages: Option<Arc<ErrorPages>>,
+    error_log: Option<Arc<ErrorLog>>,
+    deadline: Option<Instant>,
+}
+
+impl<'a> Exchange<'a> {
+    /// Wraps an accepted FCGI request.
+    pub fn new(request: &'a mut dyn Request) -> Exchange<'a> {
+        Exchange {
+            request,
+            status: 200,
+            headers: Headers::new(),
+            headers_sent: false,
+            bytes_written: 0,
+            filters: Vec::new(),
+            filters_begun: false,
+            finished: false,
+            path_params: Vec::new(),
+            extensions: Extensions::new(),
+            error_pages: None,
+            error_log: None,
+            deadline: None,
+        }
+    }
+
+    /// Get a value of a FCGI parameter from the environment.
+    pub fn param(&self, name: &str) -> Option<String> {
+        self.request.get_param(name)
+    }
+
+    /// All FastCGI parameters of the request, see `Request::params`.
+    pub fn params(&self) -> Vec<(String, String)> {
+        self.request.params()
+    }
+
+    /// The role of the application for this request. Authorizers answer
+    /// with 200 to grant access, adding variables for the following
+    /// handlers with `set_variable`, and any other status to deny it.
+    pub fn role(&self) -> Role {
+        self.request.role()
+    }
+
+    /// The address of the peer of the connection, see `Request::peer_addr`.
+    /// The address of the HTTP client is the `REMOTE_ADDR` parameter.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.request.peer_addr()
+    }
+
+    /// Passes a variable to the handlers after an authorizer, as a
+    /// `Variable-NAME` response header which the web server turns into the
+    /// parameter `NAME`.
+    pub fn set_variable(&mut self, name: &str, value: &str) {
+        self.headers.set(&format!("Variable-{}", name), value);
+    }
+
+    /// Switches the body of a filter request to the file data once the
+    /// standard input has been read to its end. The native transport fails
+    /// reading the data if its length differs from `filter_data_length`.
+    pub fn start_filter_data(&mut self) -> io::Result<()> {
+        if !self.request.start_filter_data() {
+            return Err(io::Error::other("no filter data, or standard input left unread"));
+        }
+        Ok(())
+    }
+
+    /// The length of the file data of a filter request, FCGI_DATA_LENGTH.
+    pub fn filter_data_length(&self) -> Option<u64> {
+        self.param("FCGI_DATA_LENGTH").and_then(|length| length.parse().ok())
+    }
+
+    /// The modification time of the file data of a filter request,
+    /// FCGI_DATA_LAST_MOD, e.g. for caching the filtered output.
+    pub fn filter_data_last_modified(&self) -> Option<SystemTime> {
+        self.param("FCGI_DATA_LAST_MOD")
+            .and_then(|secs| secs.parse().ok())
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    /// The request method, GET if the web server did not pass one.
+    pub fn method(&self) -> String {
+        self.param("REQUEST_METHOD").unwrap_or_else(|| String::from("GET"))
+    }
+
+    /// The request path below the script, taken from PATH_INFO.
+    pub fn path(&self) -> String {
+        self.param("PATH_INFO").unwrap_or_default()
+    }
+
+    /// The raw query string without the leading '?'.
+    pub fn query_string(&self) -> String {
+        self.param("QUERY_STRING").unwrap_or_default()
+    }
+
+    /// The decoded name and value pairs of the query string, see
+    /// `urlencoding::parse_query`.
+    pub fn query_params(&self) -> Vec<(String, String)> {
+        urlencoding::parse_query(&self.query_string())
+    }
+
+    /// Returns the first decoded value of a query parameter.
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query_params().into_iter().find(|(n, _)| n == name).map(|(_, value)| value)
+    }
+
+    /// Deserializes the query string into `T`, with the `urlencoded`
+    /// feature. Fields missing from the query or with values of the wrong
+    /// type are reported by name; see `ExtractError::status`.
+    #[cfg(feature = "urlencoded")]
+    pub fn query_as<T: serde::de::DeserializeOwned>(&self) -> Result<T, ExtractError> {
+        extract::from_urlencoded(self.query_string().as_bytes())
+    }
+
+    /// Reads a form posted as `application/x-www-form-urlencoded` and
+    /// deserializes it into `T`, with the `urlencoded` feature. Fails with
+    /// 415 for other Content-Types and with 413 if the body is larger than
+    /// `limit` bytes, see `ExtractError::status`.
+    #[cfg(feature = "urlencoded")]
+    pub fn form_as<T: serde::de::DeserializeOwned>(&mut self, limit: u64) -> Result<T, ExtractError> {
+        extract::check_content_type(self.header("Content-Type").as_deref(), "application/x-www-form-urlencoded")?;
+        let content_length = self.header("Content-Length").and_then(|len| len.parse().ok());
+        let body = extract::read_body(&mut *self, content_length, limit)?;
+        extract::from_urlencoded(&body)
+    }
+
+    /// Reads a JSON body of at most `limit` bytes and deserializes it into
+    /// `T`, with the `json` feature. The Content-Type must be
+    /// `application/json` or end in `+json`; see `ExtractError::status`
+    /// for the status to answer failures with.
+    #[cfg(feature = "json")]
+    pub fn read_json<T: serde::de::DeserializeOwned>(&mut self, limit: u64) -> Result<T, ExtractError> {
+        extract::check_json_content_type(self.header("Content-Type").as_deref())?;
+        let content_length = self.header("Content-Length").and_then(|len| len.parse().ok());
+        let body = extract::read_body(&mut *self, content_length, limit)?;
+        extract::from_json(&body)
+    }
+
+    /// Reads a MessagePack body of at most `limit` bytes and deserializes
+    /// it into `T`, with the `msgpack` feature. The Content-Type must be
+    /// `application/msgpack`, `application/x-msgpack` or
+    /// `application/vnd.msgpack`; failures are reported as by `read_json`.
+    #[cfg(feature = "msgpack")]
+    pub fn read_msgpack<T: serde::de::DeserializeOwned>(&mut self, limit: u64) -> Result<T, ExtractError> {
+        extract::check_msgpack_content_type(self.header("Content-Type").as_deref())?;
+        let content_length = self.header("Content-Length").and_then(|len| len.parse().ok());
+        let body = extract::read_body(&mut *self, content_length, limit)?;
+        extract::from_msgpack(&body)
+    }
+
+    /// Reads a protobuf body of at most `limit` bytes and decodes it into
+    /// the message `T`, with the `protobuf` feature. The Content-Type must
+    /// be `application/x-protobuf` or `application/protobuf`.
+    #[cfg(feature = "protobuf")]
+    pub fn read_protobuf<T: prost::Message + Default>(&mut self, limit: u64) -> Result<T, ExtractError> {
+        extract::check_protobuf_content_type(self.header("Content-Type").as_deref())?;
+        let content_length = self.header("Content-Length").and_then(|len| len.parse().ok());
+        let body = extract::read_body(&mut *self, content_length, limit)?;
+        extract::from_protobuf(&body)
+    }
+
+    /// Returns a request header as passed by the web server, e.g.
+    /// `header("Accept-Encoding")` reads `HTTP_ACCEPT_ENCODING`.
+    pub fn header(&self, name: &str) -> Option<String> {
+        let upper = name.to_ascii_uppercase().replace('-', "_");
+        match upper.as_str() {
+            "CONTENT_TYPE" | "CONTENT_LENGTH" => self.param(&upper),
+            _ => self.param(&format!("HTTP_{}", upper)),
+        }
+    }
+
+    /// Returns the value of a cookie sent by the client, without the
+    /// quotes it may be enclosed in.
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        let cookies = self.param("HTTP_COOKIE")?;
+        cookies.split(';')
+            .filter_map(|cookie| cookie.split_once('='))
+            .find(|(cookie, _)| cookie.trim() == name)
+            .map(|(_, value)| {
+                let value = value.trim();
+                String::from(value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value))
+            })
+    }
+
+    /// The date of the If-Modified-Since header, None if it is absent or
+    /// invalid.
+    pub fn if_modified_since(&self) -> Option<SystemTime> {
+        self.header("If-Modified-Since").and_then(|date| httpdate::parse_http_date(&date))
+    }
+
+    /// Returns false if the client's copy, as dated by If-Modified-Since,
+    /// is still current for a resource last modified at `modified`, in
+    /// which case handlers would answer with 304. If-Modified-Since is
+    /// ignored when If-None-Match is present, as RFC 7232 demands.
+    pub fn modified_since(&self, modified: SystemTime) -> bool {
+        if self.header("If-None-Match").is_some() {
+            return true;
+        }
+        let secs = |time: SystemTime| time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        match self.if_modified_since() {
+            // Dates in headers have a resolution of seconds.
+            Some(since) => secs(modified) > secs(since),
+            None => true,
+        }
+    }
+
+    /// Values attached to the request by middleware, e.g. the
+    /// authenticated user.
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// Mutable access to the values attached to the request.
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    /// The application state of the given type, see `ServerBuilder::state`.
+    pub fn state<S: Send + Sync + 'static>(&self) -> Option<Arc<S>> {
+        self.extensions.get::<Arc<S>>().cloned()
+    }
+
+    /// Returns the decoded value of a path parameter captured by the router.
+    pub fn path_param(&self, name: &str) -> Option<&str> {
+        self.path_params.iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Parses a captured path parameter. Returns None if the parameter is
+    /// missing or does not parse.
+    pub fn path_param_as<T: FromStr>(&self, name: &str) -> Option<T> {
+        self.path_param(name).and_then(|v| v.parse().ok())
+    }
+
+    /// All captured path parameters in pattern order.
+    pub fn path_params(&self) -> &[(String, String)] {
+        &self.path_params
+    }
+
+    /// Replaces the captured path parameters. Called by the router.
+    pub fn set_path_params(&mut self, params: Vec<(String, String)>) {
+        self.path_params = params;
+    }
+
+    /// Returns a reader over the request body with its Content-Encoding
+    /// (gzip or deflate) removed. Reading fails with `InvalidData` once the
+    /// decoded body exceeds `limit` bytes, and creating the reader fails
+    /// with `InvalidInput` for unsupported encodings, which handlers would
+    /// usually answer with 415.
+    #[cfg(feature = "compression")]
+    pub fn decoded_body(&mut self, limit: u64) -> io::Result<LimitedReader<Box<dyn Read + '_>>> {
+        let encoding = self.header("Content-Encoding");
+        let decoded = body::decode(encoding.as_deref(), self)?;
+        Ok(LimitedReader::new(decoded, limit))
+    }
+
+    /// Returns a parser over the parts of a `multipart/form-data` body.
+    /// Fails with `InvalidInput` if the Content-Type is not multipart or
+    /// lacks a boundary, which handlers would usually answer with 415.
+    pub fn multipart(&mut self) -> io::Result<Multipart<&mut Exchange<'a>>> {
+        let content_type = self.header("Content-Type").unwrap_or_default();
+        Multipart::with_content_type(self, &content_type)
+    }
+
+    /// The response status, 200 unless changed.
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// Sets the response status. Has no effect once headers are sent, so
+    /// `status` keeps returning the one sent.
+    pub fn set_status(&mut self, status: u16) {
+        if !self.headers_sent {
+            self.status = status;
+        }
+    }
+
+    /// The response headers collected so far.
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// Mutable access to the response headers.
+    pub fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+
+    /// Shorthand for `headers_mut().set(name, value)`.
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers.set(name, value);
+    }
+
+    /// Redirects to `uri` with the given status, e.g. 303 after a form
+    /// post, by setting the status and the Location header.
+    pub fn redirect(&mut self, status: u16, uri: &UriBuilder) {
+        self.set_status(status);
+        self.headers.set("Location", &uri.build());
+    }
+
+    /// Sets the Last-Modified header.
+    pub fn set_last_modified(&mut self, modified: SystemTime) {
+        self.headers.set("Last-Modified", &httpdate::format_http_date(modified));
+    }
+
+    /// Sets Content-Disposition so that browsers save the body as a file
+    /// named `filename`. Names with other than printable ASCII are sent
+    /// as `filename*` too, next to a fallback with those replaced by `_`.
+    pub fn set_attachment(&mut self, filename: &str) {
+        let fallback: String = filename.chars()
+            .map(|c| if (c.is_ascii_graphic() || c == ' ') && c != '"' && c != '\\' { c } else { '_' })
+            .collect();
+        let mut value = format!("attachment; filename=\"{}\"", fallback);
+        if fallback != filename {
+            value.push_str("; filename*=UTF-8''");
+            value.push_str(&urlencoding::encode_path_segment(filename));
+        }
+        self.headers.set("Content-Disposition", &value);
+    }
+
+    /// Returns true once the status line and headers have been written.
+    pub fn headers_sent(&self) -> bool {
+        self.headers_sent
+    }
+
+    /// Number of body bytes written to the output stream so far, after
+    /// body filters have been applied.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// Adds a filter for the response body. Filters added later sit closer
+    /// to the handler: they see the body first and pass their output on to
+    /// the filters added before them.
+    pub fn add_filter(&mut self, filter: Box<dyn BodyFilter>) {
+        self.filters.push(filter);
+    }
+
+    fn begin_filters(&mut self) {
+        if self.filters_begun {
+            return;
+        }
+        self.filters_begun = true;
+        let status = self.status;
+        for filter in self.filters.iter_mut().rev() {
+            filter.begin(status, &mut self.headers);
+        }
+    }
+
+    /// Passes data through the filter chain, returning the final output.
+    fn run_filters(&mut self, data: &[u8], op: FilterOp) -> io::Result<Vec<u8>> {
+        self.begin_filters();
+        let mut chunk = data.to_vec();
+        for filter in self.filters.iter_mut().rev() {
+            let mut out = Vec::new();
+            if !chunk.is_empty() {
+                filter.write(&chunk, &mut out)?;
+            }
+            match op {
+                FilterOp::Write => {}
+                FilterOp::Flush => filter.flush(&mut out)?,
+                FilterOp::Finish => filter.finish(&mut self.headers, &mut out)?,
+            }
+            chunk = out;
+        }
+        Ok(chunk)
+    }
+
+    /// Writes filtered output, sending the headers first if necessary.
+    fn write_output(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.send_headers()?;
+        write_fully(self.request, data)?;
+        self.bytes_written += data.len() as u64;
+        Ok(())
+    }
+
+    /// Writes the status and headers to the output stream. Does nothing
+    /// if they have already been sent.
+    pub fn send_headers(&mut self) -> io::Result<()> {
+        if self.headers_sent {
+            return Ok(());
+        }
+        self.begin_filters();
+        let mut head = format!("Status: {} {}\r\n", self.status, reason_phrase(self.status));
+        for (name, value) in self.headers.iter() {
+            if name.contains(['\r', '\n', ':']) || value.contains(['\r', '\n']) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid response header {:?}", name)));
+            }
+            head.push_str(name);
+            head.push_str(": ");
+            head.push_str(value);
+            head.push_str("\r\n");
+        }
+        head.push_str("\r\n");
+        self.headers_sent = true;
+        write_fully(self.request, head.as_bytes())
+    }
+
+    /// Writes a chunk of the response body, sending the headers first if
+    /// necessary.
+    pub fn write_body(&mut self, data: &[u8]) -> io::Result<()> {
+        self.check_deadline()?;
+        if self.filters.is_empty() {
+            return self.write_output(data);
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+        let out = self.run_filters(data, FilterOp::Write)?;
+        self.write_output(&out)
+    }
+
+    /// Renders `view` as the body, setting its Content-Type unless one was
+    /// set before. The output is passed on in blocks as it is rendered;
+    /// if rendering fails before the first block is full nothing has been
+    /// sent, and the handler can still answer with an error page.
+    #[cfg(any(feature = "askama", feature = "tera", feature = "handlebars"))]
+    pub fn render<R: RenderResponse + ?Sized>(&mut self, view: &R) -> io::Result<()> {
+        if !self.headers.contains("Content-Type") {
+            self.headers.set("Content-Type", view.content_type());
+        }
+        let mut out = io::BufWriter::with_capacity(8192, &mut *self);
+        match view.render_to(&mut out) {
+            Ok(()) => out.flush(),
+            Err(e) => {
+                // Drop what was rendered of a failed view.
+                let _ = out.into_parts();
+                Err(e)
+            }
+        }
+    }
+
+    /// Writes the contents of a file as the body, setting Content-Length
+    /// and, unless set before, a Content-Type guessed from the extension,
+    /// see `mime::guess`. Set it from a `MimeTypes` to apply overrides.
+    pub fn write_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len();
+        if !self.headers.contains("Content-Type") {
+            self.headers.set("Content-Type", mime::guess(path));
+        }
+        self.headers.set("Content-Length", &len.to_string());
+        io::copy(&mut file, self)?;
+        Ok(())
+    }
+
+    /// Turns the response into a stream of Server-Sent Events, see the
+    /// `sse` module. The headers are sent at once.
+    pub fn event_stream(&mut self) -> io::Result<EventStream<'_, 'a>> {
+        EventStream::new(self)
+    }
+
+    /// Sends an `http::Response`: sets its status and headers and writes
+    /// its body, with the `http` feature. Headers set before are kept
+    /// unless the response replaces them.
+    #[cfg(feature = "http")]
+    pub fn send_response<B: AsRef<[u8]>>(&mut self, response: http::Response<B>) -> io::Result<()> {
+        let (parts, body) = response.into_parts();
+        self.set_status(parts.status.as_u16());
+        for name in parts.headers.keys() {
+            self.headers.remove(name.as_str());
+        }
+        for (name, value) in &parts.headers {
+            let value = value.to_str()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("invalid response header {:?}", name)))?;
+            self.headers.append(name.as_str(), value);
+        }
+        self.write_body(body.as_ref())
+    }
+
+    /// Serializes `value` as the JSON body of the response, with the `json`
+    /// feature, setting Content-Type to `application/json` unless a
+    /// Content-Type was set before.
+    #[cfg(feature = "json")]
+    pub fn respond_json<T: serde::Serialize + ?Sized>(&mut self, value: &T) -> io::Result<()> {
+        let body = serde_json::to_vec(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if self.headers.get("Content-Type").is_none() {
+            self.headers.set("Content-Type", "application/json");
+        }
+        self.write_body(&body)
+    }
+
+    /// Serializes `value` as the MessagePack body of the response, with the
+    /// `msgpack` feature, setting Content-Type to `application/msgpack`
+    /// unless a Content-Type was set before. Structs are written as maps
+    /// with their field names, as clients in other languages expect.
+    #[cfg(feature = "msgpack")]
+    pub fn respond_msgpack<T: serde::Serialize + ?Sized>(&mut self, value: &T) -> io::Result<()> {
+        let body = rmp_serde::to_vec_named(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if self.headers.get("Content-Type").is_none() {
+            self.headers.set("Content-Type", "application/msgpack");
+        }
+        self.write_body(&body)
+    }
+
+    /// Encodes `message` as the protobuf body of the response, with the
+    /// `protobuf` feature, setting Content-Type to `application/x-protobuf`
+    /// unless a Content-Type was set before.
+    #[cfg(feature = "protobuf")]
+    pub fn respond_protobuf<T: prost::Message>(&mut self, message: &T) -> io::Result<()> {
+        let body = message.encode_to_vec();
+        if self.headers.get("Content-Type").is_none() {
+            self.headers.set("Content-Type", "application/x-protobuf");
+        }
+        self.write_body(&body)
+    }
+
+    /// Returns a writer streaming CSV records, setting Content-Type to
+    /// `text/csv` unless a Content-Type was set before.
+    pub fn csv(&mut self) -> CsvWriter<&mut Exchange<'a>> {
+        if self.headers.get("Content-Type").is_none() {
+            self.headers.set("Content-Type", csv::CONTENT_TYPE);
+        }
+        CsvWriter::new(self)
+    }
+
+    /// Returns a writer for an XML document, setting Content-Type to
+    /// `application/xml` unless a Content-Type was set before, e.g. to
+    /// `application/atom+xml` for a feed.
+    pub fn xml(&mut self) -> io::Result<XmlWriter<&mut Exchange<'a>>> {
+        if self.headers.get("Content-Type").is_none() {
+            self.headers.set("Content-Type", xml::CONTENT_TYPE);
+        }
+        XmlWriter::new(self)
+    }
+
+    /// Returns a writer streaming values as newline-delimited JSON, with
+    /// the `json` feature, setting Content-Type to `application/x-ndjson`
+    /// unless a Content-Type was set before.
+    #[cfg(feature = "json")]
+    pub fn ndjson(&mut self) -> NdjsonWriter<&mut Exchange<'a>> {
+        if self.headers.get("Content-Type").is_none() {
+            self.headers.set("Content-Type", ndjson::CONTENT_TYPE);
+        }
+        NdjsonWriter::new(self)
+    }
+
+    /// Completes the response: finishes the body filters, sends the
+    /// headers if nothing was written and flushes the output stream.
+    /// Further calls have no effect.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        if !self.filters.is_empty() {
+            let out = self.run_filters(&[], FilterOp::Finish)?;
+            self.write_output(&out)?;
+        }
+        self.send_headers()?;
+        self.request.flush(StreamType::OutStream);
+        Ok(())
+    }
+
+    /// Sets the error pages used by `respond_error`. Called by the server.
+    pub fn set_error_pages(&mut self, error_pages: Option<Arc<ErrorPages>>) {
+        self.error_pages = error_pages;
+    }
+
+    /// Sets the file which receives the messages passed to `error`. Called
+    /// by the server.
+    pub fn set_error_log(&mut self, error_log: Option<Arc<ErrorLog>>) {
+        self.error_log = error_log;
+    }
+
+    /// Sets the status and writes the configured error response for it,
+    /// a plain-text reason phrase unless `ErrorPages` were configured.
+    /// Headers set before, like Allow for 405, are kept.
+    pub fn respond_error(&mut self, status: u16) {
+        self.set_status(status);
+        match self.error_pages.clone() {
+            Some(pages) => pages.respond(self),
+            None => default_response(self),
+        }
+    }
+
+    /// Sets the time by which the request should be answered. Once it has
+    /// passed, reading the body and writing the response fail with
+    /// `TimedOut`, so handlers stop at their next I/O. Handlers doing long
+    /// computations should check `deadline_exceeded` themselves.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    /// The deadline of the request, if one is set.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Time left until the deadline, None without a deadline.
+    pub fn time_remaining(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Returns true once the deadline has passed.
+    pub fn deadline_exceeded(&self) -> bool {
+        self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Returns true once the web server has aborted the request, see the
+    /// `abort` module. Reading the body fails then.
+    pub fn is_aborted(&self) -> bool {
+        self.request.abort_token().is_aborted()
+    }
+
+    /// The token set when the web server aborts the request, e.g. to hand
+    /// to threads working for the handler.
+    pub fn abort_token(&self) -> AbortToken {
+        self.request.abort_token()
+    }
+
+    fn check_deadline(&self) -> io::Result<()> {
+        if self.deadline_exceeded() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "request deadline exceeded"));
+        }
+        Ok(())
+    }
+
+    /// Writes the given message into the FCGI error stream, and into the
+    /// `ErrorLog` if the server has one. If the log redirects messages they
+    /// only go to the stream when writing the file fails.
+    pub fn error(&mut self, msg: &str) {
+        let logged = match self.error_log {
+            Some(ref log) => log.write(msg).is_ok() && log.is_redirect(),
+            None => false,
+        };
+        if !logged {
+            self.request.error(msg);
+        }
+    }
+
+    /// Direct access to the underlying FCGI request.
+    pub fn request(&mut self) -> &mut dyn Request {
+        self.request
+    }
+}
+
+fn write_fully(request: &mut dyn Request, data: &[u8]) -> io::Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    if request.write_bytes(data) < 0 {
+        return Err(io::Error::new(io::ErrorKind::BrokenPipe, "failed to write to FCGI output stream"));
+    }
+    Ok(())
+}
+
+impl<'a> Read for Exchange<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.check_deadline()?;
+        if self.is_aborted() {
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "request aborted by the web server"));
+        }
+        let n = self.request.read_bytes(buf);
+        if n < 0 {
+            return Err(io::Error::other("failed to read FCGI input stream"));
+        }
+        Ok(n as usize)
+    }
+}
+
+impl<'a> Write for Exchange<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_body(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.filters.is_empty() {
+            let out = self.run_filters(&[], FilterOp::Flush)?;
+            self.write_output(&out)?;
+        }
+        self.send_headers()?;
+        self.request.flush(StreamType::OutStream);
+        Ok(())
+    }
+}
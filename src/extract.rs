@@ -0,0 +1,212 @@
+//! Typed access to request data through serde or prost. With the
+//! `urlencoded` feature `Exchange::query_as` deserializes the query string
+//! into a struct, `Exchange::form_as` a form posted as
+//! `application/x-www-form-urlencoded`; with the `json` feature
+//! `Exchange::read_json` a JSON body, with the `msgpack` feature
+//! `Exchange::read_msgpack` a MessagePack body, and with the `protobuf`
+//! feature `Exchange::read_protobuf` a protobuf message.
+//!
+//! ```ignore
+//! #[derive(Deserialize)]
+//! struct Search { q: String, page: Option<u32> }
+//!
+//! match exchange.query_as::<Search>() {
+//!     Ok(search) => { /* ... */ }
+//!     Err(e) => exchange.respond_error(e.status()),
+//! }
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+
+#[cfg(any(feature = "urlencoded", feature = "json", feature = "msgpack"))]
+use serde::de::DeserializeOwned;
+
+/// Why request data could not be turned into the requested type.
+#[derive(Debug)]
+pub enum ExtractError {
+    /// A required field is absent.
+    MissingField(String),
+    /// A field is present but its value does not fit the type.
+    InvalidField {
+        /// The field, e.g. `page`, or `address.zip` in nested types.
+        field: String,
+        /// What is wrong with it.
+        message: String,
+    },
+    /// The data is malformed as a whole.
+    Invalid(String),
+    /// The body has a different Content-Type, given here if there is one.
+    UnsupportedMediaType(Option<String>),
+    /// The body is larger than the limit.
+    TooLarge,
+    /// Reading the body failed.
+    Io(io::Error),
+}
+
+impl ExtractError {
+    /// The status to answer with: 415 for the wrong Content-Type, 413 for
+    /// bodies over the limit and 400 otherwise.
+    pub fn status(&self) -> u16 {
+        match *self {
+            ExtractError::UnsupportedMediaType(_) => 415,
+            ExtractError::TooLarge => 413,
+            _ => 400,
+        }
+    }
+}
+
+impl fmt::Display for ExtractError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExtractError::MissingField(ref field) => write!(f, "missing field `{}`", field),
+            ExtractError::InvalidField { ref field, ref message } => write!(f, "invalid field `{}`: {}", field, message),
+            ExtractError::Invalid(ref message) => write!(f, "invalid request data: {}", message),
+            ExtractError::UnsupportedMediaType(Some(ref content_type)) => {
+                write!(f, "unsupported Content-Type {}", content_type)
+            }
+            ExtractError::UnsupportedMediaType(None) => write!(f, "missing Content-Type"),
+            ExtractError::TooLarge => write!(f, "request body exceeds the size limit"),
+            ExtractError::Io(ref e) => write!(f, "failed to read the request body: {}", e),
+        }
+    }
+}
+
+impl Error for ExtractError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ExtractError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Checks that the media type of `content_type`, without parameters such
+/// as the charset, is `expected`.
+#[cfg(any(feature = "urlencoded", feature = "msgpack", feature = "protobuf"))]
+pub(crate) fn check_content_type(content_type: Option<&str>, expected: &str) -> Result<(), ExtractError> {
+    match content_type {
+        Some(value) if value.split(';').next().unwrap_or("").trim().eq_ignore_ascii_case(expected) => Ok(()),
+        _ => Err(ExtractError::UnsupportedMediaType(content_type.map(String::from))),
+    }
+}
+
+/// Reads a whole body of at most `limit` bytes, refusing right away if
+/// its announced `content_length` is larger.
+pub(crate) fn read_body<R: Read>(body: R, content_length: Option<u64>, limit: u64) -> Result<Vec<u8>, ExtractError> {
+    if content_length.is_some_and(|len| len > limit) {
+        return Err(ExtractError::TooLarge);
+    }
+    let mut data = Vec::new();
+    // Read one byte more than allowed to detect oversized bodies.
+    body.take(limit.saturating_add(1)).read_to_end(&mut data).map_err(ExtractError::Io)?;
+    if data.len() as u64 > limit {
+        return Err(ExtractError::TooLarge);
+    }
+    Ok(data)
+}
+
+/// Turns the error of a deserializer at `field`, "." for the top level,
+/// into the matching `ExtractError`.
+#[cfg(any(feature = "urlencoded", feature = "json", feature = "msgpack"))]
+fn field_error(field: String, message: String) -> ExtractError {
+    // serde reports missing fields on the enclosing struct.
+    if let Some(rest) = message.strip_prefix("missing field `") {
+        let name = rest.split('`').next().unwrap_or(rest);
+        let name = if field == "." { String::from(name) } else { format!("{}.{}", field, name) };
+        return ExtractError::MissingField(name);
+    }
+    if field == "." {
+        ExtractError::Invalid(message)
+    } else {
+        ExtractError::InvalidField { field, message }
+    }
+}
+
+/// Deserializes `application/x-www-form-urlencoded` data, telling which
+/// field is missing or invalid on errors.
+#[cfg(feature = "urlencoded")]
+pub(crate) fn from_urlencoded<T: DeserializeOwned>(data: &[u8]) -> Result<T, ExtractError> {
+    let deserializer = serde_urlencoded::Deserializer::new(form_urlencoded::parse(data));
+    serde_path_to_error::deserialize(deserializer).map_err(|e| {
+        let field = e.path().to_string();
+        field_error(field, e.into_inner().to_string())
+    })
+}
+
+/// Checks that `content_type` is JSON: `application/json` or a type with
+/// the `+json` suffix, such as `application/merge-patch+json`.
+#[cfg(feature = "json")]
+pub(crate) fn check_json_content_type(content_type: Option<&str>) -> Result<(), ExtractError> {
+    let media_type = content_type.map(|value| value.split(';').next().unwrap_or("").trim().to_ascii_lowercase());
+    match media_type {
+        Some(ref media_type) if media_type == "application/json" || media_type.ends_with("+json") => Ok(()),
+        _ => Err(ExtractError::UnsupportedMediaType(content_type.map(String::from))),
+    }
+}
+
+/// Deserializes a JSON document, telling which field is missing or
+/// invalid on errors.
+#[cfg(feature = "json")]
+pub(crate) fn from_json<T: DeserializeOwned>(data: &[u8]) -> Result<T, ExtractError> {
+    let mut deserializer = serde_json::Deserializer::from_slice(data);
+    let value = serde_path_to_error::deserialize(&mut deserializer).map_err(|e| {
+        let field = e.path().to_string();
+        let e = e.into_inner();
+        match e.classify() {
+            serde_json::error::Category::Data => field_error(field, e.to_string()),
+            _ => ExtractError::Invalid(e.to_string()),
+        }
+    })?;
+    deserializer.end().map_err(|e| ExtractError::Invalid(e.to_string()))?;
+    Ok(value)
+}
+
+/// Checks that `content_type` is MessagePack: `application/msgpack`, or
+/// `application/x-msgpack` or `application/vnd.msgpack` as older clients
+/// send.
+#[cfg(feature = "msgpack")]
+pub(crate) fn check_msgpack_content_type(content_type: Option<&str>) -> Result<(), ExtractError> {
+    check_content_type(content_type, "application/msgpack")
+        .or_else(|_| check_content_type(content_type, "application/x-msgpack"))
+        .or_else(|_| check_content_type(content_type, "application/vnd.msgpack"))
+}
+
+/// Deserializes a MessagePack value, telling which field is missing or
+/// invalid on errors.
+#[cfg(feature = "msgpack")]
+pub(crate) fn from_msgpack<T: DeserializeOwned>(data: &[u8]) -> Result<T, ExtractError> {
+    use rmp_serde::decode::Error;
+
+    let mut rest = data;
+    let mut deserializer = rmp_serde::Deserializer::new(&mut rest);
+    let value = serde_path_to_error::deserialize(&mut deserializer).map_err(|e| {
+        let field = e.path().to_string();
+        let e = e.into_inner();
+        match e {
+            Error::Syntax(_) | Error::TypeMismatch(_) | Error::OutOfRange | Error::LengthMismatch(_) => {
+                field_error(field, e.to_string())
+            }
+            _ => ExtractError::Invalid(e.to_string()),
+        }
+    })?;
+    if !deserializer.into_inner().is_empty() {
+        return Err(ExtractError::Invalid(String::from("trailing data after the MessagePack value")));
+    }
+    Ok(value)
+}
+
+/// Checks that `content_type` is protobuf: `application/x-protobuf` or
+/// `application/protobuf`.
+#[cfg(feature = "protobuf")]
+pub(crate) fn check_protobuf_content_type(content_type: Option<&str>) -> Result<(), ExtractError> {
+    check_content_type(content_type, "application/x-protobuf")
+        .or_else(|_| check_content_type(content_type, "application/protobuf"))
+}
+
+/// Decodes a protobuf message.
+#[cfg(feature = "protobuf")]
+pub(crate) fn from_protobuf<T: prost::Message + Default>(data: &[u8]) -> Result<T, ExtractError> {
+    T::decode(data).map_err(|e| ExtractError::Invalid(e.to_string()))
+}
@@ -0,0 +1,42 @@
+//! The handler trait used by the high-level server.
+
+use std::sync::Arc;
+
+use crate::exchange::Exchange;
+
+/// Handles requests accepted by the high-level server.
+///
+/// A handler is shared between all worker threads. Closures taking an
+/// `&mut Exchange` implement this trait.
+pub trait Handler: Send + Sync + 'static {
+    /// Produces the response for a single request.
+    fn handle(&self, exchange: &mut Exchange);
+}
+
+impl<F> Handler for F where F: Fn(&mut Exchange) + Send + Sync + 'static {
+    fn handle(&self, exchange: &mut Exchange) {
+        self(exchange)
+    }
+}
+
+/// A handler which is passed shared application state, such as connection
+/// pools and caches, with every request. Create one with `with_state`.
+pub struct WithState<S, F> {
+    state: Arc<S>,
+    handler: F,
+}
+
+/// Wraps a closure taking the exchange and the application state.
+pub fn with_state<S, F>(state: Arc<S>, handler: F) -> WithState<S, F>
+    where S: Send + Sync + 'static, F: Fn(&mut Exchange, &S) + Send + Sync + 'static
+{
+    WithState { state, handler }
+}
+
+impl<S, F> Handler for WithState<S, F>
+    where S: Send + Sync + 'static, F: Fn(&mut Exchange, &S) + Send + Sync + 'static
+{
+    fn handle(&self, exchange: &mut Exchange) {
+        (self.handler)(exchange, &self.state)
+    }
+}
@@ -0,0 +1,139 @@
+//! Response header handling for the high-level server.
+
+/// An ordered list of response headers. Header names are compared
+/// case-insensitively, values are kept as given.
+#[derive(Clone, Debug, Default)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+
+impl Headers {
+    /// Creates an empty header list.
+    pub fn new() -> Headers {
+        Headers { entries: Vec::new() }
+    }
+
+    /// Returns the first value of the named header.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Returns all values of the named header in insertion order.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries.iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Returns true if at least one header with the given name is present.
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Replaces all values of the named header with a single value.
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    /// Adds a value for the named header, keeping existing values.
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.entries.push((String::from(name), String::from(value)));
+    }
+
+    /// Removes all values of the named header.
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    /// Iterates over all (name, value) pairs in insertion order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    /// Number of header lines.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if no headers are set.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// The length of the head of CGI output, up to and including the empty
+/// line which ends it, or None if that line has not been written yet.
+pub(crate) fn cgi_head_len(output: &[u8]) -> Option<usize> {
+    let mut start = 0;
+    while let Some(i) = output[start..].iter().position(|&b| b == b'\n') {
+        let line = &output[start..start + i];
+        start += i + 1;
+        if line.is_empty() || line == b"\r" {
+            return Some(start);
+        }
+    }
+    None
+}
+
+/// Parses the head of CGI output into the status, taken from the `Status`
+/// header or 200 if there is none, and the other headers.
+pub(crate) fn parse_cgi_head(head: &[u8]) -> (u16, Headers) {
+    let mut headers = Headers::new();
+    for line in head.split(|&b| b == b'\n') {
+        let line = String::from_utf8_lossy(line);
+        if let Some((name, value)) = line.trim_end_matches('\r').split_once(':') {
+            headers.append(name.trim(), value.trim());
+        }
+    }
+    let status = headers.get("Status")
+        .and_then(|status| status.split_whitespace().next())
+        .and_then(|code| code.parse().ok())
+        .unwrap_or(200);
+    headers.remove("Status");
+    (status, headers)
+}
+
+/// Returns the standard reason phrase for an HTTP status code.
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        422 => "Unprocessable Entity",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    }
+}
@@ -0,0 +1,101 @@
+//! Liveness and readiness probes for orchestrators.
+
+use crate::exchange::Exchange;
+use crate::middleware::{Middleware, Next};
+use crate::router::request_path;
+use crate::server::{PoolStatus, ShutdownHandle};
+
+type Check = Box<dyn Fn() -> bool + Send + Sync>;
+
+/// Answers liveness and readiness probes on two paths, `/healthz` and
+/// `/readyz` by default. Install it with `ServerBuilder::health_check`.
+///
+/// The liveness probe succeeds while at least one worker is running. The
+/// readiness probe additionally fails once the server is draining for
+/// shutdown and when one of the registered checks fails. Failing probes are
+/// answered with 503. The body is a small JSON document describing the
+/// worker pool.
+pub struct HealthCheck {
+    liveness_path: String,
+    readiness_path: String,
+    checks: Vec<Check>,
+    handle: Option<ShutdownHandle>,
+}
+
+impl Default for HealthCheck {
+    fn default() -> HealthCheck {
+        HealthCheck::new()
+    }
+}
+
+impl HealthCheck {
+    /// Creates probes on `/healthz` and `/readyz`.
+    pub fn new() -> HealthCheck {
+        HealthCheck {
+            liveness_path: String::from("/healthz"),
+            readiness_path: String::from("/readyz"),
+            checks: Vec::new(),
+            handle: None,
+        }
+    }
+
+    /// Sets the path of the liveness probe.
+    pub fn liveness_path(mut self, path: &str) -> HealthCheck {
+        self.liveness_path = String::from(path);
+        self
+    }
+
+    /// Sets the path of the readiness probe.
+    pub fn readiness_path(mut self, path: &str) -> HealthCheck {
+        self.readiness_path = String::from(path);
+        self
+    }
+
+    /// Adds a check which must pass for the application to be ready, e.g.
+    /// a database ping.
+    pub fn readiness_check<F>(mut self, check: F) -> HealthCheck
+        where F: Fn() -> bool + Send + Sync + 'static
+    {
+        self.checks.push(Box::new(check));
+        self
+    }
+
+    /// Connects the probes to a server's worker pool.
+    pub(crate) fn attach(mut self, handle: ShutdownHandle) -> HealthCheck {
+        self.handle = Some(handle);
+        self
+    }
+
+    fn respond(&self, exchange: &mut Exchange, readiness: bool) {
+        let status = self.handle.as_ref().map(|handle| handle.pool_status());
+        let live = status.is_none_or(|s| s.live_workers > 0);
+        let ok = if readiness {
+            live && !status.is_some_and(|s| s.draining) && self.checks.iter().all(|check| check())
+        } else {
+            live
+        };
+        let mut body = format!("{{\"status\":\"{}\"", if ok { "ok" } else { "unavailable" });
+        if let Some(PoolStatus { workers, live_workers, in_flight, queued, draining }) = status {
+            body.push_str(&format!(",\"workers\":{},\"live_workers\":{},\"in_flight\":{},\"queued\":{},\"draining\":{}",
+                                   workers, live_workers, in_flight, queued, draining));
+        }
+        body.push_str("}\n");
+        exchange.set_status(if ok { 200 } else { 503 });
+        exchange.set_header("Content-Type", "application/json");
+        exchange.set_header("Cache-Control", "no-store");
+        let _ = exchange.write_body(body.as_bytes());
+    }
+}
+
+impl Middleware for HealthCheck {
+    fn call(&self, exchange: &mut Exchange, next: Next) {
+        let path = request_path(exchange);
+        if path == self.liveness_path {
+            self.respond(exchange, false);
+        } else if path == self.readiness_path {
+            self.respond(exchange, true);
+        } else {
+            next.run(exchange);
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! Conversions between requests and the types of the `http` crate, with
+//! the `http` feature.
+//!
+//! `http::Request` is what web frameworks are built on, so turning a
+//! FastCGI request into one is the first step of running their handlers
+//! here. The request is rebuilt from the CGI parameters: the method from
+//! `REQUEST_METHOD`, the URI from `REQUEST_URI` (or `SCRIPT_NAME`,
+//! `PATH_INFO` and `QUERY_STRING`), the version from `SERVER_PROTOCOL` and
+//! the headers from the `HTTP_*` variables, `CONTENT_TYPE` and
+//! `CONTENT_LENGTH`. All parameters are also attached as a `Params`
+//! extension, for what has no place in an HTTP request such as
+//! `REMOTE_ADDR`.
+//!
+//! ```ignore
+//! let request: http::Request<Vec<u8>> = http::Request::try_from(&mut *exchange)?;
+//! let response = app(request);
+//! exchange.send_response(response)?;
+//! ```
+//!
+//! Converting into an `http::Request<ExchangeBody>` instead leaves the input
+//! unread: the body is a reader on the exchange, so large uploads pass
+//! through without being held in memory. The async server has a streaming
+//! body of its own, see `async_server::tower::Body`.
+//!
+//! In the other direction `Exchange::send_response` writes the status,
+//! headers and body of an `http::Response`.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Read};
+
+use http::header::{HeaderName, HeaderValue};
+use http::{Method, Request, Uri, Version};
+
+use crate::exchange::Exchange;
+
+/// The FastCGI parameters of a request, attached to the `http::Request`
+/// made from it.
+#[derive(Clone, Debug, Default)]
+pub struct Params(pub Vec<(String, String)>);
+
+impl Params {
+    /// Gets the value of a parameter.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.iter().find(|&(n, _)| n == name).map(|(_, value)| value.as_str())
+    }
+}
+
+fn invalid<E: fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Rebuilds the HTTP request from the CGI parameters, see the module
+/// documentation. Fails if they do not make a valid method or URI.
+pub fn request_from_params<B>(params: &[(String, String)], body: B) -> io::Result<Request<B>> {
+    let param = |name: &str| params.iter().find(|&(n, _)| n == name).map(|(_, value)| value.as_str());
+    let method = Method::from_bytes(param("REQUEST_METHOD").unwrap_or("GET").as_bytes()).map_err(invalid)?;
+    let uri = match param("REQUEST_URI") {
+        Some(uri) if !uri.is_empty() => uri.parse::<Uri>().map_err(invalid)?,
+        _ => {
+            let mut uri = format!("{}{}", param("SCRIPT_NAME").unwrap_or(""), param("PATH_INFO").unwrap_or(""));
+            if uri.is_empty() {
+                uri.push('/');
+            }
+            match param("QUERY_STRING") {
+                Some(query) if !query.is_empty() => uri = format!("{}?{}", uri, query),
+                _ => {}
+            }
+            uri.parse::<Uri>().map_err(invalid)?
+        }
+    };
+    let version = match param("SERVER_PROTOCOL") {
+        Some("HTTP/1.0") => Version::HTTP_10,
+        Some("HTTP/2") | Some("HTTP/2.0") => Version::HTTP_2,
+        Some("HTTP/3") | Some("HTTP/3.0") => Version::HTTP_3,
+        _ => Version::HTTP_11,
+    };
+    let mut request = Request::builder().method(method).uri(uri).version(version);
+    for (name, value) in params {
+        let header = match name.as_str() {
+            "CONTENT_TYPE" | "CONTENT_LENGTH" => name.as_str(),
+            _ => match name.strip_prefix("HTTP_") {
+                Some(header) => header,
+                None => continue,
+            },
+        };
+        if value.is_empty() && header == "CONTENT_LENGTH" {
+            continue;
+        }
+        let header = HeaderName::from_bytes(header.to_ascii_lowercase().replace('_', "-").as_bytes());
+        if let (Ok(header), Ok(value)) = (header, HeaderValue::from_str(value)) {
+            request = request.header(header, value);
+        }
+    }
+    request.extension(Params(params.to_vec())).body(body).map_err(invalid)
+}
+
+/// Reads the whole input of the request as the body.
+impl<'a, 'b> TryFrom<&'a mut Exchange<'b>> for Request<Vec<u8>> {
+    type Error = io::Error;
+
+    fn try_from(exchange: &'a mut Exchange<'b>) -> io::Result<Request<Vec<u8>>> {
+        let mut body = Vec::new();
+        exchange.read_to_end(&mut body)?;
+        request_from_params(&exchange.params(), body)
+    }
+}
+
+/// The body of an `http::Request` made from an exchange: reads the input
+/// of the request as it arrives.
+pub struct ExchangeBody<'e, 'a> {
+    exchange: &'e mut Exchange<'a>,
+}
+
+impl<'e, 'a> ExchangeBody<'e, 'a> {
+    /// The exchange, e.g. to send the response once the body is read.
+    pub fn exchange(&mut self) -> &mut Exchange<'a> {
+        self.exchange
+    }
+}
+
+impl<'e, 'a> Read for ExchangeBody<'e, 'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.exchange.read(buf)
+    }
+}
+
+impl<'e, 'a> fmt::Debug for ExchangeBody<'e, 'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ExchangeBody")
+    }
+}
+
+/// Leaves the input to be read from the body.
+impl<'e, 'a> TryFrom<&'e mut Exchange<'a>> for Request<ExchangeBody<'e, 'a>> {
+    type Error = io::Error;
+
+    fn try_from(exchange: &'e mut Exchange<'a>) -> io::Result<Request<ExchangeBody<'e, 'a>>> {
+        let params = exchange.params();
+        request_from_params(&params, ExchangeBody { exchange })
+    }
+}
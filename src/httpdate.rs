@@ -0,0 +1,144 @@
+//! Dates in HTTP headers such as Date, Last-Modified, Expires and
+//! If-Modified-Since, and timestamps for logs.
+//!
+//! `format_http_date` writes the IMF-fixdate format of RFC 7231,
+//! `parse_http_date` also reads the obsolete RFC 850 and asctime formats,
+//! as recipients must.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun",
+                            "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// A UTC timestamp broken down into calendar fields.
+pub(crate) struct DateTime {
+    pub year: u64,
+    pub month: u64,
+    pub day: u64,
+    pub hour: u64,
+    pub minute: u64,
+    pub second: u64,
+    /// Days since 1970-01-01, which was a Thursday.
+    days: u64,
+}
+
+impl DateTime {
+    /// Breaks down a timestamp, clamping times before 1970 to the epoch.
+    pub fn from_system_time(time: SystemTime) -> DateTime {
+        let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let days = secs / 86400;
+        let rem = secs % 86400;
+
+        // Civil date from days since 1970-01-01, see
+        // http://howardhinnant.github.io/date_algorithms.html
+        let z = days + 719_468;
+        let era = z / 146_097;
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        DateTime {
+            year,
+            month,
+            day,
+            hour: rem / 3600,
+            minute: rem % 3600 / 60,
+            second: rem % 60,
+            days,
+        }
+    }
+
+    pub fn weekday_name(&self) -> &'static str {
+        WEEKDAYS[(self.days % 7) as usize]
+    }
+
+    pub fn month_name(&self) -> &'static str {
+        MONTHS[(self.month - 1) as usize]
+    }
+}
+
+/// Formats a timestamp as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn format_http_date(time: SystemTime) -> String {
+    let dt = DateTime::from_system_time(time);
+    format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+            dt.weekday_name(), dt.day, dt.month_name(), dt.year, dt.hour, dt.minute, dt.second)
+}
+
+/// Formats a timestamp as RFC 3339 in UTC, e.g. `1994-11-06T08:49:37Z`.
+pub(crate) fn format_rfc3339(time: SystemTime) -> String {
+    let dt = DateTime::from_system_time(time);
+    format!("{}-{:02}-{:02}T{:02}:{:02}:{:02}Z", dt.year, dt.month, dt.day, dt.hour, dt.minute, dt.second)
+}
+
+/// Days since 1970-01-01 of a civil date, the inverse of the algorithm in
+/// `DateTime::from_system_time`.
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Parses `HH:MM:SS`.
+fn parse_time(time: &str) -> Option<(u64, u64, u64)> {
+    let mut parts = time.split(':').map(|part| if part.len() == 2 { part.parse::<u64>().ok() } else { None });
+    let (hour, minute, second) = (parts.next()??, parts.next()??, parts.next()??);
+    if parts.next().is_some() || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    Some((hour, minute, second))
+}
+
+fn parse_month(month: &str) -> Option<u64> {
+    MONTHS.iter().position(|&name| name == month).map(|i| i as u64 + 1)
+}
+
+/// Parses a date in any of the formats HTTP allows: the IMF-fixdate
+/// `Sun, 06 Nov 1994 08:49:37 GMT`, the RFC 850 format
+/// `Sunday, 06-Nov-94 08:49:37 GMT` and the asctime format
+/// `Sun Nov  6 08:49:37 1994`. Returns None for anything else and for
+/// dates before 1970. The weekday is not checked.
+pub fn parse_http_date(value: &str) -> Option<SystemTime> {
+    let value = value.trim();
+    let fields: Vec<&str> = value.split_whitespace().collect();
+    let (year, month, day, time) = match fields[..] {
+        [weekday, day, month, year, time, "GMT"] if weekday.ends_with(',') && day.len() == 2 && year.len() == 4 => {
+            (year.parse().ok()?, parse_month(month)?, day.parse().ok()?, time)
+        }
+        [weekday, date, time, "GMT"] if weekday.ends_with(',') => {
+            let mut parts = date.split('-');
+            let (day, month, year) = (parts.next()?, parts.next()?, parts.next()?);
+            if parts.next().is_some() || day.len() != 2 || year.len() != 2 {
+                return None;
+            }
+            // Two-digit years more than 50 years in the future are in the
+            // past, RFC 7231 section 7.1.1.1.
+            let year: u64 = year.parse().ok()?;
+            let year = if year < 70 { 2000 + year } else { 1900 + year };
+            (year, parse_month(month)?, day.parse().ok()?, time)
+        }
+        [_, month, day, time, year] if year.len() == 4 => {
+            (year.parse().ok()?, parse_month(month)?, day.parse().ok()?, time)
+        }
+        _ => return None,
+    };
+    let (hour, minute, second) = parse_time(time)?;
+    if year < 1970 || !(1..=31).contains(&day) {
+        return None;
+    }
+    let secs = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+    let time = UNIX_EPOCH + Duration::from_secs(secs);
+    // Days past the end of the month, e.g. 31 Apr, would roll over.
+    if DateTime::from_system_time(time).day != day {
+        return None;
+    }
+    Some(time)
+}
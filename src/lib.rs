@@ -43,31 +43,195 @@
 //! ```
 
 extern crate libc;
+#[cfg(feature = "compression")]
+extern crate flate2;
+#[cfg(feature = "config")]
+extern crate toml;
+#[cfg(feature = "log")]
+extern crate log;
+#[cfg(feature = "evented")]
+extern crate mio;
+#[cfg(feature = "tls")]
+extern crate rustls;
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(feature = "async")]
+extern crate futures_core;
+#[cfg(feature = "async-futures")]
+extern crate futures_io;
+#[cfg(feature = "async-futures")]
+extern crate tokio_util;
+#[cfg(feature = "tower")]
+extern crate bytes;
+#[cfg(feature = "http")]
+extern crate http;
+#[cfg(feature = "tower")]
+extern crate http_body;
+#[cfg(feature = "tower")]
+extern crate tower_service;
+/// Logs an event of the server outside of requests, e.g. a failed
+/// connection, through the `log` crate at the given level:
+/// `server_log!(warn, "...", ...)`. Without the `log` feature it is dropped.
+macro_rules! server_log {
+    ($level:ident, $($arg:tt)*) => {{
+        #[cfg(feature = "log")]
+        log::$level!($($arg)*);
+        #[cfg(not(feature = "log"))]
+        let _ = format_args!($($arg)*);
+    }};
+}
+
+#[cfg(not(any(feature = "ffi", feature = "pure")))]
+compile_error!("enable the `ffi` feature to use libfcgi or the `pure` feature for the native implementation");
+
+#[cfg(feature = "ffi")]
 use std::default::Default;
+#[cfg(feature = "ffi")]
 use std::ffi;
+#[cfg(feature = "ffi")]
 use std::ffi::{CString};
+use std::net::SocketAddr;
 use std::os::unix::io::{RawFd};
+pub mod abort;
+pub mod ajp;
+#[cfg(feature = "async")]
+pub mod async_server;
+pub mod auth;
+#[cfg(any(feature = "digest", feature = "signed-urls"))]
+mod base64;
+pub mod body;
+pub mod client;
+mod conn;
+#[cfg(feature = "ffi")]
 pub mod capi;
+pub mod cgi;
+pub mod csv;
+pub mod daemon;
+pub mod dev_server;
+pub mod error_log;
+pub mod error_pages;
+pub mod exchange;
+pub mod extensions;
+#[cfg(any(feature = "urlencoded", feature = "json", feature = "protobuf", feature = "msgpack"))]
+pub mod extract;
+pub mod handler;
+pub mod headers;
+pub mod health;
+#[cfg(feature = "http")]
+pub mod http_compat;
+pub mod httpdate;
+pub mod listen;
+#[cfg(feature = "log")]
+pub mod logger;
+pub mod longpoll;
+pub mod metrics;
+pub mod middleware;
+pub mod mime;
+pub mod multipart;
+#[cfg(feature = "pure")]
+pub mod native;
+#[cfg(feature = "json")]
+pub mod ndjson;
+pub mod parser;
+pub mod protocol;
+pub mod proxy;
+#[cfg(any(feature = "askama", feature = "tera", feature = "handlebars"))]
+pub mod render;
+#[cfg(feature = "pure")]
+pub mod replay;
+pub mod router;
+pub mod scgi;
+pub mod server;
+#[cfg(feature = "signed-urls")]
+pub mod signed_url;
+pub mod sse;
+pub mod static_files;
+pub mod systemd;
+#[cfg(test)]
+mod testing;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod upload;
+pub mod uri;
+pub mod urlencoding;
+pub mod uwsgi;
+pub mod xml;
+
+pub use crate::abort::AbortToken;
+pub use crate::ajp::AjpRequest;
+pub use crate::auth::{Authenticate, Authenticator, Challenge, Principal};
+pub use crate::cgi::CgiRequest;
+pub use crate::csv::CsvWriter;
+pub use crate::dev_server::DevRequest;
+use crate::protocol::Role;
+pub use crate::error_log::ErrorLog;
+pub use crate::error_pages::ErrorPages;
+pub use crate::exchange::{BodyFilter, Exchange};
+pub use crate::extensions::Extensions;
+#[cfg(any(feature = "urlencoded", feature = "json", feature = "protobuf", feature = "msgpack"))]
+pub use crate::extract::ExtractError;
+pub use crate::handler::Handler;
+pub use crate::headers::Headers;
+pub use crate::health::HealthCheck;
+pub use crate::listen::{ListenAddr, UnixSocketOptions};
+pub use crate::longpoll::LongPoll;
+pub use crate::metrics::Metrics;
+pub use crate::middleware::{Middleware, Next};
+pub use crate::mime::MimeTypes;
+pub use crate::multipart::Multipart;
+#[cfg(feature = "pure")]
+pub use crate::native::{Capabilities, Listener, NativeRequest, OutputOptions};
+#[cfg(feature = "json")]
+pub use crate::ndjson::NdjsonWriter;
+#[cfg(any(feature = "askama", feature = "tera", feature = "handlebars"))]
+pub use crate::render::RenderResponse;
+pub use crate::router::Router;
+pub use crate::scgi::ScgiRequest;
+pub use crate::server::{run, serve, serve_with_state, ConfigError, PoolStatus, Protocol, Server, ServerBuilder, ServerConfig, ShutdownHandle};
+#[cfg(feature = "signed-urls")]
+pub use crate::signed_url::UrlSigner;
+pub use crate::sse::EventStream;
+pub use crate::static_files::{StaticFiles, StaticMounts};
+pub use crate::upload::{Form, UploadedFile, Uploads};
+pub use crate::uri::UriBuilder;
+pub use crate::uwsgi::UwsgiRequest;
+pub use crate::xml::XmlWriter;
 
 /// Initialize the FCGX library. Returns true upon success.
+#[cfg(feature = "ffi")]
 pub fn initialize_fcgi() -> bool {
     unsafe {
         return capi::FCGX_Init() == 0;
     }
 }
 
+/// Nothing to initialize without libfcgi, always true.
+#[cfg(not(feature = "ffi"))]
+pub fn initialize_fcgi() -> bool {
+    true
+}
+
 /// Returns true if this process appears to be a CGI process
 /// rather than a FastCGI process.
+#[cfg(feature = "ffi")]
 pub fn is_cgi() -> bool {
     unsafe {
         return capi::FCGX_IsCGI() != 0;
     }
 }
 
+/// Returns true if this process appears to be a CGI process
+/// rather than a FastCGI process.
+#[cfg(not(feature = "ffi"))]
+pub fn is_cgi() -> bool {
+    native::is_cgi()
+}
+
 #[derive(Clone,Copy)]
 pub enum StreamType { OutStream, InStream, ErrStream }
 
-/// Methods for working with an FCGI request object. A default implementation is provided within this package.
+/// Methods for working with an FCGI request object. A default implementation is provided within this package,
+/// `DefaultRequest` using libfcgi, and with the `pure` feature `NativeRequest`.
 pub trait Request {
 
     /// Creates a new already initialized instance of an FCGI request.
@@ -91,9 +255,45 @@ pub trait Request {
     /// Writes the given String into the error stream.
     fn error(&mut self, msg: &str) -> i32;
 
+    /// Writes raw bytes into the output stream. Returns the number
+    /// of bytes written or -1 on error. The default implementation
+    /// goes through `write`, replacing invalid UTF-8, so implementations
+    /// able to write binary data should override it.
+    fn write_bytes(&mut self, buf: &[u8]) -> i32 {
+        if self.write(&String::from_utf8_lossy(buf)) < 0 {
+            return -1;
+        }
+        buf.len() as i32
+    }
+
+    /// Reads up to buf.len() bytes from the input stream into buf.
+    /// Returns the number of bytes read, 0 at the end of input. The
+    /// default implementation goes through `read`, so it reads only what
+    /// `read` passes on unchanged; implementations should override it.
+    fn read_bytes(&mut self, buf: &mut [u8]) -> i32 {
+        let (msg, n) = self.read(buf.len() as i32);
+        if n < 0 {
+            return n;
+        }
+        let len = msg.len().min(buf.len());
+        buf[..len].copy_from_slice(&msg.as_bytes()[..len]);
+        len as i32
+    }
+
     /// Reads the entire input into a String, returns the
-    /// empty string of no input was read.
-    fn readall(&mut self) -> String;
+    /// empty string of no input was read. Invalid UTF-8 is replaced.
+    fn readall(&mut self) -> String {
+        let mut input = Vec::new();
+        let mut buffer = [0; 8192];
+        loop {
+            let n = self.read_bytes(&mut buffer);
+            if n <= 0 {
+                break;
+            }
+            input.extend_from_slice(&buffer[..n as usize]);
+        }
+        String::from_utf8_lossy(&input).into_owned()
+    }
 
     /// Reads up to n consecutive bytes from the input stream
     /// and returns them as String.  Performs no interpretation
@@ -104,14 +304,55 @@ pub trait Request {
 
     /// Flushes any buffered output
     fn flush(&mut self, stream_type: StreamType);
+
+    /// The token set when the web server aborts the request. Transports
+    /// which do not notice aborts return a token which is never set.
+    fn abort_token(&self) -> AbortToken {
+        AbortToken::new()
+    }
+
+    /// The role the web server assigned to the application for the
+    /// request. Transports supporting only responders return `Responder`.
+    fn role(&self) -> Role {
+        Role::Responder
+    }
+
+    /// Switches the input stream of a filter request to the file data, the
+    /// FCGI_DATA stream, once the standard input has been read to its end.
+    /// Returns false for other roles or if input is left.
+    fn start_filter_data(&mut self) -> bool {
+        false
+    }
+
+    /// The address of the peer of the connection the request came on, if
+    /// the transport knows it: the web server, or the address it connected
+    /// from to a load balancer speaking the PROXY protocol.
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// All parameters of the request, for transports which can list them.
+    fn params(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+}
+
+/// Implements `Request::read` over `read_bytes`, for the transports.
+pub(crate) fn read_lossy<R: Request + ?Sized>(request: &mut R, n: i32) -> (String, i32) {
+    let mut buffer = vec![0; n.max(0) as usize];
+    let count = request.read_bytes(&mut buffer).max(0);
+    buffer.truncate(count as usize);
+    (String::from_utf8_lossy(&buffer).into_owned(), count)
 }
 
 /// Default implementation for FCGI request
+#[cfg(feature = "ffi")]
 #[allow(missing_copy_implementations)]
 pub struct DefaultRequest {
     raw_request: capi::FCGX_Request
 }
 
+#[cfg(feature = "ffi")]
 impl Request for DefaultRequest {
     fn new() -> Option<DefaultRequest> {
         let mut request: capi::FCGX_Request = Default::default();
@@ -160,6 +401,24 @@ impl Request for DefaultRequest {
         }
     }
 
+    fn params(&self) -> Vec<(String, String)> {
+        let mut params = Vec::new();
+        let mut envp = self.raw_request.envp as *const *const libc::c_char;
+        if envp.is_null() {
+            return params;
+        }
+        unsafe {
+            while !(*envp).is_null() {
+                let entry = ffi::CStr::from_ptr(*envp).to_string_lossy();
+                if let Some((name, value)) = entry.split_once('=') {
+                    params.push((String::from(name), String::from(value)));
+                }
+                envp = envp.offset(1);
+            }
+        }
+        params
+    }
+
     fn write(&mut self, msg: &str) -> i32 {
         let cstr = ffi::CString::new(msg).unwrap();
         unsafe {
@@ -174,27 +433,30 @@ impl Request for DefaultRequest {
         }
     }
 
-    fn read(&mut self, n: i32) -> (String, i32) {
+    fn write_bytes(&mut self, buf: &[u8]) -> i32 {
         unsafe {
-            let size = (n + 1) as usize;
-            let mut buffer = Vec::with_capacity(size);
-            let pdst = buffer.as_mut_ptr();
-            let byte_count = capi::FCGX_GetStr(pdst, n, self.raw_request.in_stream);
-            buffer.set_len(byte_count as usize);
-            let result_cstr = ffi::CStr::from_ptr(pdst);
-            let result_str = result_cstr.to_str().unwrap();
-            return (String::from(result_str), byte_count);
+            capi::FCGX_PutStr(buf.as_ptr() as *const libc::c_char, buf.len() as libc::c_int, self.raw_request.out_stream)
         }
     }
 
-    fn readall(&mut self) -> String {
-        let (mut msg, mut n) = self.read(512);
-        while n == 512 {
-            let (new_msg, new_n) = self.read(512);
-            msg = msg + new_msg.as_ref();
-            n = new_n;
+    fn read_bytes(&mut self, buf: &mut [u8]) -> i32 {
+        unsafe {
+            capi::FCGX_GetStr(buf.as_mut_ptr() as *mut libc::c_char, buf.len() as libc::c_int, self.raw_request.in_stream)
+        }
+    }
+
+    fn read(&mut self, n: i32) -> (String, i32) {
+        read_lossy(self, n)
+    }
+
+    fn role(&self) -> Role {
+        Role::from_u16(self.raw_request.role as u16)
+    }
+
+    fn start_filter_data(&mut self) -> bool {
+        unsafe {
+            capi::FCGX_StartFilterData(self.raw_request.in_stream) == 0
         }
-        return msg;
     }
 
     fn flush(&mut self, stream_type: StreamType) {
@@ -0,0 +1,291 @@
+//! Binding the listen socket in-process instead of relying on spawn-fcgi.
+//!
+//! A `ListenAddr` is written as `host:port` (`:port` listens on all IPv4
+//! addresses) for TCP, or as `unix:/path` or an absolute path for a Unix
+//! socket. On Linux, `@name` (or `unix:@name`) names a socket in the
+//! abstract namespace, which has no file to manage. An already listening
+//! socket is selected with `fd:N`, e.g. `fd:0` as set up by spawn-fcgi, or
+//! `systemd` for the first socket passed by socket activation, so the same
+//! binary runs under any of them. The address can come from the
+//! configuration, the FCGI_LISTEN environment variable or a `--listen`
+//! command line argument.
+//!
+//! Unix sockets are set up according to `UnixSocketOptions`: a stale socket
+//! file left behind by a crashed process is removed before binding, and
+//! the file mode and owner can be set so the web server may connect.
+
+use std::env;
+use std::error::Error;
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{IntoRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use libc;
+
+use crate::systemd;
+
+/// An address the server can bind itself.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    /// A TCP socket address.
+    Tcp(SocketAddr),
+    /// A Unix socket path.
+    Unix(PathBuf),
+    /// A Linux abstract socket name, without the leading NUL byte.
+    Abstract(String),
+    /// A listening socket the process inherited.
+    Inherited(RawFd),
+    /// The first socket passed by systemd socket activation.
+    Systemd,
+}
+
+/// Error returned when parsing a malformed `ListenAddr`.
+#[derive(Debug)]
+pub struct InvalidListenAddr(String);
+
+impl fmt::Display for InvalidListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid listen address {:?}", self.0)
+    }
+}
+
+impl Error for InvalidListenAddr {}
+
+impl FromStr for ListenAddr {
+    type Err = InvalidListenAddr;
+
+    fn from_str(s: &str) -> Result<ListenAddr, InvalidListenAddr> {
+        let invalid = || InvalidListenAddr(String::from(s));
+        if s == "systemd" {
+            return Ok(ListenAddr::Systemd);
+        }
+        if let Some(fd) = s.strip_prefix("fd:") {
+            return fd.parse().ok().filter(|&fd| fd >= 0).map(ListenAddr::Inherited).ok_or_else(invalid);
+        }
+        let unix = s.strip_prefix("unix:");
+        if let Some(name) = unix.unwrap_or(s).strip_prefix('@') {
+            if name.is_empty() {
+                return Err(invalid());
+            }
+            return Ok(ListenAddr::Abstract(String::from(name)));
+        }
+        if let Some(path) = unix {
+            if path.is_empty() {
+                return Err(invalid());
+            }
+            return Ok(ListenAddr::Unix(PathBuf::from(path)));
+        }
+        if s.starts_with('/') {
+            return Ok(ListenAddr::Unix(PathBuf::from(s)));
+        }
+        if let Some(port) = s.strip_prefix(':') {
+            let port = port.parse().map_err(|_| invalid())?;
+            return Ok(ListenAddr::Tcp(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port)));
+        }
+        s.parse().map(ListenAddr::Tcp).map_err(|_| invalid())
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ListenAddr::Tcp(ref addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(ref path) => write!(f, "unix:{}", path.display()),
+            ListenAddr::Abstract(ref name) => write!(f, "@{}", name),
+            ListenAddr::Inherited(fd) => write!(f, "fd:{}", fd),
+            ListenAddr::Systemd => write!(f, "systemd"),
+        }
+    }
+}
+
+impl ListenAddr {
+    /// Reads the address from the FCGI_LISTEN environment variable.
+    pub fn from_env() -> Option<Result<ListenAddr, InvalidListenAddr>> {
+        env::var("FCGI_LISTEN").ok().map(|addr| addr.parse())
+    }
+
+    /// Reads the address from a `--listen ADDR` or `--listen=ADDR`
+    /// command line argument.
+    pub fn from_args() -> Option<Result<ListenAddr, InvalidListenAddr>> {
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            if arg == "--listen" {
+                return Some(args.next().unwrap_or_default().parse());
+            }
+            if let Some(addr) = arg.strip_prefix("--listen=") {
+                return Some(addr.parse());
+            }
+        }
+        None
+    }
+
+    /// Binds and listens on the address with the default Unix socket
+    /// options, returning the socket.
+    pub fn bind(&self) -> io::Result<RawFd> {
+        self.bind_with(&UnixSocketOptions::default())
+    }
+
+    /// Binds and listens on the address, returning the socket. The options
+    /// only apply to Unix sockets with a path. An inherited socket is
+    /// duplicated instead.
+    pub fn bind_with(&self, options: &UnixSocketOptions) -> io::Result<RawFd> {
+        match *self {
+            ListenAddr::Tcp(addr) => Ok(TcpListener::bind(addr)?.into_raw_fd()),
+            ListenAddr::Unix(ref path) => {
+                if options.remove_stale {
+                    remove_stale_socket(path)?;
+                }
+                let fd = UnixListener::bind(path)?.into_raw_fd();
+                if let Err(e) = options.apply(path) {
+                    unsafe { libc::close(fd) };
+                    return Err(e);
+                }
+                Ok(fd)
+            }
+            ListenAddr::Abstract(ref name) => bind_abstract(name),
+            ListenAddr::Inherited(fd) => dup(fd),
+            ListenAddr::Systemd => match systemd::listen_fds(true).first() {
+                Some(&fd) => dup(fd),
+                None => Err(io::Error::new(io::ErrorKind::NotFound, "no socket passed by systemd")),
+            },
+        }
+    }
+
+    /// Removes the socket file of a Unix socket bound with
+    /// `unlink_on_shutdown`. Called by the server after it has stopped.
+    pub fn cleanup(&self, options: &UnixSocketOptions) {
+        if let ListenAddr::Unix(ref path) = *self {
+            if options.unlink_on_shutdown {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+/// How the socket file of a Unix socket is set up and cleaned up.
+#[derive(Clone, Debug)]
+pub struct UnixSocketOptions {
+    /// Permission bits for the socket file, e.g. 0o660. Left to the umask
+    /// if None.
+    pub mode: Option<u32>,
+    /// User id to own the socket file.
+    pub uid: Option<u32>,
+    /// Group id to own the socket file, typically the web server's group.
+    pub gid: Option<u32>,
+    /// Removes an existing socket file before binding if no process is
+    /// listening on it any more.
+    pub remove_stale: bool,
+    /// Removes the socket file when the server shuts down. After dropping
+    /// privileges this needs write access to the socket's directory.
+    pub unlink_on_shutdown: bool,
+}
+
+impl Default for UnixSocketOptions {
+    fn default() -> UnixSocketOptions {
+        UnixSocketOptions {
+            mode: None,
+            uid: None,
+            gid: None,
+            remove_stale: true,
+            unlink_on_shutdown: true,
+        }
+    }
+}
+
+impl UnixSocketOptions {
+    fn apply(&self, path: &Path) -> io::Result<()> {
+        if let Some(mode) = self.mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+        if self.uid.is_some() || self.gid.is_some() {
+            let c_path = CString::new(path.as_os_str().as_bytes())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            // An id of -1 leaves the owner or group unchanged.
+            let uid = self.uid.unwrap_or(u32::MAX) as libc::uid_t;
+            let gid = self.gid.unwrap_or(u32::MAX) as libc::gid_t;
+            if unsafe { libc::chown(c_path.as_ptr(), uid, gid) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn bind_abstract(name: &str) -> io::Result<RawFd> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::SocketAddr;
+
+    let addr = SocketAddr::from_abstract_name(name.as_bytes())?;
+    Ok(UnixListener::bind_addr(&addr)?.into_raw_fd())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_abstract(_name: &str) -> io::Result<RawFd> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "abstract sockets are only supported on Linux"))
+}
+
+/// Removes a socket file nobody listens on. Other files, and sockets of
+/// running servers, are left for bind to fail on.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(ref metadata) if metadata.file_type().is_socket() => {}
+        _ => return Ok(()),
+    }
+    match UnixStream::connect(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+/// Duplicates an inherited socket, checking that it is one.
+fn dup(fd: RawFd) -> io::Result<RawFd> {
+    let mut kind: libc::c_int = 0;
+    let mut len = mem::size_of_val(&kind) as libc::socklen_t;
+    let is_socket = unsafe {
+        libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_TYPE, &mut kind as *mut _ as *mut libc::c_void, &mut len)
+    } == 0;
+    if !is_socket {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("fd {} is not a socket", fd)));
+    }
+    let copy = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if copy < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(copy)
+}
+
+/// Changes the length of the queue of pending connections of a listening
+/// socket. Larger values absorb bursts while all workers are busy; the
+/// kernel caps them, e.g. at net.core.somaxconn on Linux.
+pub fn set_backlog(fd: RawFd, backlog: usize) -> io::Result<()> {
+    let backlog = backlog.min(libc::c_int::MAX as usize) as libc::c_int;
+    if unsafe { libc::listen(fd, backlog) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Binds the address and moves the socket to `target`, usually fd 0 where
+/// the FastCGI library expects the listen socket of a process started by
+/// spawn-fcgi or the web server.
+pub fn bind_to_fd(addr: &ListenAddr, options: &UnixSocketOptions, target: RawFd) -> io::Result<()> {
+    let fd = addr.bind_with(options)?;
+    if fd != target {
+        let result = unsafe { libc::dup2(fd, target) };
+        unsafe { libc::close(fd) };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,158 @@
+//! A backend for the `log` crate writing to the FastCGI error stream.
+//!
+//! While a worker of the high-level server handles a request, records
+//! logged on its thread go to that request's error stream, so library logs
+//! end up in the web server's error log next to the request. They also go
+//! to the server's `ErrorLog`, if one is configured. Records logged outside
+//! of requests, e.g. at startup or on threads spawned by a handler, are
+//! written to stderr.
+//!
+//! Requires the `log` feature.
+
+use std::cell::RefCell;
+#[cfg(not(feature = "pure"))]
+use std::ffi::CString;
+use std::io::Write;
+use std::sync::Arc;
+
+#[cfg(not(feature = "pure"))]
+use libc;
+use log::{self, Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+use crate::error_log::ErrorLog;
+#[cfg(feature = "pure")]
+use crate::native::{ErrorSink, NativeRequest};
+#[cfg(not(feature = "pure"))]
+use crate::{capi, DefaultRequest};
+
+/// Where records of the request handled on the current thread go.
+struct Sink {
+    #[cfg(not(feature = "pure"))]
+    err_stream: *mut libc::c_void,
+    #[cfg(feature = "pure")]
+    err_stream: Option<ErrorSink>,
+    error_log: Option<Arc<ErrorLog>>,
+}
+
+impl Sink {
+    #[cfg(not(feature = "pure"))]
+    fn write_stream(&self, line: &str) {
+        if self.err_stream.is_null() {
+            let _ = std::io::stderr().write_all(line.as_bytes());
+        } else if let Ok(msg) = CString::new(line.as_bytes()) {
+            unsafe { capi::FCGX_PutS(msg.as_ptr(), self.err_stream) };
+        }
+    }
+
+    #[cfg(feature = "pure")]
+    fn write_stream(&self, line: &str) {
+        match self.err_stream {
+            Some(ref stream) => { let _ = stream.write(line); }
+            None => { let _ = std::io::stderr().write_all(line.as_bytes()); }
+        }
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Sink>> = const { RefCell::new(None) };
+}
+
+/// Routes records logged on the current thread to the request until
+/// dropped.
+pub(crate) struct RequestScope(());
+
+impl Drop for RequestScope {
+    fn drop(&mut self) {
+        CURRENT.with(|current| *current.borrow_mut() = None);
+    }
+}
+
+/// Called by the server before it hands an accepted request to the
+/// handler.
+#[cfg(not(feature = "pure"))]
+pub(crate) fn enter(request: &DefaultRequest, error_log: Option<Arc<ErrorLog>>) -> RequestScope {
+    set_sink(Sink { err_stream: request.raw_request.err_stream, error_log })
+}
+
+/// Called by the server before it hands an accepted request to the
+/// handler.
+#[cfg(feature = "pure")]
+pub(crate) fn enter(request: &NativeRequest, error_log: Option<Arc<ErrorLog>>) -> RequestScope {
+    set_sink(Sink { err_stream: request.error_sink(), error_log })
+}
+
+/// Like `enter`, for requests of transports without an error stream:
+/// records go to stderr instead.
+pub(crate) fn enter_stderr(error_log: Option<Arc<ErrorLog>>) -> RequestScope {
+    #[cfg(not(feature = "pure"))]
+    let err_stream = std::ptr::null_mut();
+    #[cfg(feature = "pure")]
+    let err_stream = None;
+    set_sink(Sink { err_stream, error_log })
+}
+
+fn set_sink(sink: Sink) -> RequestScope {
+    CURRENT.with(|current| *current.borrow_mut() = Some(sink));
+    RequestScope(())
+}
+
+/// A `log::Log` implementation, see the module documentation.
+pub struct FcgiLogger {
+    level: LevelFilter,
+}
+
+impl FcgiLogger {
+    /// Logs records up to the given level.
+    pub fn new(level: LevelFilter) -> FcgiLogger {
+        FcgiLogger { level }
+    }
+
+    /// Installs a logger for records up to the given level as the global
+    /// logger. Fails if a logger has been installed already.
+    pub fn init(level: LevelFilter) -> Result<(), SetLoggerError> {
+        log::set_boxed_logger(Box::new(FcgiLogger::new(level)))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+}
+
+impl Log for FcgiLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format!("{} {}: {}\n", level_name(record.level()), record.target(), record.args());
+        let written = CURRENT.with(|current| match *current.borrow() {
+            Some(ref sink) => {
+                let redirected = match sink.error_log {
+                    Some(ref log) => log.write(&line).is_ok() && log.is_redirect(),
+                    None => false,
+                };
+                if !redirected {
+                    sink.write_stream(&line);
+                }
+                true
+            }
+            None => false,
+        });
+        if !written {
+            let _ = std::io::stderr().write_all(line.as_bytes());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::Error => "ERROR",
+        Level::Warn => "WARN",
+        Level::Info => "INFO",
+        Level::Debug => "DEBUG",
+        Level::Trace => "TRACE",
+    }
+}
@@ -0,0 +1,129 @@
+//! Long polling: holding a request open until there is something to
+//! answer with.
+//!
+//! `LongPoll` waits on a channel, a condition variable or any blocking
+//! poll, up to a maximum time. Meanwhile it can send keepalive bytes, so
+//! the web server and proxies in between do not give up on an idle
+//! response, and it stops early when the web server aborts the request:
+//!
+//! ```ignore
+//! let poll = LongPoll::new(Duration::from_secs(30)).keepalive(Duration::from_secs(10));
+//! exchange.set_header("Content-Type", "application/json");
+//! match poll.recv(exchange, &updates)? {
+//!     Some(update) => exchange.respond_json(&update)?,
+//!     None => exchange.write_all(b"[]")?,
+//! }
+//! ```
+//!
+//! The first keepalive sends the status and headers, so a handler which
+//! uses keepalives has to set them before waiting. The default keepalive
+//! is a line break, which JSON and most text formats ignore before the
+//! body.
+
+use std::io::{self, Write};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::exchange::Exchange;
+
+/// How long a single poll may block, so aborts are noticed in time.
+const MAX_POLL: Duration = Duration::from_secs(1);
+/// Time left before the request deadline for writing the response.
+const DEADLINE_MARGIN: Duration = Duration::from_secs(1);
+
+/// Waits for a response to a long-polling request, see the module
+/// documentation.
+#[derive(Clone, Debug)]
+pub struct LongPoll {
+    timeout: Duration,
+    keepalive: Option<Duration>,
+    keepalive_data: Vec<u8>,
+}
+
+impl LongPoll {
+    /// Waits at most `timeout`, without keepalives. A request deadline
+    /// shortens the wait so that there is time left to respond.
+    pub fn new(timeout: Duration) -> LongPoll {
+        LongPoll { timeout, keepalive: None, keepalive_data: b"\n".to_vec() }
+    }
+
+    /// Sends a keepalive every `interval` while waiting.
+    pub fn keepalive(mut self, interval: Duration) -> LongPoll {
+        self.keepalive = Some(interval);
+        self
+    }
+
+    /// Sets the bytes sent as keepalive, a line break by default, e.g.
+    /// `b":\n"` for an event stream.
+    pub fn keepalive_data(mut self, data: &[u8]) -> LongPoll {
+        self.keepalive_data = data.to_vec();
+        self
+    }
+
+    /// Waits until `poll` returns a value. `poll` is called repeatedly
+    /// with the time it may block for. Returns None once the timeout has
+    /// passed, and an error if the request was aborted or a keepalive
+    /// could not be sent.
+    pub fn wait<T, F>(&self, exchange: &mut Exchange, mut poll: F) -> io::Result<Option<T>>
+        where F: FnMut(Duration) -> Option<T>
+    {
+        let start = Instant::now();
+        let mut deadline = start + self.timeout;
+        if let Some(remaining) = exchange.time_remaining() {
+            deadline = deadline.min(start + remaining.saturating_sub(DEADLINE_MARGIN));
+        }
+        if self.keepalive.is_some() && !exchange.headers_sent() {
+            exchange.set_header("X-Accel-Buffering", "no");
+        }
+        let mut next_keepalive = self.keepalive.map(|interval| start + interval);
+        loop {
+            if exchange.is_aborted() {
+                return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "request aborted by the web server"));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            if let (Some(at), Some(interval)) = (next_keepalive, self.keepalive) {
+                if now >= at {
+                    exchange.write_body(&self.keepalive_data)?;
+                    exchange.flush()?;
+                    next_keepalive = Some(now + interval);
+                }
+            }
+            let until = next_keepalive.map_or(deadline, |at| at.min(deadline));
+            if let Some(value) = poll(until.saturating_duration_since(now).min(MAX_POLL)) {
+                return Ok(Some(value));
+            }
+        }
+    }
+
+    /// Waits for a message on `receiver`. Returns None on timeout, or at
+    /// once when all senders are gone.
+    pub fn recv<T>(&self, exchange: &mut Exchange, receiver: &Receiver<T>) -> io::Result<Option<T>> {
+        let received = self.wait(exchange, |timeout| match receiver.recv_timeout(timeout) {
+            Ok(message) => Some(Some(message)),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => Some(None),
+        })?;
+        Ok(received.flatten())
+    }
+
+    /// Waits until `take` returns a value for the state guarded by
+    /// `mutex`. `take` is called first and then whenever `condvar` is
+    /// notified; it may take the value out of the state.
+    pub fn wait_on<S, T, F>(&self, exchange: &mut Exchange, mutex: &Mutex<S>, condvar: &Condvar,
+                            mut take: F) -> io::Result<Option<T>>
+        where F: FnMut(&mut S) -> Option<T>
+    {
+        self.wait(exchange, |timeout| {
+            let mut state = mutex.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(value) = take(&mut state) {
+                return Some(value);
+            }
+            let (mut state, _) = condvar.wait_timeout(state, timeout).unwrap_or_else(|e| e.into_inner());
+            take(&mut state)
+        })
+    }
+}
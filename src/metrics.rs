@@ -0,0 +1,211 @@
+//! Request metrics in the Prometheus text exposition format.
+//!
+//! `Metrics` is a middleware recording every request passing through it.
+//! If a path is configured it also answers requests for that path with the
+//! rendered metrics; alternatively `Metrics::handler` can be mounted on a
+//! router.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::exchange::Exchange;
+use crate::handler::Handler;
+use crate::middleware::{Middleware, Next};
+use crate::router::request_path;
+
+/// Upper bounds of the latency histogram buckets in seconds.
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+struct Registry {
+    statuses: Mutex<BTreeMap<u16, u64>>,
+    buckets: [AtomicU64; 11],
+    duration_count: AtomicU64,
+    duration_sum_micros: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    in_flight: AtomicUsize,
+    workers: AtomicUsize,
+    queue_depth: AtomicUsize,
+    queue_wait_count: AtomicU64,
+    queue_wait_sum_micros: AtomicU64,
+}
+
+/// Counts requests, response statuses, latencies, bytes in and out and
+/// busy workers. Clones share the same counters.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Arc<Registry>,
+    path: Option<String>,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new()
+    }
+}
+
+impl Metrics {
+    /// Creates an empty set of metrics which does not answer any path.
+    pub fn new() -> Metrics {
+        Metrics {
+            registry: Arc::new(Registry {
+                statuses: Mutex::new(BTreeMap::new()),
+                buckets: Default::default(),
+                duration_count: AtomicU64::new(0),
+                duration_sum_micros: AtomicU64::new(0),
+                bytes_in: AtomicU64::new(0),
+                bytes_out: AtomicU64::new(0),
+                in_flight: AtomicUsize::new(0),
+                workers: AtomicUsize::new(0),
+                queue_depth: AtomicUsize::new(0),
+                queue_wait_count: AtomicU64::new(0),
+                queue_wait_sum_micros: AtomicU64::new(0),
+            }),
+            path: None,
+        }
+    }
+
+    /// Serves the rendered metrics on the given path, e.g. `/metrics`.
+    /// Requests for it are not counted.
+    pub fn path(mut self, path: &str) -> Metrics {
+        self.path = Some(String::from(path));
+        self
+    }
+
+    /// Sets the size of the worker pool. Called by the server.
+    pub fn set_workers(&self, workers: usize) {
+        self.registry.workers.store(workers, Ordering::Relaxed);
+    }
+
+    /// Sets the number of requests waiting in the accept queue. Called by
+    /// the server.
+    pub fn set_queue_depth(&self, depth: usize) {
+        self.registry.queue_depth.store(depth, Ordering::Relaxed);
+    }
+
+    /// Records how long a request waited in the accept queue. Called by
+    /// the server.
+    pub fn record_queue_wait(&self, wait: Duration) {
+        self.registry.queue_wait_count.fetch_add(1, Ordering::Relaxed);
+        self.registry.queue_wait_sum_micros.fetch_add(wait.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Number of requests currently being handled.
+    pub fn in_flight(&self) -> usize {
+        self.registry.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// A handler rendering the metrics, for mounting on a router.
+    pub fn handler(&self) -> MetricsHandler {
+        MetricsHandler { metrics: self.clone() }
+    }
+
+    fn record(&self, status: u16, seconds: f64, bytes_in: u64, bytes_out: u64) {
+        let registry = &self.registry;
+        *registry.statuses.lock().unwrap_or_else(|e| e.into_inner()).entry(status).or_insert(0) += 1;
+        for (bucket, &bound) in registry.buckets.iter().zip(BUCKETS.iter()) {
+            if seconds <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        registry.duration_count.fetch_add(1, Ordering::Relaxed);
+        registry.duration_sum_micros.fetch_add((seconds * 1e6) as u64, Ordering::Relaxed);
+        registry.bytes_in.fetch_add(bytes_in, Ordering::Relaxed);
+        registry.bytes_out.fetch_add(bytes_out, Ordering::Relaxed);
+    }
+
+    /// Renders all metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let registry = &self.registry;
+        let mut out = String::new();
+        out.push_str("# HELP fcgi_requests_total Requests handled, by response status.\n");
+        out.push_str("# TYPE fcgi_requests_total counter\n");
+        for (status, count) in registry.statuses.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+            let _ = writeln!(out, "fcgi_requests_total{{status=\"{}\"}} {}", status, count);
+        }
+
+        out.push_str("# HELP fcgi_request_duration_seconds Time spent handling requests.\n");
+        out.push_str("# TYPE fcgi_request_duration_seconds histogram\n");
+        let count = registry.duration_count.load(Ordering::Relaxed);
+        for (bucket, bound) in registry.buckets.iter().zip(BUCKETS.iter()) {
+            let _ = writeln!(out, "fcgi_request_duration_seconds_bucket{{le=\"{}\"}} {}",
+                             bound, bucket.load(Ordering::Relaxed));
+        }
+        let _ = writeln!(out, "fcgi_request_duration_seconds_bucket{{le=\"+Inf\"}} {}", count);
+        let _ = writeln!(out, "fcgi_request_duration_seconds_sum {}",
+                         registry.duration_sum_micros.load(Ordering::Relaxed) as f64 / 1e6);
+        let _ = writeln!(out, "fcgi_request_duration_seconds_count {}", count);
+
+        let simple: [(&str, &str, &str, u64); 5] = [
+            ("fcgi_request_bytes_total", "counter", "Request body bytes announced by Content-Length.",
+             registry.bytes_in.load(Ordering::Relaxed)),
+            ("fcgi_response_bytes_total", "counter", "Response body bytes written.",
+             registry.bytes_out.load(Ordering::Relaxed)),
+            ("fcgi_requests_in_flight", "gauge", "Requests currently being handled.",
+             registry.in_flight.load(Ordering::Relaxed) as u64),
+            ("fcgi_workers", "gauge", "Size of the worker pool.",
+             registry.workers.load(Ordering::Relaxed) as u64),
+            ("fcgi_accept_queue_depth", "gauge", "Accepted requests waiting for a worker.",
+             registry.queue_depth.load(Ordering::Relaxed) as u64),
+        ];
+        for &(name, kind, help, value) in &simple {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, value);
+        }
+
+        out.push_str("# HELP fcgi_accept_queue_wait_seconds Time accepted requests waited for a worker.\n");
+        out.push_str("# TYPE fcgi_accept_queue_wait_seconds summary\n");
+        let _ = writeln!(out, "fcgi_accept_queue_wait_seconds_sum {}",
+                         registry.queue_wait_sum_micros.load(Ordering::Relaxed) as f64 / 1e6);
+        let _ = writeln!(out, "fcgi_accept_queue_wait_seconds_count {}",
+                         registry.queue_wait_count.load(Ordering::Relaxed));
+        out
+    }
+}
+
+fn respond(metrics: &Metrics, exchange: &mut Exchange) {
+    let body = metrics.render();
+    exchange.set_header("Content-Type", "text/plain; version=0.0.4");
+    let _ = exchange.write_body(body.as_bytes());
+}
+
+/// Decrements the in-flight gauge even if the handler panics.
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl<'a> Drop for InFlight<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Middleware for Metrics {
+    fn call(&self, exchange: &mut Exchange, next: Next) {
+        if let Some(ref path) = self.path {
+            if request_path(exchange) == *path {
+                return respond(self, exchange);
+            }
+        }
+        let start = Instant::now();
+        let bytes_in = exchange.param("CONTENT_LENGTH").and_then(|len| len.parse().ok()).unwrap_or(0);
+        self.registry.in_flight.fetch_add(1, Ordering::Relaxed);
+        {
+            let _in_flight = InFlight(&self.registry.in_flight);
+            next.run(exchange);
+        }
+        let elapsed = start.elapsed();
+        self.record(exchange.status(), elapsed.as_secs_f64(), bytes_in, exchange.bytes_written());
+    }
+}
+
+/// Renders `Metrics`, see `Metrics::handler`.
+pub struct MetricsHandler {
+    metrics: Metrics,
+}
+
+impl Handler for MetricsHandler {
+    fn handle(&self, exchange: &mut Exchange) {
+        respond(&self.metrics, exchange);
+    }
+}
@@ -0,0 +1,90 @@
+//! Access logging in Common or Combined Log Format.
+
+use std::io::Write;
+use std::time::{Instant, SystemTime};
+
+use crate::exchange::Exchange;
+use crate::httpdate::DateTime;
+use crate::middleware::{LogTarget, Middleware, Next};
+
+/// The layout of an access log line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `host ident user [date] "request" status bytes duration`
+    Common,
+    /// Common followed by the quoted Referer and User-Agent.
+    Combined,
+}
+
+/// Writes one line per request, after the request has been handled.
+///
+/// Lines follow the Common or Combined Log Format with the handling time
+/// in microseconds appended, e.g.
+/// `10.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /index HTTP/1.1" 200 2326 1520`.
+/// By default lines go to the request's FCGI error stream.
+pub struct AccessLog {
+    format: LogFormat,
+    target: LogTarget,
+}
+
+impl Default for AccessLog {
+    fn default() -> AccessLog {
+        AccessLog::new()
+    }
+}
+
+impl AccessLog {
+    /// Logs in Common Log Format to the FCGI error stream.
+    pub fn new() -> AccessLog {
+        AccessLog {
+            format: LogFormat::Common,
+            target: LogTarget::ErrorStream,
+        }
+    }
+
+    /// Sets the log line format.
+    pub fn format(mut self, format: LogFormat) -> AccessLog {
+        self.format = format;
+        self
+    }
+
+    /// Writes the log lines to the given writer instead of the FCGI error
+    /// stream.
+    pub fn writer<W: Write + Send + 'static>(mut self, writer: W) -> AccessLog {
+        self.target = LogTarget::writer(writer);
+        self
+    }
+
+    fn format_line(&self, exchange: &Exchange, elapsed_micros: u128) -> String {
+        let param = |name: &str| exchange.param(name).filter(|v| !v.is_empty());
+        let now = DateTime::from_system_time(SystemTime::now());
+        let bytes = match exchange.bytes_written() {
+            0 => String::from("-"),
+            n => n.to_string(),
+        };
+        let mut line = format!("{} - {} [{:02}/{}/{}:{:02}:{:02}:{:02} +0000] \"{} {} {}\" {} {} {}",
+            param("REMOTE_ADDR").unwrap_or_else(|| String::from("-")),
+            param("REMOTE_USER").unwrap_or_else(|| String::from("-")),
+            now.day, now.month_name(), now.year, now.hour, now.minute, now.second,
+            exchange.method(),
+            param("REQUEST_URI").unwrap_or_else(|| exchange.path()),
+            param("SERVER_PROTOCOL").unwrap_or_else(|| String::from("HTTP/1.0")),
+            exchange.status(), bytes, elapsed_micros);
+        if self.format == LogFormat::Combined {
+            line.push_str(&format!(" \"{}\" \"{}\"",
+                param("HTTP_REFERER").unwrap_or_else(|| String::from("-")).replace('"', "\\\""),
+                param("HTTP_USER_AGENT").unwrap_or_else(|| String::from("-")).replace('"', "\\\"")));
+        }
+        line.push('\n');
+        line
+    }
+}
+
+impl Middleware for AccessLog {
+    fn call(&self, exchange: &mut Exchange, next: Next) {
+        let start = Instant::now();
+        next.run(exchange);
+        let line = self.format_line(exchange, start.elapsed().as_micros());
+        self.target.write(exchange, &line);
+    }
+}
@@ -0,0 +1,330 @@
+//! Caching complete responses in memory.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::exchange::{BodyFilter, Exchange};
+use crate::headers::Headers;
+use crate::middleware::{Middleware, Next};
+
+struct Entry {
+    status: u16,
+    headers: Headers,
+    body: Arc<Vec<u8>>,
+    stored: Instant,
+    expires: Instant,
+}
+
+struct Store {
+    entries: HashMap<String, Entry>,
+    total_size: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Limits {
+    ttl: Duration,
+    max_entries: usize,
+    max_body_size: usize,
+    max_total_size: usize,
+}
+
+impl Store {
+    fn get(&mut self, key: &str) -> Option<(u16, Headers, Arc<Vec<u8>>, Duration)> {
+        let now = Instant::now();
+        let expired = match self.entries.get(key) {
+            Some(entry) if entry.expires > now => {
+                return Some((entry.status, entry.headers.clone(), entry.body.clone(), now - entry.stored));
+            }
+            Some(_) => true,
+            None => false,
+        };
+        if expired {
+            if let Some(entry) = self.entries.remove(key) {
+                self.total_size -= entry.body.len();
+            }
+        }
+        None
+    }
+
+    fn insert(&mut self, limits: &Limits, key: String, status: u16, headers: Headers, body: Vec<u8>) {
+        if body.len() > limits.max_body_size || body.len() > limits.max_total_size || limits.max_entries == 0 {
+            return;
+        }
+        let now = Instant::now();
+        if let Some(old) = self.entries.remove(&key) {
+            self.total_size -= old.body.len();
+        }
+        let expired: Vec<String> = self.entries.iter()
+            .filter(|(_, entry)| entry.expires <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            if let Some(entry) = self.entries.remove(&key) {
+                self.total_size -= entry.body.len();
+            }
+        }
+        // Evict the entries closest to expiry until the new one fits.
+        while self.entries.len() >= limits.max_entries || self.total_size + body.len() > limits.max_total_size {
+            let oldest = self.entries.iter()
+                .min_by_key(|(_, entry)| entry.expires)
+                .map(|(key, _)| key.clone());
+            match oldest.and_then(|key| self.entries.remove(&key)) {
+                Some(entry) => self.total_size -= entry.body.len(),
+                None => break,
+            }
+        }
+        self.total_size += body.len();
+        self.entries.insert(key, Entry {
+            status,
+            headers,
+            body: Arc::new(body),
+            stored: now,
+            expires: now + limits.ttl,
+        });
+    }
+}
+
+/// Returns true if the response may be stored in a shared cache and
+/// varies at most on the request headers the cache keys on.
+fn is_cacheable(status: u16, headers: &Headers, vary: &[String]) -> bool {
+    if status != 200 || headers.contains("Set-Cookie") {
+        return false;
+    }
+    let no_store = headers.get_all("Cache-Control")
+        .flat_map(|value| value.split(','))
+        .map(|directive| directive.trim().to_ascii_lowercase())
+        .any(|directive| directive == "no-store" || directive == "private" || directive == "no-cache");
+    if no_store {
+        return false;
+    }
+    headers.get_all("Vary")
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim())
+        .all(|name| vary.iter().any(|v| v.eq_ignore_ascii_case(name)))
+}
+
+/// Records the response of a cache miss and stores it when it is complete.
+struct CaptureFilter {
+    store: Arc<Mutex<Store>>,
+    limits: Limits,
+    vary: Arc<Vec<String>>,
+    key: String,
+    status: u16,
+    body: Vec<u8>,
+    too_large: bool,
+}
+
+impl BodyFilter for CaptureFilter {
+    fn begin(&mut self, status: u16, _headers: &mut Headers) {
+        self.status = status;
+    }
+
+    fn write(&mut self, data: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        if !self.too_large {
+            if self.body.len() + data.len() > self.limits.max_body_size {
+                self.too_large = true;
+                self.body = Vec::new();
+            } else {
+                self.body.extend_from_slice(data);
+            }
+        }
+        out.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn finish(&mut self, headers: &mut Headers, _out: &mut Vec<u8>) -> io::Result<()> {
+        if !self.too_large && is_cacheable(self.status, headers, &self.vary) {
+            let body = std::mem::take(&mut self.body);
+            let mut store = self.store.lock().unwrap_or_else(|e| e.into_inner());
+            store.insert(&self.limits, self.key.clone(), self.status, headers.clone(), body);
+        }
+        Ok(())
+    }
+}
+
+/// Caches complete GET responses in memory.
+///
+/// Entries are keyed by REQUEST_URI and the values of the request headers
+/// configured with `vary`, and expire after the TTL. HEAD requests are
+/// answered from cached GET responses. Only 200 responses without
+/// Set-Cookie, without `Cache-Control: no-store, no-cache` or `private`,
+/// and whose Vary header lists only headers the cache keys on are stored.
+/// Hits carry an Age header.
+///
+/// Requests with an Authorization header bypass the cache, as the response
+/// may be meant for that client only. Since hits are answered without
+/// running the layers inside the cache, authentication and other access
+/// checks have to sit outside it, added to the stack before the cache.
+pub struct ResponseCache {
+    store: Arc<Mutex<Store>>,
+    limits: Limits,
+    vary: Arc<Vec<String>>,
+}
+
+impl ResponseCache {
+    /// Creates a cache keeping responses for `ttl`, holding at most 1024
+    /// entries and 64 MiB in total, with bodies of up to 1 MiB each.
+    pub fn new(ttl: Duration) -> ResponseCache {
+        ResponseCache {
+            store: Arc::new(Mutex::new(Store { entries: HashMap::new(), total_size: 0 })),
+            limits: Limits {
+                ttl,
+                max_entries: 1024,
+                max_body_size: 1 << 20,
+                max_total_size: 64 << 20,
+            },
+            vary: Arc::new(Vec::new()),
+        }
+    }
+
+    /// Sets the maximum number of cached responses.
+    pub fn max_entries(mut self, max_entries: usize) -> ResponseCache {
+        self.limits.max_entries = max_entries;
+        self
+    }
+
+    /// Responses with larger bodies are not cached.
+    pub fn max_body_size(mut self, bytes: usize) -> ResponseCache {
+        self.limits.max_body_size = bytes;
+        self
+    }
+
+    /// Sets the maximum total size of all cached bodies.
+    pub fn max_total_size(mut self, bytes: usize) -> ResponseCache {
+        self.limits.max_total_size = bytes;
+        self
+    }
+
+    /// Adds request headers whose values become part of the cache key,
+    /// e.g. Accept-Encoding when used together with compression.
+    pub fn vary(mut self, headers: &[&str]) -> ResponseCache {
+        Arc::make_mut(&mut self.vary).extend(headers.iter().map(|h| String::from(*h)));
+        self
+    }
+
+    fn key(&self, exchange: &Exchange) -> String {
+        let mut key = exchange.param("REQUEST_URI").unwrap_or_else(|| exchange.path());
+        for name in self.vary.iter() {
+            key.push('\n');
+            key.push_str(&exchange.header(name).unwrap_or_default());
+        }
+        key
+    }
+}
+
+impl Middleware for ResponseCache {
+    fn call(&self, exchange: &mut Exchange, next: Next) {
+        let method = exchange.method();
+        if method != "GET" && method != "HEAD" || exchange.header("Authorization").is_some() {
+            return next.run(exchange);
+        }
+        let key = self.key(exchange);
+        let cached = self.store.lock().unwrap_or_else(|e| e.into_inner()).get(&key);
+        if let Some((status, headers, body, age)) = cached {
+            exchange.set_status(status);
+            *exchange.headers_mut() = headers;
+            exchange.set_header("Age", &age.as_secs().to_string());
+            if method == "HEAD" {
+                let _ = exchange.send_headers();
+            } else {
+                let _ = exchange.write_body(&body);
+            }
+            return;
+        }
+        if method == "GET" {
+            exchange.add_filter(Box::new(CaptureFilter {
+                store: self.store.clone(),
+                limits: self.limits,
+                vary: self.vary.clone(),
+                key,
+                status: 200,
+                body: Vec::new(),
+                too_large: false,
+            }));
+        }
+        next.run(exchange);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::ResponseCache;
+    use crate::exchange::Exchange;
+    use crate::middleware::Stack;
+    use crate::testing::TestRequest;
+
+    /// A cached handler answering with `headers`, and its number of calls.
+    fn cached(headers: &'static [(&'static str, &'static str)]) -> (Stack, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let handler = move |exchange: &mut Exchange| {
+            let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            for &(name, value) in headers {
+                exchange.set_header(name, value);
+            }
+            exchange.write_body(format!("response {}", n).as_bytes()).unwrap();
+        };
+        (Stack::new(handler).layer(ResponseCache::new(Duration::from_secs(60))), calls)
+    }
+
+    #[test]
+    fn hit() {
+        let (stack, calls) = cached(&[]);
+        let first = TestRequest::get("/a").run(&stack);
+        let second = TestRequest::get("/a").run(&stack);
+        assert_eq!(second.body, first.body);
+        assert_eq!(second.header("Age"), Some("0"));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        TestRequest::get("/b").run(&stack);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn head_from_get() {
+        let (stack, calls) = cached(&[("Content-Type", "text/plain")]);
+        TestRequest::get("/a").run(&stack);
+        let head = TestRequest::with_method("HEAD", "/a").run(&stack);
+        assert_eq!(head.status, 200);
+        assert_eq!(head.header("Content-Type"), Some("text/plain"));
+        assert!(head.body.is_empty());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn authorization() {
+        let (stack, calls) = cached(&[]);
+        TestRequest::get("/a").run(&stack);
+        let private = TestRequest::get("/a").header("Authorization", "Bearer x").run(&stack);
+        assert_eq!(private.body, b"response 2");
+        assert_eq!(private.header("Age"), None);
+        // Neither served from nor stored in the cache.
+        let (stack, calls_auth) = cached(&[]);
+        TestRequest::get("/a").header("Authorization", "Bearer x").run(&stack);
+        TestRequest::get("/a").run(&stack);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(calls_auth.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn not_stored() {
+        let uncacheable: [&'static [(&'static str, &'static str)]; 4] = [
+            &[("Set-Cookie", "session=1")],
+            &[("Cache-Control", "private")],
+            &[("Cache-Control", "max-age=60, no-store")],
+            &[("Vary", "Accept-Language")],
+        ];
+        for headers in uncacheable.iter() {
+            let (stack, calls) = cached(headers);
+            TestRequest::get("/a").run(&stack);
+            let second = TestRequest::get("/a").run(&stack);
+            assert_eq!(second.body, b"response 2", "{:?}", headers);
+            assert_eq!(calls.load(Ordering::SeqCst), 2);
+        }
+    }
+}
@@ -0,0 +1,323 @@
+//! gzip/deflate compression of response bodies.
+//!
+//! Requires the `compression` feature.
+
+use std::io;
+use std::io::Write;
+
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression as Level;
+
+use crate::exchange::{BodyFilter, Exchange};
+use crate::headers::Headers;
+use crate::middleware::{Middleware, Next};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Coding {
+    Gzip,
+    Deflate,
+}
+
+impl Coding {
+    fn name(self) -> &'static str {
+        match self {
+            Coding::Gzip => "gzip",
+            Coding::Deflate => "deflate",
+        }
+    }
+}
+
+/// Picks the preferred supported coding from an Accept-Encoding header.
+/// gzip wins over deflate when both have the same quality.
+fn negotiate(accept_encoding: &str) -> Option<Coding> {
+    let mut best: Option<(Coding, f32)> = None;
+    let mut wildcard: Option<f32> = None;
+    let mut explicit = Vec::new();
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let quality = parts
+            .filter_map(|p| p.trim().strip_prefix("q=").and_then(|q| q.trim().parse::<f32>().ok()))
+            .next()
+            .unwrap_or(1.0);
+        let coding = match name.as_str() {
+            "gzip" | "x-gzip" => Coding::Gzip,
+            "deflate" => Coding::Deflate,
+            "*" => {
+                wildcard = Some(quality);
+                continue;
+            }
+            _ => continue,
+        };
+        explicit.push(coding);
+        if quality > 0.0 && best.is_none_or(|(c, q)| quality > q || (quality == q && c == Coding::Deflate)) {
+            best = Some((coding, quality));
+        }
+    }
+    if best.is_none() {
+        if let Some(q) = wildcard {
+            if q > 0.0 && !explicit.contains(&Coding::Gzip) {
+                return Some(Coding::Gzip);
+            }
+        }
+    }
+    best.map(|(coding, _)| coding)
+}
+
+/// Returns true for content types which usually compress well.
+fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    // Event streams are flushed event by event, which compression defeats.
+    (mime.starts_with("text/") && mime != "text/event-stream")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(mime.as_str(),
+                    "application/json" | "application/javascript" | "application/xml"
+                    | "application/x-javascript" | "image/svg+xml" | "application/wasm")
+}
+
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn buffer(&mut self) -> &mut Vec<u8> {
+        match *self {
+            Encoder::Gzip(ref mut e) => e.get_mut(),
+            Encoder::Deflate(ref mut e) => e.get_mut(),
+        }
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match *self {
+            Encoder::Gzip(ref mut e) => e,
+            Encoder::Deflate(ref mut e) => e,
+        }
+    }
+
+    fn try_finish(&mut self) -> io::Result<()> {
+        match *self {
+            Encoder::Gzip(ref mut e) => e.try_finish(),
+            Encoder::Deflate(ref mut e) => e.try_finish(),
+        }
+    }
+}
+
+/// The body filter installed by `Compression` for a single response.
+struct CompressionFilter {
+    coding: Coding,
+    level: u32,
+    min_size: u64,
+    encoder: Option<Encoder>,
+    seen_data: bool,
+}
+
+impl CompressionFilter {
+    fn drain(&mut self, out: &mut Vec<u8>) {
+        if let Some(ref mut encoder) = self.encoder {
+            out.append(encoder.buffer());
+        }
+    }
+}
+
+impl BodyFilter for CompressionFilter {
+    fn begin(&mut self, status: u16, headers: &mut Headers) {
+        let content_type = headers.get("Content-Type").unwrap_or("");
+        let too_small = headers.get("Content-Length")
+            .and_then(|len| len.trim().parse::<u64>().ok())
+            .is_some_and(|len| len < self.min_size);
+        if status < 200 || status == 204 || status == 206 || status == 304
+            || headers.contains("Content-Encoding")
+            || !is_compressible(content_type) {
+            return;
+        }
+        headers.append("Vary", "Accept-Encoding");
+        if too_small {
+            return;
+        }
+        headers.remove("Content-Length");
+        headers.set("Content-Encoding", self.coding.name());
+        let level = Level::new(self.level);
+        self.encoder = Some(match self.coding {
+            Coding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), level)),
+            Coding::Deflate => Encoder::Deflate(ZlibEncoder::new(Vec::new(), level)),
+        });
+    }
+
+    fn write(&mut self, data: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        match self.encoder {
+            Some(ref mut encoder) => {
+                self.seen_data = true;
+                encoder.writer().write_all(data)?;
+            }
+            None => {
+                out.extend_from_slice(data);
+                return Ok(());
+            }
+        }
+        self.drain(out);
+        Ok(())
+    }
+
+    fn flush(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        if let Some(ref mut encoder) = self.encoder {
+            encoder.writer().flush()?;
+        }
+        self.drain(out);
+        Ok(())
+    }
+
+    fn finish(&mut self, headers: &mut Headers, out: &mut Vec<u8>) -> io::Result<()> {
+        if self.encoder.is_none() {
+            return Ok(());
+        }
+        if !self.seen_data {
+            // Nothing to compress, e.g. a HEAD response: keep the body empty.
+            headers.remove("Content-Encoding");
+            self.encoder = None;
+            return Ok(());
+        }
+        if let Some(ref mut encoder) = self.encoder {
+            encoder.try_finish()?;
+        }
+        self.drain(out);
+        Ok(())
+    }
+}
+
+/// Compresses response bodies with gzip or deflate, as negotiated with the
+/// client's Accept-Encoding header.
+///
+/// Only responses with a compressible Content-Type (text other than event
+/// streams, JSON, XML, JavaScript, SVG) and without a Content-Encoding of
+/// their own are compressed. Content-Length is removed from compressed responses, and
+/// `Vary: Accept-Encoding` is added to all compressible ones.
+pub struct Compression {
+    level: u32,
+    min_size: u64,
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::new()
+    }
+}
+
+impl Compression {
+    /// Compresses with the default level 6, skipping bodies smaller than
+    /// 256 bytes when their Content-Length is known.
+    pub fn new() -> Compression {
+        Compression { level: 6, min_size: 256 }
+    }
+
+    /// Sets the compression level from 0 (none) to 9 (best).
+    pub fn level(mut self, level: u32) -> Compression {
+        self.level = level.min(9);
+        self
+    }
+
+    /// Responses with a Content-Length below this size are left alone.
+    pub fn min_size(mut self, min_size: u64) -> Compression {
+        self.min_size = min_size;
+        self
+    }
+}
+
+impl Middleware for Compression {
+    fn call(&self, exchange: &mut Exchange, next: Next) {
+        let coding = exchange.header("Accept-Encoding").and_then(|accept| negotiate(&accept));
+        if let Some(coding) = coding {
+            exchange.add_filter(Box::new(CompressionFilter {
+                coding,
+                level: self.level,
+                min_size: self.min_size,
+                encoder: None,
+                seen_data: false,
+            }));
+        }
+        next.run(exchange);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::{GzDecoder, ZlibDecoder};
+
+    use super::{negotiate, Coding, Compression};
+    use crate::exchange::Exchange;
+    use crate::middleware::Stack;
+    use crate::testing::{Response, TestRequest};
+
+    /// Answers with `len` bytes of `content_type`, with a Content-Length
+    /// if `known_length` is set.
+    fn call(compression: Compression, accept: &str, content_type: &'static str, len: usize, known_length: bool) -> Response {
+        let stack = Stack::new(move |exchange: &mut Exchange| {
+            exchange.set_header("Content-Type", content_type);
+            if known_length {
+                exchange.set_header("Content-Length", &len.to_string());
+            }
+            exchange.write_body(&body(len)).unwrap();
+        }).layer(compression);
+        TestRequest::get("/").header("Accept-Encoding", accept).run(&stack)
+    }
+
+    fn body(len: usize) -> Vec<u8> {
+        b"abcdefgh".iter().cycle().take(len).cloned().collect()
+    }
+
+    #[test]
+    fn negotiation() {
+        assert_eq!(negotiate("gzip, deflate"), Some(Coding::Gzip));
+        assert_eq!(negotiate("deflate, gzip"), Some(Coding::Gzip));
+        assert_eq!(negotiate("gzip;q=0.5, deflate"), Some(Coding::Deflate));
+        assert_eq!(negotiate("gzip;q=0, deflate;q=0"), None);
+        assert_eq!(negotiate("*"), Some(Coding::Gzip));
+        assert_eq!(negotiate("gzip;q=0, *"), None);
+        assert_eq!(negotiate("br, identity"), None);
+        assert_eq!(negotiate(""), None);
+    }
+
+    #[test]
+    fn compresses() {
+        let response = call(Compression::new(), "gzip", "text/plain", 1000, true);
+        assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.header("Content-Length"), None);
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+        let mut decoded = Vec::new();
+        GzDecoder::new(&response.body[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, body(1000));
+
+        let response = call(Compression::new(), "deflate", "application/json", 1000, false);
+        assert_eq!(response.header("Content-Encoding"), Some("deflate"));
+        let mut decoded = Vec::new();
+        ZlibDecoder::new(&response.body[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, body(1000));
+    }
+
+    #[test]
+    fn min_size() {
+        let response = call(Compression::new(), "gzip", "text/plain", 100, true);
+        assert_eq!(response.header("Content-Encoding"), None);
+        assert_eq!(response.header("Content-Length"), Some("100"));
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.body, body(100));
+        let response = call(Compression::new().min_size(50), "gzip", "text/plain", 100, true);
+        assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+        // Without a length the size is unknown up front.
+        let response = call(Compression::new(), "gzip", "text/plain", 100, false);
+        assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+    }
+
+    #[test]
+    fn skipped() {
+        let response = call(Compression::new(), "gzip", "image/png", 1000, true);
+        assert_eq!((response.header("Content-Encoding"), response.header("Vary")), (None, None));
+        assert_eq!(response.body, body(1000));
+        let response = call(Compression::new(), "identity", "text/plain", 1000, true);
+        assert_eq!(response.header("Content-Encoding"), None);
+        assert_eq!(response.header("Content-Length"), Some("1000"));
+    }
+}
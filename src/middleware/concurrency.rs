@@ -0,0 +1,112 @@
+//! Limiting the number of concurrently handled requests.
+
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::exchange::Exchange;
+use crate::middleware::{Middleware, Next};
+
+struct State {
+    active: usize,
+    waiting: usize,
+    max_active: usize,
+    max_queued: usize,
+    queue_timeout: Duration,
+}
+
+/// Limits how many requests are handled at the same time, independent of
+/// the number of workers, e.g. to protect a database from stampedes.
+///
+/// Requests over the limit wait for a free slot if fewer than `max_queued`
+/// requests are already waiting, for at most the queue timeout. Requests
+/// which cannot be queued or time out are answered with 503 and a
+/// Retry-After header. The limits can be changed while requests are being
+/// handled.
+pub struct ConcurrencyLimit {
+    state: Mutex<State>,
+    released: Condvar,
+}
+
+/// Releases a slot when dropped, also when the handler panics.
+struct Permit<'a>(&'a ConcurrencyLimit);
+
+impl<'a> Drop for Permit<'a> {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.active -= 1;
+        self.0.released.notify_one();
+    }
+}
+
+impl ConcurrencyLimit {
+    /// Allows `max_active` concurrent requests without queueing.
+    pub fn new(max_active: usize) -> ConcurrencyLimit {
+        ConcurrencyLimit {
+            state: Mutex::new(State {
+                active: 0,
+                waiting: 0,
+                max_active,
+                max_queued: 0,
+                queue_timeout: Duration::from_secs(0),
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Lets up to `max_queued` requests wait up to `timeout` for a slot.
+    pub fn queue(mut self, max_queued: usize, timeout: Duration) -> ConcurrencyLimit {
+        {
+            let state = self.state.get_mut().unwrap_or_else(|e| e.into_inner());
+            state.max_queued = max_queued;
+            state.queue_timeout = timeout;
+        }
+        self
+    }
+
+    /// Changes the limits. Requests already being handled keep their slots
+    /// even if the new limit is lower.
+    pub fn set_limits(&self, max_active: usize, max_queued: usize, queue_timeout: Duration) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.max_active = max_active;
+        state.max_queued = max_queued;
+        state.queue_timeout = queue_timeout;
+        self.released.notify_all();
+    }
+
+    fn acquire(&self) -> Option<Permit<'_>> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.active < state.max_active {
+            state.active += 1;
+            return Some(Permit(self));
+        }
+        if state.waiting >= state.max_queued {
+            return None;
+        }
+        state.waiting += 1;
+        let deadline = Instant::now() + state.queue_timeout;
+        while state.active >= state.max_active {
+            let now = Instant::now();
+            if now >= deadline {
+                state.waiting -= 1;
+                return None;
+            }
+            state = self.released.wait_timeout(state, deadline - now)
+                .unwrap_or_else(|e| e.into_inner()).0;
+        }
+        state.waiting -= 1;
+        state.active += 1;
+        Some(Permit(self))
+    }
+}
+
+impl Middleware for ConcurrencyLimit {
+    fn call(&self, exchange: &mut Exchange, next: Next) {
+        match self.acquire() {
+            Some(_permit) => next.run(exchange),
+            None => {
+                exchange.set_header("Retry-After", "1");
+                exchange.respond_error(503);
+            }
+        }
+    }
+}
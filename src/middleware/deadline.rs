@@ -0,0 +1,54 @@
+//! Limiting the wall-clock time spent on a request.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::exchange::Exchange;
+use crate::headers::Headers;
+use crate::middleware::{Middleware, Next};
+
+/// Gives every request a time budget, see `Exchange::set_deadline`.
+///
+/// Handlers are stopped cooperatively: once the budget is spent, their
+/// reads and writes fail. A request which overran its budget is reported
+/// to the error stream and, if its response has not started yet, answered
+/// with 504.
+pub struct Deadline {
+    budget: Mutex<Option<Duration>>,
+}
+
+impl Deadline {
+    /// Allows each request to take up to `budget`.
+    pub fn new(budget: Duration) -> Deadline {
+        Deadline { budget: Mutex::new(Some(budget)) }
+    }
+
+    /// Changes the budget for requests starting from now on. None lets
+    /// requests take as long as they need.
+    pub fn set_budget(&self, budget: Option<Duration>) {
+        *self.budget.lock().unwrap_or_else(|e| e.into_inner()) = budget;
+    }
+}
+
+impl Middleware for Deadline {
+    fn call(&self, exchange: &mut Exchange, next: Next) {
+        let budget = match *self.budget.lock().unwrap_or_else(|e| e.into_inner()) {
+            Some(budget) => budget,
+            None => return next.run(exchange),
+        };
+        let start = Instant::now();
+        exchange.set_deadline(Some(start + budget));
+        next.run(exchange);
+        if !exchange.deadline_exceeded() {
+            return;
+        }
+        exchange.set_deadline(None);
+        let msg = format!("request {} {} exceeded its deadline of {} ms after {} ms\n",
+            exchange.method(), exchange.path(), budget.as_millis(), start.elapsed().as_millis());
+        exchange.error(&msg);
+        if !exchange.headers_sent() {
+            *exchange.headers_mut() = Headers::new();
+            exchange.respond_error(504);
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Digests of response bodies, for clients which verify downloads.
+//!
+//! Requires the `digest` feature.
+
+use std::io;
+
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+use crate::base64;
+use crate::exchange::{BodyFilter, Exchange};
+use crate::headers::Headers;
+use crate::middleware::{Middleware, Next};
+
+/// The body filter installed by `ResponseDigest` for a single response.
+struct DigestFilter {
+    max_size: usize,
+    /// Set while the body is collected; cleared for responses which go
+    /// out without a digest.
+    enabled: bool,
+    sha256: Sha256,
+    md5: Option<Md5>,
+    buffer: Vec<u8>,
+}
+
+impl DigestFilter {
+    /// Passes the collected body on and stops collecting.
+    fn give_up(&mut self, out: &mut Vec<u8>) {
+        self.enabled = false;
+        out.append(&mut self.buffer);
+    }
+}
+
+impl BodyFilter for DigestFilter {
+    fn begin(&mut self, status: u16, headers: &mut Headers) {
+        let content_type = headers.get("Content-Type").unwrap_or("");
+        self.enabled = status >= 200 && status != 204 && status != 304
+            && !content_type.trim_start().to_ascii_lowercase().starts_with("text/event-stream");
+    }
+
+    fn write(&mut self, data: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        if !self.enabled {
+            out.extend_from_slice(data);
+            return Ok(());
+        }
+        if self.buffer.len() + data.len() > self.max_size {
+            self.give_up(out);
+            out.extend_from_slice(data);
+            return Ok(());
+        }
+        self.sha256.update(data);
+        if let Some(ref mut md5) = self.md5 {
+            md5.update(data);
+        }
+        self.buffer.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn flush(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        // The headers go out with the flush, before the digest is known.
+        self.give_up(out);
+        Ok(())
+    }
+
+    fn finish(&mut self, headers: &mut Headers, out: &mut Vec<u8>) -> io::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let sha256 = base64::encode(&self.sha256.finalize_reset());
+        headers.set("Content-Digest", &format!("sha-256=:{}:", sha256));
+        headers.set("Digest", &format!("SHA-256={}", sha256));
+        if let Some(ref mut md5) = self.md5 {
+            headers.set("Content-MD5", &base64::encode(&md5.finalize_reset()));
+        }
+        headers.set("Content-Length", &self.buffer.len().to_string());
+        self.give_up(out);
+        Ok(())
+    }
+}
+
+/// Adds a SHA-256 digest of the body to responses, as `Content-Digest`
+/// (RFC 9530) and the older `Digest` header, and optionally
+/// `Content-MD5`.
+///
+/// Since FastCGI responses have no trailers, the body is held back until
+/// it is complete and the digest known. Bodies larger than the maximum
+/// size, responses flushed by the handler, e.g. event streams, and HEAD
+/// requests go out without a digest. Added before `Compression`, the
+/// digest covers the compressed body, as `Content-Digest` requires.
+pub struct ResponseDigest {
+    max_size: usize,
+    content_md5: bool,
+}
+
+impl Default for ResponseDigest {
+    fn default() -> ResponseDigest {
+        ResponseDigest::new()
+    }
+}
+
+impl ResponseDigest {
+    /// Adds SHA-256 digests to bodies of up to 16 MiB.
+    pub fn new() -> ResponseDigest {
+        ResponseDigest { max_size: 16 * 1024 * 1024, content_md5: false }
+    }
+
+    /// Sets the largest body held back for a digest.
+    pub fn max_size(mut self, max_size: usize) -> ResponseDigest {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Adds a `Content-MD5` header too, for older clients.
+    pub fn content_md5(mut self, content_md5: bool) -> ResponseDigest {
+        self.content_md5 = content_md5;
+        self
+    }
+}
+
+impl Middleware for ResponseDigest {
+    fn call(&self, exchange: &mut Exchange, next: Next) {
+        if exchange.method() != "HEAD" {
+            exchange.add_filter(Box::new(DigestFilter {
+                max_size: self.max_size,
+                enabled: false,
+                sha256: Sha256::new(),
+                md5: if self.content_md5 { Some(Md5::new()) } else { None },
+                buffer: Vec::new(),
+            }));
+        }
+        next.run(exchange);
+    }
+}
@@ -0,0 +1,190 @@
+//! Allowing or denying requests by client address.
+
+use std::error::Error;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::exchange::Exchange;
+use crate::middleware::{Middleware, Next};
+
+/// An IP network in CIDR notation, e.g. `10.0.0.0/8` or `fd00::/8`. A
+/// plain address is a network containing only that address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    /// Creates a network, None if the prefix length is too long for the
+    /// address family.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<IpNet> {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max {
+            return None;
+        }
+        Some(IpNet { addr, prefix_len })
+    }
+
+    /// Returns true if the address lies within the network. IPv4-mapped
+    /// IPv6 addresses are matched against IPv4 networks.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            v4 => v4,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(u32::from(net) as u128, u32::from(ip) as u128, 32, self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(ip), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: u128, ip: u128, bits: u8, prefix_len: u8) -> bool {
+    if prefix_len == 0 {
+        return true;
+    }
+    let shift = bits - prefix_len;
+    net >> shift == ip >> shift
+}
+
+/// Error returned when parsing an `IpNet` fails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidIpNet(String);
+
+impl fmt::Display for InvalidIpNet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid IP network {:?}", self.0)
+    }
+}
+
+impl Error for InvalidIpNet {}
+
+impl FromStr for IpNet {
+    type Err = InvalidIpNet;
+
+    fn from_str(s: &str) -> Result<IpNet, InvalidIpNet> {
+        let invalid = || InvalidIpNet(String::from(s));
+        let (addr, prefix_len) = match s.find('/') {
+            Some(slash) => (&s[..slash], Some(&s[slash + 1..])),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let prefix_len = match prefix_len {
+            Some(len) => len.parse().map_err(|_| invalid())?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        IpNet::new(addr, prefix_len).ok_or_else(invalid)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Rule {
+    Allow,
+    Deny,
+}
+
+/// Allows or denies requests by REMOTE_ADDR.
+///
+/// Rules are checked in the order they were added and the first matching
+/// rule decides. Requests matching no rule are denied unless
+/// `allow_unmatched(true)` is set. Denied requests are answered with 403.
+///
+/// If the web server sits behind reverse proxies, `trust_proxy` makes the
+/// filter take the client address from X-Forwarded-For for requests coming
+/// from a trusted proxy: the rightmost address not belonging to a trusted
+/// proxy is used.
+pub struct IpFilter {
+    rules: Vec<(Rule, IpNet)>,
+    allow_unmatched: bool,
+    trusted_proxies: Vec<IpNet>,
+}
+
+impl Default for IpFilter {
+    fn default() -> IpFilter {
+        IpFilter::new()
+    }
+}
+
+impl IpFilter {
+    /// Creates a filter without rules, denying all requests.
+    pub fn new() -> IpFilter {
+        IpFilter {
+            rules: Vec::new(),
+            allow_unmatched: false,
+            trusted_proxies: Vec::new(),
+        }
+    }
+
+    /// Allows clients from the network.
+    pub fn allow(mut self, net: IpNet) -> IpFilter {
+        self.rules.push((Rule::Allow, net));
+        self
+    }
+
+    /// Denies clients from the network.
+    pub fn deny(mut self, net: IpNet) -> IpFilter {
+        self.rules.push((Rule::Deny, net));
+        self
+    }
+
+    /// Sets whether requests matching no rule are allowed.
+    pub fn allow_unmatched(mut self, allow: bool) -> IpFilter {
+        self.allow_unmatched = allow;
+        self
+    }
+
+    /// Trusts X-Forwarded-For on requests from the network.
+    pub fn trust_proxy(mut self, net: IpNet) -> IpFilter {
+        self.trusted_proxies.push(net);
+        self
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
+
+    /// Determines the client address, None if REMOTE_ADDR is missing or
+    /// malformed.
+    fn client_addr(&self, exchange: &Exchange) -> Option<IpAddr> {
+        let remote: IpAddr = exchange.param("REMOTE_ADDR")?.trim().parse().ok()?;
+        if !self.is_trusted(remote) {
+            return Some(remote);
+        }
+        let forwarded = match exchange.header("X-Forwarded-For") {
+            Some(forwarded) => forwarded,
+            None => return Some(remote),
+        };
+        let mut client = remote;
+        for hop in forwarded.rsplit(',') {
+            client = hop.trim().parse().ok()?;
+            if !self.is_trusted(client) {
+                break;
+            }
+        }
+        Some(client)
+    }
+
+    fn allows(&self, ip: IpAddr) -> bool {
+        match self.rules.iter().find(|&&(_, net)| net.contains(ip)) {
+            Some(&(rule, _)) => rule == Rule::Allow,
+            None => self.allow_unmatched,
+        }
+    }
+}
+
+impl Middleware for IpFilter {
+    fn call(&self, exchange: &mut Exchange, next: Next) {
+        match self.client_addr(exchange) {
+            Some(ip) if self.allows(ip) => next.run(exchange),
+            _ => exchange.respond_error(403),
+        }
+    }
+}
@@ -0,0 +1,360 @@
+//! Authenticating requests by JSON Web Tokens.
+//!
+//! Requires the `jwt` feature.
+
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use jsonwebtoken::errors::{Error, ErrorKind};
+use jsonwebtoken::Validation;
+use serde::de::DeserializeOwned;
+
+use crate::auth::{Authenticator, Challenge, Principal};
+use crate::exchange::Exchange;
+use crate::middleware::{Middleware, Next};
+
+pub use jsonwebtoken::{Algorithm, DecodingKey};
+
+/// The claims of a validated token, stored in the exchange's extensions
+/// by `JwtAuth`:
+///
+/// ```ignore
+/// let user = exchange.extensions().get::<Claims>().and_then(|claims| claims.0["sub"].as_str());
+/// ```
+#[derive(Clone, Debug)]
+pub struct Claims<T = serde_json::Value>(pub T);
+
+/// The challenge for requests without a token.
+fn missing_token() -> Challenge {
+    Challenge::unauthorized("Bearer")
+}
+
+/// The challenge for requests with a token which is not valid.
+fn invalid_token() -> Challenge {
+    Challenge::unauthorized("Bearer error=\"invalid_token\"")
+}
+
+/// A key tokens may be signed with.
+struct Key {
+    /// The `kid` of tokens signed with the key, None for any.
+    id: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+/// Accepts requests carrying a valid JSON Web Token as a bearer token in
+/// the Authorization header or, if configured, in a cookie, and stores its
+/// claims as `Claims<T>` in the exchange's extensions.
+///
+/// The signature has to match one of the keys, picked by algorithm and
+/// the `kid` of the token, the token must not be expired, and its `aud`
+/// and `iss` must be present and among the configured ones, if any.
+/// Other requests are answered with 401 and a `WWW-Authenticate: Bearer`
+/// challenge, unless the token is optional and missing.
+///
+/// ```ignore
+/// let auth = JwtAuth::<serde_json::Value>::new()
+///     .key(Algorithm::HS256, DecodingKey::from_secret(secret))
+///     .audience("api")
+///     .cookie("session");
+/// ```
+pub struct JwtAuth<T = serde_json::Value> {
+    keys: Vec<Key>,
+    audience: Vec<String>,
+    issuer: Vec<String>,
+    leeway: u64,
+    cookie: Option<String>,
+    optional: bool,
+    claims: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned + Send + 'static> Default for JwtAuth<T> {
+    fn default() -> JwtAuth<T> {
+        JwtAuth::new()
+    }
+}
+
+impl<T: DeserializeOwned + Send + 'static> JwtAuth<T> {
+    /// Creates the middleware without keys, rejecting every token until
+    /// keys are added.
+    pub fn new() -> JwtAuth<T> {
+        JwtAuth {
+            keys: Vec::new(),
+            audience: Vec::new(),
+            issuer: Vec::new(),
+            leeway: 60,
+            cookie: None,
+            optional: false,
+            claims: PhantomData,
+        }
+    }
+
+    /// Accepts tokens signed with `key` using `algorithm`, whatever their
+    /// `kid`.
+    pub fn key(mut self, algorithm: Algorithm, key: DecodingKey) -> JwtAuth<T> {
+        self.keys.push(Key { id: None, algorithm, key });
+        self
+    }
+
+    /// Accepts tokens with the `kid` `id` signed with `key`, e.g. while
+    /// keys are rotated.
+    pub fn key_id(mut self, id: &str, algorithm: Algorithm, key: DecodingKey) -> JwtAuth<T> {
+        self.keys.push(Key { id: Some(String::from(id)), algorithm, key });
+        self
+    }
+
+    /// Adds an accepted audience. Without any, `aud` is not checked.
+    pub fn audience(mut self, audience: &str) -> JwtAuth<T> {
+        self.audience.push(String::from(audience));
+        self
+    }
+
+    /// Adds an accepted issuer. Without any, `iss` is not checked.
+    pub fn issuer(mut self, issuer: &str) -> JwtAuth<T> {
+        self.issuer.push(String::from(issuer));
+        self
+    }
+
+    /// Sets the allowed clock skew for `exp` and `nbf`, a minute by
+    /// default.
+    pub fn leeway(mut self, leeway: Duration) -> JwtAuth<T> {
+        self.leeway = leeway.as_secs();
+        self
+    }
+
+    /// Also takes the token from the cookie `name` if there is no
+    /// Authorization header.
+    pub fn cookie(mut self, name: &str) -> JwtAuth<T> {
+        self.cookie = Some(String::from(name));
+        self
+    }
+
+    /// Lets requests without a token through, without claims. Invalid
+    /// tokens are still refused.
+    pub fn optional(mut self, optional: bool) -> JwtAuth<T> {
+        self.optional = optional;
+        self
+    }
+
+    /// Returns the token of the request, if it carries one.
+    pub fn token(&self, exchange: &Exchange) -> Option<String> {
+        let bearer = exchange.header("Authorization").and_then(|authorization| {
+            let (scheme, token) = authorization.trim().split_once(' ')?;
+            if scheme.eq_ignore_ascii_case("Bearer") { Some(String::from(token.trim())) } else { None }
+        });
+        bearer.or_else(|| self.cookie.as_ref().and_then(|name| exchange.cookie(name)))
+    }
+
+    /// Validates `token` and returns its claims.
+    pub fn validate(&self, token: &str) -> Result<T, Error> {
+        let header = jsonwebtoken::decode_header(token)?;
+        let candidates = self.keys.iter().filter(|key| {
+            key.algorithm == header.alg && key.id.as_ref().is_none_or(|id| header.kid.as_ref() == Some(id))
+        });
+        let mut result = Err(Error::from(ErrorKind::InvalidAlgorithm));
+        for key in candidates {
+            let mut validation = Validation::new(key.algorithm);
+            validation.leeway = self.leeway;
+            // Tokens without the claim would pass the checks otherwise.
+            if self.audience.is_empty() {
+                validation.validate_aud = false;
+            } else {
+                validation.set_audience(&self.audience);
+                validation.required_spec_claims.insert(String::from("aud"));
+            }
+            if !self.issuer.is_empty() {
+                validation.set_issuer(&self.issuer);
+                validation.required_spec_claims.insert(String::from("iss"));
+            }
+            result = jsonwebtoken::decode::<T>(token, &key.key, &validation).map(|data| data.claims);
+            match result {
+                Err(ref e) if *e.kind() == ErrorKind::InvalidSignature => continue,
+                _ => break,
+            }
+        }
+        result
+    }
+}
+
+impl<T: DeserializeOwned + Send + 'static> Middleware for JwtAuth<T> {
+    fn call(&self, exchange: &mut Exchange, next: Next) {
+        let token = match self.token(exchange) {
+            Some(token) => token,
+            None if self.optional => return next.run(exchange),
+            None => return missing_token().respond(exchange),
+        };
+        match self.validate(&token) {
+            Ok(claims) => {
+                exchange.extensions_mut().insert(Claims(claims));
+                next.run(exchange);
+            }
+            Err(_) => invalid_token().respond(exchange),
+        }
+    }
+}
+
+/// Names the principal by the `sub` claim, with the other claims which
+/// are strings as attributes, e.g. for `auth::Authenticate` to answer
+/// authorizer requests. Tokens are always required.
+impl Authenticator for JwtAuth<serde_json::Value> {
+    fn authenticate(&self, exchange: &Exchange) -> Result<Principal, Challenge> {
+        let token = self.token(exchange).ok_or_else(missing_token)?;
+        let claims = self.validate(&token).map_err(|_| invalid_token())?;
+        let claims = claims.as_object().ok_or_else(invalid_token)?;
+        let subject = claims.get("sub").and_then(|sub| sub.as_str()).ok_or_else(invalid_token)?;
+        let principal = claims.iter()
+            .filter(|&(name, _)| name != "sub")
+            .filter_map(|(name, value)| value.as_str().map(|value| (name, value)))
+            .fold(Principal::new(subject), |principal, (name, value)| principal.attribute(name, value));
+        Ok(principal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::{json, Value};
+
+    use super::{Algorithm, Claims, DecodingKey, JwtAuth};
+    use crate::auth::Authenticate;
+    use crate::exchange::Exchange;
+    use crate::middleware::Stack;
+    use crate::protocol::Role;
+    use crate::testing::{Response, TestRequest};
+
+    const SECRET: &[u8] = b"secret";
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    fn token(header: Header, claims: Value, secret: &[u8]) -> String {
+        jsonwebtoken::encode(&header, &claims, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    fn with_kid(kid: &str) -> Header {
+        Header { kid: Some(String::from(kid)), ..Header::new(Algorithm::HS256) }
+    }
+
+    fn claims() -> Value {
+        json!({ "sub": "alice", "aud": "api", "iss": "https://issuer", "exp": now() + 60 })
+    }
+
+    fn auth() -> JwtAuth {
+        JwtAuth::new().key(Algorithm::HS256, DecodingKey::from_secret(SECRET))
+    }
+
+    /// Runs `auth` in front of a handler answering with the subject of
+    /// the claims, `-` without claims.
+    fn call(auth: JwtAuth, request: TestRequest) -> Response {
+        let stack = Stack::new(|exchange: &mut Exchange| {
+            let subject = exchange.extensions().get::<Claims>()
+                .map_or(String::from("-"), |claims| String::from(claims.0["sub"].as_str().unwrap_or_default()));
+            exchange.write_body(subject.as_bytes()).unwrap();
+        }).layer(auth);
+        let mut request = request;
+        request.run(&stack)
+    }
+
+    #[test]
+    fn valid() {
+        let claims = auth().validate(&token(Header::default(), claims(), SECRET)).unwrap();
+        assert_eq!(claims["sub"], "alice");
+    }
+
+    #[test]
+    fn expired() {
+        let expired = json!({ "sub": "alice", "exp": now() - 3600 });
+        assert!(auth().validate(&token(Header::default(), expired, SECRET)).is_err());
+        let within_leeway = json!({ "sub": "alice", "exp": now() - 30 });
+        assert!(auth().validate(&token(Header::default(), within_leeway, SECRET)).is_ok());
+    }
+
+    #[test]
+    fn wrong_signature() {
+        assert!(auth().validate(&token(Header::default(), claims(), b"other")).is_err());
+        let mut forged = token(Header::default(), claims(), SECRET);
+        forged.pop();
+        assert!(auth().validate(&forged).is_err());
+    }
+
+    #[test]
+    fn wrong_algorithm() {
+        assert!(auth().validate(&token(Header::new(Algorithm::HS384), claims(), SECRET)).is_err());
+    }
+
+    #[test]
+    fn key_ids() {
+        let auth = JwtAuth::<Value>::new()
+            .key_id("old", Algorithm::HS256, DecodingKey::from_secret(b"old secret"))
+            .key_id("new", Algorithm::HS256, DecodingKey::from_secret(SECRET));
+        assert!(auth.validate(&token(with_kid("new"), claims(), SECRET)).is_ok());
+        assert!(auth.validate(&token(with_kid("old"), claims(), b"old secret")).is_ok());
+        // Signed with the right secret, but naming another key.
+        assert!(auth.validate(&token(with_kid("old"), claims(), SECRET)).is_err());
+        assert!(auth.validate(&token(with_kid("unknown"), claims(), SECRET)).is_err());
+        assert!(auth.validate(&token(Header::default(), claims(), SECRET)).is_err());
+    }
+
+    #[test]
+    fn audience() {
+        let token = token(Header::default(), claims(), SECRET);
+        assert!(auth().audience("api").validate(&token).is_ok());
+        assert!(auth().audience("other").audience("api").validate(&token).is_ok());
+        assert!(auth().audience("other").validate(&token).is_err());
+        let without = json!({ "sub": "alice", "exp": now() + 60 });
+        assert!(auth().audience("api").validate(&self::token(Header::default(), without, SECRET)).is_err());
+    }
+
+    #[test]
+    fn issuer() {
+        let token = token(Header::default(), claims(), SECRET);
+        assert!(auth().issuer("https://issuer").validate(&token).is_ok());
+        assert!(auth().issuer("https://other").validate(&token).is_err());
+        let without = json!({ "sub": "alice", "exp": now() + 60 });
+        assert!(auth().issuer("https://issuer").validate(&self::token(Header::default(), without, SECRET)).is_err());
+    }
+
+    #[test]
+    fn middleware() {
+        let bearer = format!("Bearer {}", token(Header::default(), claims(), SECRET));
+        let response = call(auth(), TestRequest::get("/").header("Authorization", &bearer));
+        assert_eq!((response.status, &response.body[..]), (200, &b"alice"[..]));
+        let response = call(auth(), TestRequest::get("/"));
+        assert_eq!((response.status, response.header("WWW-Authenticate")), (401, Some("Bearer")));
+        let response = call(auth(), TestRequest::get("/").header("Authorization", "Bearer x.y.z"));
+        assert_eq!((response.status, response.header("WWW-Authenticate")), (401, Some("Bearer error=\"invalid_token\"")));
+    }
+
+    #[test]
+    fn cookie() {
+        let cookie = format!("theme=dark; session={}", token(Header::default(), claims(), SECRET));
+        let response = call(auth().cookie("session"), TestRequest::get("/").header("Cookie", &cookie));
+        assert_eq!((response.status, &response.body[..]), (200, &b"alice"[..]));
+        assert_eq!(call(auth(), TestRequest::get("/").header("Cookie", &cookie)).status, 401);
+    }
+
+    #[test]
+    fn optional() {
+        let response = call(auth().optional(true), TestRequest::get("/"));
+        assert_eq!((response.status, &response.body[..]), (200, &b"-"[..]));
+        // A token which is sent has to be valid.
+        let response = call(auth().optional(true), TestRequest::get("/").header("Authorization", "Bearer x.y.z"));
+        assert_eq!(response.status, 401);
+    }
+
+    #[test]
+    fn authenticator() {
+        let stack = Stack::new(|_: &mut Exchange| {}).layer(Authenticate::new(auth().optional(true)));
+        let bearer = format!("Bearer {}", token(Header::default(), claims(), SECRET));
+        let response = TestRequest::get("/").role(Role::Authorizer).header("Authorization", &bearer).run(&stack);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Variable-REMOTE_USER"), Some("alice"));
+        assert_eq!(response.header("Variable-AUTH_ISS"), Some("https://issuer"));
+        // Required even though the middleware would let it pass.
+        let response = TestRequest::get("/").role(Role::Authorizer).run(&stack);
+        assert_eq!((response.status, response.header("WWW-Authenticate")), (401, Some("Bearer")));
+    }
+}
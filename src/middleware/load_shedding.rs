@@ -0,0 +1,125 @@
+//! Rejecting requests early while the server is overloaded.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::exchange::Exchange;
+use crate::middleware::{Middleware, Next};
+
+/// Weight of the latest request in the moving average of the latency.
+const LATENCY_WEIGHT: f64 = 0.2;
+
+/// The load seen by a `LoadShedder` when a request arrives.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Load {
+    /// Requests being handled behind the load shedder, including requests
+    /// waiting for a concurrency slot.
+    pub in_flight: usize,
+    /// Exponential moving average of the handling time of recent requests.
+    pub latency: Duration,
+}
+
+/// Decides whether a request is rejected, given the current load.
+pub trait OverloadPolicy: Send + Sync + 'static {
+    /// Returns true if the request should be answered with 503.
+    fn should_shed(&self, load: &Load) -> bool;
+}
+
+impl<F> OverloadPolicy for F where F: Fn(&Load) -> bool + Send + Sync + 'static {
+    fn should_shed(&self, load: &Load) -> bool {
+        self(load)
+    }
+}
+
+/// Sheds requests while this many are already in flight.
+#[derive(Clone, Copy, Debug)]
+pub struct QueueLength(pub usize);
+
+impl OverloadPolicy for QueueLength {
+    fn should_shed(&self, load: &Load) -> bool {
+        load.in_flight >= self.0
+    }
+}
+
+/// Sheds requests while the average latency exceeds the target. A request
+/// is always admitted when none are in flight, so the average recovers
+/// once the load has dropped.
+#[derive(Clone, Copy, Debug)]
+pub struct Latency(pub Duration);
+
+impl OverloadPolicy for Latency {
+    fn should_shed(&self, load: &Load) -> bool {
+        load.in_flight > 0 && load.latency > self.0
+    }
+}
+
+/// Answers requests with 503 and a Retry-After header as long as the
+/// policy considers the server overloaded, so that the requests which are
+/// admitted still get answered in time instead of latency growing for
+/// everyone. Policies are `QueueLength`, `Latency` or any closure taking
+/// the `Load`.
+pub struct LoadShedder {
+    policy: Box<dyn OverloadPolicy>,
+    retry_after: Duration,
+    load: Mutex<Load>,
+}
+
+/// Records the handling time when dropped, also when the handler panics.
+struct InFlight<'a> {
+    shedder: &'a LoadShedder,
+    start: Instant,
+}
+
+impl<'a> Drop for InFlight<'a> {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let mut load = self.shedder.load.lock().unwrap_or_else(|e| e.into_inner());
+        load.in_flight -= 1;
+        let average = load.latency.as_secs_f64() * (1.0 - LATENCY_WEIGHT) + elapsed * LATENCY_WEIGHT;
+        load.latency = Duration::from_secs_f64(average);
+    }
+}
+
+impl LoadShedder {
+    /// Sheds load according to the policy, asking clients to retry after
+    /// one second.
+    pub fn new<P: OverloadPolicy>(policy: P) -> LoadShedder {
+        LoadShedder {
+            policy: Box::new(policy),
+            retry_after: Duration::from_secs(1),
+            load: Mutex::new(Load { in_flight: 0, latency: Duration::from_secs(0) }),
+        }
+    }
+
+    /// Sets the Retry-After value of rejected requests, in whole seconds.
+    pub fn retry_after(mut self, retry_after: Duration) -> LoadShedder {
+        self.retry_after = retry_after;
+        self
+    }
+
+    /// The current load.
+    pub fn load(&self) -> Load {
+        *self.load.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn admit(&self) -> Option<InFlight<'_>> {
+        let mut load = self.load.lock().unwrap_or_else(|e| e.into_inner());
+        if self.policy.should_shed(&load) {
+            return None;
+        }
+        load.in_flight += 1;
+        Some(InFlight { shedder: self, start: Instant::now() })
+    }
+}
+
+impl Middleware for LoadShedder {
+    fn call(&self, exchange: &mut Exchange, next: Next) {
+        match self.admit() {
+            Some(_in_flight) => next.run(exchange),
+            None => {
+                exchange.set_header("Retry-After", &self.retry_after.as_secs().max(1).to_string());
+                exchange.respond_error(503);
+            }
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! Composable middleware for the high-level server.
+//!
+//! A middleware wraps the handler and every middleware added after it.
+//! It may inspect or modify the exchange before calling `next.run()`,
+//! look at the response afterwards, or answer the request itself without
+//! calling `next` at all.
+
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use crate::exchange::Exchange;
+use crate::handler::Handler;
+
+pub mod access_log;
+pub mod cache;
+#[cfg(feature = "compression")]
+pub mod compression;
+pub mod concurrency;
+pub mod deadline;
+#[cfg(feature = "digest")]
+pub mod digest;
+pub mod ip_filter;
+#[cfg(feature = "jwt")]
+pub mod jwt;
+pub mod load_shedding;
+pub mod slow_log;
+
+pub use self::access_log::{AccessLog, LogFormat};
+pub use self::cache::ResponseCache;
+#[cfg(feature = "compression")]
+pub use self::compression::Compression;
+pub use self::concurrency::ConcurrencyLimit;
+pub use self::deadline::Deadline;
+#[cfg(feature = "digest")]
+pub use self::digest::ResponseDigest;
+pub use self::ip_filter::{IpFilter, IpNet};
+#[cfg(feature = "jwt")]
+pub use self::jwt::{Claims, JwtAuth};
+pub use self::load_shedding::{LoadShedder, OverloadPolicy};
+pub use self::slow_log::SlowLog;
+
+/// A layer around a handler.
+pub trait Middleware: Send + Sync + 'static {
+    /// Handles the request, usually by delegating to `next`.
+    fn call(&self, exchange: &mut Exchange, next: Next);
+}
+
+impl<F> Middleware for F where F: Fn(&mut Exchange, Next) + Send + Sync + 'static {
+    fn call(&self, exchange: &mut Exchange, next: Next) {
+        self(exchange, next)
+    }
+}
+
+/// Shared middleware, e.g. to change its settings while the server runs.
+impl<M: Middleware> Middleware for Arc<M> {
+    fn call(&self, exchange: &mut Exchange, next: Next) {
+        (**self).call(exchange, next)
+    }
+}
+
+/// The remainder of a middleware stack.
+#[derive(Clone, Copy)]
+pub struct Next<'a> {
+    middleware: &'a [Box<dyn Middleware>],
+    handler: &'a dyn Handler,
+}
+
+impl<'a> Next<'a> {
+    /// Runs the remaining middleware and finally the handler.
+    pub fn run(self, exchange: &mut Exchange) {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.call(exchange, Next { middleware: rest, handler: self.handler }),
+            None => self.handler.handle(exchange),
+        }
+    }
+}
+
+/// A handler wrapped in a stack of middleware. The middleware added first
+/// is the outermost one and sees the request first.
+pub struct Stack {
+    middleware: Vec<Box<dyn Middleware>>,
+    handler: Box<dyn Handler>,
+}
+
+impl Stack {
+    /// Creates a stack without middleware around the given handler.
+    pub fn new<H: Handler>(handler: H) -> Stack {
+        Stack {
+            middleware: Vec::new(),
+            handler: Box::new(handler),
+        }
+    }
+
+    /// Adds a middleware inside all previously added ones.
+    pub fn layer<M: Middleware>(self, middleware: M) -> Stack {
+        self.layer_boxed(Box::new(middleware))
+    }
+
+    /// Adds an already boxed middleware inside all previously added ones.
+    pub fn layer_boxed(mut self, middleware: Box<dyn Middleware>) -> Stack {
+        self.middleware.push(middleware);
+        self
+    }
+}
+
+impl Handler for Stack {
+    fn handle(&self, exchange: &mut Exchange) {
+        Next { middleware: &self.middleware, handler: &*self.handler }.run(exchange)
+    }
+}
+
+/// Where logging middleware writes its lines.
+pub(crate) enum LogTarget {
+    ErrorStream,
+    Writer(Mutex<Box<dyn Write + Send>>),
+}
+
+impl LogTarget {
+    pub(crate) fn writer<W: Write + Send + 'static>(writer: W) -> LogTarget {
+        LogTarget::Writer(Mutex::new(Box::new(writer)))
+    }
+
+    pub(crate) fn write(&self, exchange: &mut Exchange, line: &str) {
+        match *self {
+            LogTarget::ErrorStream => exchange.error(line),
+            LogTarget::Writer(ref writer) => {
+                let mut writer = writer.lock().unwrap_or_else(|e| e.into_inner());
+                let _ = writer.write_all(line.as_bytes());
+            }
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! Logging requests which take longer than a threshold.
+
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::exchange::Exchange;
+use crate::middleware::{LogTarget, Middleware, Next};
+
+/// Parameters recorded by default, see `SlowLog::params`.
+const DEFAULT_PARAMS: &[&str] = &[
+    "REQUEST_URI",
+    "QUERY_STRING",
+    "CONTENT_TYPE",
+    "CONTENT_LENGTH",
+    "REMOTE_ADDR",
+    "HTTP_USER_AGENT",
+];
+
+/// Writes a line for every request taking at least the threshold, to find
+/// pathological endpoints. The line records the method, path, duration,
+/// status and a snapshot of selected FCGI parameters, e.g.
+/// `slow request: GET /search 2310 ms status 200 QUERY_STRING="q=a%2A" ...`.
+/// By default lines go to the request's FCGI error stream.
+pub struct SlowLog {
+    threshold: Mutex<Option<Duration>>,
+    params: Vec<String>,
+    target: LogTarget,
+}
+
+impl SlowLog {
+    /// Logs requests taking `threshold` or longer to the FCGI error stream.
+    pub fn new(threshold: Duration) -> SlowLog {
+        SlowLog {
+            threshold: Mutex::new(Some(threshold)),
+            params: DEFAULT_PARAMS.iter().map(|p| String::from(*p)).collect(),
+            target: LogTarget::ErrorStream,
+        }
+    }
+
+    /// Changes the threshold, None to stop logging.
+    pub fn set_threshold(&self, threshold: Option<Duration>) {
+        *self.threshold.lock().unwrap_or_else(|e| e.into_inner()) = threshold;
+    }
+
+    /// Sets the FCGI parameters recorded with each slow request. Parameters
+    /// which are not set are left out.
+    pub fn params(mut self, params: &[&str]) -> SlowLog {
+        self.params = params.iter().map(|p| String::from(*p)).collect();
+        self
+    }
+
+    /// Writes the log lines to the given writer instead of the FCGI error
+    /// stream.
+    pub fn writer<W: Write + Send + 'static>(mut self, writer: W) -> SlowLog {
+        self.target = LogTarget::writer(writer);
+        self
+    }
+
+    fn format_line(&self, exchange: &Exchange, elapsed: Duration) -> String {
+        let mut line = format!("slow request: {} {} {} ms status {}",
+            exchange.method(), exchange.path(), elapsed.as_millis(), exchange.status());
+        for name in &self.params {
+            if let Some(value) = exchange.param(name) {
+                line.push_str(&format!(" {}={:?}", name, value));
+            }
+        }
+        line.push('\n');
+        line
+    }
+}
+
+impl Middleware for SlowLog {
+    fn call(&self, exchange: &mut Exchange, next: Next) {
+        let start = Instant::now();
+        next.run(exchange);
+        let elapsed = start.elapsed();
+        let threshold = *self.threshold.lock().unwrap_or_else(|e| e.into_inner());
+        if threshold.is_some_and(|threshold| elapsed >= threshold) {
+            let line = self.format_line(exchange, elapsed);
+            self.target.write(exchange, &line);
+        }
+    }
+}
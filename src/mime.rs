@@ -0,0 +1,121 @@
+//! Content types of files by their extension, for `StaticFiles` and
+//! `Exchange::write_file`.
+//!
+//! `guess` knows the common types of the web. `MimeTypes` adds overrides
+//! on top, e.g. for extensions specific to an application; the server
+//! fills one from the `mime_types` setting for its static mounts.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+/// The type of files with unknown extensions.
+pub const DEFAULT_TYPE: &str = "application/octet-stream";
+
+/// Returns the content type for a file extension, given without the dot
+/// and in any case. Text types carry `charset=utf-8`.
+pub fn from_extension(ext: &str) -> Option<&'static str> {
+    let content_type = match ext.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "application/javascript",
+        "json" | "map" => "application/json",
+        "jsonld" => "application/ld+json",
+        "webmanifest" => "application/manifest+json",
+        "txt" => "text/plain; charset=utf-8",
+        "md" => "text/markdown; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "ics" => "text/calendar; charset=utf-8",
+        "xml" => "application/xml",
+        "rss" => "application/rss+xml",
+        "atom" => "application/atom+xml",
+        "yaml" | "yml" => "application/yaml",
+        "toml" => "application/toml",
+        "wasm" => "application/wasm",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "apng" => "image/apng",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "bmp" => "image/bmp",
+        "tif" | "tiff" => "image/tiff",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "mp3" => "audio/mpeg",
+        "ogg" | "oga" => "audio/ogg",
+        "wav" => "audio/wav",
+        "flac" => "audio/flac",
+        "m4a" => "audio/mp4",
+        "mp4" | "m4v" => "video/mp4",
+        "webm" => "video/webm",
+        "ogv" => "video/ogg",
+        "mov" => "video/quicktime",
+        "pdf" => "application/pdf",
+        "rtf" => "application/rtf",
+        "epub" => "application/epub+zip",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "bz2" => "application/x-bzip2",
+        "xz" => "application/x-xz",
+        "7z" => "application/x-7z-compressed",
+        "doc" => "application/msword",
+        "docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        "xls" => "application/vnd.ms-excel",
+        "xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        "ppt" => "application/vnd.ms-powerpoint",
+        "pptx" => "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+        "odt" => "application/vnd.oasis.opendocument.text",
+        _ => return None,
+    };
+    Some(content_type)
+}
+
+/// Returns the content type for a file path by its extension,
+/// `application/octet-stream` if the extension is unknown.
+pub fn guess(path: &Path) -> &'static str {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(from_extension)
+        .unwrap_or(DEFAULT_TYPE)
+}
+
+/// Content types by extension which take precedence over `guess`.
+#[derive(Clone, Debug, Default)]
+pub struct MimeTypes {
+    overrides: HashMap<String, String>,
+}
+
+impl MimeTypes {
+    /// Creates a map without overrides.
+    pub fn new() -> MimeTypes {
+        MimeTypes::default()
+    }
+
+    /// Serves files with the extension `ext`, without the dot, as
+    /// `content_type`.
+    pub fn insert(mut self, ext: &str, content_type: &str) -> MimeTypes {
+        self.overrides.insert(ext.trim_start_matches('.').to_ascii_lowercase(), String::from(content_type));
+        self
+    }
+
+    /// Returns the content type for a file path: an override for its
+    /// extension, else what `guess` returns.
+    pub fn get(&self, path: &Path) -> &str {
+        let ext = path.extension().and_then(|ext| ext.to_str()).map(str::to_ascii_lowercase);
+        match ext.and_then(|ext| self.overrides.get(&ext)) {
+            Some(content_type) => content_type,
+            None => guess(path),
+        }
+    }
+}
+
+impl<'a> From<&'a [(String, String)]> for MimeTypes {
+    fn from(overrides: &'a [(String, String)]) -> MimeTypes {
+        overrides.iter().fold(MimeTypes::new(), |types, (ext, content_type)| types.insert(ext, content_type))
+    }
+}
@@ -0,0 +1,339 @@
+//! A streaming parser for `multipart/form-data` bodies, as posted by HTML
+//! forms with file inputs.
+//!
+//! Parts are read one after the other, each as a reader over its data, so
+//! uploads never have to be held in memory as a whole:
+//!
+//! ```ignore
+//! let mut multipart = exchange.multipart()?;
+//! while let Some(mut part) = multipart.next_part()? {
+//!     if part.filename().is_some() {
+//!         io::copy(&mut part, &mut File::create("/tmp/upload")?)?;
+//!     }
+//! }
+//! ```
+
+use std::io::{self, Read};
+
+use crate::headers::Headers;
+use crate::urlencoding::decode_path;
+
+/// How much of the body is read at a time.
+const CHUNK_SIZE: usize = 8192;
+/// The longest header block of a part accepted.
+const MAX_HEADERS_LEN: usize = 16 * 1024;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid multipart body: {}", message))
+}
+
+/// Returns the value of a parameter of a header such as Content-Type or
+/// Content-Disposition, unquoting quoted strings.
+fn header_param(value: &str, name: &str) -> Option<String> {
+    let mut rest = value.split_once(';')?.1;
+    loop {
+        let (key, after) = rest.split_once('=')?;
+        let after = after.trim_start();
+        let (param, next) = if let Some(quoted) = after.strip_prefix('"') {
+            let mut param = String::new();
+            let mut chars = quoted.char_indices();
+            let end = loop {
+                match chars.next() {
+                    Some((_, '\\')) => param.extend(chars.next().map(|(_, c)| c)),
+                    Some((i, '"')) => break i + 1,
+                    Some((_, c)) => param.push(c),
+                    None => break quoted.len(),
+                }
+            };
+            (param, quoted[end..].split_once(';').map_or("", |(_, next)| next))
+        } else {
+            match after.split_once(';') {
+                Some((param, next)) => (String::from(param.trim()), next),
+                None => (String::from(after.trim()), ""),
+            }
+        };
+        if key.trim().eq_ignore_ascii_case(name) {
+            return Some(param);
+        }
+        if next.is_empty() {
+            return None;
+        }
+        rest = next;
+    }
+}
+
+/// Returns the boundary of a `multipart/*` Content-Type, None for other
+/// types or if the boundary is missing or too long.
+pub fn boundary(content_type: &str) -> Option<String> {
+    let media_type = content_type.split(';').next().unwrap_or("").trim();
+    if !media_type.to_ascii_lowercase().starts_with("multipart/") {
+        return None;
+    }
+    header_param(content_type, "boundary").filter(|boundary| !boundary.is_empty() && boundary.len() <= 70)
+}
+
+/// A parser over the parts of a multipart body.
+pub struct Multipart<R> {
+    reader: R,
+    /// `CRLF--boundary`, which ends every part.
+    delimiter: Vec<u8>,
+    /// Data read from `reader` but not consumed yet.
+    buffer: Vec<u8>,
+    /// Set while the data of a part, or the preamble, is being read.
+    in_part: bool,
+    /// Set after the closing delimiter.
+    done: bool,
+}
+
+impl<R: Read> Multipart<R> {
+    /// Parses `reader` as a multipart body with the given boundary.
+    pub fn new(reader: R, boundary: &str) -> Multipart<R> {
+        let mut delimiter = b"\r\n--".to_vec();
+        delimiter.extend_from_slice(boundary.as_bytes());
+        // The first delimiter may come without the line break before it.
+        Multipart { reader, delimiter, buffer: b"\r\n".to_vec(), in_part: true, done: false }
+    }
+
+    /// Parses `reader` as a multipart body with the boundary taken from
+    /// the Content-Type. Fails with `InvalidInput` if the type is not
+    /// multipart or has no boundary.
+    pub fn with_content_type(reader: R, content_type: &str) -> io::Result<Multipart<R>> {
+        let boundary = boundary(content_type)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a multipart Content-Type"))?;
+        Ok(Multipart::new(reader, &boundary))
+    }
+
+    /// Returns the next part, None after the last one. The rest of the
+    /// previous part is skipped.
+    pub fn next_part(&mut self) -> io::Result<Option<Part<'_, R>>> {
+        if self.done {
+            return Ok(None);
+        }
+        io::copy(&mut PartData(self), &mut io::sink())?;
+        // The delimiter is followed by "--" on the last one, otherwise by
+        // optional whitespace and the line break.
+        let line = self.read_line(MAX_HEADERS_LEN)?;
+        if line.starts_with(b"--") {
+            self.done = true;
+            return Ok(None);
+        }
+        if line.iter().any(|&b| b != b' ' && b != b'\t') {
+            return Err(invalid("garbage after the boundary"));
+        }
+        let mut headers = Headers::new();
+        let mut headers_len = 0;
+        loop {
+            let max = MAX_HEADERS_LEN.checked_sub(headers_len).ok_or_else(|| invalid("part headers too large"))?;
+            let line = self.read_line(max)?;
+            headers_len += line.len() + 2;
+            if line.is_empty() {
+                break;
+            }
+            let line = String::from_utf8_lossy(&line);
+            let (name, value) = line.split_once(':').ok_or_else(|| invalid("bad header line"))?;
+            headers.append(name.trim(), value.trim());
+        }
+        self.in_part = true;
+        let disposition = headers.get("Content-Disposition").unwrap_or("");
+        let name = header_param(disposition, "name");
+        let filename = header_param(disposition, "filename*")
+            .and_then(|value| {
+                let (charset, encoded) = value.split_once("''")?;
+                if charset.eq_ignore_ascii_case("UTF-8") { decode_path(encoded) } else { None }
+            })
+            .or_else(|| header_param(disposition, "filename"));
+        Ok(Some(Part { multipart: self, headers, name, filename }))
+    }
+
+    /// Reads more of the body into the buffer, returning false at its end.
+    fn fill(&mut self) -> io::Result<bool> {
+        let len = self.buffer.len();
+        self.buffer.resize(len + CHUNK_SIZE, 0);
+        let result = loop {
+            match self.reader.read(&mut self.buffer[len..]) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                result => break result,
+            }
+        };
+        self.buffer.truncate(len + *result.as_ref().unwrap_or(&0));
+        Ok(result? > 0)
+    }
+
+    /// Reads a line of at most `max` bytes without the CRLF.
+    fn read_line(&mut self, max: usize) -> io::Result<Vec<u8>> {
+        let mut searched = 0;
+        loop {
+            if let Some(i) = self.buffer[searched..].windows(2).position(|w| w == b"\r\n") {
+                let end = searched + i;
+                if end > max {
+                    return Err(invalid("part headers too large"));
+                }
+                let line = self.buffer[..end].to_vec();
+                self.buffer.drain(..end + 2);
+                return Ok(line);
+            }
+            searched = self.buffer.len().saturating_sub(1);
+            if self.buffer.len() > max {
+                return Err(invalid("part headers too large"));
+            }
+            if !self.fill()? {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "multipart body cut short"));
+            }
+        }
+    }
+
+    /// Reads data of the current part, returning 0 once its delimiter has
+    /// been reached.
+    fn read_data(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.in_part {
+            let found = self.buffer.windows(self.delimiter.len()).position(|w| w == &self.delimiter[..]);
+            // Without a delimiter, all but a possible start of one is data.
+            let available = match found {
+                Some(0) => {
+                    self.buffer.drain(..self.delimiter.len());
+                    self.in_part = false;
+                    break;
+                }
+                Some(i) => i,
+                None => self.buffer.len().saturating_sub(self.delimiter.len() - 1),
+            };
+            if available > 0 && !out.is_empty() {
+                let n = available.min(out.len());
+                out[..n].copy_from_slice(&self.buffer[..n]);
+                self.buffer.drain(..n);
+                return Ok(n);
+            }
+            if out.is_empty() {
+                return Ok(0);
+            }
+            if !self.fill()? {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "multipart body ends without the closing boundary"));
+            }
+        }
+        Ok(0)
+    }
+}
+
+/// Reads the data of the current part, see `Multipart::read_data`.
+struct PartData<'a, R>(&'a mut Multipart<R>);
+
+impl<'a, R: Read> Read for PartData<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read_data(buf)
+    }
+}
+
+/// A part of a multipart body: its headers, and its data through `Read`.
+pub struct Part<'a, R> {
+    multipart: &'a mut Multipart<R>,
+    headers: Headers,
+    name: Option<String>,
+    filename: Option<String>,
+}
+
+impl<'a, R: Read> Part<'a, R> {
+    /// The form field name from the Content-Disposition.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The file name of an uploaded file, as sent by the client: do not
+    /// use it as a path without sanitizing it.
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    /// The Content-Type of the part, if the client sent one.
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers.get("Content-Type")
+    }
+
+    /// All headers of the part.
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// Reads the rest of the data as text, for form fields.
+    pub fn text(&mut self) -> io::Result<String> {
+        let mut text = String::new();
+        self.read_to_string(&mut text)?;
+        Ok(text)
+    }
+}
+
+impl<'a, R: Read> Read for Part<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.multipart.read_data(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor};
+
+    use super::{Multipart, MAX_HEADERS_LEN};
+
+    fn parse(body: &str) -> io::Result<Vec<(Option<String>, String)>> {
+        let mut multipart = Multipart::new(Cursor::new(body.as_bytes().to_vec()), "XyZ");
+        let mut parts = Vec::new();
+        while let Some(mut part) = multipart.next_part()? {
+            let name = part.name().map(String::from);
+            parts.push((name, part.text()?));
+        }
+        Ok(parts)
+    }
+
+    fn with_headers(headers: &str) -> String {
+        format!("--XyZ\r\n{}\r\n\r\ndata\r\n--XyZ--\r\n", headers)
+    }
+
+    #[test]
+    fn parts() {
+        let body = "preamble\r\n--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\none\r\n\
+                    --XyZ\r\nContent-Disposition: form-data; name=\"b\"\r\n\r\ntwo\r\nlines\r\n--XyZ--\r\n";
+        let parts = parse(body).unwrap();
+        assert_eq!(parts, vec![
+            (Some(String::from("a")), String::from("one")),
+            (Some(String::from("b")), String::from("two\r\nlines")),
+        ]);
+    }
+
+    #[test]
+    fn oversized_header_line() {
+        let headers = format!("X-Long: {}", "a".repeat(MAX_HEADERS_LEN));
+        let e = parse(&with_headers(&headers)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn oversized_header_block() {
+        // Short lines arrive in the buffer together, past the limit.
+        let headers = vec!["X-Short: 0123456789"; 2 * MAX_HEADERS_LEN / 20].join("\r\n");
+        let e = parse(&with_headers(&headers)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn header_block_at_limit() {
+        // Header lines and the empty line ending them, each with CRLF.
+        let line = "X-Pad: ";
+        let count = 100;
+        let pad = (MAX_HEADERS_LEN - 2) / count - line.len() - 2;
+        let headers = vec![format!("{}{}", line, "p".repeat(pad)); count].join("\r\n");
+        assert_eq!(parse(&with_headers(&headers)).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn truncated_headers() {
+        let e = parse("--XyZ\r\nContent-Disposition: form-data; na").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        let e = parse("--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn truncated_data() {
+        let e = parse("--XyZ\r\n\r\nno closing boundary").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,1218 @@
+//! A FastCGI implementation in Rust, used instead of libfcgi with the
+//! `pure` feature.
+//!
+//! `NativeRequest` speaks the FastCGI wire protocol, see the `protocol`
+//! module, and implements the same `Request` trait as `DefaultRequest`, so
+//! the crate can be used on targets where the C library is not available.
+//! Build with `default-features = false, features = ["pure"]` to drop the
+//! dependency on libfcgi entirely; the high-level server then accepts
+//! `NativeRequest`s.
+//!
+//! A `Listener` accepts connections on the listen socket, or on several
+//! with `Listener::with_fds`, in a thread of its own and reads each
+//! connection in another thread, which hands requests to the
+//! `NativeRequest`s created from the listener once their parameters are
+//! complete. With `Capabilities::mpxs_conns` several requests are handled
+//! at the same time on one connection, their output records interleaved;
+//! otherwise further requests on a busy connection are rejected with
+//! `CantMpxConn`.
+//!
+//! When the web server aborts a request, or the connection is lost, the
+//! request's `AbortToken` is set and its further output discarded. Once the
+//! handler finishes, only `FCGI_END_REQUEST` is sent for it.
+//!
+//! A connection is kept open for further requests if the web server sets
+//! `FCGI_KEEP_CONN`, and closed once its requests have ended otherwise.
+//! Idle connections are closed when a shutdown is pending.
+//!
+//! Requests sending more than 256 KiB of parameters are ended with
+//! `FCGI_OVERLOADED`, as are further requests while 64 of a connection
+//! are receiving theirs.
+//!
+//! All three roles are supported, with the role passed in the `FCGI_ROLE`
+//! parameter like libfcgi does. Authorizer requests have no input. Filter
+//! requests switch to the `FCGI_DATA` stream with `start_filter_data`;
+//! reading it fails if its length differs from `FCGI_DATA_LENGTH`.
+//! `FCGI_GET_VALUES` queries are answered with the `Capabilities`, other
+//! management records with `FCGI_UNKNOWN_TYPE`.
+//!
+//! Output is collected and sent in records as large as possible, cut and
+//! padded according to the `OutputOptions`, so streaming many small writes
+//! does not cost a system call each.
+//!
+//! For diagnosing web server integration, `Listener::set_trace` writes
+//! every record received and sent to stderr: its type, request id, length
+//! and a hexdump of its content. `Listener::set_recording` saves the bytes
+//! received on each connection to a file, which the `replay` module feeds
+//! to a handler again in tests.
+//!
+//! Behind a TCP load balancer, `Listener::set_proxy_protocol` reads the
+//! address of the actual peer from a PROXY protocol header at the start of
+//! each connection, see `Request::peer_addr`.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::mem;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use libc;
+#[cfg(feature = "evented")]
+use mio;
+#[cfg(feature = "tls")]
+use rustls;
+
+use crate::abort::AbortToken;
+#[cfg(feature = "tls")]
+use crate::tls;
+use crate::protocol::{self, EndRequest, ProtocolError, ProtocolStatus, Record, RecordType, Role, UnknownType};
+use crate::proxy;
+use crate::{Request, StreamType};
+
+#[cfg(feature = "evented")]
+mod evented;
+
+/// Input buffered for a request which is not read by its handler. Reading
+/// the connection pauses when it is reached.
+const INPUT_BUFFER: usize = 1024 * 1024;
+/// The size of the parameters of a request. Requests sending more are
+/// ended with `Overloaded`.
+const MAX_PARAMS_LEN: usize = 256 * 1024;
+/// Requests of a connection receiving their parameters at the same time.
+/// Further ones are rejected with `Overloaded`.
+const MAX_BUILDING: usize = 64;
+/// How often threads waiting for connections or requests check for a
+/// pending shutdown.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static SHUTDOWN_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Makes `accept` return false instead of waiting for further requests,
+/// like libfcgi's `FCGX_ShutdownPending`. Requests which have been read
+/// already are still returned, and connections reject new ones with
+/// `Overloaded`.
+pub fn shutdown_pending() {
+    SHUTDOWN_PENDING.store(true, Ordering::SeqCst);
+}
+
+/// Returns true if this process appears to be a CGI process, i.e. stdin
+/// is not a listen socket. The same test as libfcgi's `FCGX_IsCGI`.
+pub fn is_cgi() -> bool {
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of_val(&addr) as libc::socklen_t;
+    let result = unsafe { libc::getpeername(0, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) };
+    !(result != 0 && io::Error::last_os_error().raw_os_error() == Some(libc::ENOTCONN))
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// The roles requests are accepted for.
+const ROLES: &[Role] = &[Role::Responder, Role::Authorizer, Role::Filter];
+
+/// The value of the `FCGI_ROLE` parameter.
+pub(crate) fn role_name(role: Role) -> &'static str {
+    match role {
+        Role::Responder => "RESPONDER",
+        Role::Authorizer => "AUTHORIZER",
+        Role::Filter => "FILTER",
+        Role::Other(_) => "UNKNOWN",
+    }
+}
+
+/// Reads a connection, copying what is read to the recording file of
+/// `Listener::set_recording` if there is one.
+struct Recorder {
+    socket: Socket,
+    file: Option<File>,
+}
+
+impl Read for Recorder {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.socket.read(buf)?;
+        if let Some(ref mut file) = self.file {
+            if let Err(e) = file.write_all(&buf[..n]) {
+                server_log!(warn, "failed to record session: {}", e);
+                self.file = None;
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// Writes a record to stderr for `Listener::set_trace`, at once so the
+/// records of different connections are not mixed.
+fn trace_record(direction: &str, record: &Record) {
+    let mut dump = format!("fcgi: {} {:?} id={} len={}\n",
+                           direction, record.record_type, record.request_id, record.content.len());
+    for (i, line) in record.content.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        let text: String = line.iter()
+            .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+            .collect();
+        dump.push_str(&format!("fcgi:   {:04x}  {:<47}  {}\n", i * 16, hex.join(" "), text));
+    }
+    let _ = io::stderr().write_all(dump.as_bytes());
+}
+
+/// Decodes the name-value pairs of a PARAMS stream.
+pub(crate) fn decode_params(data: &[u8]) -> Result<Vec<(String, String)>, ProtocolError> {
+    protocol::name_values(data)
+        .map(|pair| pair.map(|(name, value)| {
+            (String::from_utf8_lossy(name).into_owned(), String::from_utf8_lossy(value).into_owned())
+        }))
+        .collect()
+}
+
+/// The values reported to the web server in reply to `FCGI_GET_VALUES`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    /// `FCGI_MAX_CONNS`, the number of connections accepted concurrently.
+    pub max_conns: usize,
+    /// `FCGI_MAX_REQS`, the number of requests handled concurrently.
+    pub max_reqs: usize,
+    /// `FCGI_MPXS_CONNS`, whether requests are multiplexed over one
+    /// connection. Also enables multiplexing.
+    pub mpxs_conns: bool,
+}
+
+impl Default for Capabilities {
+    /// The values libfcgi reports: one connection with one request.
+    fn default() -> Capabilities {
+        Capabilities { max_conns: 1, max_reqs: 1, mpxs_conns: false }
+    }
+}
+
+impl Capabilities {
+    /// Encodes the `FCGI_GET_VALUES_RESULT` content for the names asked
+    /// for in a `FCGI_GET_VALUES` query. Unknown names are left out.
+    pub(crate) fn get_values_result(&self, query: &[u8]) -> Vec<u8> {
+        let mut result = Vec::new();
+        for (name, _) in protocol::name_values(query).filter_map(Result::ok) {
+            let value = match name {
+                b"FCGI_MAX_CONNS" => self.max_conns.to_string(),
+                b"FCGI_MAX_REQS" => self.max_reqs.to_string(),
+                b"FCGI_MPXS_CONNS" => String::from(if self.mpxs_conns { "1" } else { "0" }),
+                _ => continue,
+            };
+            protocol::encode_name_value(&mut result, name, value.as_bytes());
+        }
+        result
+    }
+}
+
+/// How response output is cut into records, see
+/// `Listener::set_output_options`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputOptions {
+    /// Output collected before it is sent, unless flushed earlier.
+    pub buffer_size: usize,
+    /// The largest content of an output record, from 1 to
+    /// `MAX_CONTENT_LEN`.
+    pub record_size: usize,
+    /// Pads records to a multiple of 8 bytes as the specification
+    /// recommends.
+    pub padding: bool,
+}
+
+impl Default for OutputOptions {
+    /// Full records of `MAX_ALIGNED_CONTENT_LEN` bytes, which need no
+    /// padding.
+    fn default() -> OutputOptions {
+        OutputOptions {
+            buffer_size: protocol::MAX_ALIGNED_CONTENT_LEN,
+            record_size: protocol::MAX_ALIGNED_CONTENT_LEN,
+            padding: true,
+        }
+    }
+}
+
+enum Socket {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Socket {
+    /// Takes ownership of an accepted connection.
+    fn from_accepted(fd: RawFd, family: libc::c_int) -> Socket {
+        if family == libc::AF_UNIX {
+            Socket::Unix(unsafe { UnixStream::from_raw_fd(fd) })
+        } else {
+            Socket::Tcp(unsafe { TcpStream::from_raw_fd(fd) })
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Socket> {
+        match *self {
+            Socket::Tcp(ref stream) => stream.try_clone().map(Socket::Tcp),
+            Socket::Unix(ref stream) => stream.try_clone().map(Socket::Unix),
+        }
+    }
+
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {
+            Socket::Tcp(ref stream) => stream.as_raw_fd(),
+            Socket::Unix(ref stream) => stream.as_raw_fd(),
+        }
+    }
+
+    /// The address of the peer, None for Unix sockets.
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        match *self {
+            Socket::Tcp(ref stream) => stream.peer_addr().ok(),
+            Socket::Unix(_) => None,
+        }
+    }
+
+    fn shutdown(&self) {
+        let _ = match *self {
+            Socket::Tcp(ref stream) => stream.shutdown(Shutdown::Both),
+            Socket::Unix(ref stream) => stream.shutdown(Shutdown::Both),
+        };
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Socket::Tcp(ref mut stream) => stream.read(buf),
+            Socket::Unix(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Socket::Tcp(ref mut stream) => stream.write(buf),
+            Socket::Unix(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Socket::Tcp(ref mut stream) => stream.flush(),
+            Socket::Unix(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+/// The state of a request whose parameters have been read.
+#[derive(Default)]
+struct Slot {
+    input: VecDeque<Vec<u8>>,
+    /// Bytes queued in `input` and `data`.
+    input_len: usize,
+    input_done: bool,
+    data: VecDeque<Vec<u8>>,
+    data_done: bool,
+    /// The FCGI_DATA bytes still expected as announced by FCGI_DATA_LENGTH.
+    data_left: Option<u64>,
+    data_invalid: bool,
+    stderr_used: bool,
+    aborted: AbortToken,
+}
+
+impl Slot {
+    fn new(role: Role, params: &[(String, String)]) -> Slot {
+        let data_left = params.iter()
+            .find(|param| param.0 == "FCGI_DATA_LENGTH")
+            .and_then(|param| param.1.parse().ok());
+        Slot {
+            input_done: role == Role::Authorizer,
+            data_done: role != Role::Filter,
+            data_left,
+            ..Slot::default()
+        }
+    }
+
+    fn stream_done(&self, data: bool) -> bool {
+        if data { self.data_done } else { self.input_done }
+    }
+
+    /// Queues a STDIN or DATA record, an empty one ends the stream.
+    fn push(&mut self, data: bool, content: Vec<u8>) {
+        if !data {
+            if content.is_empty() {
+                self.input_done = true;
+            } else {
+                self.input_len += content.len();
+                self.input.push_back(content);
+            }
+            return;
+        }
+        if self.data_done {
+            return;
+        }
+        if content.is_empty() {
+            self.data_done = true;
+            self.data_invalid |= self.data_left.is_some_and(|left| left > 0);
+            return;
+        }
+        if let Some(ref mut left) = self.data_left {
+            match left.checked_sub(content.len() as u64) {
+                Some(rest) => *left = rest,
+                None => self.data_invalid = true,
+            }
+        }
+        self.input_len += content.len();
+        self.data.push_back(content);
+    }
+
+    /// Ends both input streams, e.g. when the request is aborted.
+    fn close(&mut self) {
+        self.input_done = true;
+        self.data_done = true;
+    }
+}
+
+#[derive(Default)]
+struct ConnectionState {
+    slots: HashMap<u16, Slot>,
+    /// Requests begun and not yet ended, including those still receiving
+    /// their parameters.
+    open: usize,
+    /// Set by a request without `FCGI_KEEP_CONN`: the connection is closed
+    /// once no request is open.
+    close_when_idle: bool,
+}
+
+impl ConnectionState {
+    /// True if the record is input for a request which has as much input
+    /// buffered as allowed.
+    fn input_full(&self, record: &Record) -> bool {
+        let data = match record.record_type {
+            RecordType::Stdin => false,
+            RecordType::Data => true,
+            _ => return false,
+        };
+        match self.slots.get(&record.request_id) {
+            Some(slot) => slot.input_len >= INPUT_BUFFER && !slot.stream_done(data),
+            None => false,
+        }
+    }
+}
+
+/// A connection from the web server, shared by its reading thread and the
+/// requests handled on it.
+struct Connection {
+    socket: Socket,
+    output: Mutex<Socket>,
+    state: Mutex<ConnectionState>,
+    changed: Condvar,
+    trace: bool,
+    options: OutputOptions,
+    /// The peer address, replaced by the source of a PROXY protocol header.
+    peer: Mutex<Option<SocketAddr>>,
+    /// Wakes the event loop reading the connection, see the `evented`
+    /// module.
+    #[cfg(feature = "evented")]
+    waker: Option<Arc<mio::Waker>>,
+    /// Set while the event loop waits for a request to take input.
+    #[cfg(feature = "evented")]
+    blocked: AtomicBool,
+}
+
+impl Connection {
+    fn send(&self, records: &[u8]) -> io::Result<()> {
+        if self.trace {
+            let mut rest = records;
+            while let Ok(Some((record, used))) = Record::decode(rest) {
+                trace_record("sent", &record);
+                rest = &rest[used..];
+            }
+        }
+        // The event loop makes the socket non-blocking, then writes wait
+        // for it here.
+        let mut output = lock(&self.output);
+        let mut rest = records;
+        while !rest.is_empty() {
+            match output.write(rest) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "connection closed")),
+                Ok(n) => rest = &rest[n..],
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    let mut poll_fd = libc::pollfd { fd: output.as_raw_fd(), events: libc::POLLOUT, revents: 0 };
+                    unsafe { libc::poll(&mut poll_fd, 1, -1) };
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Wakes whoever waits for a change of the state.
+    fn notify(&self) {
+        self.changed.notify_all();
+        #[cfg(feature = "evented")]
+        {
+            if self.blocked.load(Ordering::SeqCst) {
+                if let Some(ref waker) = self.waker {
+                    let _ = waker.wake();
+                }
+            }
+        }
+    }
+
+    fn end_request(&self, request_id: u16, protocol_status: ProtocolStatus) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(16);
+        EndRequest { app_status: 0, protocol_status }.to_record(request_id).encode(&mut buf);
+        self.send(&buf)
+    }
+
+    /// Answers a record sent with the null request id.
+    fn management(&self, record: &Record, capabilities: &Capabilities) -> io::Result<()> {
+        let mut buf = Vec::new();
+        if record.record_type == RecordType::GetValues {
+            let result = capabilities.get_values_result(&record.content);
+            protocol::encode_record(&mut buf, RecordType::GetValuesResult, protocol::NULL_REQUEST_ID, &result);
+        } else {
+            UnknownType { record_type: record.record_type.as_u8() }.to_record().encode(&mut buf);
+        }
+        self.send(&buf)
+    }
+
+    /// Sends the final records of a request and forgets it, closing the
+    /// connection if it is not kept open. The request is forgotten before
+    /// the web server can react to the records, so it may begin the next
+    /// request on the connection right away. The state is not locked while
+    /// sending: the reading thread may hold up the web server's reads while
+    /// it waits for input space.
+    fn end(&self, request_id: u16, records: &[u8]) -> io::Result<()> {
+        {
+            let mut state = lock(&self.state);
+            state.slots.remove(&request_id);
+            state.open = state.open.saturating_sub(1);
+        }
+        self.notify();
+        let result = self.send(records);
+        let state = lock(&self.state);
+        if state.open == 0 && (state.close_when_idle || result.is_err()) {
+            self.socket.shutdown();
+        }
+        result
+    }
+
+    /// Waits until the input of the record can be buffered.
+    fn wait_for_space(&self, record: &Record) {
+        let mut state = lock(&self.state);
+        while state.input_full(record) {
+            state = self.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Takes the peer address from the PROXY protocol header read from the
+    /// connection. An error closes the connection.
+    fn proxied(&self, header: io::Result<proxy::ProxyHeader>) -> io::Result<()> {
+        match header {
+            Ok(header) => {
+                if let Some(source) = header.source {
+                    *lock(&self.peer) = Some(source);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                server_log!(warn, "closing connection: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Aborts the requests of a connection which can no longer be read,
+    /// closing it unless a request still writes output.
+    fn closed(&self) {
+        let mut state = lock(&self.state);
+        for slot in state.slots.values_mut() {
+            slot.close();
+            slot.aborted.abort();
+        }
+        if state.open == 0 {
+            self.socket.shutdown();
+        }
+        self.changed.notify_all();
+    }
+
+    /// Waits until a record arrives on an idle connection. Returns false
+    /// once a shutdown is pending, to close the connection.
+    fn wait_for_request(&self) -> bool {
+        while lock(&self.state).open == 0 {
+            if SHUTDOWN_PENDING.load(Ordering::SeqCst) {
+                return false;
+            }
+            let mut poll_fd = libc::pollfd { fd: self.socket.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+            if unsafe { libc::poll(&mut poll_fd, 1, POLL_INTERVAL.as_millis() as libc::c_int) } > 0 {
+                break;
+            }
+        }
+        true
+    }
+}
+
+/// Reads the records of a connection until it is closed, see the module
+/// documentation.
+fn read_connection<R: Read>(listener: &Listener, connection: &Arc<Connection>, reader: R) -> io::Result<()> {
+    let mut reader = BufReader::new(reader);
+    if listener.proxy_protocol.load(Ordering::SeqCst) {
+        connection.proxied(proxy::read_header(&mut reader))?;
+    }
+    // Roles and parameters of requests which are not complete yet.
+    let mut building = HashMap::new();
+    loop {
+        if reader.buffer().is_empty() && !connection.wait_for_request() {
+            return Ok(());
+        }
+        let record = match Record::read_from(&mut reader)? {
+            Some(record) => record,
+            None => return Ok(()),
+        };
+        connection.wait_for_space(&record);
+        receive(listener, connection, &mut building, record)?;
+    }
+}
+
+/// Acts on a record received on a connection. Input for a request is
+/// buffered even if `ConnectionState::input_full`, the caller waits for
+/// space first.
+fn receive(listener: &Listener, connection: &Arc<Connection>, building: &mut HashMap<u16, (Role, Vec<u8>)>,
+           record: Record) -> io::Result<()> {
+    if connection.trace {
+        trace_record("received", &record);
+    }
+    let id = record.request_id;
+    if id == protocol::NULL_REQUEST_ID {
+        return connection.management(&record, &listener.capabilities());
+    }
+    match record.record_type {
+        RecordType::BeginRequest => {
+            let begin = record.begin_request()?;
+            let rejected = {
+                let mut state = lock(&connection.state);
+                if building.contains_key(&id) || state.slots.contains_key(&id) {
+                    return Ok(());
+                }
+                if state.open > 0 && !listener.capabilities().mpxs_conns {
+                    Some(ProtocolStatus::CantMpxConn)
+                } else if building.len() >= MAX_BUILDING {
+                    Some(ProtocolStatus::Overloaded)
+                } else if !ROLES.contains(&begin.role) {
+                    Some(ProtocolStatus::UnknownRole)
+                } else if SHUTDOWN_PENDING.load(Ordering::SeqCst) {
+                    Some(ProtocolStatus::Overloaded)
+                } else {
+                    state.open += 1;
+                    state.close_when_idle |= !begin.keep_conn();
+                    None
+                }
+            };
+            match rejected {
+                Some(status) => connection.end_request(id, status)?,
+                None => {
+                    building.insert(id, (begin.role, Vec::new()));
+                }
+            }
+        }
+        RecordType::Params if building.contains_key(&id) => {
+            if !record.content.is_empty() {
+                let params = &mut building.get_mut(&id).unwrap().1;
+                if params.len() + record.content.len() > MAX_PARAMS_LEN {
+                    building.remove(&id);
+                    return end_unstarted(connection, id, ProtocolStatus::Overloaded);
+                }
+                params.extend_from_slice(&record.content);
+                return Ok(());
+            }
+            let (role, params) = building.remove(&id).unwrap();
+            let mut params = decode_params(&params)?;
+            params.push((String::from("FCGI_ROLE"), String::from(role_name(role))));
+            let slot = Slot::new(role, &params);
+            let aborted = slot.aborted.clone();
+            lock(&connection.state).slots.insert(id, slot);
+            listener.push(Accepted { connection: connection.clone(), request_id: id, role, params, aborted });
+        }
+        RecordType::Stdin | RecordType::Data => {
+            let data = record.record_type == RecordType::Data;
+            if let Some(slot) = lock(&connection.state).slots.get_mut(&id) {
+                slot.push(data, record.content);
+            }
+            connection.notify();
+        }
+        RecordType::AbortRequest => {
+            if building.remove(&id).is_some() {
+                end_unstarted(connection, id, ProtocolStatus::RequestComplete)?;
+            } else if let Some(slot) = lock(&connection.state).slots.get_mut(&id) {
+                slot.close();
+                slot.aborted.abort();
+                connection.notify();
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Ends a request which is still receiving its parameters.
+fn end_unstarted(connection: &Connection, request_id: u16, protocol_status: ProtocolStatus) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(16);
+    EndRequest { app_status: 0, protocol_status }.to_record(request_id).encode(&mut buf);
+    connection.end(request_id, &buf)
+}
+
+/// A request whose parameters have been read.
+struct Accepted {
+    connection: Arc<Connection>,
+    request_id: u16,
+    role: Role,
+    params: Vec<(String, String)>,
+    aborted: AbortToken,
+}
+
+#[derive(Default)]
+struct ListenerState {
+    accepted: VecDeque<Accepted>,
+    started: bool,
+    closed: bool,
+}
+
+/// Accepts connections on one or more listen sockets and reads their
+/// requests, see the module documentation. The `NativeRequest`s created
+/// from one listener share its connections.
+pub struct Listener {
+    fds: Vec<RawFd>,
+    capabilities: Mutex<Capabilities>,
+    trace: AtomicBool,
+    proxy_protocol: AtomicBool,
+    recording: Mutex<Option<PathBuf>>,
+    recorded: AtomicUsize,
+    closing: AtomicBool,
+    output_options: Mutex<OutputOptions>,
+    #[cfg(feature = "tls")]
+    tls: Mutex<Option<Arc<rustls::ServerConfig>>>,
+    #[cfg(feature = "evented")]
+    event_loop: AtomicBool,
+    state: Mutex<ListenerState>,
+    ready: Condvar,
+}
+
+impl Listener {
+    /// Creates a listener for the listen socket. Accepting starts with the
+    /// first call to `NativeRequest::accept`.
+    pub fn new(fd: RawFd) -> Arc<Listener> {
+        Listener::with_fds(vec![fd])
+    }
+
+    /// Creates a listener for several listen sockets, e.g. a TCP port and
+    /// a few Unix sockets. A single thread waits for connections on all of
+    /// them with poll, and their requests go to the same workers.
+    pub fn with_fds(fds: Vec<RawFd>) -> Arc<Listener> {
+        Arc::new(Listener {
+            fds,
+            capabilities: Mutex::new(Capabilities::default()),
+            trace: AtomicBool::new(false),
+            proxy_protocol: AtomicBool::new(false),
+            recording: Mutex::new(None),
+            recorded: AtomicUsize::new(0),
+            closing: AtomicBool::new(false),
+            output_options: Mutex::new(OutputOptions::default()),
+            #[cfg(feature = "tls")]
+            tls: Mutex::new(None),
+            #[cfg(feature = "evented")]
+            event_loop: AtomicBool::new(false),
+            state: Mutex::new(ListenerState::default()),
+            ready: Condvar::new(),
+        })
+    }
+
+    /// Sets the values reported to the web server, by default those of
+    /// libfcgi.
+    pub fn set_capabilities(&self, capabilities: Capabilities) {
+        *lock(&self.capabilities) = capabilities;
+    }
+
+    /// The values reported to the web server.
+    pub fn capabilities(&self) -> Capabilities {
+        *lock(&self.capabilities)
+    }
+
+    /// Writes the records of connections accepted from now on to stderr,
+    /// see the module documentation.
+    pub fn set_trace(&self, trace: bool) {
+        self.trace.store(trace, Ordering::SeqCst);
+    }
+
+    /// Reads a PROXY protocol header at the start of every connection
+    /// accepted from now on, see the `proxy` module.
+    pub fn set_proxy_protocol(&self, enabled: bool) {
+        self.proxy_protocol.store(enabled, Ordering::SeqCst);
+    }
+
+    /// Saves the bytes received on connections accepted from now on to
+    /// files in the directory, one per connection, or stops recording.
+    pub fn set_recording(&self, dir: Option<PathBuf>) {
+        *lock(&self.recording) = dir;
+    }
+
+    /// Terminates TLS on TCP connections accepted from now on, see the
+    /// `tls` module, or accepts them in plaintext again. Unix socket
+    /// connections stay plaintext.
+    #[cfg(feature = "tls")]
+    pub fn set_tls(&self, config: Option<Arc<rustls::ServerConfig>>) {
+        *lock(&self.tls) = config;
+    }
+
+    /// Reads all connections in one thread with an event loop instead of a
+    /// thread per connection, see the `evented` module. Takes effect if
+    /// set before the first request is accepted.
+    #[cfg(feature = "evented")]
+    pub fn set_event_loop(&self, enabled: bool) {
+        self.event_loop.store(enabled, Ordering::SeqCst);
+    }
+
+    /// Stops accepting connections. Requests already read are still handed
+    /// out, then `NativeRequest::accept` returns false.
+    pub fn close(&self) {
+        self.closing.store(true, Ordering::SeqCst);
+    }
+
+    /// Opens the file recording a new connection, named after the time,
+    /// the process and a counter.
+    fn recording_file(&self) -> Option<File> {
+        let dir = lock(&self.recording).clone()?;
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let n = self.recorded.fetch_add(1, Ordering::SeqCst);
+        let path = dir.join(format!("{}-{}-{}.fcgi", secs, process::id(), n));
+        match fs::create_dir_all(&dir).and_then(|_| File::create(&path)) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                server_log!(warn, "failed to record session to {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Sets how the output of requests on connections accepted from now on
+    /// is cut into records.
+    pub fn set_output_options(&self, options: OutputOptions) {
+        assert!((1..=protocol::MAX_CONTENT_LEN).contains(&options.record_size),
+                "FastCGI record size out of range");
+        *lock(&self.output_options) = options;
+    }
+
+    fn push(&self, accepted: Accepted) {
+        lock(&self.state).accepted.push_back(accepted);
+        self.ready.notify_one();
+    }
+
+    /// Waits for the next request, `None` once the listener is closed or a
+    /// shutdown is pending.
+    fn next(self: &Arc<Listener>) -> Option<Accepted> {
+        let mut state = lock(&self.state);
+        if !state.started {
+            state.started = true;
+            let listener = self.clone();
+            #[cfg(feature = "evented")]
+            let spawned = if self.event_loop.load(Ordering::SeqCst) {
+                thread::Builder::new().name(String::from("fcgi-events")).spawn(move || evented::run(listener))
+            } else {
+                thread::Builder::new().name(String::from("fcgi-accept")).spawn(move || listener.accept_loop())
+            };
+            #[cfg(not(feature = "evented"))]
+            let spawned = thread::Builder::new().name(String::from("fcgi-accept"))
+                .spawn(move || listener.accept_loop());
+            if spawned.is_err() {
+                state.closed = true;
+            }
+        }
+        loop {
+            if let Some(accepted) = state.accepted.pop_front() {
+                return Some(accepted);
+            }
+            if state.closed || SHUTDOWN_PENDING.load(Ordering::SeqCst) {
+                return None;
+            }
+            state = self.ready.wait_timeout(state, POLL_INTERVAL).unwrap_or_else(|e| e.into_inner()).0;
+        }
+    }
+
+    fn accept_loop(self: Arc<Listener>) {
+        // Another process sharing a socket may take a connection between
+        // poll and accept, which must not block then.
+        for &fd in &self.fds {
+            unsafe {
+                let flags = libc::fcntl(fd, libc::F_GETFL);
+                libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+            }
+        }
+        let mut poll_fds: Vec<libc::pollfd> = self.fds.iter()
+            .map(|&fd| libc::pollfd { fd, events: libc::POLLIN, revents: 0 })
+            .collect();
+        while !poll_fds.is_empty() && !SHUTDOWN_PENDING.load(Ordering::SeqCst)
+            && !self.closing.load(Ordering::SeqCst)
+        {
+            let count = poll_fds.len() as libc::nfds_t;
+            if unsafe { libc::poll(poll_fds.as_mut_ptr(), count, POLL_INTERVAL.as_millis() as libc::c_int) } <= 0 {
+                continue;
+            }
+            let mut failed = Vec::new();
+            for (i, poll_fd) in poll_fds.iter().enumerate() {
+                if poll_fd.revents == 0 {
+                    continue;
+                }
+                match self.accept(poll_fd.fd) {
+                    Ok(Some((socket, peer))) => {
+                        let _ = self.serve_connection(socket, peer);
+                    }
+                    Ok(None) => {}
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(_) => failed.push(i),
+                }
+            }
+            // A socket which cannot accept any more, e.g. because it was
+            // shut down, is no longer watched.
+            for i in failed.into_iter().rev() {
+                poll_fds.remove(i);
+            }
+        }
+        lock(&self.state).closed = true;
+        self.ready.notify_all();
+    }
+
+    /// Accepts a connection from a listen socket. Returns None if a
+    /// connection could not be set up and the next one may be accepted
+    /// right away, `WouldBlock` if there is none for now, and other errors
+    /// if the socket failed.
+    fn accept(&self, listen_fd: RawFd) -> io::Result<Option<(Socket, Option<SocketAddr>)>> {
+        let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut len = mem::size_of_val(&addr) as libc::socklen_t;
+        let fd = unsafe { libc::accept(listen_fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) };
+        if fd < 0 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::EINTR) | Some(libc::ECONNABORTED) => Ok(None),
+                Some(libc::EAGAIN) => Err(e),
+                Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::ENOBUFS) | Some(libc::ENOMEM) => {
+                    thread::sleep(POLL_INTERVAL);
+                    Err(io::Error::from(io::ErrorKind::WouldBlock))
+                }
+                _ => Err(e),
+            };
+        }
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        let socket = Socket::from_accepted(fd, addr.ss_family as libc::c_int);
+        let peer = socket.peer_addr();
+        #[cfg(feature = "tls")]
+        let socket = match (socket, lock(&self.tls).clone()) {
+            (Socket::Tcp(stream), Some(config)) => match tls::terminate(stream, config) {
+                Ok(plain) => Socket::Unix(plain),
+                Err(_) => return Ok(None),
+            },
+            (socket, _) => socket,
+        };
+        Ok(Some((socket, peer)))
+    }
+
+    /// Sets up a new connection and the reader of its input.
+    fn connection(&self, socket: Socket, peer: Option<SocketAddr>) -> io::Result<(Connection, Recorder)> {
+        let reader = Recorder { socket: socket.try_clone()?, file: self.recording_file() };
+        let connection = Connection {
+            output: Mutex::new(socket.try_clone()?),
+            socket,
+            state: Mutex::new(ConnectionState::default()),
+            changed: Condvar::new(),
+            trace: self.trace.load(Ordering::SeqCst),
+            options: *lock(&self.output_options),
+            peer: Mutex::new(peer),
+            #[cfg(feature = "evented")]
+            waker: None,
+            #[cfg(feature = "evented")]
+            blocked: AtomicBool::new(false),
+        };
+        Ok((connection, reader))
+    }
+
+    fn serve_connection(self: &Arc<Listener>, socket: Socket, peer: Option<SocketAddr>) -> io::Result<()> {
+        let (connection, reader) = self.connection(socket, peer)?;
+        let connection = Arc::new(connection);
+        let listener = self.clone();
+        thread::Builder::new().name(String::from("fcgi-connection")).spawn(move || {
+            let _ = read_connection(&listener, &connection, reader);
+            connection.closed();
+        })?;
+        Ok(())
+    }
+}
+
+/// Writes to the error stream of a request from outside of it, used by the
+/// `log` backend.
+#[cfg_attr(not(feature = "log"), allow(dead_code))]
+pub(crate) struct ErrorSink {
+    connection: Arc<Connection>,
+    request_id: u16,
+}
+
+#[cfg_attr(not(feature = "log"), allow(dead_code))]
+impl ErrorSink {
+    pub(crate) fn write(&self, msg: &str) -> io::Result<()> {
+        match lock(&self.connection.state).slots.get_mut(&self.request_id) {
+            Some(ref slot) if slot.aborted.is_aborted() => return Ok(()),
+            Some(slot) => slot.stderr_used = true,
+            None => {}
+        }
+        let mut buf = Vec::with_capacity(msg.len() + 16);
+        protocol::encode_stream(&mut buf, RecordType::Stderr, self.request_id, msg.as_bytes());
+        self.connection.send(&buf)
+    }
+}
+
+/// A FastCGI request read from the wire, see the module documentation.
+pub struct NativeRequest {
+    listener: Arc<Listener>,
+    current: Option<Accepted>,
+    input: Vec<u8>,
+    input_pos: usize,
+    /// Set by `start_filter_data`, reads come from the FCGI_DATA stream.
+    reading_data: bool,
+    output: Vec<u8>,
+}
+
+impl NativeRequest {
+    /// Creates a request accepting from the listener, sharing its
+    /// connections with the other requests created from it.
+    pub fn from_listener(listener: Arc<Listener>) -> NativeRequest {
+        NativeRequest {
+            listener,
+            current: None,
+            input: Vec::new(),
+            input_pos: 0,
+            reading_data: false,
+            output: Vec::new(),
+        }
+    }
+
+    /// Sets the values reported to the web server by the listener of this
+    /// request, see `Listener::set_capabilities`.
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.listener.set_capabilities(capabilities);
+    }
+
+    /// Traces the records of the listener of this request, see
+    /// `Listener::set_trace`.
+    pub fn set_trace(&mut self, trace: bool) {
+        self.listener.set_trace(trace);
+    }
+
+    /// Sets how output is cut into records, see
+    /// `Listener::set_output_options`.
+    pub fn set_output_options(&mut self, options: OutputOptions) {
+        self.listener.set_output_options(options);
+    }
+
+    #[cfg_attr(not(feature = "log"), allow(dead_code))]
+    pub(crate) fn error_sink(&self) -> Option<ErrorSink> {
+        self.current.as_ref().map(|current| {
+            ErrorSink { connection: current.connection.clone(), request_id: current.request_id }
+        })
+    }
+
+    /// Waits until there is unread input or the input has ended. Returns
+    /// false if the file data of a filter does not have the announced
+    /// length.
+    fn fill_input(&mut self) -> bool {
+        let current = match self.current {
+            Some(ref current) => current,
+            None => return true,
+        };
+        let connection = &current.connection;
+        let mut state = lock(&connection.state);
+        loop {
+            let slot = match state.slots.get_mut(&current.request_id) {
+                Some(slot) => slot,
+                None => return true,
+            };
+            if self.reading_data && slot.data_invalid {
+                return false;
+            }
+            let queue = if self.reading_data { &mut slot.data } else { &mut slot.input };
+            if let Some(chunk) = queue.pop_front() {
+                slot.input_len -= chunk.len();
+                self.input = chunk;
+                self.input_pos = 0;
+                connection.notify();
+                return true;
+            }
+            if slot.stream_done(self.reading_data) {
+                return true;
+            }
+            state = connection.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Sends the collected output. Unless `all` is set, a rest smaller than
+    /// a full record is kept for the next records.
+    fn flush_output(&mut self, all: bool) -> io::Result<()> {
+        let current = match self.current {
+            Some(ref current) => current,
+            None => return Err(io::Error::new(io::ErrorKind::NotConnected, "no request accepted")),
+        };
+        if current.aborted.is_aborted() {
+            self.output.clear();
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "request aborted by the web server"));
+        }
+        if self.output.is_empty() {
+            return Ok(());
+        }
+        let options = current.connection.options;
+        let mut len = self.output.len();
+        if !all && len >= options.record_size {
+            len -= len % options.record_size;
+        }
+        let alignment = if options.padding { 8 } else { 1 };
+        let mut buf = Vec::with_capacity(len + len / options.record_size * 16 + 16);
+        protocol::encode_stream_sized(&mut buf, RecordType::Stdout, current.request_id, &self.output[..len],
+                                      options.record_size, alignment);
+        self.output.drain(..len);
+        current.connection.send(&buf)
+    }
+}
+
+impl Request for NativeRequest {
+    fn new() -> Option<NativeRequest> {
+        NativeRequest::new_with_fd(0)
+    }
+
+    fn new_with_fd(fd: RawFd) -> Option<NativeRequest> {
+        Some(NativeRequest::from_listener(Listener::new(fd)))
+    }
+
+    fn accept(&mut self) -> bool {
+        self.finish();
+        self.current = self.listener.next();
+        self.current.is_some()
+    }
+
+    fn finish(&mut self) {
+        if self.current.is_some() {
+            let _ = self.flush_output(true);
+        }
+        if let Some(current) = self.current.take() {
+            let id = current.request_id;
+            let stderr_used = lock(&current.connection.state).slots.get(&id).is_some_and(|slot| slot.stderr_used);
+            let mut buf = Vec::with_capacity(32);
+            if !current.aborted.is_aborted() {
+                protocol::encode_record(&mut buf, RecordType::Stdout, id, &[]);
+                if stderr_used {
+                    protocol::encode_record(&mut buf, RecordType::Stderr, id, &[]);
+                }
+            }
+            let end = EndRequest { app_status: 0, protocol_status: ProtocolStatus::RequestComplete };
+            end.to_record(id).encode(&mut buf);
+            let _ = current.connection.end(id, &buf);
+        }
+        self.input.clear();
+        self.input_pos = 0;
+        self.reading_data = false;
+        self.output.clear();
+    }
+
+    fn get_param(&self, name: &str) -> Option<String> {
+        let current = self.current.as_ref()?;
+        current.params.iter().find(|param| param.0 == name).map(|param| param.1.clone())
+    }
+
+    fn params(&self) -> Vec<(String, String)> {
+        self.current.as_ref().map(|current| current.params.clone()).unwrap_or_default()
+    }
+
+    fn write(&mut self, msg: &str) -> i32 {
+        self.write_bytes(msg.as_bytes())
+    }
+
+    fn error(&mut self, msg: &str) -> i32 {
+        let _ = self.flush_output(true);
+        match self.error_sink().map(|sink| sink.write(msg)) {
+            Some(Ok(())) => msg.len() as i32,
+            _ => -1,
+        }
+    }
+
+    fn write_bytes(&mut self, buf: &[u8]) -> i32 {
+        if self.current.as_ref().is_none_or(|current| current.aborted.is_aborted()) {
+            self.output.clear();
+            return -1;
+        }
+        self.output.extend_from_slice(buf);
+        let buffer_size = self.current.as_ref().map_or(0, |current| current.connection.options.buffer_size);
+        if self.output.len() >= buffer_size && self.flush_output(false).is_err() {
+            return -1;
+        }
+        buf.len() as i32
+    }
+
+    fn read_bytes(&mut self, buf: &mut [u8]) -> i32 {
+        if self.abort_token().is_aborted() {
+            return -1;
+        }
+        let mut count = 0;
+        while count < buf.len() {
+            if self.input_pos == self.input.len() {
+                if !self.fill_input() {
+                    return -1;
+                }
+                if self.input_pos == self.input.len() {
+                    break;
+                }
+            }
+            let n = (buf.len() - count).min(self.input.len() - self.input_pos);
+            buf[count..count + n].copy_from_slice(&self.input[self.input_pos..self.input_pos + n]);
+            self.input_pos += n;
+            count += n;
+        }
+        count as i32
+    }
+
+    fn read(&mut self, n: i32) -> (String, i32) {
+        crate::read_lossy(self, n)
+    }
+
+    fn flush(&mut self, stream_type: StreamType) {
+        if let StreamType::OutStream = stream_type {
+            let _ = self.flush_output(true);
+        }
+        if let Some(ref current) = self.current {
+            let _ = lock(&current.connection.output).flush();
+        }
+    }
+
+    fn abort_token(&self) -> AbortToken {
+        self.current.as_ref().map(|current| current.aborted.clone()).unwrap_or_default()
+    }
+
+    fn role(&self) -> Role {
+        self.current.as_ref().map_or(Role::Responder, |current| current.role)
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.current.as_ref().and_then(|current| *lock(&current.connection.peer))
+    }
+
+    fn start_filter_data(&mut self) -> bool {
+        if self.reading_data || self.role() != Role::Filter || self.input_pos < self.input.len() {
+            return false;
+        }
+        let at_end = {
+            let current = self.current.as_ref().unwrap();
+            let state = lock(&current.connection.state);
+            state.slots.get(&current.request_id).is_none_or(|slot| slot.input_done && slot.input.is_empty())
+        };
+        self.reading_data = at_end;
+        at_end
+    }
+}
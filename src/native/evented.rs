@@ -0,0 +1,276 @@
+//! An event loop reading all connections of a `Listener` in one thread,
+//! with the `evented` feature.
+//!
+//! By default every connection has a thread blocked reading it, which is
+//! wasteful when a web server keeps thousands of mostly idle connections
+//! open. With `Listener::set_event_loop` (or the `event_loop` setting of the
+//! server) a single thread waits for all listen sockets and connections
+//! with mio, decodes the records which arrive and hands complete requests
+//! to the workers as before. Workers still write their output to the
+//! socket themselves, waiting for it if the web server reads slowly.
+//!
+//! When a request has as much input buffered as allowed, its connection is
+//! not read further until the handler takes some; the other connections
+//! carry on. TLS connections keep their own thread each, see the `tls`
+//! module.
+
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read};
+use std::os::unix::io::RawFd;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use libc;
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Token, Waker};
+
+use crate::protocol::{Record, Role};
+use crate::proxy;
+use super::{lock, receive, Connection, Listener, Recorder, POLL_INTERVAL, SHUTDOWN_PENDING};
+
+/// The token of the waker, listen sockets and connections count up from 0.
+const WAKE: Token = Token(usize::MAX);
+/// How much is read from a connection at once.
+const READ_SIZE: usize = 64 * 1024;
+
+/// A connection read by the event loop.
+struct Evented {
+    connection: Arc<Connection>,
+    reader: Recorder,
+    /// Bytes read and not decoded yet.
+    buf: Vec<u8>,
+    /// Set until the PROXY protocol header has been read.
+    proxy_header: bool,
+    /// A record waiting for its request to take input.
+    pending: Option<Record>,
+    /// Roles and parameters of requests which are not complete yet.
+    building: HashMap<u16, (Role, Vec<u8>)>,
+    eof: bool,
+}
+
+impl Evented {
+    /// Handles buffered records and reads more until the socket has no more
+    /// data or a request has to take input first. Returns false once the
+    /// connection is done.
+    fn advance(&mut self, listener: &Listener) -> io::Result<bool> {
+        loop {
+            if !self.handle(listener)? {
+                return Ok(true);
+            }
+            if self.eof || (self.idle() && SHUTDOWN_PENDING.load(Ordering::SeqCst)) {
+                return Ok(false);
+            }
+            let len = self.buf.len();
+            self.buf.resize(len + READ_SIZE, 0);
+            let result = self.reader.read(&mut self.buf[len..]);
+            self.buf.truncate(len + *result.as_ref().unwrap_or(&0));
+            match result {
+                Ok(0) => self.eof = true,
+                Ok(_) => {}
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(true),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Handles the complete records in the buffer. Returns false if a
+    /// request has to take input first.
+    fn handle(&mut self, listener: &Listener) -> io::Result<bool> {
+        let connection = self.connection.clone();
+        if let Some(record) = self.pending.take() {
+            if !self.has_space(&record) {
+                self.pending = Some(record);
+                return Ok(false);
+            }
+            receive(listener, &connection, &mut self.building, record)?;
+        }
+        let mut used = 0;
+        if self.proxy_header {
+            let mut rest = &self.buf[..];
+            match proxy::read_header(&mut rest) {
+                Err(ref e) if e.kind() == ErrorKind::UnexpectedEof && !self.eof => return Ok(true),
+                header => {
+                    used = self.buf.len() - rest.len();
+                    self.proxy_header = false;
+                    connection.proxied(header)?;
+                }
+            }
+        }
+        let result = loop {
+            match Record::decode(&self.buf[used..]) {
+                Ok(Some((record, n))) => {
+                    used += n;
+                    if !self.has_space(&record) {
+                        self.pending = Some(record);
+                        break Ok(false);
+                    }
+                    if let Err(e) = receive(listener, &connection, &mut self.building, record) {
+                        break Err(e);
+                    }
+                }
+                Ok(None) => break Ok(true),
+                Err(e) => break Err(e.into()),
+            }
+        };
+        self.buf.drain(..used);
+        result
+    }
+
+    /// True if the record can be handled now. Otherwise the connection is
+    /// marked as blocked, so the request taking input wakes the loop.
+    fn has_space(&self, record: &Record) -> bool {
+        let state = lock(&self.connection.state);
+        let full = state.input_full(record);
+        self.connection.blocked.store(full, Ordering::SeqCst);
+        !full
+    }
+
+    /// True if no request is open or being received.
+    fn idle(&self) -> bool {
+        self.buf.is_empty() && self.pending.is_none() && lock(&self.connection.state).open == 0
+    }
+
+    fn fd(&self) -> RawFd {
+        self.connection.socket.as_raw_fd()
+    }
+}
+
+/// Accepts and reads connections until the listener is closed or a shutdown
+/// is pending and all connections are done.
+pub(super) fn run(listener: Arc<Listener>) {
+    if let Err(e) = serve(&listener) {
+        server_log!(error, "event loop failed: {}", e);
+    }
+    stop_accepting(&listener);
+}
+
+fn stop_accepting(listener: &Listener) {
+    lock(&listener.state).closed = true;
+    listener.ready.notify_all();
+}
+
+fn serve(listener: &Arc<Listener>) -> io::Result<()> {
+    let mut poll = Poll::new()?;
+    let waker = Arc::new(Waker::new(poll.registry(), WAKE)?);
+    let mut listening = Vec::new();
+    for (i, &fd) in listener.fds.iter().enumerate() {
+        set_nonblocking(fd)?;
+        poll.registry().register(&mut SourceFd(&fd), Token(i), Interest::READABLE)?;
+        listening.push(fd);
+    }
+    let mut connections: HashMap<usize, Evented> = HashMap::new();
+    let mut next_token = listener.fds.len();
+    let mut events = Events::with_capacity(1024);
+    let mut accepting = true;
+    loop {
+        if accepting && (SHUTDOWN_PENDING.load(Ordering::SeqCst) || listener.closing.load(Ordering::SeqCst)) {
+            accepting = false;
+            for fd in &listening {
+                let _ = poll.registry().deregister(&mut SourceFd(fd));
+            }
+            stop_accepting(listener);
+        }
+        if !accepting && connections.is_empty() {
+            return Ok(());
+        }
+        if let Err(e) = poll.poll(&mut events, Some(POLL_INTERVAL)) {
+            if e.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+
+        let mut ready: Vec<usize> = Vec::new();
+        let mut woken = false;
+        let mut listen_ready = events.is_empty();
+        for event in events.iter() {
+            match event.token() {
+                WAKE => woken = true,
+                Token(i) if i < listener.fds.len() => listen_ready = true,
+                Token(i) => ready.push(i),
+            }
+        }
+        if woken {
+            ready.extend(connections.iter().filter(|&(_, c)| c.pending.is_some()).map(|(&i, _)| i));
+        }
+        if SHUTDOWN_PENDING.load(Ordering::SeqCst) {
+            ready.extend(connections.iter().filter(|&(_, c)| c.idle()).map(|(&i, _)| i));
+        }
+
+        // Listen sockets are also tried after a timeout, in case accepting
+        // failed for lack of file descriptors before.
+        if accepting && listen_ready {
+            let mut failed = Vec::new();
+            for (i, &fd) in listening.iter().enumerate() {
+                loop {
+                    match listener.accept(fd) {
+                        Ok(Some((socket, peer))) => {
+                            let (mut connection, reader) = match listener.connection(socket, peer) {
+                                Ok(connection) => connection,
+                                Err(_) => continue,
+                            };
+                            connection.waker = Some(waker.clone());
+                            let evented = Evented {
+                                connection: Arc::new(connection),
+                                reader,
+                                buf: Vec::new(),
+                                proxy_header: listener.proxy_protocol.load(Ordering::SeqCst),
+                                pending: None,
+                                building: HashMap::new(),
+                                eof: false,
+                            };
+                            let fd = evented.fd();
+                            if set_nonblocking(fd).is_err() || poll.registry()
+                                .register(&mut SourceFd(&fd), Token(next_token), Interest::READABLE).is_err()
+                            {
+                                evented.connection.closed();
+                                continue;
+                            }
+                            connections.insert(next_token, evented);
+                            next_token += 1;
+                        }
+                        Ok(None) => {}
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                        Err(_) => {
+                            failed.push(i);
+                            break;
+                        }
+                    }
+                }
+            }
+            for i in failed.into_iter().rev() {
+                let _ = poll.registry().deregister(&mut SourceFd(&listening[i]));
+                listening.remove(i);
+            }
+            if listening.is_empty() {
+                accepting = false;
+                stop_accepting(listener);
+            }
+        }
+
+        ready.sort_unstable();
+        ready.dedup();
+        for token in ready {
+            let done = match connections.get_mut(&token) {
+                Some(evented) => !evented.advance(listener).unwrap_or(false),
+                None => false,
+            };
+            if done {
+                let evented = connections.remove(&token).unwrap();
+                let _ = poll.registry().deregister(&mut SourceFd(&evented.fd()));
+                evented.connection.closed();
+            }
+        }
+    }
+}
+
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
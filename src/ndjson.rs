@@ -0,0 +1,90 @@
+//! Streaming newline-delimited JSON, with the `json` feature, for exports
+//! too large to build in memory.
+//!
+//! Every value is serialized onto a line of its own. The output is passed
+//! on in blocks and flushed to the client at least once per flush
+//! interval, so a slow producer still shows progress:
+//!
+//! ```ignore
+//! let mut out = exchange.ndjson();
+//! out.write_iter(db.rows()?)?;
+//! out.finish()?;
+//! ```
+
+use std::io::{self, BufWriter, Write};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// The Content-Type of newline-delimited JSON.
+pub const CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// How much output is collected before it is written.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Writes values as newline-delimited JSON, see the module documentation.
+pub struct NdjsonWriter<W: Write> {
+    out: BufWriter<W>,
+    flush_interval: Duration,
+    last_flush: Instant,
+    count: u64,
+}
+
+impl<W: Write> NdjsonWriter<W> {
+    /// Writes to `out`, flushing at least once a second.
+    pub fn new(out: W) -> NdjsonWriter<W> {
+        NdjsonWriter {
+            out: BufWriter::with_capacity(BUFFER_SIZE, out),
+            flush_interval: Duration::from_secs(1),
+            last_flush: Instant::now(),
+            count: 0,
+        }
+    }
+
+    /// Sets how long written values may wait in the buffer.
+    pub fn flush_interval(mut self, interval: Duration) -> NdjsonWriter<W> {
+        self.flush_interval = interval;
+        self
+    }
+
+    /// Writes a value as a line.
+    pub fn write<T: Serialize + ?Sized>(&mut self, value: &T) -> io::Result<()> {
+        // Write errors come back as they were, others as InvalidData.
+        serde_json::to_writer(&mut self.out, value).map_err(io::Error::from)?;
+        self.out.write_all(b"\n")?;
+        self.count += 1;
+        if self.last_flush.elapsed() >= self.flush_interval {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes every value of `values`, returning how many were written.
+    pub fn write_iter<I>(&mut self, values: I) -> io::Result<u64>
+        where I: IntoIterator, I::Item: Serialize
+    {
+        let start = self.count;
+        for value in values {
+            self.write(&value)?;
+        }
+        Ok(self.count - start)
+    }
+
+    /// Sends the buffered lines to the client.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.last_flush = Instant::now();
+        self.out.flush()
+    }
+
+    /// The number of values written so far.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Flushes the rest of the output and returns the number of values
+    /// written.
+    pub fn finish(mut self) -> io::Result<u64> {
+        self.flush()?;
+        Ok(self.count)
+    }
+}
@@ -0,0 +1,307 @@
+//! An incremental FastCGI record parser.
+//!
+//! `Parser` is fed the bytes of a connection as they arrive, in pieces of
+//! any size, and yields `Event`s borrowing from them: the header of each
+//! record, its content in one or more pieces, and its end. It never
+//! allocates and holds at most one header, so its memory use is bounded
+//! whatever the input, which makes it suitable for fuzzing and for custom
+//! event loops. Collecting content, e.g. the name-value pairs of a
+//! `Params` stream, and limiting how much of it is kept is left to the
+//! caller.
+//!
+//! ```
+//! use fcgi::parser::{Event, Parser};
+//! use fcgi::protocol::{self, RecordType};
+//!
+//! let mut buf = Vec::new();
+//! protocol::encode_record(&mut buf, RecordType::Stdin, 1, b"hello");
+//! let mut parser = Parser::new();
+//! let mut content = Vec::new();
+//! for piece in buf.chunks(3) {
+//!     for event in parser.feed(piece) {
+//!         if let Event::Content(data) = event.unwrap() {
+//!             content.extend_from_slice(data);
+//!         }
+//!     }
+//! }
+//! assert_eq!(content, b"hello");
+//! assert!(parser.is_idle());
+//! ```
+
+use crate::protocol::{Header, ProtocolError, RecordType, HEADER_LEN};
+
+/// A step of parsing, see the module documentation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event<'a> {
+    /// The header of the next record.
+    Header(Header),
+    /// A piece of the content of the current record.
+    Content(&'a [u8]),
+    /// The end of the current record, after its content and padding.
+    End,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum State {
+    Header,
+    Content,
+    Padding,
+    Failed(ProtocolError),
+}
+
+/// Parses records from bytes fed in pieces, see the module documentation.
+#[derive(Clone, Debug)]
+pub struct Parser {
+    state: State,
+    header: [u8; HEADER_LEN],
+    header_len: usize,
+    content_left: usize,
+    padding_left: usize,
+}
+
+impl Default for Parser {
+    fn default() -> Parser {
+        Parser::new()
+    }
+}
+
+impl Parser {
+    /// Creates a parser expecting the header of a record.
+    pub fn new() -> Parser {
+        Parser { state: State::Header, header: [0; HEADER_LEN], header_len: 0, content_left: 0, padding_left: 0 }
+    }
+
+    /// True at a record boundary, where the input may end.
+    pub fn is_idle(&self) -> bool {
+        self.state == State::Header && self.header_len == 0
+    }
+
+    /// Parses the next event from the start of `buf`. Returns the event, if
+    /// `buf` completes one, and the number of bytes used, which are not fed
+    /// again. After an error the parser fails all further input.
+    pub fn parse<'a>(&mut self, buf: &'a [u8]) -> Result<(Option<Event<'a>>, usize), ProtocolError> {
+        match self.state {
+            State::Failed(ref e) => Err(e.clone()),
+            State::Header => {
+                let n = (HEADER_LEN - self.header_len).min(buf.len());
+                self.header[self.header_len..self.header_len + n].copy_from_slice(&buf[..n]);
+                self.header_len += n;
+                if self.header_len < HEADER_LEN {
+                    return Ok((None, n));
+                }
+                self.header_len = 0;
+                let header = match Header::decode(&self.header).and_then(check_header) {
+                    Ok(header) => header.expect("complete header"),
+                    Err(e) => {
+                        self.state = State::Failed(e.clone());
+                        return Err(e);
+                    }
+                };
+                self.content_left = header.content_length as usize;
+                self.padding_left = header.padding_length as usize;
+                self.state = State::Content;
+                Ok((Some(Event::Header(header)), n))
+            }
+            State::Content if self.content_left > 0 => {
+                if buf.is_empty() {
+                    return Ok((None, 0));
+                }
+                let n = self.content_left.min(buf.len());
+                self.content_left -= n;
+                Ok((Some(Event::Content(&buf[..n])), n))
+            }
+            State::Content => {
+                self.state = State::Padding;
+                self.parse(buf)
+            }
+            State::Padding => {
+                let n = self.padding_left.min(buf.len());
+                self.padding_left -= n;
+                if self.padding_left > 0 {
+                    return Ok((None, n));
+                }
+                self.state = State::Header;
+                Ok((Some(Event::End), n))
+            }
+        }
+    }
+
+    /// Parses all of `buf`, yielding its events. The iterator ends after an
+    /// error.
+    pub fn feed<'p, 'a>(&'p mut self, buf: &'a [u8]) -> Events<'p, 'a> {
+        Events { parser: self, buf, done: false }
+    }
+}
+
+/// Rejects headers announcing less content than their record type has.
+fn check_header(header: Option<Header>) -> Result<Option<Header>, ProtocolError> {
+    if let Some(header) = header {
+        let min_len = match header.record_type {
+            RecordType::BeginRequest | RecordType::EndRequest | RecordType::UnknownType => 8,
+            _ => 0,
+        };
+        if (header.content_length as usize) < min_len {
+            return Err(ProtocolError::ShortContent(header.record_type));
+        }
+    }
+    Ok(header)
+}
+
+/// The events of the bytes fed to a parser, see `Parser::feed`.
+pub struct Events<'p, 'a> {
+    parser: &'p mut Parser,
+    buf: &'a [u8],
+    done: bool,
+}
+
+impl<'p, 'a> Iterator for Events<'p, 'a> {
+    type Item = Result<Event<'a>, ProtocolError>;
+
+    fn next(&mut self) -> Option<Result<Event<'a>, ProtocolError>> {
+        while !self.done {
+            match self.parser.parse(self.buf) {
+                Ok((event, used)) => {
+                    self.buf = &self.buf[used..];
+                    if event.is_some() {
+                        return event.map(Ok);
+                    }
+                    self.done = self.buf.is_empty();
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Event, Parser};
+    use crate::protocol::{self, Header, ProtocolError, RecordType, HEADER_LEN, VERSION_1};
+
+    /// An event with the content owned, consecutive pieces joined.
+    #[derive(Debug, PartialEq, Eq)]
+    enum Owned {
+        Header(RecordType, u16, u16, u8),
+        Content(Vec<u8>),
+        End,
+    }
+
+    /// Feeds `input` in pieces of `size` bytes, collecting the events up
+    /// to the first error.
+    fn parse(parser: &mut Parser, input: &[u8], size: usize) -> (Vec<Owned>, Option<ProtocolError>) {
+        let mut events = Vec::new();
+        for piece in input.chunks(size) {
+            for event in parser.feed(piece) {
+                match event {
+                    Ok(Event::Header(h)) => {
+                        events.push(Owned::Header(h.record_type, h.request_id, h.content_length, h.padding_length));
+                    }
+                    Ok(Event::Content(data)) => match events.last_mut() {
+                        Some(Owned::Content(content)) => content.extend_from_slice(data),
+                        _ => events.push(Owned::Content(data.to_vec())),
+                    },
+                    Ok(Event::End) => events.push(Owned::End),
+                    Err(e) => return (events, Some(e)),
+                }
+            }
+        }
+        (events, None)
+    }
+
+    fn header(record_type: RecordType, content_length: u16, padding_length: u8) -> Vec<u8> {
+        let mut buf = Vec::new();
+        Header { record_type, request_id: 1, content_length, padding_length }.encode(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn byte_at_a_time() {
+        let mut input = Vec::new();
+        protocol::encode_record(&mut input, RecordType::Stdin, 1, b"hello");
+        let mut parser = Parser::new();
+        let (events, error) = parse(&mut parser, &input, 1);
+        assert_eq!(error, None);
+        assert_eq!(events, [Owned::Header(RecordType::Stdin, 1, 5, 3), Owned::Content(b"hello".to_vec()), Owned::End]);
+        assert!(parser.is_idle());
+    }
+
+    #[test]
+    fn partial_header() {
+        let mut parser = Parser::new();
+        let input = header(RecordType::Stdin, 0, 0);
+        assert_eq!(parser.parse(&input[..3]), Ok((None, 3)));
+        assert!(!parser.is_idle());
+        assert_eq!(parse(&mut parser, &input[3..], 8).0, [Owned::Header(RecordType::Stdin, 1, 0, 0), Owned::End]);
+        assert!(parser.is_idle());
+    }
+
+    #[test]
+    fn padding() {
+        let mut input = header(RecordType::Stdout, 3, 5);
+        input.extend_from_slice(b"abc\0\0\0\0\0");
+        input.extend(header(RecordType::Stdout, 0, 7));
+        input.extend_from_slice(&[0; 7]);
+        for size in [1, 2, 5, input.len()] {
+            let mut parser = Parser::new();
+            let (events, error) = parse(&mut parser, &input, size);
+            assert_eq!(error, None);
+            assert_eq!(events, [
+                Owned::Header(RecordType::Stdout, 1, 3, 5), Owned::Content(b"abc".to_vec()), Owned::End,
+                Owned::Header(RecordType::Stdout, 1, 0, 7), Owned::End,
+            ], "pieces of {}", size);
+            assert!(parser.is_idle());
+        }
+        // Within the padding the record has not ended yet.
+        let mut parser = Parser::new();
+        let (events, _) = parse(&mut parser, &input[..HEADER_LEN + 5], 64);
+        assert_eq!(events.last(), Some(&Owned::Content(b"abc".to_vec())));
+        assert!(!parser.is_idle());
+    }
+
+    #[test]
+    fn several_records() {
+        let mut input = Vec::new();
+        protocol::encode_record(&mut input, RecordType::Params, 1, b"\x01\x01ab");
+        protocol::encode_record(&mut input, RecordType::Params, 1, b"");
+        protocol::encode_record(&mut input, RecordType::Stdin, 2, b"x");
+        let mut parser = Parser::new();
+        let (events, error) = parse(&mut parser, &input, input.len());
+        assert_eq!(error, None);
+        assert_eq!(events, [
+            Owned::Header(RecordType::Params, 1, 4, 4), Owned::Content(b"\x01\x01ab".to_vec()), Owned::End,
+            Owned::Header(RecordType::Params, 1, 0, 0), Owned::End,
+            Owned::Header(RecordType::Stdin, 2, 1, 7), Owned::Content(b"x".to_vec()), Owned::End,
+        ]);
+    }
+
+    #[test]
+    fn short_content() {
+        let mut parser = Parser::new();
+        let (events, error) = parse(&mut parser, &header(RecordType::BeginRequest, 4, 0), 8);
+        assert!(events.is_empty());
+        assert_eq!(error, Some(ProtocolError::ShortContent(RecordType::BeginRequest)));
+        let mut parser = Parser::new();
+        assert_eq!(parse(&mut parser, &header(RecordType::EndRequest, 7, 1), 8).1,
+                   Some(ProtocolError::ShortContent(RecordType::EndRequest)));
+    }
+
+    #[test]
+    fn failed_is_sticky() {
+        let mut input = header(RecordType::Stdin, 0, 0);
+        input[0] = VERSION_1 + 1;
+        let mut parser = Parser::new();
+        let error = Some(ProtocolError::UnsupportedVersion(VERSION_1 + 1));
+        assert_eq!(parse(&mut parser, &input, 8).1, error);
+        // Valid input later does not recover the parser, nor does none.
+        assert_eq!(parse(&mut parser, &header(RecordType::Stdin, 0, 0), 8).1, error);
+        assert_eq!(parser.parse(&[]), Err(ProtocolError::UnsupportedVersion(VERSION_1 + 1)));
+        assert!(!parser.is_idle());
+        let mut events = parser.feed(&[1, 2, 3]);
+        assert_eq!(events.next(), Some(Err(ProtocolError::UnsupportedVersion(VERSION_1 + 1))));
+        assert_eq!(events.next(), None);
+    }
+}
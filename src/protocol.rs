@@ -0,0 +1,600 @@
+//! The FastCGI wire format.
+//!
+//! Types for the records defined by the FastCGI specification and
+//! functions encoding them into and decoding them from byte buffers, for
+//! building tools on top of the protocol. The native transport of the
+//! `pure` feature is implemented with this module.
+//!
+//! A record is an 8 byte header followed by up to 65535 bytes of content
+//! and up to 255 bytes of padding. Streams such as `Stdout` are sent as a
+//! series of records ended by one with empty content.
+//!
+//! The `Params`, `GetValues` and `GetValuesResult` streams hold name-value
+//! pairs. Each pair starts with the lengths of the name and the value,
+//! encoded in one byte if below 128 and in four bytes with the high bit set
+//! otherwise, followed by the name and the value without terminators.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+
+/// The protocol version, the only one defined.
+pub const VERSION_1: u8 = 1;
+
+/// Length of a record header.
+pub const HEADER_LEN: usize = 8;
+
+/// The largest content of a single record.
+pub const MAX_CONTENT_LEN: usize = 65535;
+
+/// The largest content length which is a multiple of 8, so records of this
+/// size need no padding.
+pub const MAX_ALIGNED_CONTENT_LEN: usize = 65528;
+
+/// The request id of management records.
+pub const NULL_REQUEST_ID: u16 = 0;
+
+/// The flag of `BeginRequest` asking the application to keep the
+/// connection open after the request.
+pub const KEEP_CONN: u8 = 1;
+
+/// The type of a record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RecordType {
+    BeginRequest,
+    AbortRequest,
+    EndRequest,
+    Params,
+    Stdin,
+    Stdout,
+    Stderr,
+    Data,
+    GetValues,
+    GetValuesResult,
+    UnknownType,
+    /// A type not defined by the specification.
+    Other(u8),
+}
+
+impl RecordType {
+    pub fn from_u8(value: u8) -> RecordType {
+        match value {
+            1 => RecordType::BeginRequest,
+            2 => RecordType::AbortRequest,
+            3 => RecordType::EndRequest,
+            4 => RecordType::Params,
+            5 => RecordType::Stdin,
+            6 => RecordType::Stdout,
+            7 => RecordType::Stderr,
+            8 => RecordType::Data,
+            9 => RecordType::GetValues,
+            10 => RecordType::GetValuesResult,
+            11 => RecordType::UnknownType,
+            other => RecordType::Other(other),
+        }
+    }
+
+    pub fn as_u8(self) -> u8 {
+        match self {
+            RecordType::BeginRequest => 1,
+            RecordType::AbortRequest => 2,
+            RecordType::EndRequest => 3,
+            RecordType::Params => 4,
+            RecordType::Stdin => 5,
+            RecordType::Stdout => 6,
+            RecordType::Stderr => 7,
+            RecordType::Data => 8,
+            RecordType::GetValues => 9,
+            RecordType::GetValuesResult => 10,
+            RecordType::UnknownType => 11,
+            RecordType::Other(other) => other,
+        }
+    }
+
+    /// True for the types sent with `NULL_REQUEST_ID`.
+    pub fn is_management(self) -> bool {
+        matches!(self, RecordType::GetValues | RecordType::GetValuesResult | RecordType::UnknownType)
+    }
+}
+
+/// The role of the application in a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Role {
+    Responder,
+    Authorizer,
+    Filter,
+    /// A role not defined by the specification.
+    Other(u16),
+}
+
+impl Role {
+    pub fn from_u16(value: u16) -> Role {
+        match value {
+            1 => Role::Responder,
+            2 => Role::Authorizer,
+            3 => Role::Filter,
+            other => Role::Other(other),
+        }
+    }
+
+    pub fn as_u16(self) -> u16 {
+        match self {
+            Role::Responder => 1,
+            Role::Authorizer => 2,
+            Role::Filter => 3,
+            Role::Other(other) => other,
+        }
+    }
+}
+
+/// How a request ended, sent in `EndRequest`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ProtocolStatus {
+    /// The request was handled normally.
+    RequestComplete,
+    /// Rejected, the application does not multiplex connections.
+    CantMpxConn,
+    /// Rejected, the application is out of resources.
+    Overloaded,
+    /// Rejected, the role is not supported.
+    UnknownRole,
+    /// A status not defined by the specification.
+    Other(u8),
+}
+
+impl ProtocolStatus {
+    pub fn from_u8(value: u8) -> ProtocolStatus {
+        match value {
+            0 => ProtocolStatus::RequestComplete,
+            1 => ProtocolStatus::CantMpxConn,
+            2 => ProtocolStatus::Overloaded,
+            3 => ProtocolStatus::UnknownRole,
+            other => ProtocolStatus::Other(other),
+        }
+    }
+
+    pub fn as_u8(self) -> u8 {
+        match self {
+            ProtocolStatus::RequestComplete => 0,
+            ProtocolStatus::CantMpxConn => 1,
+            ProtocolStatus::Overloaded => 2,
+            ProtocolStatus::UnknownRole => 3,
+            ProtocolStatus::Other(other) => other,
+        }
+    }
+}
+
+/// Error decoding a record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// The header has a version other than `VERSION_1`.
+    UnsupportedVersion(u8),
+    /// The content is too short for the record type.
+    ShortContent(RecordType),
+    /// A name-value pair extends past the end of its stream.
+    TruncatedNameValue,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProtocolError::UnsupportedVersion(version) => write!(f, "unsupported FastCGI version {}", version),
+            ProtocolError::ShortContent(record_type) => write!(f, "short FastCGI {:?} record", record_type),
+            ProtocolError::TruncatedNameValue => write!(f, "truncated FastCGI name-value pair"),
+        }
+    }
+}
+
+impl Error for ProtocolError {}
+
+impl From<ProtocolError> for io::Error {
+    fn from(e: ProtocolError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// The header of a record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub record_type: RecordType,
+    pub request_id: u16,
+    pub content_length: u16,
+    pub padding_length: u8,
+}
+
+impl Header {
+    /// Decodes the first `HEADER_LEN` bytes of `buf`, `None` if it is
+    /// shorter.
+    pub fn decode(buf: &[u8]) -> Result<Option<Header>, ProtocolError> {
+        if buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        if buf[0] != VERSION_1 {
+            return Err(ProtocolError::UnsupportedVersion(buf[0]));
+        }
+        Ok(Some(Header {
+            record_type: RecordType::from_u8(buf[1]),
+            request_id: u16::from_be_bytes([buf[2], buf[3]]),
+            content_length: u16::from_be_bytes([buf[4], buf[5]]),
+            padding_length: buf[6],
+        }))
+    }
+
+    /// Appends the encoded header to `buf`.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let id = self.request_id.to_be_bytes();
+        let length = self.content_length.to_be_bytes();
+        buf.extend_from_slice(&[VERSION_1, self.record_type.as_u8(), id[0], id[1],
+                                length[0], length[1], self.padding_length, 0]);
+    }
+
+    /// Length of the record including the header and the padding.
+    pub fn record_len(&self) -> usize {
+        HEADER_LEN + self.content_length as usize + self.padding_length as usize
+    }
+}
+
+/// A record with its content, without the padding.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub record_type: RecordType,
+    pub request_id: u16,
+    pub content: Vec<u8>,
+}
+
+impl Record {
+    /// Creates a record. The content must not be longer than
+    /// `MAX_CONTENT_LEN`.
+    pub fn new(record_type: RecordType, request_id: u16, content: Vec<u8>) -> Record {
+        assert!(content.len() <= MAX_CONTENT_LEN, "FastCGI record content too long");
+        Record { record_type, request_id, content }
+    }
+
+    /// Decodes the record at the start of `buf`. Returns the record and
+    /// the number of bytes it took, or `None` if `buf` does not hold a
+    /// complete record yet.
+    pub fn decode(buf: &[u8]) -> Result<Option<(Record, usize)>, ProtocolError> {
+        let header = match Header::decode(buf)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        if buf.len() < header.record_len() {
+            return Ok(None);
+        }
+        let content = buf[HEADER_LEN..HEADER_LEN + header.content_length as usize].to_vec();
+        let record = Record { record_type: header.record_type, request_id: header.request_id, content };
+        Ok(Some((record, header.record_len())))
+    }
+
+    /// Reads the next record, `None` if the reader is at its end.
+    pub fn read_from<R: Read + ?Sized>(reader: &mut R) -> io::Result<Option<Record>> {
+        let mut header = [0u8; HEADER_LEN];
+        loop {
+            match reader.read(&mut header[..1]) {
+                Ok(0) => return Ok(None),
+                Ok(_) => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        reader.read_exact(&mut header[1..])?;
+        let header = Header::decode(&header)?.expect("complete header");
+        let mut content = vec![0; header.record_len() - HEADER_LEN];
+        reader.read_exact(&mut content)?;
+        content.truncate(header.content_length as usize);
+        Ok(Some(Record { record_type: header.record_type, request_id: header.request_id, content }))
+    }
+
+    /// Appends the encoded record, padded to a multiple of 8 bytes, to
+    /// `buf`.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        encode_record(buf, self.record_type, self.request_id, &self.content);
+    }
+
+    /// The body of a `BeginRequest` record.
+    pub fn begin_request(&self) -> Result<BeginRequest, ProtocolError> {
+        BeginRequest::decode(&self.content)
+    }
+
+    /// The body of an `EndRequest` record.
+    pub fn end_request(&self) -> Result<EndRequest, ProtocolError> {
+        EndRequest::decode(&self.content)
+    }
+}
+
+/// Appends a record, padded to a multiple of 8 bytes, to `buf` without
+/// creating a `Record`. The content must not be longer than
+/// `MAX_CONTENT_LEN`.
+pub fn encode_record(buf: &mut Vec<u8>, record_type: RecordType, request_id: u16, content: &[u8]) {
+    encode_record_aligned(buf, record_type, request_id, content, 8);
+}
+
+/// Appends a record padded to a multiple of `alignment` bytes, at most
+/// 256. An alignment of 1 leaves out the padding.
+pub fn encode_record_aligned(buf: &mut Vec<u8>, record_type: RecordType, request_id: u16, content: &[u8],
+                             alignment: usize) {
+    assert!(content.len() <= MAX_CONTENT_LEN, "FastCGI record content too long");
+    assert!((1..=256).contains(&alignment), "FastCGI record alignment out of range");
+    let padding = (alignment - content.len() % alignment) % alignment;
+    let header = Header {
+        record_type,
+        request_id,
+        content_length: content.len() as u16,
+        padding_length: padding as u8,
+    };
+    header.encode(buf);
+    buf.extend_from_slice(content);
+    buf.resize(buf.len() + padding, 0);
+}
+
+/// Appends `data` as the records of a stream, split at `MAX_CONTENT_LEN`.
+/// The empty record ending the stream is not included.
+pub fn encode_stream(buf: &mut Vec<u8>, record_type: RecordType, request_id: u16, data: &[u8]) {
+    encode_stream_sized(buf, record_type, request_id, data, MAX_CONTENT_LEN, 8);
+}
+
+/// Appends `data` as the records of a stream with up to `record_size`
+/// bytes of content each, padded like `encode_record_aligned`.
+pub fn encode_stream_sized(buf: &mut Vec<u8>, record_type: RecordType, request_id: u16, data: &[u8],
+                           record_size: usize, alignment: usize) {
+    for chunk in data.chunks(record_size) {
+        encode_record_aligned(buf, record_type, request_id, chunk, alignment);
+    }
+}
+
+/// The body of a `BeginRequest` record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BeginRequest {
+    pub role: Role,
+    pub flags: u8,
+}
+
+impl BeginRequest {
+    pub fn decode(content: &[u8]) -> Result<BeginRequest, ProtocolError> {
+        if content.len() < 8 {
+            return Err(ProtocolError::ShortContent(RecordType::BeginRequest));
+        }
+        Ok(BeginRequest { role: Role::from_u16(u16::from_be_bytes([content[0], content[1]])), flags: content[2] })
+    }
+
+    pub fn encode(&self) -> [u8; 8] {
+        let role = self.role.as_u16().to_be_bytes();
+        [role[0], role[1], self.flags, 0, 0, 0, 0, 0]
+    }
+
+    /// True if the connection stays open after the request.
+    pub fn keep_conn(&self) -> bool {
+        self.flags & KEEP_CONN != 0
+    }
+
+    /// The `BeginRequest` record for `request_id`.
+    pub fn to_record(&self, request_id: u16) -> Record {
+        Record::new(RecordType::BeginRequest, request_id, self.encode().to_vec())
+    }
+}
+
+/// The body of an `EndRequest` record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EndRequest {
+    /// The exit status of the application, as of a CGI program.
+    pub app_status: u32,
+    pub protocol_status: ProtocolStatus,
+}
+
+impl EndRequest {
+    pub fn decode(content: &[u8]) -> Result<EndRequest, ProtocolError> {
+        if content.len() < 8 {
+            return Err(ProtocolError::ShortContent(RecordType::EndRequest));
+        }
+        Ok(EndRequest {
+            app_status: u32::from_be_bytes([content[0], content[1], content[2], content[3]]),
+            protocol_status: ProtocolStatus::from_u8(content[4]),
+        })
+    }
+
+    pub fn encode(&self) -> [u8; 8] {
+        let status = self.app_status.to_be_bytes();
+        [status[0], status[1], status[2], status[3], self.protocol_status.as_u8(), 0, 0, 0]
+    }
+
+    /// The `EndRequest` record for `request_id`.
+    pub fn to_record(&self, request_id: u16) -> Record {
+        Record::new(RecordType::EndRequest, request_id, self.encode().to_vec())
+    }
+}
+
+/// The body of an `UnknownType` record, the reply to a management record
+/// of a type the receiver does not know.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnknownType {
+    pub record_type: u8,
+}
+
+impl UnknownType {
+    pub fn decode(content: &[u8]) -> Result<UnknownType, ProtocolError> {
+        match content.first() {
+            Some(&record_type) if content.len() >= 8 => Ok(UnknownType { record_type }),
+            _ => Err(ProtocolError::ShortContent(RecordType::UnknownType)),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; 8] {
+        [self.record_type, 0, 0, 0, 0, 0, 0, 0]
+    }
+
+    /// The `UnknownType` record.
+    pub fn to_record(&self) -> Record {
+        Record::new(RecordType::UnknownType, NULL_REQUEST_ID, self.encode().to_vec())
+    }
+}
+
+/// The largest length of a name or value, stored in 31 bits.
+pub const MAX_NAME_VALUE_LEN: usize = 0x7fff_ffff;
+
+/// Appends the encoded length of a name or value to `buf`. Panics if it is
+/// larger than `MAX_NAME_VALUE_LEN`.
+pub fn encode_length(buf: &mut Vec<u8>, len: usize) {
+    assert!(len <= MAX_NAME_VALUE_LEN, "FastCGI name or value too long");
+    if len < 0x80 {
+        buf.push(len as u8);
+    } else {
+        buf.extend_from_slice(&(len as u32 | 0x8000_0000).to_be_bytes());
+    }
+}
+
+/// Decodes the length at the start of `buf`. Returns the length and the
+/// number of bytes it took, or `None` if `buf` is too short.
+pub fn decode_length(buf: &[u8]) -> Option<(usize, usize)> {
+    match buf.first() {
+        Some(&len) if len < 0x80 => Some((len as usize, 1)),
+        Some(_) if buf.len() >= 4 => {
+            Some((u32::from_be_bytes([buf[0] & 0x7f, buf[1], buf[2], buf[3]]) as usize, 4))
+        }
+        _ => None,
+    }
+}
+
+/// Appends an encoded name-value pair to `buf`.
+pub fn encode_name_value(buf: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+    encode_length(buf, name.len());
+    encode_length(buf, value.len());
+    buf.extend_from_slice(name);
+    buf.extend_from_slice(value);
+}
+
+/// Encodes name-value pairs, e.g. the parameters of a request, into the
+/// content of a stream. Split the result with `encode_stream`.
+pub fn encode_name_values<I, N, V>(pairs: I) -> Vec<u8>
+    where I: IntoIterator<Item = (N, V)>, N: AsRef<[u8]>, V: AsRef<[u8]>
+{
+    let mut buf = Vec::new();
+    for (name, value) in pairs {
+        encode_name_value(&mut buf, name.as_ref(), value.as_ref());
+    }
+    buf
+}
+
+/// Decodes the name-value pair at the start of `buf`. Returns the name,
+/// the value and the number of bytes the pair took, or `None` if `buf`
+/// does not hold a complete pair.
+pub fn decode_name_value(buf: &[u8]) -> Option<(&[u8], &[u8], usize)> {
+    let (name_len, first) = decode_length(buf)?;
+    let (value_len, second) = decode_length(&buf[first..])?;
+    let start = first + second;
+    let end = start.checked_add(name_len)?.checked_add(value_len)?;
+    if end > buf.len() {
+        return None;
+    }
+    Some((&buf[start..start + name_len], &buf[start + name_len..end], end))
+}
+
+/// Iterates over the name-value pairs of the complete content of a
+/// stream, see `name_values`.
+#[derive(Clone, Debug)]
+pub struct NameValues<'a> {
+    buf: &'a [u8],
+}
+
+/// Returns an iterator over the name-value pairs of the complete content
+/// of a stream. It yields `TruncatedNameValue` once, if the content ends
+/// within a pair.
+pub fn name_values(buf: &[u8]) -> NameValues<'_> {
+    NameValues { buf }
+}
+
+impl<'a> Iterator for NameValues<'a> {
+    type Item = Result<(&'a [u8], &'a [u8]), ProtocolError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        match decode_name_value(self.buf) {
+            Some((name, value, len)) => {
+                self.buf = &self.buf[len..];
+                Some(Ok((name, value)))
+            }
+            None => {
+                self.buf = &[];
+                Some(Err(ProtocolError::TruncatedNameValue))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_length, decode_name_value, encode_length, encode_name_value, encode_name_values, name_values,
+                ProtocolError, MAX_NAME_VALUE_LEN};
+
+    fn length(len: usize) -> Vec<u8> {
+        let mut buf = Vec::new();
+        encode_length(&mut buf, len);
+        buf
+    }
+
+    #[test]
+    fn lengths() {
+        assert_eq!(length(0), [0]);
+        assert_eq!(length(127), [0x7f]);
+        assert_eq!(length(128), [0x80, 0, 0, 0x80]);
+        assert_eq!(length(MAX_NAME_VALUE_LEN), [0xff, 0xff, 0xff, 0xff]);
+        for len in [0, 1, 127, 128, 129, 0xffff, 0x1_0000, MAX_NAME_VALUE_LEN] {
+            let buf = length(len);
+            assert_eq!(decode_length(&buf), Some((len, buf.len())), "{}", len);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn length_too_large() {
+        length(MAX_NAME_VALUE_LEN + 1);
+    }
+
+    #[test]
+    fn high_bit() {
+        // The high bit marks the four byte form and is not part of the length.
+        assert_eq!(decode_length(&[0x80, 0, 0, 5]), Some((5, 4)));
+        assert_eq!(decode_length(&[0x81, 0x02, 0x03, 0x04]), Some((0x0102_0304, 4)));
+        // A four byte length may also be used for short ones.
+        let (name, value, used) = decode_name_value(&[0x80, 0, 0, 1, 1, b'n', b'v']).unwrap();
+        assert_eq!((name, value, used), (&b"n"[..], &b"v"[..], 7));
+    }
+
+    #[test]
+    fn truncated() {
+        assert_eq!(decode_length(&[]), None);
+        assert_eq!(decode_length(&[0x80]), None);
+        assert_eq!(decode_length(&[0x80, 0, 0]), None);
+        assert_eq!(decode_name_value(&[3]), None);
+        assert_eq!(decode_name_value(&[3, 0x80, 0, 0]), None);
+        assert_eq!(decode_name_value(&[3, 2, b'a', b'b', b'c', b'x']), None);
+        assert_eq!(decode_name_value(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]), None);
+        let mut content = encode_name_values([("A", "1"), ("B", "22")]);
+        content.pop();
+        let pairs: Vec<_> = name_values(&content).collect();
+        assert_eq!(pairs, [Ok((&b"A"[..], &b"1"[..])), Err(ProtocolError::TruncatedNameValue)]);
+    }
+
+    #[test]
+    fn empty() {
+        let mut buf = Vec::new();
+        encode_name_value(&mut buf, b"", b"");
+        encode_name_value(&mut buf, b"NAME", b"");
+        encode_name_value(&mut buf, b"", b"value");
+        assert_eq!(buf[..2], [0, 0]);
+        let pairs: Vec<_> = name_values(&buf).map(Result::unwrap).collect();
+        assert_eq!(pairs, [(&b""[..], &b""[..]), (b"NAME", b""), (b"", b"value")]);
+        assert_eq!(name_values(&[]).count(), 0);
+    }
+
+    #[test]
+    fn round_trip() {
+        let long = [b'x'; 128];
+        let pairs = [(&b"SHORT"[..], &b"v"[..]), (&long[..127], &long[..128]), (&long[..], &long[..127])];
+        let content = encode_name_values(pairs.iter().cloned());
+        assert_eq!(content.len(), (1 + 1 + 5 + 1) + (1 + 4 + 127 + 128) + (4 + 1 + 128 + 127));
+        let decoded: Vec<_> = name_values(&content).map(Result::unwrap).collect();
+        assert_eq!(decoded, pairs);
+    }
+}
@@ -0,0 +1,135 @@
+//! The HAProxy PROXY protocol, versions 1 and 2.
+//!
+//! A TCP load balancer in front of the application hides the address of
+//! the peer which connected to it. With the PROXY protocol it sends that
+//! address in a header before the forwarded data, which
+//! `Listener::set_proxy_protocol` (or the `proxy_protocol` setting of the
+//! server) reads from every connection. `Exchange::peer_addr` then returns
+//! the source address of the header instead of the load balancer's.
+//!
+//! The header is required on every connection once enabled: a connection
+//! without one is closed, as the protocol specification demands, so only
+//! enable it when all peers are behind the load balancer. Combined with
+//! TLS, the header is expected inside the encrypted stream.
+
+use std::io::{self, ErrorKind, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str;
+
+/// The start of a version 1 header.
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// The longest version 1 header, including the line break.
+const V1_MAX_LEN: usize = 107;
+/// The signature of a version 2 header.
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The addresses of a PROXY protocol header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProxyHeader {
+    /// The address of the peer which connected to the load balancer, None
+    /// for health checks of the load balancer itself (`LOCAL` and
+    /// `UNKNOWN`) and for protocols other than TCP over IPv4 or IPv6.
+    pub source: Option<SocketAddr>,
+    /// The address the peer connected to.
+    pub destination: Option<SocketAddr>,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("invalid PROXY protocol header: {}", message))
+}
+
+/// Reads a version 1 or 2 header from the start of a connection, and not a
+/// byte more.
+pub fn read_header<R: Read>(reader: &mut R) -> io::Result<ProxyHeader> {
+    let mut start = [0; 6];
+    reader.read_exact(&mut start)?;
+    if start == V1_PREFIX {
+        read_v1(reader)
+    } else if start == V2_SIGNATURE[..6] {
+        read_v2(reader)
+    } else {
+        Err(invalid("missing"))
+    }
+}
+
+fn read_v1<R: Read>(reader: &mut R) -> io::Result<ProxyHeader> {
+    let mut line = Vec::with_capacity(V1_MAX_LEN);
+    line.extend_from_slice(V1_PREFIX);
+    let mut byte = [0; 1];
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LEN {
+            return Err(invalid("line too long"));
+        }
+        reader.read_exact(&mut byte)?;
+        line.push(byte[0]);
+    }
+    let line = str::from_utf8(&line[V1_PREFIX.len()..line.len() - 2]).map_err(|_| invalid("not text"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[0] {
+        "UNKNOWN" => Ok(ProxyHeader { source: None, destination: None }),
+        "TCP4" | "TCP6" if fields.len() == 5 => {
+            let addr = |ip: &str, port: &str| -> io::Result<SocketAddr> {
+                let ip: IpAddr = ip.parse().map_err(|_| invalid("bad address"))?;
+                let port: u16 = port.parse().map_err(|_| invalid("bad port"))?;
+                if ip.is_ipv4() != (fields[0] == "TCP4") {
+                    return Err(invalid("address of the wrong family"));
+                }
+                Ok(SocketAddr::new(ip, port))
+            };
+            Ok(ProxyHeader {
+                source: Some(addr(fields[1], fields[3])?),
+                destination: Some(addr(fields[2], fields[4])?),
+            })
+        }
+        _ => Err(invalid("unsupported protocol")),
+    }
+}
+
+fn read_v2<R: Read>(reader: &mut R) -> io::Result<ProxyHeader> {
+    let mut header = [0; 10];
+    reader.read_exact(&mut header)?;
+    if header[..6] != V2_SIGNATURE[6..] {
+        return Err(invalid("bad signature"));
+    }
+    let version_command = header[6];
+    let family = header[7];
+    let len = u16::from_be_bytes([header[8], header[9]]) as usize;
+    let mut addresses = vec![0; len];
+    reader.read_exact(&mut addresses)?;
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL: a connection of the load balancer itself.
+        0 => return Ok(ProxyHeader { source: None, destination: None }),
+        1 => {}
+        _ => return Err(invalid("unsupported command")),
+    }
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+    match family {
+        // TCP over IPv4
+        0x11 if len >= 12 => {
+            let ip = |at: usize| {
+                IpAddr::V4(Ipv4Addr::new(addresses[at], addresses[at + 1], addresses[at + 2], addresses[at + 3]))
+            };
+            Ok(ProxyHeader {
+                source: Some(SocketAddr::new(ip(0), port(8))),
+                destination: Some(SocketAddr::new(ip(4), port(10))),
+            })
+        }
+        // TCP over IPv6
+        0x21 if len >= 36 => {
+            let ip = |at: usize| {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&addresses[at..at + 16]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
+            Ok(ProxyHeader {
+                source: Some(SocketAddr::new(ip(0), port(32))),
+                destination: Some(SocketAddr::new(ip(16), port(34))),
+            })
+        }
+        0x11 | 0x21 => Err(invalid("addresses too short")),
+        _ => Ok(ProxyHeader { source: None, destination: None }),
+    }
+}
@@ -0,0 +1,160 @@
+//! Rendering templates straight into the response.
+//!
+//! A `RenderResponse` writes its output to a writer instead of returning a
+//! `String`, so large pages go out as they are rendered.
+//! `Exchange::render` sets the Content-Type and buffers the output in
+//! blocks. Adapters for template engines are enabled by features of the
+//! same name, one of which is required for the module:
+//!
+//! ```ignore
+//! // askama
+//! exchange.render(&Askama::new(&IndexPage { user }))?;
+//! // tera
+//! exchange.render(&Tera::new(&templates, "index.html", &context))?;
+//! // handlebars
+//! exchange.render(&Handlebars::new(&registry, "index", &data))?;
+//! ```
+
+use std::io::{self, Write};
+
+#[cfg(any(feature = "tera", feature = "handlebars"))]
+use crate::mime;
+
+/// The Content-Type of rendered output unless stated otherwise.
+pub const HTML: &str = "text/html; charset=utf-8";
+
+/// Output which renders itself into the response body.
+pub trait RenderResponse {
+    /// The Content-Type of the output, HTML unless overridden.
+    fn content_type(&self) -> &str {
+        HTML
+    }
+
+    /// Writes the output to `out`. Errors of the template engine are
+    /// returned as `io::Error`s.
+    fn render_to(&self, out: &mut dyn Write) -> io::Result<()>;
+}
+
+impl RenderResponse for str {
+    fn render_to(&self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_all(self.as_bytes())
+    }
+}
+
+impl RenderResponse for String {
+    fn render_to(&self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_all(self.as_bytes())
+    }
+}
+
+/// The Content-Type for a template by the extension of its name, e.g.
+/// `feed.xml`, HTML for unknown extensions.
+#[cfg(any(feature = "tera", feature = "handlebars"))]
+fn content_type_of(name: &str) -> &'static str {
+    name.rsplit_once('.')
+        .and_then(|(_, ext)| mime::from_extension(ext))
+        .unwrap_or(HTML)
+}
+
+/// Renders an askama template, with the `askama` feature.
+#[cfg(feature = "askama")]
+pub struct Askama<'a, T: ?Sized> {
+    template: &'a T,
+    content_type: &'a str,
+}
+
+#[cfg(feature = "askama")]
+impl<'a, T: askama::Template + ?Sized> Askama<'a, T> {
+    /// Renders `template` as HTML.
+    pub fn new(template: &'a T) -> Askama<'a, T> {
+        Askama { template, content_type: HTML }
+    }
+
+    /// Sets the Content-Type of the output.
+    pub fn with_content_type(mut self, content_type: &'a str) -> Askama<'a, T> {
+        self.content_type = content_type;
+        self
+    }
+}
+
+#[cfg(feature = "askama")]
+impl<'a, T: askama::Template + ?Sized> RenderResponse for Askama<'a, T> {
+    fn content_type(&self) -> &str {
+        self.content_type
+    }
+
+    fn render_to(&self, out: &mut dyn Write) -> io::Result<()> {
+        askama::Template::write_into(self.template, out)
+    }
+}
+
+/// Renders a template of a `tera::Tera`, with the `tera` feature.
+#[cfg(feature = "tera")]
+pub struct Tera<'a> {
+    tera: &'a tera::Tera,
+    name: &'a str,
+    context: &'a tera::Context,
+    content_type: &'a str,
+}
+
+#[cfg(feature = "tera")]
+impl<'a> Tera<'a> {
+    /// Renders the template `name` with `context`. The Content-Type follows
+    /// the extension of the name, HTML if it has none.
+    pub fn new(tera: &'a tera::Tera, name: &'a str, context: &'a tera::Context) -> Tera<'a> {
+        Tera { tera, name, context, content_type: content_type_of(name) }
+    }
+
+    /// Sets the Content-Type of the output.
+    pub fn with_content_type(mut self, content_type: &'a str) -> Tera<'a> {
+        self.content_type = content_type;
+        self
+    }
+}
+
+#[cfg(feature = "tera")]
+impl<'a> RenderResponse for Tera<'a> {
+    fn content_type(&self) -> &str {
+        self.content_type
+    }
+
+    fn render_to(&self, out: &mut dyn Write) -> io::Result<()> {
+        self.tera.render_to(self.name, self.context, out).map_err(io::Error::other)
+    }
+}
+
+/// Renders a template of a `handlebars::Handlebars` registry, with the
+/// `handlebars` feature.
+#[cfg(feature = "handlebars")]
+pub struct Handlebars<'a, T> {
+    registry: &'a handlebars::Handlebars<'a>,
+    name: &'a str,
+    data: &'a T,
+    content_type: &'a str,
+}
+
+#[cfg(feature = "handlebars")]
+impl<'a, T: serde::Serialize> Handlebars<'a, T> {
+    /// Renders the template `name` with `data`. The Content-Type follows
+    /// the extension of the name, HTML if it has none.
+    pub fn new(registry: &'a handlebars::Handlebars<'a>, name: &'a str, data: &'a T) -> Handlebars<'a, T> {
+        Handlebars { registry, name, data, content_type: content_type_of(name) }
+    }
+
+    /// Sets the Content-Type of the output.
+    pub fn with_content_type(mut self, content_type: &'a str) -> Handlebars<'a, T> {
+        self.content_type = content_type;
+        self
+    }
+}
+
+#[cfg(feature = "handlebars")]
+impl<'a, T: serde::Serialize> RenderResponse for Handlebars<'a, T> {
+    fn content_type(&self) -> &str {
+        self.content_type
+    }
+
+    fn render_to(&self, out: &mut dyn Write) -> io::Result<()> {
+        self.registry.render_to_write(self.name, self.data, out).map_err(io::Error::other)
+    }
+}
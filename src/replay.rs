@@ -0,0 +1,101 @@
+//! Replaying recorded FastCGI sessions against a handler.
+//!
+//! With `ServerConfig::record_sessions` (or `Listener::set_recording`) the
+//! native transport saves the bytes each web server connection sent to a
+//! file. Feeding such a file to a handler again reproduces the requests of
+//! the session exactly, e.g. for a regression test of a web server behaviour
+//! observed in production:
+//!
+//! ```no_run
+//! use fcgi::Exchange;
+//! use fcgi::protocol::RecordType;
+//!
+//! let records = fcgi::replay::replay_file("tests/sessions/chunked.fcgi", |ex: &mut Exchange| {
+//!     ex.write_body(b"hello").unwrap();
+//! }).unwrap();
+//! assert!(records.iter().any(|record| record.record_type == RecordType::EndRequest));
+//! ```
+//!
+//! The handler is called directly on an `Exchange`, without the middleware
+//! and hooks of a server.
+
+use std::fs;
+use std::io::{self, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::thread;
+
+use crate::exchange::Exchange;
+use crate::handler::Handler;
+use crate::native::{Listener, NativeRequest};
+use crate::protocol::{self, Record, RecordType};
+use crate::Request;
+
+/// Feeds the session to the handler through the native transport and
+/// returns the records written back, in the order they were sent, once
+/// every request of the session has ended and every management record has
+/// been answered. A panic of the handler is returned as an error.
+pub fn replay<H: Handler>(session: &[u8], handler: H) -> io::Result<Vec<Record>> {
+    let mut expected = 0;
+    let mut rest = session;
+    while let Some((record, used)) = Record::decode(rest)? {
+        if record.record_type == RecordType::BeginRequest || record.request_id == protocol::NULL_REQUEST_ID {
+            expected += 1;
+        }
+        rest = &rest[used..];
+    }
+
+    let socket = TcpListener::bind("127.0.0.1:0")?;
+    let listener = Listener::new(socket.as_raw_fd());
+    let worker_listener = listener.clone();
+    let worker = thread::Builder::new().name(String::from("fcgi-replay")).spawn(move || {
+        let mut request = NativeRequest::from_listener(worker_listener);
+        let mut panicked = false;
+        while request.accept() {
+            let mut exchange = Exchange::new(&mut request);
+            // The request must end even if the handler panics, or reading
+            // the output would wait forever.
+            panicked |= panic::catch_unwind(AssertUnwindSafe(|| handler.handle(&mut exchange))).is_err();
+            let _ = exchange.finish();
+        }
+        panicked
+    })?;
+
+    // The connection stays open until all output is read: the transport
+    // aborts the requests of a connection closed by the web server.
+    let mut stream = TcpStream::connect(socket.local_addr()?)?;
+    let mut input = stream.try_clone()?;
+    let session = session.to_vec();
+    let writer = thread::spawn(move || input.write_all(&session));
+    let mut records = Vec::new();
+    let mut answered = 0;
+    let result = loop {
+        if answered == expected {
+            break Ok(());
+        }
+        match Record::read_from(&mut stream) {
+            Ok(Some(record)) => {
+                if record.record_type == RecordType::EndRequest || record.request_id == protocol::NULL_REQUEST_ID {
+                    answered += 1;
+                }
+                records.push(record);
+            }
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+    let _ = stream.shutdown(Shutdown::Both);
+    let _ = writer.join();
+    listener.close();
+    if worker.join().unwrap_or(true) {
+        return Err(io::Error::other("replayed handler panicked"));
+    }
+    result.map(|_| records)
+}
+
+/// Replays a session recorded to a file, see `replay`.
+pub fn replay_file<P: AsRef<Path>, H: Handler>(path: P, handler: H) -> io::Result<Vec<Record>> {
+    replay(&fs::read(path)?, handler)
+}
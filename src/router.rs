@@ -0,0 +1,383 @@
+//! Dispatching requests to handlers by method and path.
+//!
+//! The path matched is PATH_INFO, or SCRIPT_NAME if the web server passes
+//! the whole path there and leaves PATH_INFO empty. A pattern ending in
+//! `*` matches every path starting with the part before the `*`; any other
+//! pattern must match the path exactly.
+//!
+//! Patterns may contain parameters such as `/users/{id}/posts/{post_id}`.
+//! A parameter matches a non-empty part of one path segment; the captured
+//! value is percent-decoded and available through `Exchange::path_param`
+//! or, parsed into typed values, through the `typed` handler adapter.
+//!
+//! If several routes match, the pattern with the most literal characters
+//! wins, and an exact pattern wins over a prefix pattern.
+//!
+//! Routes may be restricted to a set of methods; routes accepting GET also
+//! accept HEAD. A request whose path matches only routes for other methods
+//! is answered with 405 and an Allow header listing the accepted methods.
+
+use std::marker::PhantomData;
+use std::str::FromStr;
+
+use crate::exchange::Exchange;
+use crate::handler::Handler;
+use crate::urlencoding::decode_path;
+
+enum Part {
+    Literal(String),
+    Param(String),
+}
+
+struct Pattern {
+    parts: Vec<Part>,
+    prefix: bool,
+    literal_len: usize,
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Pattern {
+        let (template, prefix) = match pattern.strip_suffix('*') {
+            Some(template) => (template, true),
+            None => (pattern, false),
+        };
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => break,
+            };
+            if start > 0 {
+                parts.push(Part::Literal(String::from(&rest[..start])));
+            }
+            parts.push(Part::Param(String::from(&rest[start + 1..end])));
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(String::from(rest)));
+        }
+        let literal_len = parts.iter().map(|part| match *part {
+            Part::Literal(ref literal) => literal.len(),
+            Part::Param(_) => 0,
+        }).sum();
+        Pattern { parts, prefix, literal_len }
+    }
+
+    /// Matches the path, returning the decoded parameters on success.
+    fn matches(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let mut params = Vec::new();
+        let mut rest = path;
+        for part in &self.parts {
+            match *part {
+                Part::Literal(ref literal) => {
+                    rest = rest.strip_prefix(literal.as_str())?;
+                }
+                Part::Param(ref name) => {
+                    let end = rest.find('/').unwrap_or(rest.len());
+                    if end == 0 {
+                        return None;
+                    }
+                    params.push((name.clone(), decode_path(&rest[..end])?));
+                    rest = &rest[end..];
+                }
+            }
+        }
+        if rest.is_empty() || self.prefix {
+            Some(params)
+        } else {
+            None
+        }
+    }
+
+    /// Orders overlapping patterns: more literal characters win, and an
+    /// exact pattern wins over a prefix pattern of the same length.
+    fn priority(&self) -> (usize, bool) {
+        (self.literal_len, !self.prefix)
+    }
+}
+
+struct Route {
+    methods: Option<Vec<String>>,
+    pattern: Pattern,
+    handler: Box<dyn Handler>,
+}
+
+impl Route {
+    /// HEAD requests are accepted by routes which accept GET.
+    fn allows(&self, method: &str) -> bool {
+        match self.methods {
+            None => true,
+            Some(ref methods) => methods.iter().any(|m| m == method || (m == "GET" && method == "HEAD")),
+        }
+    }
+}
+
+enum Lookup<'a> {
+    Found(&'a Route, Vec<(String, String)>),
+    /// The path matched, but only for the listed methods.
+    MethodNotAllowed(String),
+    NotFound,
+}
+
+/// Routes requests to handlers by REQUEST_METHOD and path.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    fallback: Option<Box<dyn Handler>>,
+}
+
+impl Router {
+    /// Creates a router without any routes.
+    pub fn new() -> Router {
+        Router { routes: Vec::new(), fallback: None }
+    }
+
+    /// Sets the handler for requests matching no route. By default they
+    /// are answered with the server's 404 response.
+    pub fn fallback<H: Handler>(mut self, handler: H) -> Router {
+        self.fallback = Some(Box::new(handler));
+        self
+    }
+
+    /// Adds a route for the given method and path pattern.
+    pub fn route<H: Handler>(self, method: &str, pattern: &str, handler: H) -> Router {
+        self.methods(&[method], pattern, handler)
+    }
+
+    /// Adds a route for the path pattern which accepts any of the given
+    /// methods.
+    pub fn methods<H: Handler>(self, methods: &[&str], pattern: &str, handler: H) -> Router {
+        let methods = methods.iter().map(|m| m.to_ascii_uppercase()).collect();
+        self.add(Some(methods), pattern, handler)
+    }
+
+    /// Adds a route matching the path pattern for every method.
+    pub fn any<H: Handler>(self, pattern: &str, handler: H) -> Router {
+        self.add(None, pattern, handler)
+    }
+
+    /// Adds a GET route.
+    pub fn get<H: Handler>(self, pattern: &str, handler: H) -> Router {
+        self.route("GET", pattern, handler)
+    }
+
+    /// Adds a POST route.
+    pub fn post<H: Handler>(self, pattern: &str, handler: H) -> Router {
+        self.route("POST", pattern, handler)
+    }
+
+    /// Adds a PUT route.
+    pub fn put<H: Handler>(self, pattern: &str, handler: H) -> Router {
+        self.route("PUT", pattern, handler)
+    }
+
+    /// Adds a DELETE route.
+    pub fn delete<H: Handler>(self, pattern: &str, handler: H) -> Router {
+        self.route("DELETE", pattern, handler)
+    }
+
+    fn add<H: Handler>(mut self, methods: Option<Vec<String>>, pattern: &str, handler: H) -> Router {
+        self.routes.push(Route {
+            methods,
+            pattern: Pattern::parse(pattern),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    fn find(&self, method: &str, path: &str) -> Lookup<'_> {
+        let mut best: Option<(&Route, Vec<(String, String)>)> = None;
+        let mut allowed: Vec<&str> = Vec::new();
+        for route in &self.routes {
+            let params = match route.pattern.matches(path) {
+                Some(params) => params,
+                None => continue,
+            };
+            if !route.allows(method) {
+                if let Some(ref methods) = route.methods {
+                    for m in methods {
+                        allowed.push(m);
+                        if m == "GET" {
+                            allowed.push("HEAD");
+                        }
+                    }
+                }
+                continue;
+            }
+            if best.as_ref().is_none_or(|(b, _)| route.pattern.priority() > b.pattern.priority()) {
+                best = Some((route, params));
+            }
+        }
+        match best {
+            Some((route, params)) => Lookup::Found(route, params),
+            None if !allowed.is_empty() => {
+                allowed.sort_unstable();
+                allowed.dedup();
+                Lookup::MethodNotAllowed(allowed.join(", "))
+            }
+            None => Lookup::NotFound,
+        }
+    }
+}
+
+/// The path used for routing: PATH_INFO, falling back to SCRIPT_NAME.
+pub(crate) fn request_path(exchange: &Exchange) -> String {
+    match exchange.param("PATH_INFO") {
+        Some(ref path) if !path.is_empty() => path.clone(),
+        _ => exchange.param("SCRIPT_NAME").unwrap_or_default(),
+    }
+}
+
+impl Handler for Router {
+    fn handle(&self, exchange: &mut Exchange) {
+        let method = exchange.method();
+        let path = request_path(exchange);
+        match self.find(&method, &path) {
+            Lookup::Found(route, params) => {
+                exchange.set_path_params(params);
+                route.handler.handle(exchange)
+            }
+            Lookup::MethodNotAllowed(allow) => {
+                exchange.set_header("Allow", &allow);
+                exchange.respond_error(405);
+            }
+            Lookup::NotFound => match self.fallback {
+                Some(ref fallback) => fallback.handle(exchange),
+                None => exchange.respond_error(404),
+            },
+        }
+    }
+}
+
+/// Path parameter types which can be extracted as a whole, see `typed`.
+/// Implemented for tuples of `FromStr` types, which are filled from the
+/// captured parameters in pattern order.
+pub trait FromPathParams: Sized {
+    /// Parses the captured parameters, None if any of them does not parse.
+    fn from_path_params(params: &[(String, String)]) -> Option<Self>;
+}
+
+macro_rules! tuple_from_path_params {
+    ($count:expr; $($name:ident: $index:tt),+) => {
+        impl<$($name: FromStr),+> FromPathParams for ($($name,)+) {
+            fn from_path_params(params: &[(String, String)]) -> Option<Self> {
+                if params.len() != $count {
+                    return None;
+                }
+                Some(($(params[$index].1.parse::<$name>().ok()?,)+))
+            }
+        }
+    }
+}
+
+tuple_from_path_params!(1; A: 0);
+tuple_from_path_params!(2; A: 0, B: 1);
+tuple_from_path_params!(3; A: 0, B: 1, C: 2);
+tuple_from_path_params!(4; A: 0, B: 1, C: 2, D: 3);
+tuple_from_path_params!(5; A: 0, B: 1, C: 2, D: 3, E: 4);
+
+/// A handler receiving its path parameters already parsed. Created by
+/// `typed`.
+pub struct Typed<T, F> {
+    handler: F,
+    params: PhantomData<fn() -> T>,
+}
+
+/// Wraps a handler taking typed path parameters. Requests whose captured
+/// parameters do not parse into `T` are answered with 404.
+///
+/// ```ignore
+/// router.get("/users/{id}/posts/{post_id}",
+///     router::typed(|ex: &mut Exchange, (id, post_id): (u64, u32)| { ... }));
+/// ```
+pub fn typed<T, F>(handler: F) -> Typed<T, F>
+    where T: FromPathParams + 'static, F: Fn(&mut Exchange, T) + Send + Sync + 'static
+{
+    Typed { handler, params: PhantomData }
+}
+
+impl<T, F> Handler for Typed<T, F>
+    where T: FromPathParams + 'static, F: Fn(&mut Exchange, T) + Send + Sync + 'static
+{
+    fn handle(&self, exchange: &mut Exchange) {
+        match T::from_path_params(exchange.path_params()) {
+            Some(params) => (self.handler)(exchange, params),
+            None => exchange.respond_error(404),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{typed, Router};
+    use crate::exchange::Exchange;
+    use crate::testing::TestRequest;
+
+    /// Answers with the name of the route and its path parameters.
+    fn named(name: &'static str) -> impl Fn(&mut Exchange) + Send + Sync + 'static {
+        move |exchange: &mut Exchange| {
+            let params: Vec<String> = exchange.path_params().iter().map(|(n, v)| format!("{}={}", n, v)).collect();
+            exchange.write_body(format!("{} {}", name, params.join(",")).trim_end().as_bytes()).unwrap();
+        }
+    }
+
+    fn body(router: &Router, method: &str, uri: &str) -> (u16, String) {
+        let response = TestRequest::with_method(method, uri).run(router);
+        (response.status, String::from_utf8(response.body).unwrap())
+    }
+
+    #[test]
+    fn params() {
+        let router = Router::new().get("/users/{id}/posts/{post}", named("post"));
+        assert_eq!(body(&router, "GET", "/users/42/posts/7"), (200, String::from("post id=42,post=7")));
+        assert_eq!(body(&router, "GET", "/users/a%20b%2Fc/posts/x+y"), (200, String::from("post id=a b/c,post=x+y")));
+        assert_eq!(body(&router, "GET", "/users/%zz/posts/7").0, 404);
+        assert_eq!(body(&router, "GET", "/users/%ff/posts/7").0, 404);
+        assert_eq!(body(&router, "GET", "/users//posts/7").0, 404);
+        assert_eq!(body(&router, "GET", "/users/42/posts/7/comments").0, 404);
+    }
+
+    #[test]
+    fn priority() {
+        let router = Router::new()
+            .get("/users/*", named("prefix"))
+            .get("/users/{id}", named("user"))
+            .get("/users/me", named("me"))
+            .get("/users/me*", named("me prefix"));
+        assert_eq!(body(&router, "GET", "/users/me").1, "me");
+        assert_eq!(body(&router, "GET", "/users/42").1, "user id=42");
+        assert_eq!(body(&router, "GET", "/users/42/posts").1, "prefix");
+        assert_eq!(body(&router, "GET", "/users/meet").1, "me prefix");
+        assert_eq!(body(&router, "GET", "/usersx").0, 404);
+    }
+
+    #[test]
+    fn methods() {
+        let router = Router::new()
+            .get("/items", named("list"))
+            .post("/items", named("create"))
+            .fallback(named("fallback"));
+        assert_eq!(body(&router, "POST", "/items").1, "create");
+        assert_eq!(body(&router, "HEAD", "/items").0, 200);
+        let response = TestRequest::with_method("DELETE", "/items").run(&router);
+        assert_eq!((response.status, response.header("Allow")), (405, Some("GET, HEAD, POST")));
+        assert_eq!(body(&router, "DELETE", "/other").1, "fallback");
+    }
+
+    #[test]
+    fn script_name() {
+        let router = Router::new().get("/app/{page}", named("page"));
+        let response = TestRequest::get("/app/home").param("PATH_INFO", "").param("SCRIPT_NAME", "/app/home").run(&router);
+        assert_eq!(response.body, b"page page=home");
+    }
+
+    #[test]
+    fn typed_params() {
+        let router = Router::new().get("/sum/{a}/{b}", typed(|exchange: &mut Exchange, (a, b): (u32, u32)| {
+            exchange.write_body((a + b).to_string().as_bytes()).unwrap();
+        }));
+        assert_eq!(body(&router, "GET", "/sum/2/3"), (200, String::from("5")));
+        assert_eq!(body(&router, "GET", "/sum/2/x").0, 404);
+    }
+}
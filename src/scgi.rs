@@ -0,0 +1,137 @@
+//! The SCGI protocol, as an alternative transport to FastCGI.
+//!
+//! Web servers such as nginx (`scgi_pass`) and lighttpd (`mod_scgi`) open a
+//! connection per request and send the CGI parameters as a netstring of
+//! NUL-separated names and values, followed by `CONTENT_LENGTH` bytes of
+//! input:
+//!
+//! ```text
+//! 63:CONTENT_LENGTH\04\0SCGI\01\0REQUEST_METHOD\0POST\0REQUEST_URI\0/hello\0,Test
+//! ```
+//!
+//! The response is written back in CGI form, with a `Status` header, and
+//! ends when the application closes the connection. `ScgiRequest`
+//! implements the same `Request` trait as the FastCGI requests, so handlers
+//! run unchanged; the high-level server speaks SCGI with the `protocol`
+//! setting. There is no error stream: `Request::error` writes to stderr.
+
+use std::io::{self, BufRead, Read};
+use std::net::SocketAddr;
+use std::os::unix::io::RawFd;
+use std::str;
+
+use crate::conn::{self, Connection};
+use crate::{Request, StreamType};
+
+/// The longest netstring of parameters accepted.
+const MAX_PARAMS_LEN: usize = 1024 * 1024;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid SCGI request: {}", message))
+}
+
+/// Reads the parameters at the start of an SCGI request, checking that
+/// `CONTENT_LENGTH` comes first and `SCGI` is `1` as the specification
+/// demands.
+pub fn read_params<R: BufRead>(reader: &mut R) -> io::Result<Vec<(String, String)>> {
+    let mut len = Vec::new();
+    reader.take(8).read_until(b':', &mut len)?;
+    if len.pop() != Some(b':') {
+        return Err(invalid("expected the length of the parameters"));
+    }
+    let len = str::from_utf8(&len).ok()
+        .and_then(|len| len.parse::<usize>().ok())
+        .filter(|&len| len <= MAX_PARAMS_LEN)
+        .ok_or_else(|| invalid("bad length of the parameters"))?;
+    let mut data = vec![0; len + 1];
+    reader.read_exact(&mut data)?;
+    if data.pop() != Some(b',') {
+        return Err(invalid("parameters not terminated by a comma"));
+    }
+    if data.pop().is_some_and(|last| last != 0) {
+        return Err(invalid("parameters not terminated by NUL"));
+    }
+    let mut fields = data.split(|&b| b == 0);
+    let mut params = Vec::new();
+    while let Some(name) = fields.next() {
+        let value = fields.next().ok_or_else(|| invalid("parameter without a value"))?;
+        params.push((String::from_utf8_lossy(name).into_owned(), String::from_utf8_lossy(value).into_owned()));
+    }
+    if params.first().is_none_or(|(name, _)| name != "CONTENT_LENGTH") {
+        return Err(invalid("CONTENT_LENGTH must be the first parameter"));
+    }
+    if !params.iter().any(|(name, value)| name == "SCGI" && value == "1") {
+        return Err(invalid("missing SCGI parameter"));
+    }
+    Ok(params)
+}
+
+/// An SCGI request, accepted from a listen socket.
+pub struct ScgiRequest {
+    listen_fd: RawFd,
+    current: Option<Connection>,
+}
+
+impl Request for ScgiRequest {
+    /// Accepts requests from the socket passed as stdin.
+    fn new() -> Option<ScgiRequest> {
+        ScgiRequest::new_with_fd(0)
+    }
+
+    fn new_with_fd(fd: RawFd) -> Option<ScgiRequest> {
+        Some(ScgiRequest { listen_fd: fd, current: None })
+    }
+
+    /// Finishes the previous request and accepts the next. Connections
+    /// which do not start with valid parameters are closed.
+    fn accept(&mut self) -> bool {
+        self.finish();
+        self.current = conn::accept_request(self.listen_fd, read_params);
+        self.current.is_some()
+    }
+
+    /// Sends the remaining output and closes the connection.
+    fn finish(&mut self) {
+        if let Some(current) = self.current.take() {
+            current.close();
+        }
+    }
+
+    fn get_param(&self, name: &str) -> Option<String> {
+        self.current.as_ref()?.param(name)
+    }
+
+    fn params(&self) -> Vec<(String, String)> {
+        self.current.as_ref().map(|current| current.params().to_vec()).unwrap_or_default()
+    }
+
+    fn write(&mut self, msg: &str) -> i32 {
+        self.write_bytes(msg.as_bytes())
+    }
+
+    fn error(&mut self, msg: &str) -> i32 {
+        conn::write_stderr(msg)
+    }
+
+    fn write_bytes(&mut self, buf: &[u8]) -> i32 {
+        self.current.as_mut().map_or(-1, |current| current.write(buf))
+    }
+
+    fn read_bytes(&mut self, buf: &mut [u8]) -> i32 {
+        self.current.as_mut().map_or(-1, |current| current.read(buf))
+    }
+
+    fn read(&mut self, n: i32) -> (String, i32) {
+        crate::read_lossy(self, n)
+    }
+
+    fn flush(&mut self, stream_type: StreamType) {
+        if let (StreamType::OutStream, Some(current)) = (stream_type, self.current.as_mut()) {
+            current.flush();
+        }
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.current.as_ref().and_then(Connection::peer_addr)
+    }
+}
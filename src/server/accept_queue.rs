@@ -0,0 +1,111 @@
+//! A bounded queue between a single accepting thread and the workers.
+//!
+//! By default every worker blocks in accept itself. With a queue, one
+//! acceptor thread accepts requests, which includes reading their
+//! parameters, and hands them to the workers. The queue depth and the time
+//! requests wait in it are reported to the metrics. When the queue is full
+//! the acceptor stops accepting, leaving further connections in the listen
+//! backlog.
+//!
+//! Requests which have been accepted are always handled: on shutdown the
+//! acceptor stops, and the workers exit once the queue is empty.
+
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::time::Instant;
+
+use crate::Request;
+use super::transport::{self, WorkerRequest};
+use super::WorkerContext;
+
+/// An accepted request waiting for a worker. The request is boxed as the
+/// library's streams point back to it once accepted.
+pub(super) struct Queued {
+    pub(super) request: Box<WorkerRequest>,
+    pub(super) since: Instant,
+}
+
+// The request is used by one thread at a time, first the acceptor, then
+// the worker which took it from the queue.
+unsafe impl Send for Queued {}
+
+struct State {
+    items: VecDeque<Queued>,
+    closed: bool,
+}
+
+pub(super) struct AcceptQueue {
+    state: Mutex<State>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: usize,
+}
+
+impl AcceptQueue {
+    pub(super) fn new(capacity: usize) -> AcceptQueue {
+        AcceptQueue {
+            state: Mutex::new(State { items: VecDeque::new(), closed: false }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Number of requests waiting for a worker.
+    pub(super) fn len(&self) -> usize {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).items.len()
+    }
+
+    /// Adds a request, waiting while the queue is full. Returns the new
+    /// depth.
+    fn push(&self, queued: Queued) -> usize {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        while state.items.len() >= self.capacity {
+            state = self.not_full.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        state.items.push_back(queued);
+        self.not_empty.notify_one();
+        state.items.len()
+    }
+
+    /// Takes the oldest request, waiting while the queue is empty. Returns
+    /// None once the queue is closed and empty, and the remaining depth
+    /// otherwise.
+    pub(super) fn pop(&self) -> Option<(Queued, usize)> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if let Some(queued) = state.items.pop_front() {
+                self.not_full.notify_one();
+                return Some((queued, state.items.len()));
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.not_empty.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Lets workers exit once the queue is empty.
+    fn close(&self) {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).closed = true;
+        self.not_empty.notify_all();
+    }
+}
+
+/// Accepts requests into the queue until the server shuts down.
+pub(super) fn accept_loop(context: &WorkerContext, queue: &AcceptQueue) {
+    while !context.shared.is_shutting_down() {
+        let mut request = match transport::new_request(context) {
+            Some(request) => Box::new(request),
+            None => break,
+        };
+        if !request.accept() {
+            break;
+        }
+        let depth = queue.push(Queued { request, since: Instant::now() });
+        if let Some(ref metrics) = context.metrics {
+            metrics.set_queue_depth(depth);
+        }
+    }
+    queue.close();
+}
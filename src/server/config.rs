@@ -0,0 +1,671 @@
+//! Configuration of the high-level server.
+//!
+//! Settings can be loaded from a TOML file (with the `config` feature) and
+//! from environment variables. Both use the same names: the environment
+//! variable for a setting is `FCGI_` followed by its name in upper case,
+//! with the `.` of nested settings replaced by `_`:
+//!
+//! | Setting                        | Environment variable                 |
+//! |--------------------------------|--------------------------------------|
+//! | `listen`                       | `FCGI_LISTEN`                        |
+//! | `listen_backlog`               | `FCGI_LISTEN_BACKLOG`                |
+//! | `protocol`                     | `FCGI_PROTOCOL`                      |
+//! | `ajp_secret`                   | `FCGI_AJP_SECRET`                    |
+//! | `extra_listen`                 | `FCGI_EXTRA_LISTEN`                  |
+//! | `unix_socket.mode`             | `FCGI_UNIX_SOCKET_MODE`              |
+//! | `unix_socket.owner`            | `FCGI_UNIX_SOCKET_OWNER`             |
+//! | `unix_socket.group`            | `FCGI_UNIX_SOCKET_GROUP`             |
+//! | `unix_socket.remove_stale`     | `FCGI_UNIX_SOCKET_REMOVE_STALE`      |
+//! | `unix_socket.unlink_on_shutdown` | `FCGI_UNIX_SOCKET_UNLINK_ON_SHUTDOWN` |
+//! | `user`, `group`                | `FCGI_USER`, `FCGI_GROUP`            |
+//! | `daemonize`, `error_log`       | `FCGI_DAEMONIZE`, `FCGI_ERROR_LOG`   |
+//! | `pid_file`                     | `FCGI_PID_FILE`                      |
+//! | `chroot`, `working_dir`        | `FCGI_CHROOT`, `FCGI_WORKING_DIR`    |
+//! | `workers`, `max_workers`       | `FCGI_WORKERS`, `FCGI_MAX_WORKERS`   |
+//! | `worker_idle_timeout`          | `FCGI_WORKER_IDLE_TIMEOUT`           |
+//! | `processes`                    | `FCGI_PROCESSES`                     |
+//! | `accept_queue`                 | `FCGI_ACCEPT_QUEUE`                  |
+//! | `accept_batch`                 | `FCGI_ACCEPT_BATCH`                  |
+//! | `overload_accept_delay`        | `FCGI_OVERLOAD_ACCEPT_DELAY`         |
+//! | `drain_timeout`                | `FCGI_DRAIN_TIMEOUT`                 |
+//! | `exit_on_drain_timeout`        | `FCGI_EXIT_ON_DRAIN_TIMEOUT`         |
+//! | `graceful_upgrade`             | `FCGI_GRACEFUL_UPGRADE`              |
+//! | `max_conns`, `max_reqs`        | `FCGI_MAX_CONNS`, `FCGI_MAX_REQS`    |
+//! | `mpxs_conns`                   | `FCGI_MPXS_CONNS`                    |
+//! | `event_loop`                   | `FCGI_EVENT_LOOP`                    |
+//! | `trace_records`                | `FCGI_TRACE_RECORDS`                 |
+//! | `record_sessions`              | `FCGI_RECORD_SESSIONS`               |
+//! | `proxy_protocol`               | `FCGI_PROXY_PROTOCOL`                |
+//! | `output_buffer`                | `FCGI_OUTPUT_BUFFER`                 |
+//! | `record_size`                  | `FCGI_RECORD_SIZE`                   |
+//! | `record_padding`               | `FCGI_RECORD_PADDING`                |
+//! | `tls_cert`, `tls_key`          | `FCGI_TLS_CERT`, `FCGI_TLS_KEY`      |
+//! | `tls_client_ca`                | `FCGI_TLS_CLIENT_CA`                 |
+//! | `max_concurrent_requests`      | `FCGI_MAX_CONCURRENT_REQUESTS`       |
+//! | `max_queued_requests`          | `FCGI_MAX_QUEUED_REQUESTS`           |
+//! | `queue_timeout`                | `FCGI_QUEUE_TIMEOUT`                 |
+//! | `request_timeout`              | `FCGI_REQUEST_TIMEOUT`               |
+//! | `slow_request_threshold`       | `FCGI_SLOW_REQUEST_THRESHOLD`        |
+//! | `static_mounts`                | `FCGI_STATIC_MOUNTS`                 |
+//! | `mime_types`                   | `FCGI_MIME_TYPES`                    |
+//!
+//! Durations are given in seconds or with a unit of `ms`, `s`, `m` or `h`,
+//! e.g. `"30s"`. Socket modes are octal, booleans `true` or `false`, users
+//! and groups names or numeric ids. Static mounts are written as a table in
+//! TOML and as a comma-separated list in the environment:
+//!
+//! ```text
+//! [static_mounts]
+//! "/assets" = "/srv/app/assets"
+//!
+//! FCGI_STATIC_MOUNTS=/assets=/srv/app/assets,/media=/srv/app/media
+//! ```
+//!
+//! `mime_types` maps file extensions to the Content-Type the static mounts
+//! serve them with, in the same forms:
+//!
+//! ```text
+//! [mime_types]
+//! wasm = "application/wasm"
+//!
+//! FCGI_MIME_TYPES=wasm=application/wasm,log=text/plain
+//! ```
+//!
+//! `extra_listen` is an array of addresses in TOML and a comma-separated
+//! list in the environment.
+
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[cfg(feature = "config")]
+use std::fs;
+#[cfg(feature = "config")]
+use std::path::Path;
+
+#[cfg(feature = "config")]
+use toml;
+
+use crate::daemon::{lookup_group, lookup_user};
+use crate::listen::{ListenAddr, UnixSocketOptions};
+use crate::protocol;
+#[cfg(feature = "pure")]
+use crate::native::{Capabilities, OutputOptions};
+#[cfg(feature = "tls")]
+use rustls;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+#[cfg(feature = "tls")]
+use crate::tls;
+
+/// The names of all settings, in the order they are applied.
+const SETTINGS: &[&str] = &[
+    "listen",
+    "listen_backlog",
+    "protocol",
+    "ajp_secret",
+    "extra_listen",
+    "unix_socket.mode",
+    "unix_socket.owner",
+    "unix_socket.group",
+    "unix_socket.remove_stale",
+    "unix_socket.unlink_on_shutdown",
+    "user",
+    "group",
+    "daemonize",
+    "error_log",
+    "pid_file",
+    "chroot",
+    "working_dir",
+    "workers",
+    "max_workers",
+    "worker_idle_timeout",
+    "processes",
+    "accept_queue",
+    "accept_batch",
+    "overload_accept_delay",
+    "drain_timeout",
+    "exit_on_drain_timeout",
+    "graceful_upgrade",
+    "max_conns",
+    "max_reqs",
+    "mpxs_conns",
+    "event_loop",
+    "trace_records",
+    "record_sessions",
+    "proxy_protocol",
+    "output_buffer",
+    "record_size",
+    "record_padding",
+    "tls_cert",
+    "tls_key",
+    "tls_client_ca",
+    "max_concurrent_requests",
+    "max_queued_requests",
+    "queue_timeout",
+    "request_timeout",
+    "slow_request_threshold",
+    "static_mounts",
+    "mime_types",
+];
+
+/// The protocols the high-level server can speak with the web server, set
+/// as `fastcgi`, `scgi`, `uwsgi`, `ajp` or `http`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    /// FastCGI, with libfcgi or the native transport.
+    FastCgi,
+    /// SCGI, see the `scgi` module. Settings of the native transport, such
+    /// as TLS or `extra_listen`, do not apply.
+    Scgi,
+    /// uwsgi, see the `uwsgi` module. Like with SCGI, settings of the
+    /// native transport do not apply.
+    Uwsgi,
+    /// AJP 1.3, see the `ajp` module, with the secret from `ajp_secret`.
+    /// Like with SCGI, settings of the native transport do not apply.
+    Ajp,
+    /// Plain HTTP/1.1 straight from browsers, see the `dev_server` module.
+    /// For local development only.
+    Http,
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Protocol, String> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "fastcgi" | "fcgi" => Ok(Protocol::FastCgi),
+            "scgi" => Ok(Protocol::Scgi),
+            "uwsgi" => Ok(Protocol::Uwsgi),
+            "ajp" | "ajp13" => Ok(Protocol::Ajp),
+            "http" => Ok(Protocol::Http),
+            _ => Err(format!("unknown protocol `{}`", s.trim())),
+        }
+    }
+}
+
+/// Tunable settings of the high-level server.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// Address the server binds itself, taking the place of spawn-fcgi, or
+    /// an inherited socket such as `fd:0` or `systemd`. The socket is moved
+    /// to fd 0. If None, the server uses the socket it was started with.
+    pub listen: Option<ListenAddr>,
+    /// Length of the queue of pending connections, set on the listen
+    /// socket whether the server bound it or inherited it. Left as is if
+    /// None.
+    pub listen_backlog: Option<usize>,
+    /// The protocol the web server speaks on the listen socket.
+    pub protocol: Protocol,
+    /// The secret the front end must send with AJP requests, as set with
+    /// `secret=` in Apache's `ProxyPass`. Without one, any request is
+    /// accepted.
+    pub ajp_secret: Option<String>,
+    /// Further addresses the native transport accepts connections on, with
+    /// one thread watching all sockets. They are bound like `listen` and
+    /// share its Unix socket options; a graceful upgrade does not pass them
+    /// on to the new process.
+    pub extra_listen: Vec<ListenAddr>,
+    /// Mode, owner and cleanup of the socket file if `listen` is a Unix
+    /// socket.
+    pub unix_socket: UnixSocketOptions,
+    /// User to switch to after binding the socket, by name or id.
+    pub user: Option<String>,
+    /// Group to switch to after binding the socket, by name or id. Defaults
+    /// to the primary group of `user`.
+    pub group: Option<String>,
+    /// Moves the server into the background after binding the socket, see
+    /// `daemon::daemonize`.
+    pub daemonize: bool,
+    /// File receiving stdout and stderr of a daemonized server.
+    pub error_log: Option<PathBuf>,
+    /// File to write the process id to. It is locked while the server runs
+    /// and removed on shutdown, unless a chroot has made it unreachable.
+    pub pid_file: Option<PathBuf>,
+    /// Directory to chroot into after binding the socket. Paths used by
+    /// handlers are then relative to this jail, and a Unix socket outside
+    /// of it is not removed on shutdown.
+    pub chroot: Option<PathBuf>,
+    /// Working directory to change to, inside the chroot if one is set.
+    pub working_dir: Option<PathBuf>,
+    /// Number of worker threads accepting and handling requests. With
+    /// adaptive scaling this is the minimum size of the pool.
+    pub workers: usize,
+    /// Enables adaptive scaling if larger than `workers`: a worker is added
+    /// whenever all workers are busy, up to this many.
+    pub max_workers: Option<usize>,
+    /// With adaptive scaling, workers above the minimum retire once the
+    /// pool has not been saturated for this long. Workers only check this
+    /// after finishing a request, so an idle pool keeps its size.
+    pub worker_idle_timeout: Duration,
+    /// Number of worker processes. With more than one the server forks
+    /// that many children which each run the thread pool, and the parent
+    /// only supervises them, restarting children which exit. Metrics and
+    /// pool status are then tracked per child process.
+    pub processes: usize,
+    /// With a capacity, one thread accepts requests and hands them to the
+    /// workers through a queue of that size, instead of every worker
+    /// accepting by itself. The queue depth and wait time are reported to
+    /// the metrics. Accept pacing does not apply then.
+    pub accept_queue: Option<usize>,
+    /// A worker yields to other threads after handling this many requests
+    /// in a row, 0 for no limit.
+    pub accept_batch: usize,
+    /// When all workers are busy and the pool cannot grow, a worker waits
+    /// this long after finishing a request before accepting the next one.
+    /// Requests in flight get more of the CPU, and in prefork mode less
+    /// loaded processes pick up the pending connections.
+    pub overload_accept_delay: Option<Duration>,
+    /// How long shutdown waits for in-flight requests to complete.
+    pub drain_timeout: Duration,
+    /// Exits the process with status 1 if requests are still running after
+    /// the drain timeout. Each of them is logged with its worker thread
+    /// and how long it has been running.
+    pub exit_on_drain_timeout: bool,
+    /// Replaces the process with a new start of its executable on SIGUSR2,
+    /// without closing the listen socket: once the new process is ready,
+    /// this one drains and exits. See `ServerBuilder::graceful_upgrade`.
+    pub graceful_upgrade: bool,
+    /// `FCGI_MAX_CONNS` reported to web servers querying the native
+    /// transport with `FCGI_GET_VALUES`. Defaults to the size of the pool,
+    /// the workers times the processes. libfcgi reports its own values.
+    pub max_conns: Option<usize>,
+    /// `FCGI_MAX_REQS` reported to the web server, like `max_conns`.
+    pub max_reqs: Option<usize>,
+    /// `FCGI_MPXS_CONNS` reported to the web server. The native transport
+    /// then also handles several requests on one connection at the same
+    /// time, instead of rejecting all but the first.
+    pub mpxs_conns: bool,
+    /// Reads all connections of the native transport in one thread with an
+    /// event loop instead of a thread per connection, for many mostly idle
+    /// keep-alive connections. Needs the `evented` feature.
+    pub event_loop: bool,
+    /// Writes every record the native transport receives and sends to
+    /// stderr, for debugging the web server integration.
+    pub trace_records: bool,
+    /// Directory the native transport saves the bytes received on each
+    /// connection to, for replaying them with the `replay` module.
+    pub record_sessions: Option<PathBuf>,
+    /// Makes the native transport read a PROXY protocol header at the start
+    /// of every connection, from a TCP load balancer in front of the
+    /// application, see the `proxy` module.
+    pub proxy_protocol: bool,
+    /// Response output the native transport collects before sending it,
+    /// unless the handler flushes earlier.
+    pub output_buffer: usize,
+    /// The largest content of an output record of the native transport,
+    /// from 1 to 65535 bytes.
+    pub record_size: usize,
+    /// Whether the native transport pads records to a multiple of 8 bytes.
+    pub record_padding: bool,
+    /// PEM file with the certificate chain for TLS on a TCP listener of the
+    /// native transport, which needs the `tls` feature, see the `tls`
+    /// module.
+    pub tls_cert: Option<PathBuf>,
+    /// PEM file with the private key of `tls_cert`.
+    pub tls_key: Option<PathBuf>,
+    /// PEM file with the CAs a web server's client certificate must be
+    /// signed by. Without it, no client certificate is asked for.
+    pub tls_client_ca: Option<PathBuf>,
+    /// Maximum number of requests handled at the same time, unlimited
+    /// (bounded only by the workers) if None.
+    pub max_concurrent_requests: Option<usize>,
+    /// Requests allowed to wait for a slot when the concurrency limit is
+    /// reached; further requests are answered with 503.
+    pub max_queued_requests: usize,
+    /// How long a queued request waits before it is answered with 503.
+    pub queue_timeout: Duration,
+    /// Time budget for handling a request, see `middleware::Deadline`.
+    pub request_timeout: Option<Duration>,
+    /// Requests taking at least this long are logged to the error stream,
+    /// see `middleware::SlowLog`.
+    pub slow_request_threshold: Option<Duration>,
+    /// Directories served at URL prefixes before the handler is called,
+    /// see `StaticMounts`.
+    pub static_mounts: Vec<(String, PathBuf)>,
+    /// Content types by file extension for the static mounts, taking
+    /// precedence over the built-in ones, see `MimeTypes`.
+    pub mime_types: Vec<(String, String)>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            listen: None,
+            listen_backlog: None,
+            protocol: Protocol::FastCgi,
+            ajp_secret: None,
+            extra_listen: Vec::new(),
+            unix_socket: UnixSocketOptions::default(),
+            user: None,
+            group: None,
+            daemonize: false,
+            error_log: None,
+            pid_file: None,
+            chroot: None,
+            working_dir: None,
+            workers: 8,
+            max_workers: None,
+            worker_idle_timeout: Duration::from_secs(60),
+            processes: 1,
+            accept_queue: None,
+            accept_batch: 0,
+            overload_accept_delay: None,
+            drain_timeout: Duration::from_secs(30),
+            exit_on_drain_timeout: false,
+            graceful_upgrade: false,
+            max_conns: None,
+            max_reqs: None,
+            mpxs_conns: false,
+            event_loop: false,
+            trace_records: false,
+            record_sessions: None,
+            proxy_protocol: false,
+            output_buffer: protocol::MAX_ALIGNED_CONTENT_LEN,
+            record_size: protocol::MAX_ALIGNED_CONTENT_LEN,
+            record_padding: true,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            max_concurrent_requests: None,
+            max_queued_requests: 0,
+            queue_timeout: Duration::from_secs(5),
+            request_timeout: None,
+            slow_request_threshold: None,
+            static_mounts: Vec::new(),
+            mime_types: Vec::new(),
+        }
+    }
+}
+
+/// Error loading a `ServerConfig`.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The configuration file could not be read.
+    Io(io::Error),
+    /// The configuration file is not valid TOML.
+    Parse(String),
+    /// A setting is unknown or has an invalid value.
+    Invalid {
+        /// The setting, e.g. `unix_socket.mode`.
+        key: String,
+        /// What is wrong with it.
+        message: String,
+    },
+}
+
+impl ConfigError {
+    fn invalid<M: fmt::Display>(key: &str, message: M) -> ConfigError {
+        ConfigError::Invalid { key: String::from(key), message: message.to_string() }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref e) => write!(f, "failed to read configuration: {}", e),
+            ConfigError::Parse(ref msg) => write!(f, "invalid configuration: {}", msg),
+            ConfigError::Invalid { ref key, ref message } => write!(f, "invalid setting {}: {}", key, message),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ConfigError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a duration given as seconds or with a unit of ms, s, m or h.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(value.len());
+    let number: f64 = value[..split].parse().ok()?;
+    let scale = match value[split..].trim() {
+        "ms" => 0.001,
+        "" | "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => return None,
+    };
+    Duration::try_from_secs_f64(number * scale).ok()
+}
+
+fn parse<T: FromStr>(key: &str, value: &str, expected: &str) -> Result<T, ConfigError> {
+    value.trim().parse().map_err(|_| ConfigError::invalid(key, format!("expected {}", expected)))
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool, ConfigError> {
+    parse(key, value, "true or false")
+}
+
+fn parse_usize(key: &str, value: &str) -> Result<usize, ConfigError> {
+    parse(key, value, "a non-negative integer")
+}
+
+fn parse_timeout(key: &str, value: &str) -> Result<Duration, ConfigError> {
+    parse_duration(value).ok_or_else(|| ConfigError::invalid(key, "expected a duration"))
+}
+
+impl ServerConfig {
+    /// Creates a configuration from the defaults overlaid with the FCGI_*
+    /// environment variables, see the module documentation.
+    pub fn from_env() -> Result<ServerConfig, ConfigError> {
+        ServerConfig::default().overlay_env()
+    }
+
+    /// Overrides settings with the FCGI_* environment variables which are
+    /// set, e.g. to adjust a configuration loaded from a file.
+    pub fn overlay_env(mut self) -> Result<ServerConfig, ConfigError> {
+        for key in SETTINGS {
+            let var = format!("FCGI_{}", key.to_ascii_uppercase().replace('.', "_"));
+            if let Ok(value) = env::var(&var) {
+                self.set(key, &value).map_err(|e| match e {
+                    ConfigError::Invalid { message, .. } => ConfigError::Invalid { key: var, message },
+                    e => e,
+                })?;
+            }
+        }
+        Ok(self)
+    }
+
+    /// The values the native transport reports to the web server, see
+    /// `max_conns`.
+    #[cfg(feature = "pure")]
+    pub fn capabilities(&self) -> Capabilities {
+        let pool = self.max_workers.unwrap_or(0).max(self.workers) * self.processes.max(1);
+        Capabilities {
+            max_conns: self.max_conns.unwrap_or(pool),
+            max_reqs: self.max_reqs.unwrap_or(pool),
+            mpxs_conns: self.mpxs_conns,
+        }
+    }
+
+    /// How the native transport cuts output into records, see
+    /// `record_size`.
+    #[cfg(feature = "pure")]
+    pub fn output_options(&self) -> OutputOptions {
+        OutputOptions {
+            buffer_size: self.output_buffer,
+            record_size: self.record_size.clamp(1, protocol::MAX_CONTENT_LEN),
+            padding: self.record_padding,
+        }
+    }
+
+    /// Loads the TLS configuration of `tls_cert` and `tls_key`, None if no
+    /// certificate is set.
+    #[cfg(feature = "tls")]
+    pub fn tls_config(&self) -> io::Result<Option<Arc<rustls::ServerConfig>>> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => tls::server_config(cert, key, self.tls_client_ca.as_deref()).map(Some),
+            (None, None) => Ok(None),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "tls_cert and tls_key must be set together")),
+        }
+    }
+
+    /// Changes a setting given by name, parsing the value from a string.
+    /// `static_mounts.PREFIX` adds a single static mount, `mime_types.EXT`
+    /// a single content type.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        let path = || Some(PathBuf::from(value));
+        match key {
+            "listen" => self.listen = Some(value.parse().map_err(|e| ConfigError::invalid(key, e))?),
+            "listen_backlog" => self.listen_backlog = Some(parse_usize(key, value)?),
+            "protocol" => self.protocol = value.parse().map_err(|e| ConfigError::invalid(key, e))?,
+            "ajp_secret" => self.ajp_secret = Some(String::from(value)),
+            "extra_listen" => {
+                self.extra_listen = value.split(',')
+                    .filter(|addr| !addr.trim().is_empty())
+                    .map(|addr| addr.trim().parse().map_err(|e| ConfigError::invalid(key, e)))
+                    .collect::<Result<_, _>>()?;
+            }
+            "unix_socket.mode" => {
+                let mode = u32::from_str_radix(value.trim(), 8)
+                    .map_err(|_| ConfigError::invalid(key, "expected an octal mode"))?;
+                self.unix_socket.mode = Some(mode);
+            }
+            "unix_socket.owner" => {
+                let (uid, _) = lookup_user(value.trim()).map_err(|e| ConfigError::invalid(key, e))?;
+                self.unix_socket.uid = Some(uid);
+            }
+            "unix_socket.group" => {
+                let gid = lookup_group(value.trim()).map_err(|e| ConfigError::invalid(key, e))?;
+                self.unix_socket.gid = Some(gid);
+            }
+            "unix_socket.remove_stale" => self.unix_socket.remove_stale = parse_bool(key, value)?,
+            "unix_socket.unlink_on_shutdown" => self.unix_socket.unlink_on_shutdown = parse_bool(key, value)?,
+            "user" => self.user = Some(String::from(value)),
+            "group" => self.group = Some(String::from(value)),
+            "daemonize" => self.daemonize = parse_bool(key, value)?,
+            "error_log" => self.error_log = path(),
+            "pid_file" => self.pid_file = path(),
+            "chroot" => self.chroot = path(),
+            "working_dir" => self.working_dir = path(),
+            "workers" => self.workers = parse_usize(key, value)?,
+            "max_workers" => self.max_workers = Some(parse_usize(key, value)?),
+            "worker_idle_timeout" => self.worker_idle_timeout = parse_timeout(key, value)?,
+            "processes" => self.processes = parse_usize(key, value)?,
+            "accept_queue" => self.accept_queue = Some(parse_usize(key, value)?),
+            "accept_batch" => self.accept_batch = parse_usize(key, value)?,
+            "overload_accept_delay" => self.overload_accept_delay = Some(parse_timeout(key, value)?),
+            "drain_timeout" => self.drain_timeout = parse_timeout(key, value)?,
+            "exit_on_drain_timeout" => self.exit_on_drain_timeout = parse_bool(key, value)?,
+            "graceful_upgrade" => self.graceful_upgrade = parse_bool(key, value)?,
+            "max_conns" => self.max_conns = Some(parse_usize(key, value)?),
+            "max_reqs" => self.max_reqs = Some(parse_usize(key, value)?),
+            "mpxs_conns" => self.mpxs_conns = parse_bool(key, value)?,
+            "event_loop" => self.event_loop = parse_bool(key, value)?,
+            "trace_records" => self.trace_records = parse_bool(key, value)?,
+            "record_sessions" => self.record_sessions = path(),
+            "proxy_protocol" => self.proxy_protocol = parse_bool(key, value)?,
+            "output_buffer" => self.output_buffer = parse_usize(key, value)?,
+            "record_size" => {
+                let size = parse_usize(key, value)?;
+                if size == 0 || size > protocol::MAX_CONTENT_LEN {
+                    return Err(ConfigError::invalid(key, "expected a size from 1 to 65535"));
+                }
+                self.record_size = size;
+            }
+            "record_padding" => self.record_padding = parse_bool(key, value)?,
+            "tls_cert" => self.tls_cert = path(),
+            "tls_key" => self.tls_key = path(),
+            "tls_client_ca" => self.tls_client_ca = path(),
+            "max_concurrent_requests" => self.max_concurrent_requests = Some(parse_usize(key, value)?),
+            "max_queued_requests" => self.max_queued_requests = parse_usize(key, value)?,
+            "queue_timeout" => self.queue_timeout = parse_timeout(key, value)?,
+            "request_timeout" => self.request_timeout = Some(parse_timeout(key, value)?),
+            "slow_request_threshold" => self.slow_request_threshold = Some(parse_timeout(key, value)?),
+            "static_mounts" => {
+                self.static_mounts.clear();
+                for mount in value.split(',').filter(|m| !m.trim().is_empty()) {
+                    let (prefix, root) = mount.split_once('=')
+                        .ok_or_else(|| ConfigError::invalid(key, "expected PREFIX=DIRECTORY"))?;
+                    self.static_mounts.push((String::from(prefix.trim()), PathBuf::from(root.trim())));
+                }
+            }
+            "mime_types" => {
+                self.mime_types.clear();
+                for mapping in value.split(',').filter(|m| !m.trim().is_empty()) {
+                    let (ext, content_type) = mapping.split_once('=')
+                        .ok_or_else(|| ConfigError::invalid(key, "expected EXTENSION=TYPE"))?;
+                    self.mime_types.push((String::from(ext.trim()), String::from(content_type.trim())));
+                }
+            }
+            _ => if let Some(prefix) = key.strip_prefix("static_mounts.") {
+                self.static_mounts.push((String::from(prefix), PathBuf::from(value)));
+            } else if let Some(ext) = key.strip_prefix("mime_types.") {
+                self.mime_types.push((String::from(ext), String::from(value)));
+            } else {
+                return Err(ConfigError::invalid(key, "unknown setting"));
+            },
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "config")]
+impl ServerConfig {
+    /// Loads the configuration from a TOML file. Settings missing from the
+    /// file keep their default values. Keys are the setting names from the
+    /// module documentation, nested ones in tables:
+    ///
+    /// ```toml
+    /// listen = "unix:/run/app.sock"
+    /// workers = 8
+    /// max_workers = 32
+    /// drain_timeout = "30s"
+    /// max_concurrent_requests = 100
+    /// user = "www-data"
+    /// pid_file = "/run/app.pid"
+    ///
+    /// [unix_socket]
+    /// mode = 0o660
+    /// group = "www-data"
+    /// ```
+    ///
+    /// Unknown keys are rejected to catch typos.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<ServerConfig, ConfigError> {
+        let text = fs::read_to_string(path).map_err(ConfigError::Io)?;
+        ServerConfig::from_toml(&text)
+    }
+
+    /// Parses the configuration from a TOML document.
+    pub fn from_toml(text: &str) -> Result<ServerConfig, ConfigError> {
+        let table: toml::Table = text.parse().map_err(|e: toml::de::Error| ConfigError::Parse(e.to_string()))?;
+        let mut config = ServerConfig::default();
+        config.apply_toml("", &table)?;
+        Ok(config)
+    }
+
+    fn apply_toml(&mut self, prefix: &str, table: &toml::Table) -> Result<(), ConfigError> {
+        for (name, value) in table {
+            let key = format!("{}{}", prefix, name);
+            let value = match *value {
+                toml::Value::Table(ref table) if prefix.is_empty() => {
+                    self.apply_toml(&format!("{}.", key), table)?;
+                    continue;
+                }
+                toml::Value::String(ref value) => value.clone(),
+                // Modes are written as octal literals like 0o660.
+                toml::Value::Integer(mode) if key == "unix_socket.mode" => format!("{:o}", mode),
+                toml::Value::Integer(value) => value.to_string(),
+                toml::Value::Float(value) => value.to_string(),
+                toml::Value::Boolean(value) => value.to_string(),
+                toml::Value::Array(ref values) if key == "extra_listen" => {
+                    let addrs: Option<Vec<&str>> = values.iter().map(toml::Value::as_str).collect();
+                    addrs.ok_or_else(|| ConfigError::invalid(&key, "expected addresses"))?.join(",")
+                }
+                _ => return Err(ConfigError::invalid(&key, "unsupported value")),
+            };
+            self.set(&key, &value)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,39 @@
+//! Callbacks run when a worker starts and when a request starts and
+//! finishes, see `ServerBuilder::on_worker_start`, `on_accept` and
+//! `on_finish`.
+
+use std::time::Duration;
+
+use crate::exchange::Exchange;
+
+pub(super) type WorkerStart = dyn Fn(usize) + Send + Sync;
+pub(super) type Accept = dyn Fn(&mut Exchange) + Send + Sync;
+pub(super) type Finish = dyn Fn(&Exchange, Duration) + Send + Sync;
+
+/// The registered callbacks, run in the order they were added.
+#[derive(Default)]
+pub(super) struct Hooks {
+    pub(super) worker_start: Vec<Box<WorkerStart>>,
+    pub(super) accept: Vec<Box<Accept>>,
+    pub(super) finish: Vec<Box<Finish>>,
+}
+
+impl Hooks {
+    pub(super) fn worker_started(&self, id: usize) {
+        for hook in &self.worker_start {
+            hook(id);
+        }
+    }
+
+    pub(super) fn accepted(&self, exchange: &mut Exchange) {
+        for hook in &self.accept {
+            hook(exchange);
+        }
+    }
+
+    pub(super) fn finished(&self, exchange: &Exchange, elapsed: Duration) {
+        for hook in &self.finish {
+            hook(exchange, elapsed);
+        }
+    }
+}
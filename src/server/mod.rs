@@ -0,0 +1,1179 @@
+//! A multi-threaded FCGI server built on top of the `Request` trait.
+//!
+//! The server runs a fixed pool of worker threads which accept requests
+//! from the listen socket and pass them to a `Handler`. A `ShutdownHandle`
+//! stops accepting new requests; requests already being handled are allowed
+//! to complete until the configured drain timeout expires. A panicking
+//! handler only fails its own request; the worker keeps serving.
+//!
+//! In prefork mode the server forks worker processes which share the listen
+//! socket, each running its own thread pool, see `ServerConfig::processes`.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle, ThreadId};
+use std::time::{Duration, Instant};
+
+use libc;
+
+use crate::daemon::{self, Credentials, PidFile};
+use crate::error_log::ErrorLog;
+use crate::error_pages::ErrorPages;
+use crate::exchange::Exchange;
+use crate::handler::{self, Handler};
+use crate::health::HealthCheck;
+use crate::headers::Headers;
+use crate::listen::{self, InvalidListenAddr, ListenAddr, UnixSocketOptions};
+use crate::metrics::Metrics;
+use crate::middleware::{ConcurrencyLimit, Deadline, LoadShedder, Middleware, Next, SlowLog, Stack};
+use crate::mime::MimeTypes;
+#[cfg(feature = "pure")]
+use crate::native::Listener;
+use crate::static_files::StaticMounts;
+use crate::systemd::{self, Notifier};
+use crate::cgi::CgiRequest;
+use crate::{initialize_fcgi, Request};
+
+use self::accept_queue::AcceptQueue;
+use self::hooks::Hooks;
+use self::reload::{Loader, Reloader};
+use self::transport::WorkerRequest;
+use self::upgrade::Successor;
+
+mod accept_queue;
+mod config;
+mod hooks;
+mod prefork;
+mod reload;
+mod transport;
+mod upgrade;
+
+pub use self::config::{ConfigError, Protocol, ServerConfig};
+
+/// Builder for a `Server`.
+pub struct ServerBuilder {
+    config: ServerConfig,
+    listen_fd: RawFd,
+    middleware: Vec<Box<dyn Middleware>>,
+    metrics: Option<Metrics>,
+    health: Option<HealthCheck>,
+    error_pages: Option<Arc<ErrorPages>>,
+    error_log: Option<Arc<ErrorLog>>,
+    reload: Option<Box<Loader>>,
+    load_shedder: Option<LoadShedder>,
+    states: Vec<Box<dyn Middleware>>,
+    hooks: Hooks,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+}
+
+impl ServerBuilder {
+    /// Creates a builder with the default configuration, listening on
+    /// the socket passed by systemd socket activation if there is one, and
+    /// on fd 0 as set up by spawn-fcgi or the web server otherwise.
+    pub fn new() -> ServerBuilder {
+        ServerBuilder {
+            config: ServerConfig::default(),
+            listen_fd: upgrade::inherited_listen_fd()
+                .or_else(|| systemd::listen_fds(false).first().cloned())
+                .unwrap_or(0),
+            middleware: Vec::new(),
+            metrics: None,
+            health: None,
+            error_pages: None,
+            error_log: None,
+            reload: None,
+            load_shedder: None,
+            states: Vec::new(),
+            hooks: Hooks::default(),
+        }
+    }
+
+    /// Replaces the whole configuration.
+    pub fn config(mut self, config: ServerConfig) -> ServerBuilder {
+        self.config = config;
+        self
+    }
+
+    /// Loads the configuration from a TOML file, overlaid with the FCGI_*
+    /// environment variables, and reloads it on SIGHUP, see `reload_with`.
+    #[cfg(feature = "config")]
+    pub fn config_file<P: Into<PathBuf>>(mut self, path: P) -> Result<ServerBuilder, ConfigError> {
+        let path = path.into();
+        self.config = ServerConfig::from_file(&path)?.overlay_env()?;
+        Ok(self.reload_with(move || ServerConfig::from_file(&path)?.overlay_env()))
+    }
+
+    /// Reloads the configuration with `load` when the process receives
+    /// SIGHUP. The concurrency limit, request timeout, slow request
+    /// threshold and static mounts are taken from the new configuration;
+    /// other settings only take effect on restart. If loading fails, the
+    /// error is logged and the settings are kept.
+    pub fn reload_with<F>(mut self, load: F) -> ServerBuilder
+        where F: Fn() -> Result<ServerConfig, ConfigError> + Send + Sync + 'static
+    {
+        self.reload = Some(Box::new(load));
+        self
+    }
+
+    /// Sets the number of worker threads.
+    pub fn workers(mut self, workers: usize) -> ServerBuilder {
+        self.config.workers = workers;
+        self
+    }
+
+    /// Lets the pool grow from `min` up to `max` workers under load and
+    /// shrink back after the idle timeout.
+    pub fn scale_workers(mut self, min: usize, max: usize, idle_timeout: Duration) -> ServerBuilder {
+        self.config.workers = min;
+        self.config.max_workers = Some(max);
+        self.config.worker_idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Forks the given number of worker processes sharing the listen
+    /// socket, see `ServerConfig::processes`.
+    pub fn processes(mut self, processes: usize) -> ServerBuilder {
+        self.config.processes = processes;
+        self
+    }
+
+    /// Accepts requests on a single thread which hands them to the workers
+    /// through a queue of the given capacity, see
+    /// `ServerConfig::accept_queue`.
+    pub fn accept_queue(mut self, capacity: usize) -> ServerBuilder {
+        self.config.accept_queue = Some(capacity);
+        self
+    }
+
+    /// Sets the length of the listen socket's queue of pending connections,
+    /// see `ServerConfig::listen_backlog`.
+    pub fn listen_backlog(mut self, backlog: usize) -> ServerBuilder {
+        self.config.listen_backlog = Some(backlog);
+        self
+    }
+
+    /// Paces accepting: a worker yields after `batch` requests in a row (0
+    /// for no limit) and, when the pool is saturated, waits
+    /// `overload_delay` before accepting again, see
+    /// `ServerConfig::overload_accept_delay`.
+    pub fn accept_pacing(mut self, batch: usize, overload_delay: Option<Duration>) -> ServerBuilder {
+        self.config.accept_batch = batch;
+        self.config.overload_accept_delay = overload_delay;
+        self
+    }
+
+    /// Sets how long shutdown waits for in-flight requests.
+    pub fn drain_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.config.drain_timeout = timeout;
+        self
+    }
+
+    /// Exits the process with status 1 when requests are still running
+    /// after the drain timeout, instead of returning a `TimedOut` error
+    /// from `Server::run`. The stuck requests are reported either way.
+    pub fn exit_on_drain_timeout(mut self, exit: bool) -> ServerBuilder {
+        self.config.exit_on_drain_timeout = exit;
+        self
+    }
+
+    /// Enables zero-downtime upgrades: on SIGUSR2 the server starts its
+    /// executable again with the same arguments, passing on the listen
+    /// socket, and drains once the new process is ready. Deploy by
+    /// replacing the binary, then sending SIGUSR2. The new process writes
+    /// the PID file; under systemd it is announced as the main process,
+    /// which needs `NotifyAccess=all`.
+    pub fn graceful_upgrade(mut self, enabled: bool) -> ServerBuilder {
+        self.config.graceful_upgrade = enabled;
+        self
+    }
+
+    /// Limits the number of requests handled at the same time. Up to
+    /// `max_queued` further requests wait for a slot, the rest are answered
+    /// with 503.
+    pub fn max_concurrent_requests(mut self, max: usize, max_queued: usize) -> ServerBuilder {
+        self.config.max_concurrent_requests = Some(max);
+        self.config.max_queued_requests = max_queued;
+        self
+    }
+
+    /// Binds the given address when the server starts instead of using
+    /// an inherited socket.
+    pub fn listen(mut self, addr: ListenAddr) -> ServerBuilder {
+        self.config.listen = Some(addr);
+        self
+    }
+
+    /// Speaks the given protocol with the web server instead of FastCGI,
+    /// see `ServerConfig::protocol`.
+    pub fn protocol(mut self, protocol: Protocol) -> ServerBuilder {
+        self.config.protocol = protocol;
+        self
+    }
+
+    /// Also accepts connections on the address, with the `pure` feature,
+    /// see `ServerConfig::extra_listen`.
+    pub fn extra_listen(mut self, addr: ListenAddr) -> ServerBuilder {
+        self.config.extra_listen.push(addr);
+        self
+    }
+
+    /// Binds the address given by a `--listen` command line argument or,
+    /// without one, the FCGI_LISTEN environment variable. Keeps the
+    /// inherited socket if neither is set.
+    pub fn listen_from_args_or_env(mut self) -> Result<ServerBuilder, InvalidListenAddr> {
+        if let Some(addr) = ListenAddr::from_args().or_else(ListenAddr::from_env) {
+            self.config.listen = Some(addr?);
+        }
+        Ok(self)
+    }
+
+    /// Sets the mode, owner and cleanup of a Unix socket bound by the
+    /// server.
+    pub fn unix_socket(mut self, options: UnixSocketOptions) -> ServerBuilder {
+        self.config.unix_socket = options;
+        self
+    }
+
+    /// Drops root privileges once the socket is bound, switching to the
+    /// given user and group, see `daemon::drop_privileges`.
+    pub fn run_as(mut self, user: &str, group: Option<&str>) -> ServerBuilder {
+        self.config.user = Some(String::from(user));
+        self.config.group = group.map(String::from);
+        self
+    }
+
+    /// Runs the server in the background, writing stdout and stderr to
+    /// the error log if one is given.
+    pub fn daemonize<P: Into<PathBuf>>(mut self, error_log: Option<P>) -> ServerBuilder {
+        self.config.daemonize = true;
+        self.config.error_log = error_log.map(Into::into);
+        self
+    }
+
+    /// Writes the process id to the given file while the server runs.
+    pub fn pid_file<P: Into<PathBuf>>(mut self, path: P) -> ServerBuilder {
+        self.config.pid_file = Some(path.into());
+        self
+    }
+
+    /// Confines the server to a chroot jail after binding the socket and
+    /// before dropping privileges.
+    pub fn chroot<P: Into<PathBuf>>(mut self, root: P) -> ServerBuilder {
+        self.config.chroot = Some(root.into());
+        self
+    }
+
+    /// Changes the working directory before serving requests.
+    pub fn working_dir<P: Into<PathBuf>>(mut self, dir: P) -> ServerBuilder {
+        self.config.working_dir = Some(dir.into());
+        self
+    }
+
+    /// Gives every request a time budget after which it is answered with
+    /// 504, see `middleware::Deadline`.
+    pub fn request_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.config.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Logs requests taking at least `threshold`, including time spent
+    /// waiting for a concurrency slot, see `middleware::SlowLog`.
+    pub fn slow_request_threshold(mut self, threshold: Duration) -> ServerBuilder {
+        self.config.slow_request_threshold = Some(threshold);
+        self
+    }
+
+    /// Sets the socket to accept requests from.
+    pub fn listen_fd(mut self, fd: RawFd) -> ServerBuilder {
+        self.listen_fd = fd;
+        self
+    }
+
+    /// Wraps the handler in a middleware. The middleware added first is
+    /// the outermost one.
+    pub fn layer<M: Middleware>(mut self, middleware: M) -> ServerBuilder {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Records request metrics for all requests and reports the size of
+    /// the worker pool. The metrics middleware is the outermost layer.
+    pub fn metrics(mut self, metrics: &Metrics) -> ServerBuilder {
+        self.metrics = Some(metrics.clone());
+        self
+    }
+
+    /// Answers liveness and readiness probes, see `HealthCheck`. Probes are
+    /// answered before any other middleware runs.
+    pub fn health_check(mut self, health: HealthCheck) -> ServerBuilder {
+        self.health = Some(health);
+        self
+    }
+
+    /// Writes what handlers and middleware send to the FCGI error stream
+    /// into a rotating file as well, or only there, see `ErrorLog`.
+    pub fn error_stream_log(mut self, error_log: ErrorLog) -> ServerBuilder {
+        self.error_log = Some(Arc::new(error_log));
+        self
+    }
+
+    /// Rejects requests with 503 while the server is overloaded, see
+    /// `LoadShedder`. Shedding happens before any other built-in layer
+    /// but after health probes and metrics.
+    pub fn load_shedding(mut self, shedder: LoadShedder) -> ServerBuilder {
+        self.load_shedder = Some(shedder);
+        self
+    }
+
+    /// Makes shared application state, such as connection pools and
+    /// caches, available to every request through `Exchange::state`, for
+    /// all middleware and the handler. Can be called once per state type.
+    pub fn state<S: Send + Sync + 'static>(mut self, state: Arc<S>) -> ServerBuilder {
+        self.states.push(Box::new(InjectState(state)));
+        self
+    }
+
+    /// Calls `hook` with the worker id when a worker thread starts, before
+    /// it accepts requests, e.g. to open per-thread connections.
+    pub fn on_worker_start<F>(mut self, hook: F) -> ServerBuilder
+        where F: Fn(usize) + Send + Sync + 'static
+    {
+        self.hooks.worker_start.push(Box::new(hook));
+        self
+    }
+
+    /// Calls `hook` when a request has been accepted, before any
+    /// middleware runs. A panic is handled like one in the handler.
+    pub fn on_accept<F>(mut self, hook: F) -> ServerBuilder
+        where F: Fn(&mut Exchange) + Send + Sync + 'static
+    {
+        self.hooks.accept.push(Box::new(hook));
+        self
+    }
+
+    /// Calls `hook` when the response to a request has been finished, with
+    /// the time it took. The status is available from the exchange.
+    pub fn on_finish<F>(mut self, hook: F) -> ServerBuilder
+        where F: Fn(&Exchange, Duration) + Send + Sync + 'static
+    {
+        self.hooks.finish.push(Box::new(hook));
+        self
+    }
+
+    /// Sets the responses used for errors such as 404, 405 and 500.
+    pub fn error_pages(mut self, error_pages: ErrorPages) -> ServerBuilder {
+        self.error_pages = Some(Arc::new(error_pages));
+        self
+    }
+
+    /// Creates the server for the given handler.
+    pub fn build<H: Handler>(mut self, handler: H) -> Server {
+        if self.config.listen.is_some() {
+            self.listen_fd = 0;
+        }
+        let shared = Arc::new(Shared::new(self.config.workers, self.config.accept_queue));
+        let metrics = self.metrics.clone();
+        let reloader = match self.reload.take() {
+            Some(load) => Some(self.reloadable_layers(load)),
+            None => {
+                self.fixed_layers();
+                None
+            }
+        };
+        if let Some(shedder) = self.load_shedder.take() {
+            self.middleware.insert(0, Box::new(shedder));
+        }
+        if let Some(metrics) = self.metrics.take() {
+            metrics.set_workers(self.config.workers);
+            self.middleware.insert(0, Box::new(metrics));
+        }
+        if let Some(health) = self.health.take() {
+            let handle = ShutdownHandle { shared: shared.clone(), listen_fd: self.listen_fd };
+            self.middleware.insert(0, Box::new(health.attach(handle)));
+        }
+        for state in self.states.drain(..).rev() {
+            self.middleware.insert(0, state);
+        }
+        let handler: Arc<dyn Handler> = if self.middleware.is_empty() {
+            Arc::new(handler)
+        } else {
+            let stack = self.middleware.into_iter().fold(Stack::new(handler), Stack::layer_boxed);
+            Arc::new(stack)
+        };
+        Server {
+            config: self.config,
+            listen_fd: self.listen_fd,
+            extra_fds: Vec::new(),
+            handler,
+            error_pages: self.error_pages,
+            error_log: self.error_log,
+            hooks: Arc::new(self.hooks),
+            shared,
+            reloader,
+            pid_file: Mutex::new(None),
+            metrics,
+        }
+    }
+
+    // Built-in layers wrap the user's middleware, except for the static
+    // mounts which sit right in front of the handler. From the outside in:
+    // health probes, metrics, load shedding, slow log, concurrency limit,
+    // request deadline.
+
+    /// Adds the configured built-in layers.
+    fn fixed_layers(&mut self) {
+        if !self.config.static_mounts.is_empty() {
+            let mounts = StaticMounts::new(&self.config.static_mounts)
+                .mime_types(MimeTypes::from(self.config.mime_types.as_slice()));
+            self.middleware.push(Box::new(mounts));
+        }
+        if let Some(timeout) = self.config.request_timeout {
+            self.middleware.insert(0, Box::new(Deadline::new(timeout)));
+        }
+        if let Some(max) = self.config.max_concurrent_requests {
+            let limit = ConcurrencyLimit::new(max)
+                .queue(self.config.max_queued_requests, self.config.queue_timeout);
+            self.middleware.insert(0, Box::new(limit));
+        }
+        if let Some(threshold) = self.config.slow_request_threshold {
+            self.middleware.insert(0, Box::new(SlowLog::new(threshold)));
+        }
+    }
+
+    /// Adds all built-in layers which can be reconfigured, so that a
+    /// setting can be enabled by a reload.
+    fn reloadable_layers(&mut self, load: Box<Loader>) -> Reloader {
+        let reloader = Reloader {
+            load,
+            concurrency: Arc::new(ConcurrencyLimit::new(usize::MAX)),
+            deadline: Arc::new(Deadline::new(Duration::from_secs(0))),
+            slow_log: Arc::new(SlowLog::new(Duration::from_secs(0))),
+            static_mounts: Arc::new(StaticMounts::new(&self.config.static_mounts)
+                .mime_types(MimeTypes::from(self.config.mime_types.as_slice()))),
+        };
+        reloader.apply(&self.config);
+        self.middleware.push(Box::new(reloader.static_mounts.clone()));
+        self.middleware.insert(0, Box::new(reloader.deadline.clone()));
+        self.middleware.insert(0, Box::new(reloader.concurrency.clone()));
+        self.middleware.insert(0, Box::new(reloader.slow_log.clone()));
+        reloader
+    }
+}
+
+/// State shared between the server, its workers and shutdown handles.
+struct Shared {
+    state: Mutex<PoolState>,
+    workers: usize,
+    changed: Condvar,
+    in_flight: AtomicUsize,
+    /// Worker threads which have not been joined yet.
+    threads: Mutex<Vec<JoinHandle<()>>>,
+    /// Set while another process accepts from the listen socket too, so
+    /// shutdown must leave the socket intact.
+    socket_shared: AtomicBool,
+    queue: Option<AcceptQueue>,
+    /// Requests being handled, by worker thread, for reporting requests
+    /// which outlast the drain timeout.
+    active: Mutex<HashMap<ThreadId, ActiveRequest>>,
+}
+
+struct ActiveRequest {
+    thread: String,
+    request: String,
+    started: Instant,
+}
+
+/// Removes the request of the current thread from `Shared::active` when
+/// dropped.
+struct Tracked<'a>(&'a Shared);
+
+impl<'a> Drop for Tracked<'a> {
+    fn drop(&mut self) {
+        self.0.active.lock().unwrap_or_else(|e| e.into_inner()).remove(&thread::current().id());
+    }
+}
+
+struct PoolState {
+    shutting_down: bool,
+    live_workers: usize,
+    /// Last time all workers were busy, for adaptive scaling.
+    last_saturated: Instant,
+}
+
+impl Shared {
+    fn new(workers: usize, accept_queue: Option<usize>) -> Shared {
+        Shared {
+            state: Mutex::new(PoolState {
+                shutting_down: false,
+                live_workers: 0,
+                last_saturated: Instant::now(),
+            }),
+            workers,
+            changed: Condvar::new(),
+            in_flight: AtomicUsize::new(0),
+            threads: Mutex::new(Vec::new()),
+            socket_shared: AtomicBool::new(false),
+            queue: accept_queue.map(AcceptQueue::new),
+            active: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Interrupts workers blocked in accept: by shutting the listen socket
+    /// down, or by signalling the worker threads if the socket is shared.
+    fn wake_workers(&self, listen_fd: RawFd) {
+        if self.socket_shared.load(Ordering::SeqCst) {
+            upgrade::wake(&self.threads.lock().unwrap_or_else(|e| e.into_inner()));
+        } else {
+            unsafe { libc::shutdown(listen_fd, libc::SHUT_RDWR) };
+        }
+    }
+
+    /// Records the request handled by the current thread, e.g. `GET /path`.
+    fn track(&self, request: String, started: Instant) -> Tracked<'_> {
+        let current = thread::current();
+        let thread = current.name().unwrap_or("unnamed thread").to_string();
+        let active = ActiveRequest { thread, request, started };
+        self.active.lock().unwrap_or_else(|e| e.into_inner()).insert(current.id(), active);
+        Tracked(self)
+    }
+
+    /// Logs a warning for every request still being handled.
+    fn report_stuck_requests(&self) {
+        let active = self.active.lock().unwrap_or_else(|e| e.into_inner());
+        for request in active.values() {
+            server_log!(warn, "{} on {} still running after {:.1} s, abandoning it",
+                        request.request, request.thread, request.started.elapsed().as_secs_f64());
+        }
+    }
+
+    fn is_shutting_down(&self) -> bool {
+        self.state.lock().unwrap().shutting_down
+    }
+}
+
+/// Decrements the live worker count when a worker thread ends, even if
+/// it ends by unwinding. Retiring workers decrement the count themselves.
+struct WorkerGuard {
+    shared: Arc<Shared>,
+    retired: bool,
+}
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap_or_else(|e| e.into_inner());
+        if !self.retired {
+            state.live_workers -= 1;
+        }
+        self.shared.changed.notify_all();
+    }
+}
+
+/// Stops a running server from another thread.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    shared: Arc<Shared>,
+    listen_fd: RawFd,
+}
+
+impl ShutdownHandle {
+    /// Stops accepting new requests. Requests which are already being
+    /// handled may complete until the drain timeout expires. Calling this
+    /// more than once has no further effect.
+    pub fn shutdown(&self) {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            if state.shutting_down {
+                return;
+            }
+            state.shutting_down = true;
+            self.shared.changed.notify_all();
+        }
+        // Wake up workers blocked in accept().
+        transport::shutdown_pending();
+        self.shared.wake_workers(self.listen_fd);
+    }
+
+    /// Number of requests currently being handled.
+    pub fn in_flight(&self) -> usize {
+        self.shared.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Returns a snapshot of the worker pool state.
+    pub fn pool_status(&self) -> PoolStatus {
+        let state = self.shared.state.lock().unwrap_or_else(|e| e.into_inner());
+        PoolStatus {
+            workers: self.shared.workers,
+            live_workers: state.live_workers,
+            in_flight: self.shared.in_flight.load(Ordering::SeqCst),
+            queued: self.shared.queue.as_ref().map_or(0, AcceptQueue::len),
+            draining: state.shutting_down,
+        }
+    }
+}
+
+/// A snapshot of the worker pool, see `ShutdownHandle::pool_status`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PoolStatus {
+    /// Configured number of workers.
+    pub workers: usize,
+    /// Workers currently running.
+    pub live_workers: usize,
+    /// Requests currently being handled.
+    pub in_flight: usize,
+    /// Requests accepted and waiting for a worker, see
+    /// `ServerConfig::accept_queue`.
+    pub queued: usize,
+    /// True once shutdown has started.
+    pub draining: bool,
+}
+
+/// A multi-threaded FCGI server. Create one with `ServerBuilder`.
+pub struct Server {
+    config: ServerConfig,
+    listen_fd: RawFd,
+    extra_fds: Vec<RawFd>,
+    handler: Arc<dyn Handler>,
+    error_pages: Option<Arc<ErrorPages>>,
+    error_log: Option<Arc<ErrorLog>>,
+    hooks: Arc<Hooks>,
+    shared: Arc<Shared>,
+    reloader: Option<Reloader>,
+    pid_file: Mutex<Option<PidFile>>,
+    metrics: Option<Metrics>,
+}
+
+impl Server {
+    /// Returns a handle which can be used to shut the server down.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            shared: self.shared.clone(),
+            listen_fd: self.listen_fd,
+        }
+    }
+
+    /// Returns true if the process was started as a CGI program: no listen
+    /// address is configured, no socket was passed by systemd or a graceful
+    /// upgrade, and stdin is not a FastCGI listen socket.
+    pub fn is_cgi(&self) -> bool {
+        self.config.listen.is_none() && self.listen_fd == 0 && crate::is_cgi()
+    }
+
+    /// Handles the single request of a CGI process with the handler,
+    /// middleware, hooks and error pages of the server, reading the
+    /// parameters from the environment and the input from stdin, and
+    /// writing the response to stdout.
+    pub fn run_cgi(self) -> io::Result<()> {
+        let mut request = CgiRequest::new().expect("CGI request");
+        if !request.accept() {
+            return Ok(());
+        }
+        let result = {
+            let mut exchange = Exchange::new(&mut request);
+            exchange.set_error_pages(self.error_pages.clone());
+            exchange.set_error_log(self.error_log.clone());
+            let start = Instant::now();
+            dispatch(&*self.handler, &self.hooks, &mut exchange);
+            let result = exchange.finish();
+            let finished = panic::catch_unwind(AssertUnwindSafe(|| self.hooks.finished(&exchange, start.elapsed())));
+            if let Err(payload) = finished {
+                exchange.error(&format!("request finish hook panicked: {}\n", panic_message(&*payload)));
+            }
+            result
+        };
+        request.finish();
+        result
+    }
+
+    /// Runs the server until it is shut down or all workers have exited.
+    ///
+    /// Before serving, the server binds its socket, daemonizes, writes the
+    /// PID file, enters the chroot and drops privileges, each if configured
+    /// and in that order. A process started by a graceful upgrade only
+    /// writes the PID file, as it inherits everything else.
+    ///
+    /// After shutdown has been requested this waits up to the drain
+    /// timeout for in-flight requests and returns a `TimedOut` error if
+    /// some of them are still running afterwards.
+    pub fn run(mut self) -> io::Result<()> {
+        if !self.config.extra_listen.is_empty() && !cfg!(feature = "pure") {
+            return Err(io::Error::other("extra_listen needs the `pure` feature"));
+        }
+        if !self.config.extra_listen.is_empty() && self.config.protocol != Protocol::FastCgi {
+            return Err(io::Error::other("extra_listen needs the FastCGI protocol"));
+        }
+        let upgraded = upgrade::inherited_listen_fd().is_some();
+        if !upgraded {
+            if let Some(ref addr) = self.config.listen {
+                listen::bind_to_fd(addr, &self.config.unix_socket, self.listen_fd)?;
+            }
+        }
+        for addr in &self.config.extra_listen {
+            self.extra_fds.push(addr.bind_with(&self.config.unix_socket)?);
+        }
+        if !upgraded && self.config.daemonize {
+            daemon::daemonize(self.config.error_log.as_deref())?;
+        }
+        if let Some(backlog) = self.config.listen_backlog {
+            for &fd in Some(&self.listen_fd).into_iter().chain(&self.extra_fds) {
+                listen::set_backlog(fd, backlog)?;
+            }
+        }
+        self.create_pid_file()?;
+        if !upgraded {
+            let credentials = Credentials::resolve(self.config.user.as_deref(), self.config.group.as_deref())?;
+            daemon::confine(self.config.chroot.as_deref(), self.config.working_dir.as_deref())?;
+            credentials.apply()?;
+        }
+        if self.reloader.is_some() {
+            reload::install_handler()?;
+        }
+        if self.config.graceful_upgrade {
+            upgrade::install_handler()?;
+        }
+        if self.config.graceful_upgrade || self.config.processes > 1 {
+            upgrade::install_wake_handler()?;
+        }
+        upgrade::signal_ready();
+        let result = if self.config.processes > 1 {
+            prefork::run(&self)
+        } else {
+            self.run_workers(Notifier::from_env())
+        };
+        // After an upgrade the socket belongs to the new process.
+        if let Some(ref addr) = self.config.listen {
+            if !self.shared.socket_shared.load(Ordering::SeqCst) {
+                addr.cleanup(&self.config.unix_socket);
+            }
+        }
+        for addr in &self.config.extra_listen {
+            addr.cleanup(&self.config.unix_socket);
+        }
+        self.pid_file.lock().unwrap().take();
+        match result {
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut && self.config.exit_on_drain_timeout => {
+                server_log!(error, "{}, exiting", e);
+                process::exit(1);
+            }
+            result => result,
+        }
+    }
+
+    fn create_pid_file(&self) -> io::Result<()> {
+        if let Some(ref path) = self.config.pid_file {
+            *self.pid_file.lock().unwrap() = Some(PidFile::create(path.clone())?);
+        }
+        Ok(())
+    }
+
+    /// Reloads the configuration if reloading is enabled.
+    fn reload(&self) {
+        if let Some(ref reloader) = self.reloader {
+            if let Err(e) = reloader.reload() {
+                server_log!(warn, "keeping the current settings: {}", e);
+            }
+        }
+    }
+
+    /// Starts a graceful upgrade on SIGUSR2 and hands over to the new
+    /// process once it is ready, see the `upgrade` module.
+    fn poll_upgrade(&self, successor: &mut Option<Successor>, notifier: &mut Notifier) {
+        if upgrade::take_request() && successor.is_none() {
+            // The new process writes the PID file itself.
+            self.pid_file.lock().unwrap().take();
+            match Successor::spawn(self.listen_fd) {
+                Ok(started) => *successor = Some(started),
+                Err(e) => {
+                    server_log!(error, "failed to start the upgraded server: {}", e);
+                    self.restore_pid_file();
+                }
+            }
+        }
+        let ready = match *successor {
+            Some(ref mut started) => started.poll(),
+            None => return,
+        };
+        let pid = successor.as_ref().map_or(0, Successor::id);
+        match ready {
+            Some(true) => {
+                server_log!(info, "handing over to upgraded process {}", pid);
+                notifier.notify(&format!("MAINPID={}", pid));
+                self.shared.socket_shared.store(true, Ordering::SeqCst);
+                *successor = None;
+                self.shutdown_handle().shutdown();
+            }
+            Some(false) => {
+                server_log!(error, "upgraded process {} exited before becoming ready", pid);
+                *successor = None;
+                self.restore_pid_file();
+            }
+            None => {}
+        }
+    }
+
+    fn restore_pid_file(&self) {
+        if let Err(e) = self.create_pid_file() {
+            server_log!(error, "failed to restore the PID file: {}", e);
+        }
+    }
+
+    /// Runs the thread pool in the current process, reporting its state to
+    /// systemd through the notifier.
+    fn run_workers(&self, mut notifier: Notifier) -> io::Result<()> {
+        if !initialize_fcgi() {
+            return Err(io::Error::other("failed to initialize the FCGX library"));
+        }
+
+        #[cfg(feature = "tls")]
+        let tls = self.config.tls_config()?;
+        #[cfg(not(feature = "tls"))]
+        {
+            if self.config.tls_cert.is_some() {
+                return Err(io::Error::other("TLS needs the `tls` feature"));
+            }
+        }
+        if self.config.event_loop && !cfg!(feature = "evented") {
+            return Err(io::Error::other("event_loop needs the `evented` feature"));
+        }
+
+        let context = Arc::new(WorkerContext {
+            handler: self.handler.clone(),
+            error_pages: self.error_pages.clone(),
+            error_log: self.error_log.clone(),
+            hooks: self.hooks.clone(),
+            shared: self.shared.clone(),
+            protocol: self.config.protocol,
+            ajp_secret: self.config.ajp_secret.clone(),
+            listen_fd: self.listen_fd,
+            min_workers: self.config.workers,
+            max_workers: self.config.max_workers.unwrap_or(0).max(self.config.workers),
+            idle_timeout: self.config.worker_idle_timeout,
+            accept_batch: self.config.accept_batch,
+            overload_delay: self.config.overload_accept_delay,
+            metrics: self.metrics.clone(),
+            #[cfg(feature = "pure")]
+            listener: {
+                let fds = Some(self.listen_fd).into_iter().chain(self.extra_fds.iter().cloned()).collect();
+                let listener = Listener::with_fds(fds);
+                listener.set_capabilities(self.config.capabilities());
+                listener.set_trace(self.config.trace_records);
+                listener.set_recording(self.config.record_sessions.clone());
+                listener.set_proxy_protocol(self.config.proxy_protocol);
+                listener.set_output_options(self.config.output_options());
+                #[cfg(feature = "tls")]
+                listener.set_tls(tls);
+                #[cfg(feature = "evented")]
+                listener.set_event_loop(self.config.event_loop);
+                listener
+            },
+            next_id: AtomicUsize::new(0),
+        });
+        for _ in 0..self.config.workers {
+            if let Err(e) = spawn_worker(&context) {
+                self.shutdown_handle().shutdown();
+                return Err(e);
+            }
+        }
+        if self.shared.queue.is_some() {
+            if let Err(e) = spawn_acceptor(&context) {
+                self.shutdown_handle().shutdown();
+                return Err(e);
+            }
+        }
+
+        notifier.notify("READY=1");
+
+        let mut successor = None;
+        let mut state = self.shared.state.lock().unwrap();
+        while !state.shutting_down && state.live_workers > 0 {
+            let reload_poll = self.reloader.as_ref().map(|_| reload::POLL_INTERVAL);
+            let upgrade_poll = Some(upgrade::POLL_INTERVAL).filter(|_| self.config.graceful_upgrade);
+            state = match notifier.wait_time().into_iter().chain(reload_poll).chain(upgrade_poll).min() {
+                Some(wait) => self.shared.changed.wait_timeout(state, wait).unwrap().0,
+                None => self.shared.changed.wait(state).unwrap(),
+            };
+            notifier.tick(state.live_workers > 0);
+            drop(state);
+            if reload::take_request() {
+                self.reload();
+            }
+            if self.config.graceful_upgrade {
+                self.poll_upgrade(&mut successor, &mut notifier);
+            }
+            state = self.shared.state.lock().unwrap();
+        }
+        notifier.notify("STOPPING=1");
+
+        let deadline = Instant::now() + self.config.drain_timeout;
+        while state.live_workers > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                let in_flight = self.shared.in_flight.load(Ordering::SeqCst);
+                drop(state);
+                self.shared.report_stuck_requests();
+                return Err(io::Error::new(io::ErrorKind::TimedOut,
+                    format!("{} requests still in flight after drain timeout", in_flight)));
+            }
+            // A signal sent while a worker was about to enter accept is
+            // lost, so workers of a shared socket are woken repeatedly.
+            if self.shared.socket_shared.load(Ordering::SeqCst) {
+                let wait = (deadline - now).min(upgrade::WAKE_INTERVAL);
+                state = self.shared.changed.wait_timeout(state, wait).unwrap().0;
+                self.shared.wake_workers(self.listen_fd);
+            } else {
+                state = self.shared.changed.wait_timeout(state, deadline - now).unwrap().0;
+            }
+        }
+        drop(state);
+
+        let workers = std::mem::take(&mut *self.shared.threads.lock().unwrap());
+        for worker in workers {
+            let _ = worker.join();
+        }
+        Ok(())
+    }
+}
+
+/// Everything a worker thread needs to serve requests.
+struct WorkerContext {
+    handler: Arc<dyn Handler>,
+    error_pages: Option<Arc<ErrorPages>>,
+    error_log: Option<Arc<ErrorLog>>,
+    hooks: Arc<Hooks>,
+    shared: Arc<Shared>,
+    protocol: Protocol,
+    ajp_secret: Option<String>,
+    listen_fd: RawFd,
+    min_workers: usize,
+    max_workers: usize,
+    idle_timeout: Duration,
+    accept_batch: usize,
+    overload_delay: Option<Duration>,
+    metrics: Option<Metrics>,
+    #[cfg(feature = "pure")]
+    listener: Arc<Listener>,
+    next_id: AtomicUsize,
+}
+
+impl WorkerContext {
+    /// Called when a worker starts handling a request. Adds a worker if
+    /// none is left waiting in accept and the pool may still grow.
+    fn on_busy(context: &Arc<WorkerContext>) {
+        let in_flight = context.shared.in_flight.load(Ordering::SeqCst);
+        let grow = {
+            let mut state = context.shared.state.lock().unwrap_or_else(|e| e.into_inner());
+            if in_flight < state.live_workers {
+                return;
+            }
+            state.last_saturated = Instant::now();
+            !state.shutting_down && state.live_workers < context.max_workers
+        };
+        if grow {
+            let _ = spawn_worker(context);
+        }
+    }
+
+    /// Called when a worker has finished a request. Returns true if the
+    /// worker should retire because the pool has been idle long enough.
+    fn should_retire(&self, guard: &mut WorkerGuard) -> bool {
+        let mut state = self.shared.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.live_workers > self.min_workers && state.last_saturated.elapsed() >= self.idle_timeout {
+            state.live_workers -= 1;
+            guard.retired = true;
+            return true;
+        }
+        false
+    }
+
+    /// Called between requests to pace accepting, see `accept_batch` and
+    /// `overload_delay`.
+    fn pace(&self, handled_in_row: &mut usize) {
+        *handled_in_row += 1;
+        if self.accept_batch > 0 && *handled_in_row >= self.accept_batch {
+            *handled_in_row = 0;
+            thread::yield_now();
+        }
+        if let Some(delay) = self.overload_delay {
+            if self.is_overloaded() {
+                thread::sleep(delay);
+            }
+        }
+    }
+
+    /// True if every other worker is busy and the pool cannot grow.
+    fn is_overloaded(&self) -> bool {
+        let in_flight = self.shared.in_flight.load(Ordering::SeqCst);
+        let state = self.shared.state.lock().unwrap_or_else(|e| e.into_inner());
+        !state.shutting_down && in_flight + 1 >= state.live_workers && state.live_workers >= self.max_workers
+    }
+}
+
+fn spawn_worker(context: &Arc<WorkerContext>) -> io::Result<()> {
+    let id = context.next_id.fetch_add(1, Ordering::SeqCst);
+    context.shared.state.lock().unwrap_or_else(|e| e.into_inner()).live_workers += 1;
+    let worker_context = context.clone();
+    let spawned = thread::Builder::new()
+        .name(format!("fcgi-worker-{}", id))
+        .spawn(move || {
+            let mut guard = WorkerGuard { shared: worker_context.shared.clone(), retired: false };
+            worker_context.hooks.worker_started(id);
+            worker_loop(&worker_context, &mut guard);
+        });
+    match spawned {
+        Ok(thread) => {
+            let mut threads = context.shared.threads.lock().unwrap_or_else(|e| e.into_inner());
+            threads.retain(|t| !t.is_finished());
+            threads.push(thread);
+            Ok(())
+        }
+        Err(e) => {
+            context.shared.state.lock().unwrap_or_else(|e| e.into_inner()).live_workers -= 1;
+            Err(e)
+        }
+    }
+}
+
+/// Starts the thread accepting requests into the accept queue.
+fn spawn_acceptor(context: &Arc<WorkerContext>) -> io::Result<()> {
+    let acceptor_context = context.clone();
+    let thread = thread::Builder::new()
+        .name(String::from("fcgi-acceptor"))
+        .spawn(move || {
+            if let Some(ref queue) = acceptor_context.shared.queue {
+                accept_queue::accept_loop(&acceptor_context, queue);
+            }
+        })?;
+    context.shared.threads.lock().unwrap_or_else(|e| e.into_inner()).push(thread);
+    Ok(())
+}
+
+fn worker_loop(context: &Arc<WorkerContext>, guard: &mut WorkerGuard) {
+    if let Some(ref queue) = context.shared.queue {
+        while let Some((mut queued, depth)) = queue.pop() {
+            if let Some(ref metrics) = context.metrics {
+                metrics.set_queue_depth(depth);
+                metrics.record_queue_wait(queued.since.elapsed());
+            }
+            serve_request(context, &mut queued.request);
+            if context.should_retire(guard) {
+                break;
+            }
+        }
+        return;
+    }
+    let shared = &context.shared;
+    let mut request = match transport::new_request(context) {
+        Some(request) => request,
+        None => return,
+    };
+    let mut handled_in_row = 0;
+    while !shared.is_shutting_down() && request.accept() {
+        serve_request(context, &mut request);
+        if context.should_retire(guard) {
+            break;
+        }
+        context.pace(&mut handled_in_row);
+    }
+}
+
+/// Handles an accepted request and finishes it.
+fn serve_request(context: &Arc<WorkerContext>, request: &mut WorkerRequest) {
+    let shared = &context.shared;
+    shared.in_flight.fetch_add(1, Ordering::SeqCst);
+    WorkerContext::on_busy(context);
+    {
+        #[cfg(feature = "log")]
+        let _log_scope = request.log_scope(context.error_log.clone());
+        let mut exchange = Exchange::new(request);
+        exchange.set_error_pages(context.error_pages.clone());
+        exchange.set_error_log(context.error_log.clone());
+        let start = Instant::now();
+        let summary = format!("{} {}", exchange.method(),
+                              exchange.param("REQUEST_URI").unwrap_or_else(|| exchange.path()));
+        let _active = shared.track(summary, start);
+        dispatch(&*context.handler, &context.hooks, &mut exchange);
+        let _ = exchange.finish();
+        let finished = panic::catch_unwind(AssertUnwindSafe(|| context.hooks.finished(&exchange, start.elapsed())));
+        if let Err(payload) = finished {
+            exchange.error(&format!("request finish hook panicked: {}\n", panic_message(&*payload)));
+        }
+    }
+    request.finish();
+    shared.in_flight.fetch_sub(1, Ordering::SeqCst);
+}
+
+/// Runs the accept hooks and calls the handler, isolating panics to the
+/// current request. A panic is reported to the error stream and answered
+/// with a 500 response if the handler had not started its response yet.
+fn dispatch(handler: &dyn Handler, hooks: &Hooks, exchange: &mut Exchange) {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        hooks.accepted(exchange);
+        handler.handle(exchange)
+    }));
+    if let Err(payload) = result {
+        let msg = panic_message(&*payload);
+        exchange.error(&format!("request handler panicked: {}\n", msg));
+        if !exchange.headers_sent() {
+            *exchange.headers_mut() = Headers::new();
+            exchange.respond_error(500);
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(msg) = payload.downcast_ref::<&'static str>() {
+        msg
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg
+    } else {
+        "unknown panic payload"
+    }
+}
+
+/// Adds application state to the extensions of every request.
+struct InjectState<S>(Arc<S>);
+
+impl<S: Send + Sync + 'static> Middleware for InjectState<S> {
+    fn call(&self, exchange: &mut Exchange, next: Next) {
+        exchange.extensions_mut().insert(self.0.clone());
+        next.run(exchange);
+    }
+}
+
+/// Serves requests on fd 0 with the default configuration until the
+/// process is terminated.
+pub fn serve<H: Handler>(handler: H) -> io::Result<()> {
+    ServerBuilder::new().build(handler).run()
+}
+
+/// Like `serve`, but when the process was started as a CGI program, see
+/// `Server::is_cgi`, handles its single request over stdin and stdout and
+/// returns. The same binary can so be deployed either way and tried from
+/// the command line by setting the CGI variables.
+pub fn run<H: Handler>(handler: H) -> io::Result<()> {
+    let server = ServerBuilder::new().build(handler);
+    if server.is_cgi() {
+        server.run_cgi()
+    } else {
+        server.run()
+    }
+}
+
+/// Like `serve`, passing the application state to the handler with every
+/// request, see `handler::with_state`.
+pub fn serve_with_state<S, F>(state: Arc<S>, handler: F) -> io::Result<()>
+    where S: Send + Sync + 'static, F: Fn(&mut Exchange, &S) + Send + Sync + 'static
+{
+    ServerBuilder::new().state(state.clone()).build(handler::with_state(state, handler)).run()
+}
@@ -0,0 +1,188 @@
+//! Prefork mode: the parent process forks worker processes which accept
+//! requests from the shared listen socket, and restarts them when they exit.
+//!
+//! Children are tied to the parent by a pipe. The parent holds its write
+//! end and closes it on shutdown (or by exiting), which makes the children
+//! read end-of-file and start their own graceful shutdown. As the socket
+//! is shared, children wake their workers by signal instead of shutting it
+//! down.
+
+use std::io;
+use std::os::unix::io::RawFd;
+use std::process;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc;
+
+use super::{reload, Server};
+use crate::systemd::Notifier;
+
+/// How often the parent checks for exited children.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Children exiting sooner than this after being forked are restarted
+/// only after this delay, so a child failing at startup does not make the
+/// parent fork in a tight loop.
+const MIN_CHILD_LIFETIME: Duration = Duration::from_secs(1);
+
+struct Child {
+    pid: libc::pid_t,
+    started: Instant,
+}
+
+/// Forks and supervises the configured number of worker processes until
+/// the server is shut down, then waits for the children to drain.
+pub(super) fn run(server: &Server) -> io::Result<()> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (lifeline, keepalive) = (fds[0], fds[1]);
+
+    let mut notifier = Notifier::from_env();
+    let mut children: Vec<Child> = Vec::new();
+    let mut next_fork = Instant::now();
+    let mut result = Ok(());
+    let mut ready = false;
+    let mut successor = None;
+    while !server.shared.is_shutting_down() {
+        while children.len() < server.config.processes && Instant::now() >= next_fork {
+            match fork_child(server, lifeline, keepalive) {
+                Ok(pid) => children.push(Child { pid, started: Instant::now() }),
+                Err(e) => {
+                    result = Err(e);
+                    server.shutdown_handle().shutdown();
+                    break;
+                }
+            }
+        }
+        if !ready && !children.is_empty() {
+            notifier.notify("READY=1");
+            ready = true;
+        }
+        for (child, status) in reap(&mut children) {
+            server_log!(warn, "worker process {} exited unexpectedly ({})", child.pid, describe(status));
+            if child.started.elapsed() < MIN_CHILD_LIFETIME {
+                next_fork = Instant::now() + MIN_CHILD_LIFETIME;
+            }
+        }
+        notifier.tick(!children.is_empty());
+        if reload::take_request() {
+            for child in &children {
+                unsafe { libc::kill(child.pid, libc::SIGHUP) };
+            }
+        }
+        if server.config.graceful_upgrade {
+            server.poll_upgrade(&mut successor, &mut notifier);
+        }
+        let wait = notifier.wait_time().map_or(POLL_INTERVAL, |wait| wait.min(POLL_INTERVAL));
+        let state = server.shared.state.lock().unwrap();
+        if !state.shutting_down {
+            let _ = server.shared.changed.wait_timeout(state, wait).unwrap();
+        }
+    }
+    notifier.notify("STOPPING=1");
+
+    unsafe {
+        libc::close(keepalive);
+        libc::close(lifeline);
+    }
+    // Children have the same drain timeout; allow them a moment beyond it
+    // to report their result before killing them.
+    let deadline = Instant::now() + server.config.drain_timeout + MIN_CHILD_LIFETIME;
+    while !children.is_empty() {
+        for (child, status) in reap(&mut children) {
+            if !exited_cleanly(status) {
+                server_log!(warn, "worker process {} failed to drain ({})", child.pid, describe(status));
+            }
+        }
+        if children.is_empty() {
+            break;
+        }
+        if Instant::now() >= deadline {
+            let remaining = children.len();
+            for child in &children {
+                unsafe {
+                    libc::kill(child.pid, libc::SIGKILL);
+                    libc::waitpid(child.pid, std::ptr::null_mut(), 0);
+                }
+            }
+            return result.and(Err(io::Error::new(io::ErrorKind::TimedOut,
+                format!("{} worker processes still running after drain timeout", remaining))));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    result
+}
+
+/// Forks a worker process. Only returns in the parent.
+fn fork_child(server: &Server, lifeline: RawFd, keepalive: RawFd) -> io::Result<libc::pid_t> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            unsafe { libc::close(keepalive) };
+            let code = match run_child(server, lifeline) {
+                Ok(()) => 0,
+                Err(e) => {
+                    server_log!(error, "worker process {}: {}", process::id(), e);
+                    1
+                }
+            };
+            process::exit(code);
+        }
+        pid => Ok(pid),
+    }
+}
+
+/// Runs the thread pool in a child, shutting it down once the parent
+/// closes its end of the lifeline pipe.
+fn run_child(server: &Server, lifeline: RawFd) -> io::Result<()> {
+    server.shared.socket_shared.store(true, Ordering::SeqCst);
+    let handle = server.shutdown_handle();
+    thread::Builder::new()
+        .name(String::from("fcgi-lifeline"))
+        .spawn(move || {
+            let mut byte = 0u8;
+            loop {
+                let n = unsafe { libc::read(lifeline, &mut byte as *mut u8 as *mut libc::c_void, 1) };
+                if n < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                break;
+            }
+            handle.shutdown();
+        })?;
+    server.run_workers(Notifier::disabled())
+}
+
+/// Collects exited children without blocking.
+fn reap(children: &mut Vec<Child>) -> Vec<(Child, libc::c_int)> {
+    let mut exited = Vec::new();
+    loop {
+        let mut status = 0;
+        let pid = unsafe { libc::waitpid(-1, &mut status, libc::WNOHANG) };
+        if pid <= 0 {
+            break;
+        }
+        if let Some(index) = children.iter().position(|c| c.pid == pid) {
+            exited.push((children.swap_remove(index), status));
+        }
+    }
+    exited
+}
+
+fn exited_cleanly(status: libc::c_int) -> bool {
+    libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0
+}
+
+fn describe(status: libc::c_int) -> String {
+    if libc::WIFEXITED(status) {
+        format!("exit status {}", libc::WEXITSTATUS(status))
+    } else if libc::WIFSIGNALED(status) {
+        format!("signal {}", libc::WTERMSIG(status))
+    } else {
+        format!("wait status {}", status)
+    }
+}
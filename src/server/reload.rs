@@ -0,0 +1,81 @@
+//! Reloading settings on SIGHUP.
+//!
+//! The signal handler only sets a flag; the thread supervising the workers
+//! picks it up, loads the configuration again and hands the reloadable
+//! settings to the built-in layers. The listen socket, the workers and the
+//! requests in flight are not affected. In prefork mode the parent passes
+//! the signal on to its children, which each reload by themselves.
+
+use std::io;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use libc;
+
+use crate::middleware::{ConcurrencyLimit, Deadline, SlowLog};
+use crate::static_files::StaticMounts;
+use super::{ConfigError, ServerConfig};
+
+/// How often the supervising thread checks for a reload request.
+pub(super) const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sighup(_signal: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Installs the SIGHUP handler. System calls interrupted by the signal are
+/// restarted, so workers blocked in accept are not disturbed.
+pub(super) fn install_handler() -> io::Result<()> {
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = on_sighup as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(libc::SIGHUP, &action, ptr::null_mut()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Returns true once for every SIGHUP received since the last call.
+pub(super) fn take_request() -> bool {
+    RELOAD_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+/// Loads the configuration for a reload.
+pub(super) type Loader = dyn Fn() -> Result<ServerConfig, ConfigError> + Send + Sync;
+
+/// Loads the configuration and applies the reloadable settings: the
+/// concurrency limit, request timeout, slow request threshold and static
+/// mounts.
+pub(super) struct Reloader {
+    pub(super) load: Box<Loader>,
+    pub(super) concurrency: Arc<ConcurrencyLimit>,
+    pub(super) deadline: Arc<Deadline>,
+    pub(super) slow_log: Arc<SlowLog>,
+    pub(super) static_mounts: Arc<StaticMounts>,
+}
+
+impl Reloader {
+    pub(super) fn reload(&self) -> Result<(), ConfigError> {
+        let config = (self.load)()?;
+        self.apply(&config);
+        Ok(())
+    }
+
+    pub(super) fn apply(&self, config: &ServerConfig) {
+        self.concurrency.set_limits(
+            config.max_concurrent_requests.unwrap_or(usize::MAX),
+            config.max_queued_requests,
+            config.queue_timeout);
+        self.deadline.set_budget(config.request_timeout);
+        self.slow_log.set_threshold(config.slow_request_threshold);
+        self.static_mounts.replace(&config.static_mounts);
+    }
+}
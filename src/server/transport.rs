@@ -0,0 +1,155 @@
+//! The request type accepted by the workers: a FastCGI request, libfcgi's
+//! `DefaultRequest` or `NativeRequest` with the `pure` feature, or a
+//! request of the other protocol configured.
+
+use std::net::SocketAddr;
+use std::os::unix::io::RawFd;
+#[cfg(feature = "log")]
+use std::sync::Arc;
+
+#[cfg(feature = "pure")]
+use crate::native::NativeRequest as FastCgiRequest;
+#[cfg(not(feature = "pure"))]
+use crate::DefaultRequest as FastCgiRequest;
+
+use crate::abort::AbortToken;
+use crate::ajp::AjpRequest;
+use crate::dev_server::DevRequest;
+#[cfg(feature = "log")]
+use crate::error_log::ErrorLog;
+use crate::protocol::Role;
+use crate::scgi::ScgiRequest;
+use crate::uwsgi::UwsgiRequest;
+use crate::{Request, StreamType};
+use super::config::Protocol;
+use super::WorkerContext;
+
+pub(super) enum WorkerRequest {
+    FastCgi(FastCgiRequest),
+    Scgi(ScgiRequest),
+    Uwsgi(UwsgiRequest),
+    Ajp(AjpRequest),
+    Http(DevRequest),
+}
+
+/// Calls a method on the request of whichever protocol.
+macro_rules! delegate {
+    ($request:expr, $r:ident => $call:expr) => {
+        match $request {
+            WorkerRequest::FastCgi($r) => $call,
+            WorkerRequest::Scgi($r) => $call,
+            WorkerRequest::Uwsgi($r) => $call,
+            WorkerRequest::Ajp($r) => $call,
+            WorkerRequest::Http($r) => $call,
+        }
+    };
+}
+
+impl WorkerRequest {
+    /// Routes records of the `log` crate to the request while it is
+    /// handled, see `logger`.
+    #[cfg(feature = "log")]
+    pub(super) fn log_scope(&self, error_log: Option<Arc<ErrorLog>>) -> crate::logger::RequestScope {
+        match *self {
+            WorkerRequest::FastCgi(ref request) => crate::logger::enter(request, error_log),
+            _ => crate::logger::enter_stderr(error_log),
+        }
+    }
+}
+
+impl Request for WorkerRequest {
+    fn new() -> Option<WorkerRequest> {
+        FastCgiRequest::new().map(WorkerRequest::FastCgi)
+    }
+
+    fn new_with_fd(fd: RawFd) -> Option<WorkerRequest> {
+        FastCgiRequest::new_with_fd(fd).map(WorkerRequest::FastCgi)
+    }
+
+    fn accept(&mut self) -> bool {
+        delegate!(self, r => r.accept())
+    }
+
+    fn finish(&mut self) {
+        delegate!(self, r => r.finish())
+    }
+
+    fn get_param(&self, name: &str) -> Option<String> {
+        delegate!(self, r => r.get_param(name))
+    }
+
+    fn write(&mut self, msg: &str) -> i32 {
+        delegate!(self, r => r.write(msg))
+    }
+
+    fn error(&mut self, msg: &str) -> i32 {
+        delegate!(self, r => r.error(msg))
+    }
+
+    fn write_bytes(&mut self, buf: &[u8]) -> i32 {
+        delegate!(self, r => r.write_bytes(buf))
+    }
+
+    fn read_bytes(&mut self, buf: &mut [u8]) -> i32 {
+        delegate!(self, r => r.read_bytes(buf))
+    }
+
+    fn readall(&mut self) -> String {
+        delegate!(self, r => r.readall())
+    }
+
+    fn read(&mut self, n: i32) -> (String, i32) {
+        delegate!(self, r => r.read(n))
+    }
+
+    fn flush(&mut self, stream_type: StreamType) {
+        delegate!(self, r => r.flush(stream_type))
+    }
+
+    fn abort_token(&self) -> AbortToken {
+        delegate!(self, r => r.abort_token())
+    }
+
+    fn role(&self) -> Role {
+        delegate!(self, r => r.role())
+    }
+
+    fn start_filter_data(&mut self) -> bool {
+        delegate!(self, r => r.start_filter_data())
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        delegate!(self, r => r.peer_addr())
+    }
+
+    fn params(&self) -> Vec<(String, String)> {
+        delegate!(self, r => r.params())
+    }
+}
+
+/// Creates a request accepting from the listen socket of the workers.
+pub(super) fn new_request(context: &WorkerContext) -> Option<WorkerRequest> {
+    match context.protocol {
+        #[cfg(feature = "pure")]
+        Protocol::FastCgi => Some(WorkerRequest::FastCgi(FastCgiRequest::from_listener(context.listener.clone()))),
+        #[cfg(not(feature = "pure"))]
+        Protocol::FastCgi => FastCgiRequest::new_with_fd(context.listen_fd).map(WorkerRequest::FastCgi),
+        Protocol::Scgi => ScgiRequest::new_with_fd(context.listen_fd).map(WorkerRequest::Scgi),
+        Protocol::Uwsgi => UwsgiRequest::new_with_fd(context.listen_fd).map(WorkerRequest::Uwsgi),
+        Protocol::Ajp => {
+            let mut request = AjpRequest::new_with_fd(context.listen_fd)?;
+            request.set_secret(context.ajp_secret.clone());
+            Some(WorkerRequest::Ajp(request))
+        }
+        Protocol::Http => DevRequest::new_with_fd(context.listen_fd).map(WorkerRequest::Http),
+    }
+}
+
+/// Makes workers blocked in accept give up when interrupted by a signal.
+pub(super) fn shutdown_pending() {
+    #[cfg(feature = "pure")]
+    crate::native::shutdown_pending();
+    #[cfg(not(feature = "pure"))]
+    unsafe { crate::capi::FCGX_ShutdownPending() };
+    crate::conn::shutdown_pending();
+}
@@ -0,0 +1,177 @@
+//! Zero-downtime binary upgrades on SIGUSR2.
+//!
+//! The old process starts its executable again, with the same arguments,
+//! and lets the new process inherit the listen socket. The socket stays
+//! open across the upgrade, so the kernel keeps queueing connections and
+//! none are refused. Once the new process reports that it is ready, the
+//! old one stops accepting and drains its requests in flight. If the new
+//! process exits before becoming ready, the old one keeps serving.
+//!
+//! While both processes share the socket, the old one must not shut the
+//! socket down to wake its workers blocked in accept, as that would stop
+//! the new process from accepting as well. Instead each worker thread is
+//! interrupted with SIGURG.
+//!
+//! The new process learns about the upgrade from two environment
+//! variables: FCGI_UPGRADE_LISTEN_FD names the inherited socket and
+//! FCGI_UPGRADE_READY_FD the pipe it writes to once it is ready. It does
+//! not bind, daemonize, chroot or switch users again, as it inherits the
+//! state the old process set up. The executable therefore has to be
+//! reachable at the same path, inside the chroot if one is set.
+
+use std::env;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::os::unix::thread::JoinHandleExt;
+use std::process::{Child, Command};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use libc;
+
+/// How often the supervising thread checks for an upgrade request and on
+/// the new process.
+pub(super) const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often workers of a shared socket are interrupted while draining.
+pub(super) const WAKE_INTERVAL: Duration = Duration::from_millis(100);
+
+const LISTEN_FD_VAR: &str = "FCGI_UPGRADE_LISTEN_FD";
+const READY_FD_VAR: &str = "FCGI_UPGRADE_READY_FD";
+
+static UPGRADE_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigusr2(_signal: libc::c_int) {
+    UPGRADE_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn on_wake(_signal: libc::c_int) {}
+
+fn install(signal: libc::c_int, handler: extern "C" fn(libc::c_int), flags: libc::c_int) -> io::Result<()> {
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handler as libc::sighandler_t;
+        action.sa_flags = flags;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(signal, &action, ptr::null_mut()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Installs the SIGUSR2 handler requesting an upgrade.
+pub(super) fn install_handler() -> io::Result<()> {
+    install(libc::SIGUSR2, on_sigusr2, libc::SA_RESTART)
+}
+
+/// Installs the SIGURG handler used by `wake`. It is installed without
+/// SA_RESTART, so a blocked accept fails with EINTR.
+pub(super) fn install_wake_handler() -> io::Result<()> {
+    install(libc::SIGURG, on_wake, 0)
+}
+
+/// Returns true once for every SIGUSR2 received since the last call.
+pub(super) fn take_request() -> bool {
+    UPGRADE_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+/// Interrupts the given threads, so workers blocked in accept notice that
+/// the server is shutting down. The handles must not have been joined.
+pub(super) fn wake(threads: &[JoinHandle<()>]) {
+    for thread in threads.iter().filter(|t| !t.is_finished()) {
+        unsafe { libc::pthread_kill(thread.as_pthread_t(), libc::SIGURG) };
+    }
+}
+
+/// The listen socket inherited from the process being upgraded, if this
+/// process is the new one.
+pub(super) fn inherited_listen_fd() -> Option<RawFd> {
+    env::var(LISTEN_FD_VAR).ok().and_then(|fd| fd.parse().ok())
+}
+
+/// Tells the old process that this one is ready to take over. Does nothing
+/// unless this process was started by an upgrade.
+pub(super) fn signal_ready() {
+    let fd = match env::var(READY_FD_VAR).ok().and_then(|fd| fd.parse::<RawFd>().ok()) {
+        Some(fd) => fd,
+        None => return,
+    };
+    env::remove_var(READY_FD_VAR);
+    env::remove_var(LISTEN_FD_VAR);
+    unsafe {
+        libc::write(fd, b"1".as_ptr() as *const libc::c_void, 1);
+        libc::close(fd);
+    }
+}
+
+/// The new process started by an upgrade, as seen by the old one.
+pub(super) struct Successor {
+    child: Child,
+    ready: RawFd,
+}
+
+impl Successor {
+    /// Starts the current executable again with the same arguments,
+    /// passing on the listen socket.
+    pub(super) fn spawn(listen_fd: RawFd) -> io::Result<Successor> {
+        let exe = env::current_exe()?;
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (ready, notify) = (fds[0], fds[1]);
+        unsafe {
+            // Sockets from systemd are close-on-exec.
+            let flags = libc::fcntl(listen_fd, libc::F_GETFD);
+            libc::fcntl(listen_fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC);
+            libc::fcntl(ready, libc::F_SETFD, libc::FD_CLOEXEC);
+            libc::fcntl(ready, libc::F_SETFL, libc::O_NONBLOCK);
+        }
+        let child = Command::new(exe)
+            .args(env::args_os().skip(1))
+            .env(LISTEN_FD_VAR, listen_fd.to_string())
+            .env(READY_FD_VAR, notify.to_string())
+            .env_remove("LISTEN_FDS")
+            .env_remove("LISTEN_PID")
+            .env_remove("LISTEN_FDNAMES")
+            .spawn();
+        unsafe { libc::close(notify) };
+        match child {
+            Ok(child) => Ok(Successor { child, ready }),
+            Err(e) => {
+                unsafe { libc::close(ready) };
+                Err(e)
+            }
+        }
+    }
+
+    /// The process id of the new process.
+    pub(super) fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// Returns Some(true) once the new process is ready, Some(false) if it
+    /// exited before, and None while it is still starting.
+    pub(super) fn poll(&mut self) -> Option<bool> {
+        let mut byte = 0u8;
+        let n = unsafe { libc::read(self.ready, &mut byte as *mut u8 as *mut libc::c_void, 1) };
+        match n {
+            1 => Some(true),
+            0 => {
+                let _ = self.child.try_wait();
+                Some(false)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Drop for Successor {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.ready) };
+    }
+}
@@ -0,0 +1,219 @@
+//! Signed URLs, for download links which work without a session until
+//! they expire. Requires the `signed-urls` feature.
+//!
+//! A `UrlSigner` appends an `expires` time and an HMAC-SHA256 `signature`
+//! to a URI. The signature covers the path and the whole query, so
+//! neither can be changed. As middleware, e.g. in front of `StaticFiles`,
+//! the signer answers requests whose signature is missing, wrong or
+//! expired with 403:
+//!
+//! ```ignore
+//! let signer = UrlSigner::new(&key);
+//! let link = signer.sign(&UriBuilder::new().path("downloads/report.pdf").build(), Duration::from_secs(3600));
+//!
+//! let downloads = Stack::new(StaticFiles::new("/downloads", "/srv/downloads")).layer(signer.clone());
+//! let router = Router::new().get("/downloads/*", downloads);
+//! ```
+//!
+//! Requests are checked against REQUEST_URI as the web server received
+//! it, so links have to be signed with the full path, including the
+//! SCRIPT_NAME the application is mounted at.
+
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::base64;
+use crate::exchange::Exchange;
+use crate::middleware::{Middleware, Next};
+use crate::urlencoding::parse_query;
+
+/// The query parameter holding the expiry time, in seconds since the
+/// epoch.
+pub const EXPIRES_PARAM: &str = "expires";
+/// The query parameter holding the signature, always the last one.
+pub const SIGNATURE_PARAM: &str = "signature";
+
+/// Why a signed URL was refused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignatureError {
+    /// The URL carries no signature.
+    Missing,
+    /// The signature does not match the URL.
+    Invalid,
+    /// The URL is signed but has expired.
+    Expired,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            SignatureError::Missing => "URL signature missing",
+            SignatureError::Invalid => "invalid URL signature",
+            SignatureError::Expired => "signed URL expired",
+        })
+    }
+}
+
+impl Error for SignatureError {}
+
+/// Signs URIs and verifies signed requests, see the module
+/// documentation. Clones share the key.
+#[derive(Clone)]
+pub struct UrlSigner {
+    key: Arc<[u8]>,
+}
+
+impl UrlSigner {
+    /// Signs with `key`, which should be at least 32 random bytes.
+    pub fn new(key: &[u8]) -> UrlSigner {
+        UrlSigner { key: Arc::from(key) }
+    }
+
+    fn mac(&self, data: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any size");
+        mac.update(data.as_bytes());
+        mac
+    }
+
+    /// Returns `uri`, a path with an optional query, signed to be valid
+    /// for `ttl` from now.
+    pub fn sign(&self, uri: &str, ttl: Duration) -> String {
+        self.sign_until(uri, SystemTime::now() + ttl)
+    }
+
+    /// Returns `uri` signed to be valid until `expires`.
+    pub fn sign_until(&self, uri: &str, expires: SystemTime) -> String {
+        let (uri, fragment) = match uri.find('#') {
+            Some(i) => uri.split_at(i),
+            None => (uri, ""),
+        };
+        let mut signed = String::from(uri);
+        if !signed.contains('?') {
+            signed.push('?');
+        } else if !signed.ends_with('?') && !signed.ends_with('&') {
+            signed.push('&');
+        }
+        let expires = expires.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        signed.push_str(&format!("{}={}", EXPIRES_PARAM, expires));
+        let signature = base64::encode_url(&self.mac(&signed).finalize().into_bytes());
+        format!("{}&{}={}{}", signed, SIGNATURE_PARAM, signature, fragment)
+    }
+
+    /// Checks a signed URI as the client requested it, path and query.
+    pub fn verify_uri(&self, uri: &str) -> Result<(), SignatureError> {
+        let (signed, signature) = uri.rsplit_once(&format!("&{}=", SIGNATURE_PARAM)).ok_or(SignatureError::Missing)?;
+        let signature = base64::decode_url(signature).ok_or(SignatureError::Invalid)?;
+        self.mac(signed).verify_slice(&signature).map_err(|_| SignatureError::Invalid)?;
+        // The signer appends the expiry last.
+        let query = signed.split_once('?').map_or("", |(_, query)| query);
+        let expires = parse_query(query).into_iter()
+            .rev()
+            .find(|(name, _)| name == EXPIRES_PARAM)
+            .and_then(|(_, expires)| expires.parse::<u64>().ok())
+            .ok_or(SignatureError::Invalid)?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        if now > expires {
+            return Err(SignatureError::Expired);
+        }
+        Ok(())
+    }
+
+    /// Checks the signature of the request, taken from REQUEST_URI, or
+    /// from SCRIPT_NAME, PATH_INFO and QUERY_STRING if the web server
+    /// does not pass that.
+    pub fn verify(&self, exchange: &Exchange) -> Result<(), SignatureError> {
+        let uri = exchange.param("REQUEST_URI").unwrap_or_else(|| {
+            let path = exchange.param("SCRIPT_NAME").unwrap_or_default() + &exchange.param("PATH_INFO").unwrap_or_default();
+            format!("{}?{}", path, exchange.query_string())
+        });
+        self.verify_uri(&uri)
+    }
+}
+
+impl fmt::Debug for UrlSigner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UrlSigner").finish_non_exhaustive()
+    }
+}
+
+impl Middleware for UrlSigner {
+    fn call(&self, exchange: &mut Exchange, next: Next) {
+        match self.verify(exchange) {
+            Ok(()) => next.run(exchange),
+            Err(_) => exchange.respond_error(403),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::{SignatureError, UrlSigner};
+
+    fn signer() -> UrlSigner {
+        UrlSigner::new(b"0123456789abcdef0123456789abcdef")
+    }
+
+    #[test]
+    fn valid() {
+        let signer = signer();
+        let signed = signer.sign("/downloads/report.pdf", Duration::from_secs(60));
+        assert_eq!(signer.verify_uri(&signed), Ok(()));
+        let signed = signer.sign("/downloads/report.pdf?inline=1", Duration::from_secs(60));
+        assert_eq!(signer.verify_uri(&signed), Ok(()));
+    }
+
+    #[test]
+    fn fragment() {
+        let signer = signer();
+        let signed = signer.sign("/page?a=1#top", Duration::from_secs(60));
+        let (uri, fragment) = signed.split_once('#').unwrap();
+        assert_eq!(fragment, "top");
+        assert_eq!(signer.verify_uri(uri), Ok(()));
+    }
+
+    #[test]
+    fn expired() {
+        let signer = signer();
+        let signed = signer.sign_until("/file", SystemTime::now() - Duration::from_secs(1));
+        assert_eq!(signer.verify_uri(&signed), Err(SignatureError::Expired));
+    }
+
+    #[test]
+    fn tampered() {
+        let signer = signer();
+        let signed = signer.sign("/file?user=1", Duration::from_secs(60));
+        assert_eq!(signer.verify_uri(&signed.replace("/file", "/other")), Err(SignatureError::Invalid));
+        assert_eq!(signer.verify_uri(&signed.replace("user=1", "user=2")), Err(SignatureError::Invalid));
+        // Extending the expiry invalidates the signature as well.
+        let (before, after) = signed.split_once("expires=").unwrap();
+        let extended = format!("{}expires=9{}", before, after);
+        assert_eq!(signer.verify_uri(&extended), Err(SignatureError::Invalid));
+        let (unsigned, signature) = signed.rsplit_once('=').unwrap();
+        let flipped = if signature.starts_with('A') { "B" } else { "A" };
+        let forged = format!("{}={}{}", unsigned, flipped, &signature[1..]);
+        assert_eq!(signer.verify_uri(&forged), Err(SignatureError::Invalid));
+        assert_eq!(signer.verify_uri(&format!("{}&user=2", signed)), Err(SignatureError::Invalid));
+    }
+
+    #[test]
+    fn other_key() {
+        let signed = signer().sign("/file", Duration::from_secs(60));
+        let other = UrlSigner::new(b"another key of thirty-two bytes!");
+        assert_eq!(other.verify_uri(&signed), Err(SignatureError::Invalid));
+    }
+
+    #[test]
+    fn missing() {
+        let signer = signer();
+        assert_eq!(signer.verify_uri("/file"), Err(SignatureError::Missing));
+        assert_eq!(signer.verify_uri("/file?expires=9999999999"), Err(SignatureError::Missing));
+        assert_eq!(signer.verify_uri("/file?expires=1&signature=%%%"), Err(SignatureError::Invalid));
+    }
+}
@@ -0,0 +1,155 @@
+//! Server-Sent Events, for pages which update live over a long-running
+//! response.
+//!
+//! An `EventStream` sends the `text/event-stream` headers at once, tells
+//! proxies not to buffer (`X-Accel-Buffering: no` for nginx) and flushes
+//! every event to the client. Once the client has gone away sending fails,
+//! so a loop producing events ends with the request:
+//!
+//! ```ignore
+//! let mut events = exchange.event_stream()?;
+//! for update in updates {
+//!     events.send(&sse::Event::new(&update.json).event("update").id(&update.id))?;
+//! }
+//! ```
+//!
+//! The request deadline applies to event streams too; leave it unset for
+//! handlers which stream for longer.
+
+use std::io;
+use std::time::Duration;
+
+use crate::exchange::Exchange;
+
+/// The Content-Type of event streams.
+pub const CONTENT_TYPE: &str = "text/event-stream";
+
+/// Removes line breaks, which would end a field early.
+fn single_line(value: &str) -> String {
+    value.replace(['\r', '\n'], "")
+}
+
+/// An event, written as `event:`, `id:`, `retry:` and `data:` lines.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Event {
+    event: Option<String>,
+    id: Option<String>,
+    retry: Option<Duration>,
+    data: String,
+}
+
+impl Event {
+    /// An unnamed event, which browsers deliver as `message`, carrying
+    /// `data`. Line breaks in the data are kept: each line is sent as a
+    /// `data:` line of its own.
+    pub fn new(data: &str) -> Event {
+        Event { data: String::from(data), ..Event::default() }
+    }
+
+    /// Sets the event name, for `addEventListener(name, ...)`.
+    pub fn event(mut self, name: &str) -> Event {
+        self.event = Some(single_line(name));
+        self
+    }
+
+    /// Sets the id which the browser sends back as Last-Event-ID when it
+    /// reconnects.
+    pub fn id(mut self, id: &str) -> Event {
+        self.id = Some(single_line(id).replace('\0', ""));
+        self
+    }
+
+    /// Sets how long the browser waits before reconnecting.
+    pub fn retry(mut self, retry: Duration) -> Event {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// Returns the event as it is sent, ending with the blank line that
+    /// dispatches it.
+    pub fn to_frame(&self) -> String {
+        let mut frame = String::new();
+        if let Some(ref event) = self.event {
+            frame.push_str("event: ");
+            frame.push_str(event);
+            frame.push('\n');
+        }
+        if let Some(ref id) = self.id {
+            frame.push_str("id: ");
+            frame.push_str(id);
+            frame.push('\n');
+        }
+        if let Some(retry) = self.retry {
+            frame.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        for line in self.data.replace("\r\n", "\n").split(['\r', '\n']) {
+            frame.push_str("data: ");
+            frame.push_str(line);
+            frame.push('\n');
+        }
+        frame.push('\n');
+        frame
+    }
+}
+
+/// Writes events to a response, see the module documentation.
+pub struct EventStream<'e, 'a> {
+    exchange: &'e mut Exchange<'a>,
+    closed: bool,
+}
+
+impl<'e, 'a> EventStream<'e, 'a> {
+    /// Starts the event stream: sets the Content-Type, disables caching
+    /// and proxy buffering and sends the headers. Fails if the headers
+    /// have been sent already.
+    pub fn new(exchange: &'e mut Exchange<'a>) -> io::Result<EventStream<'e, 'a>> {
+        if exchange.headers_sent() {
+            return Err(io::Error::other("event stream started after the headers were sent"));
+        }
+        exchange.set_header("Content-Type", CONTENT_TYPE);
+        exchange.set_header("Cache-Control", "no-cache");
+        exchange.set_header("X-Accel-Buffering", "no");
+        exchange.headers_mut().remove("Content-Length");
+        let mut stream = EventStream { exchange, closed: false };
+        stream.write_frame("")?;
+        Ok(stream)
+    }
+
+    /// Sends an event and flushes it to the client.
+    pub fn send(&mut self, event: &Event) -> io::Result<()> {
+        self.write_frame(&event.to_frame())
+    }
+
+    /// Sends an unnamed event carrying `data`.
+    pub fn data(&mut self, data: &str) -> io::Result<()> {
+        self.send(&Event::new(data))
+    }
+
+    /// Sends a comment, which clients ignore. Sent regularly, it keeps
+    /// idle connections from timing out and notices clients which left.
+    pub fn comment(&mut self, comment: &str) -> io::Result<()> {
+        let frame: String = comment.split(['\r', '\n'])
+            .filter(|line| !line.is_empty())
+            .map(|line| format!(": {}\n", line))
+            .collect();
+        self.write_frame(if frame.is_empty() { ":\n" } else { &frame })
+    }
+
+    /// True once the client has gone away or sending failed; further
+    /// events are not sent.
+    pub fn is_closed(&self) -> bool {
+        self.closed || self.exchange.is_aborted()
+    }
+
+    fn write_frame(&mut self, frame: &str) -> io::Result<()> {
+        if self.is_closed() {
+            self.closed = true;
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "event stream closed by the client"));
+        }
+        let result = self.exchange.write_body(frame.as_bytes()).and_then(|()| io::Write::flush(self.exchange));
+        if result.is_err() || self.exchange.is_aborted() {
+            self.closed = true;
+        }
+        result
+    }
+}
@@ -0,0 +1,336 @@
+//! Serving files from a directory.
+
+use std::cmp::Reverse;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::UNIX_EPOCH;
+
+use crate::exchange::Exchange;
+use crate::handler::Handler;
+use crate::middleware::{Middleware, Next};
+use crate::mime::MimeTypes;
+use crate::router::request_path;
+use crate::urlencoding::{decode_path, encode_path};
+
+/// Serves the files below a directory for all paths starting with a URL
+/// prefix, e.g. `/assets/css/site.css` from `public/css/site.css`.
+///
+/// Only GET and HEAD are accepted. Responses carry Content-Type, by file
+/// extension with the overrides set with `mime_types`, Content-Length,
+/// Last-Modified and ETag headers. Requests with a matching If-None-Match,
+/// or without one and with an If-Modified-Since not older than the file,
+/// are answered with 304. Paths which would escape the directory, through
+/// `..` segments or symbolic links, are answered with 404.
+///
+/// Requests for a directory are redirected to the path with a trailing
+/// slash and then served from the index file, `index.html` by default.
+/// Without an index file a generated listing of the directory is served if
+/// enabled with `listing(true)`, otherwise 404.
+pub struct StaticFiles {
+    prefix: String,
+    root: PathBuf,
+    index: Option<String>,
+    listing: bool,
+    mime_types: Arc<MimeTypes>,
+}
+
+impl StaticFiles {
+    /// Serves files from `root` for paths starting with `prefix`.
+    pub fn new<P: AsRef<Path>>(prefix: &str, root: P) -> StaticFiles {
+        StaticFiles {
+            prefix: String::from(prefix.trim_end_matches('/')),
+            root: root.as_ref().to_path_buf(),
+            index: Some(String::from("index.html")),
+            listing: false,
+            mime_types: Arc::new(MimeTypes::new()),
+        }
+    }
+
+    /// Sets the file served for directory requests, None to disable.
+    pub fn index_file(mut self, index: Option<&str>) -> StaticFiles {
+        self.index = index.map(String::from);
+        self
+    }
+
+    /// Enables generated listings for directories without an index file.
+    pub fn listing(mut self, enabled: bool) -> StaticFiles {
+        self.listing = enabled;
+        self
+    }
+
+    /// Sets content types which take precedence over the built-in ones.
+    pub fn mime_types(mut self, mime_types: MimeTypes) -> StaticFiles {
+        self.mime_types = Arc::new(mime_types);
+        self
+    }
+
+    /// Returns true if the path is below the URL prefix.
+    fn matches(&self, path: &str) -> bool {
+        path.strip_prefix(self.prefix.as_str())
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    /// Maps a request path to a file below the root directory.
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let rest = path.strip_prefix(self.prefix.as_str())?;
+        if !rest.is_empty() && !rest.starts_with('/') {
+            return None;
+        }
+        let mut file = self.root.clone();
+        for segment in rest.split('/') {
+            let segment = decode_path(segment)?;
+            match segment.as_str() {
+                "" | "." => continue,
+                ".." => return None,
+                s if s.contains(['\\', '\0']) => return None,
+                s => file.push(s),
+            }
+        }
+        let root = fs::canonicalize(&self.root).ok()?;
+        let file = fs::canonicalize(file).ok()?;
+        if file.starts_with(&root) {
+            Some(file)
+        } else {
+            None
+        }
+    }
+}
+
+impl Handler for StaticFiles {
+    fn handle(&self, exchange: &mut Exchange) {
+        let method = exchange.method();
+        if method != "GET" && method != "HEAD" {
+            exchange.set_header("Allow", "GET, HEAD");
+            return exchange.respond_error(405);
+        }
+        let request_path = request_path(exchange);
+        let mut path = match self.resolve(&request_path) {
+            Some(path) => path,
+            None => return exchange.respond_error(404),
+        };
+        if path.is_dir() {
+            if !request_path.ends_with('/') {
+                return redirect_to_directory(exchange);
+            }
+            let index = self.index.as_ref().map(|index| path.join(index));
+            match index {
+                Some(ref index) if index.is_file() => path = index.clone(),
+                _ if self.listing => {
+                    if list_directory(exchange, &path, method == "HEAD").is_err() && !exchange.headers_sent() {
+                        exchange.respond_error(404);
+                    }
+                    return;
+                }
+                _ => return exchange.respond_error(404),
+            }
+        }
+        if serve_file(exchange, &path, self.mime_types.get(&path), method == "HEAD").is_err() && !exchange.headers_sent() {
+            exchange.respond_error(404);
+        }
+    }
+}
+
+/// Serves directories mounted at URL prefixes in front of the handler,
+/// passing other requests on. The longest matching prefix wins. The mounts
+/// can be replaced while the server runs; the server does so for the
+/// `static_mounts` setting when its configuration is reloaded.
+pub struct StaticMounts {
+    mounts: RwLock<Vec<StaticFiles>>,
+    mime_types: Arc<MimeTypes>,
+}
+
+impl StaticMounts {
+    /// Mounts each directory at its URL prefix.
+    pub fn new(mounts: &[(String, PathBuf)]) -> StaticMounts {
+        let mime_types = Arc::new(MimeTypes::new());
+        StaticMounts { mounts: RwLock::new(StaticMounts::build(mounts, &mime_types)), mime_types }
+    }
+
+    /// Sets content types for all mounts which take precedence over the
+    /// built-in ones.
+    pub fn mime_types(self, mime_types: MimeTypes) -> StaticMounts {
+        let mime_types = Arc::new(mime_types);
+        let mut mounts = self.mounts.into_inner().unwrap_or_else(|e| e.into_inner());
+        for mount in &mut mounts {
+            mount.mime_types = mime_types.clone();
+        }
+        StaticMounts { mounts: RwLock::new(mounts), mime_types }
+    }
+
+    /// Replaces all mounts.
+    pub fn replace(&self, mounts: &[(String, PathBuf)]) {
+        *self.mounts.write().unwrap_or_else(|e| e.into_inner()) = StaticMounts::build(mounts, &self.mime_types);
+    }
+
+    fn build(mounts: &[(String, PathBuf)], mime_types: &Arc<MimeTypes>) -> Vec<StaticFiles> {
+        let mut mounts: Vec<StaticFiles> = mounts.iter()
+            .map(|(prefix, root)| StaticFiles { mime_types: mime_types.clone(), ..StaticFiles::new(prefix, root) })
+            .collect();
+        mounts.sort_by_key(|mount| Reverse(mount.prefix.len()));
+        mounts
+    }
+}
+
+impl Middleware for StaticMounts {
+    fn call(&self, exchange: &mut Exchange, next: Next) {
+        let path = request_path(exchange);
+        let mounts = self.mounts.read().unwrap_or_else(|e| e.into_inner());
+        match mounts.iter().find(|mount| mount.matches(&path)) {
+            Some(mount) => mount.handle(exchange),
+            None => {
+                drop(mounts);
+                next.run(exchange)
+            }
+        }
+    }
+}
+
+/// Redirects to the requested URI with a slash appended to its path.
+fn redirect_to_directory(exchange: &mut Exchange) {
+    let uri = exchange.param("REQUEST_URI").unwrap_or_default();
+    let location = match uri.find('?') {
+        Some(q) => format!("{}/{}", &uri[..q], &uri[q..]),
+        None => format!("{}/", uri),
+    };
+    exchange.set_status(301);
+    exchange.set_header("Location", &location);
+}
+
+fn list_directory(exchange: &mut Exchange, dir: &Path, head_only: bool) -> io::Result<()> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let mut name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        if name.starts_with('.') {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            name.push('/');
+        }
+        entries.push(name);
+    }
+    entries.sort();
+
+    let title = html_escape(&exchange.param("REQUEST_URI").unwrap_or_default());
+    let mut page = format!("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n\
+                            <body><h1>Index of {0}</h1>\n<ul>\n<li><a href=\"../\">../</a></li>\n", title);
+    for name in &entries {
+        page.push_str(&format!("<li><a href=\"{}\">{}</a></li>\n", encode_path(name), html_escape(name)));
+    }
+    page.push_str("</ul></body></html>\n");
+
+    exchange.set_header("Content-Type", "text/html; charset=utf-8");
+    exchange.set_header("Content-Length", &page.len().to_string());
+    if head_only {
+        return exchange.send_headers();
+    }
+    exchange.write_body(page.as_bytes())
+}
+
+fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn serve_file(exchange: &mut Exchange, path: &Path, content_type: &str, head_only: bool) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    let meta = file.metadata()?;
+    if !meta.is_file() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "not a regular file"));
+    }
+    let modified = meta.modified().unwrap_or(UNIX_EPOCH);
+    let mtime = modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let etag = format!("\"{:x}-{:x}\"", mtime, meta.len());
+
+    exchange.set_header("ETag", &etag);
+    exchange.set_last_modified(modified);
+    let not_modified = match exchange.header("If-None-Match") {
+        Some(if_none_match) => if_none_match.split(',').any(|tag| tag.trim() == etag || tag.trim() == "*"),
+        None => !exchange.modified_since(modified),
+    };
+    if not_modified {
+        exchange.set_status(304);
+        return exchange.send_headers();
+    }
+    exchange.set_header("Content-Type", content_type);
+    exchange.set_header("Content-Length", &meta.len().to_string());
+    if head_only {
+        return exchange.send_headers();
+    }
+    io::copy(&mut file, exchange)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+    use std::process;
+
+    use super::StaticFiles;
+    use crate::testing::{Response, TestRequest};
+
+    /// A directory `public` with a file, an index and a link out of it,
+    /// next to `secret.txt`.
+    fn root(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("fcgi-static-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("public/docs")).unwrap();
+        fs::write(dir.join("secret.txt"), "secret").unwrap();
+        fs::write(dir.join("public/my file.txt"), "hello").unwrap();
+        fs::write(dir.join("public/docs/index.html"), "<h1>docs</h1>").unwrap();
+        symlink(dir.join("secret.txt"), dir.join("public/link.txt")).unwrap();
+        dir
+    }
+
+    fn get(files: &StaticFiles, uri: &str) -> Response {
+        TestRequest::get(uri).run(files)
+    }
+
+    #[test]
+    fn serves_files() {
+        let dir = root("serve");
+        let files = StaticFiles::new("/assets", dir.join("public"));
+        let response = get(&files, "/assets/my%20file.txt");
+        assert_eq!((response.status, &response.body[..]), (200, &b"hello"[..]));
+        assert_eq!(response.header("Content-Type"), Some("text/plain; charset=utf-8"));
+        assert_eq!(response.header("Content-Length"), Some("5"));
+        let etag = String::from(response.header("ETag").unwrap());
+        let response = TestRequest::get("/assets/my%20file.txt").header("If-None-Match", &etag).run(&files);
+        assert_eq!((response.status, response.body.len()), (304, 0));
+        assert_eq!(get(&files, "/assets/docs").header("Location"), Some("/assets/docs/"));
+        assert_eq!(get(&files, "/assets/docs/").body, b"<h1>docs</h1>");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn traversal() {
+        let dir = root("traversal");
+        let files = StaticFiles::new("/assets", dir.join("public"));
+        for uri in ["/assets/../secret.txt", "/assets/%2e%2e/secret.txt", "/assets/docs/..%2F..%2Fsecret.txt",
+                    "/assets/..%5Csecret.txt", "/assets/link.txt", "/assets/missing.txt", "/assets/%zz",
+                    "/assetssecret.txt"] {
+            let response = get(&files, uri);
+            assert_eq!(response.status, 404, "{}", uri);
+            assert!(!response.body.ends_with(b"secret"), "{}", uri);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
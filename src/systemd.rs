@@ -0,0 +1,161 @@
+//! Integration with systemd service management.
+//!
+//! With socket activation systemd binds the listen socket itself and passes
+//! it to the service as fd 3 (`SD_LISTEN_FDS_START`), announcing it in the
+//! LISTEN_FDS and LISTEN_PID variables. `ServerBuilder::new` picks up such
+//! a socket automatically, so a unit like
+//!
+//! ```text
+//! # app.socket
+//! [Socket]
+//! ListenStream=/run/app.sock
+//!
+//! # app.service
+//! [Service]
+//! ExecStart=/usr/bin/app
+//! ```
+//!
+//! works without spawn-fcgi.
+//!
+//! The server also reports its state to systemd if NOTIFY_SOCKET is set:
+//! READY=1 once the workers run, STOPPING=1 when shutdown starts and,
+//! with `WatchdogSec=` configured, WATCHDOG=1 while workers are alive.
+
+use std::env;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixDatagram;
+use std::time::{Duration, Instant};
+
+use libc;
+
+/// The first file descriptor passed by systemd.
+pub const LISTEN_FDS_START: RawFd = 3;
+
+/// Returns the sockets passed by systemd socket activation, or an empty
+/// list if the process was not socket-activated. The descriptors are
+/// marked close-on-exec. If `unset_env` is true the LISTEN_* variables are
+/// removed so child processes do not mistake the sockets for their own.
+pub fn listen_fds(unset_env: bool) -> Vec<RawFd> {
+    let fds = passed_fds();
+    if unset_env {
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+    }
+    for &fd in &fds {
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFD);
+            if flags >= 0 {
+                libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC);
+            }
+        }
+    }
+    fds
+}
+
+fn passed_fds() -> Vec<RawFd> {
+    let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+    if pid != Some(std::process::id()) {
+        return Vec::new();
+    }
+    let count = match env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<RawFd>().ok()) {
+        Some(count) if count > 0 => count,
+        _ => return Vec::new(),
+    };
+    (LISTEN_FDS_START..LISTEN_FDS_START + count).collect()
+}
+
+/// Sends a state update such as "READY=1" to the service manager. Returns
+/// false if the process is not run by systemd with notification support.
+pub fn notify(state: &str) -> io::Result<bool> {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(false),
+    };
+    let socket = UnixDatagram::unbound()?;
+    let bytes = path.to_string_lossy().into_owned();
+    if let Some(name) = bytes.strip_prefix('@') {
+        send_abstract(&socket, name, state)?;
+    } else {
+        socket.send_to(state.as_bytes(), &path)?;
+    }
+    Ok(true)
+}
+
+#[cfg(target_os = "linux")]
+fn send_abstract(socket: &UnixDatagram, name: &str, state: &str) -> io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::SocketAddr;
+
+    let addr = SocketAddr::from_abstract_name(name.as_bytes())?;
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_abstract(_socket: &UnixDatagram, _name: &str, _state: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "abstract sockets are only supported on Linux"))
+}
+
+/// The watchdog interval requested with `WatchdogSec=`, if it applies to
+/// this process. Keep-alive pings should be sent at half this interval.
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Some(pid) = env::var("WATCHDOG_PID").ok().and_then(|pid| pid.parse::<u32>().ok()) {
+        if pid != std::process::id() {
+            return None;
+        }
+    }
+    match env::var("WATCHDOG_USEC").ok().and_then(|usec| usec.parse::<u64>().ok()) {
+        Some(usec) if usec > 0 => Some(Duration::from_micros(usec)),
+        _ => None,
+    }
+}
+
+/// Sends lifecycle notifications and watchdog pings from the server's
+/// supervising loop.
+pub(crate) struct Notifier {
+    enabled: bool,
+    watchdog: Option<Duration>,
+    last_ping: Instant,
+}
+
+impl Notifier {
+    pub(crate) fn from_env() -> Notifier {
+        Notifier {
+            enabled: true,
+            watchdog: watchdog_interval().map(|interval| interval / 2),
+            last_ping: Instant::now(),
+        }
+    }
+
+    /// A notifier which sends nothing, for processes which are not the
+    /// service's main process.
+    pub(crate) fn disabled() -> Notifier {
+        Notifier { enabled: false, watchdog: None, last_ping: Instant::now() }
+    }
+
+    /// Sends a state update, ignoring errors: the server runs the same
+    /// whether systemd hears from it or not.
+    pub(crate) fn notify(&self, state: &str) {
+        if self.enabled {
+            let _ = notify(state);
+        }
+    }
+
+    /// How long the supervising loop may block before the next watchdog
+    /// ping is due, None without a watchdog.
+    pub(crate) fn wait_time(&self) -> Option<Duration> {
+        self.watchdog.map(|interval| interval.saturating_sub(self.last_ping.elapsed()))
+    }
+
+    /// Sends a watchdog ping if one is due and the server is healthy.
+    pub(crate) fn tick(&mut self, healthy: bool) {
+        if let Some(interval) = self.watchdog {
+            if healthy && self.last_ping.elapsed() >= interval {
+                self.notify("WATCHDOG=1");
+                self.last_ping = Instant::now();
+            }
+        }
+    }
+}
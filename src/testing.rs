@@ -0,0 +1,164 @@
+//! A request for the unit tests, with fixed parameters and input, which
+//! collects its output.
+
+use std::os::unix::io::RawFd;
+
+use crate::exchange::Exchange;
+use crate::handler::Handler;
+use crate::protocol::Role;
+use crate::{Request, StreamType};
+
+pub struct TestRequest {
+    role: Role,
+    params: Vec<(String, String)>,
+    input: Vec<u8>,
+    input_pos: usize,
+    output: Vec<u8>,
+}
+
+impl TestRequest {
+    /// A request for `uri` with `method`, the path as PATH_INFO.
+    pub fn with_method(method: &str, uri: &str) -> TestRequest {
+        let path = uri.split('?').next().unwrap_or("");
+        let query = uri.split_once('?').map_or("", |(_, query)| query);
+        TestRequest { role: Role::Responder, params: Vec::new(), input: Vec::new(), input_pos: 0, output: Vec::new() }
+            .param("REQUEST_METHOD", method)
+            .param("REQUEST_URI", uri)
+            .param("PATH_INFO", path)
+            .param("QUERY_STRING", query)
+    }
+
+    /// A GET request for `uri`.
+    pub fn get(uri: &str) -> TestRequest {
+        TestRequest::with_method("GET", uri)
+    }
+
+    /// Sets the role of the request, responder by default.
+    pub fn role(mut self, role: Role) -> TestRequest {
+        self.role = role;
+        self
+    }
+
+    /// Sets a parameter, replacing one of the same name.
+    pub fn param(mut self, name: &str, value: &str) -> TestRequest {
+        self.params.retain(|(n, _)| n != name);
+        self.params.push((String::from(name), String::from(value)));
+        self
+    }
+
+    /// Adds a request header, as the web server passes it.
+    pub fn header(self, name: &str, value: &str) -> TestRequest {
+        let name = name.to_ascii_uppercase().replace('-', "_");
+        let name = match name.as_str() {
+            "CONTENT_TYPE" | "CONTENT_LENGTH" => name,
+            _ => format!("HTTP_{}", name),
+        };
+        self.param(&name, value)
+    }
+
+    /// Runs `handler` for the request as the server does and returns the
+    /// response.
+    pub fn run<H: Handler + ?Sized>(&mut self, handler: &H) -> Response {
+        self.output.clear();
+        {
+            let mut exchange = Exchange::new(self);
+            handler.handle(&mut exchange);
+            exchange.finish().unwrap();
+        }
+        Response::parse(&self.output)
+    }
+}
+
+/// A response as written by an exchange.
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    fn parse(output: &[u8]) -> Response {
+        let end = output.windows(4).position(|w| w == b"\r\n\r\n").expect("response without a head");
+        let head = String::from_utf8_lossy(&output[..end]);
+        let mut status = 200;
+        let mut headers = Vec::new();
+        for line in head.split("\r\n") {
+            let (name, value) = line.split_once(": ").expect("bad response header");
+            if name == "Status" {
+                status = value[..3].parse().unwrap();
+            } else {
+                headers.push((String::from(name), String::from(value)));
+            }
+        }
+        Response { status, headers, body: output[end + 4..].to_vec() }
+    }
+
+    /// Returns the value of a header.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+}
+
+impl Request for TestRequest {
+    fn new() -> Option<TestRequest> {
+        None
+    }
+
+    fn new_with_fd(_fd: RawFd) -> Option<TestRequest> {
+        None
+    }
+
+    fn accept(&mut self) -> bool {
+        false
+    }
+
+    fn finish(&mut self) {}
+
+    fn get_param(&self, name: &str) -> Option<String> {
+        self.params.iter().find(|(n, _)| n == name).map(|(_, value)| value.clone())
+    }
+
+    fn write(&mut self, msg: &str) -> i32 {
+        self.write_bytes(msg.as_bytes())
+    }
+
+    fn error(&mut self, msg: &str) -> i32 {
+        msg.len() as i32
+    }
+
+    fn write_bytes(&mut self, buf: &[u8]) -> i32 {
+        self.output.extend_from_slice(buf);
+        buf.len() as i32
+    }
+
+    fn read_bytes(&mut self, buf: &mut [u8]) -> i32 {
+        let n = buf.len().min(self.input.len() - self.input_pos);
+        buf[..n].copy_from_slice(&self.input[self.input_pos..self.input_pos + n]);
+        self.input_pos += n;
+        n as i32
+    }
+
+    fn readall(&mut self) -> String {
+        let rest = String::from_utf8_lossy(&self.input[self.input_pos..]).into_owned();
+        self.input_pos = self.input.len();
+        rest
+    }
+
+    fn read(&mut self, n: i32) -> (String, i32) {
+        let mut buffer = vec![0; n.max(0) as usize];
+        let count = self.read_bytes(&mut buffer);
+        buffer.truncate(count as usize);
+        (String::from_utf8_lossy(&buffer).into_owned(), count)
+    }
+
+    fn flush(&mut self, _stream_type: StreamType) {}
+
+    fn role(&self) -> Role {
+        self.role
+    }
+
+    fn params(&self) -> Vec<(String, String)> {
+        self.params.clone()
+    }
+}
@@ -0,0 +1,184 @@
+//! TLS on the TCP listener of the native transport, with the `tls` feature.
+//!
+//! When the connection between the web server and the application crosses
+//! an untrusted network, `Listener::set_tls` (or the `tls_cert` and
+//! `tls_key` settings of the server) encrypts it with rustls instead of a
+//! separate stunnel. Each accepted connection gets a thread which performs
+//! the handshake and then moves data between the TLS session and a local
+//! socket pair, whose other end the transport reads like a plain
+//! connection. With `tls_client_ca` the web server must present a
+//! certificate signed by that CA.
+
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+
+use libc;
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig, ServerConnection};
+
+/// Plaintext waiting for the application before no more TLS data is read.
+const PENDING_LIMIT: usize = 64 * 1024;
+
+fn invalid<E: ToString>(e: E) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+fn read_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_reader_iter(BufReader::new(File::open(path)?))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(invalid)?;
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificate in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+/// Loads the certificate chain and the private key from PEM files. With a
+/// `client_ca` file, connections must present a certificate signed by one
+/// of its certificates.
+pub fn server_config(cert: &Path, key: &Path, client_ca: Option<&Path>) -> io::Result<Arc<ServerConfig>> {
+    let certs = read_certs(cert)?;
+    let key = PrivateKeyDer::from_pem_reader(BufReader::new(File::open(key)?)).map_err(invalid)?;
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid)?;
+    let builder = match client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(path)? {
+                roots.add(cert).map_err(invalid)?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(invalid)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(certs, key).map_err(invalid)?;
+    Ok(Arc::new(config))
+}
+
+/// Starts a thread terminating TLS on the connection and returns the local
+/// socket carrying its plaintext.
+pub(crate) fn terminate(stream: TcpStream, config: Arc<ServerConfig>) -> io::Result<UnixStream> {
+    let connection = ServerConnection::new(config).map_err(invalid)?;
+    let (local, remote) = UnixStream::pair()?;
+    stream.set_nonblocking(true)?;
+    remote.set_nonblocking(true)?;
+    thread::Builder::new().name(String::from("fcgi-tls")).spawn(move || {
+        if let Err(e) = pump(connection, stream, remote) {
+            if e.kind() != ErrorKind::ConnectionReset && e.kind() != ErrorKind::BrokenPipe {
+                server_log!(warn, "TLS connection failed: {}", e);
+            }
+        }
+    })?;
+    Ok(local)
+}
+
+/// Moves data between the TLS session and the plaintext socket until both
+/// directions are closed.
+fn pump(mut tls: ServerConnection, mut stream: TcpStream, mut plain: UnixStream) -> io::Result<()> {
+    let mut to_plain: Vec<u8> = Vec::new();
+    let mut buf = vec![0; 16 * 1024];
+    let mut stream_eof = false;
+    let mut plain_eof = false;
+    loop {
+        if stream_eof && to_plain.is_empty() {
+            let _ = plain.shutdown(Shutdown::Write);
+        }
+        if plain_eof && !tls.wants_write() {
+            return Ok(());
+        }
+        if stream_eof && plain_eof {
+            return Ok(());
+        }
+        let mut stream_events = 0;
+        if !stream_eof && tls.wants_read() && to_plain.len() < PENDING_LIMIT {
+            stream_events |= libc::POLLIN;
+        }
+        if tls.wants_write() {
+            stream_events |= libc::POLLOUT;
+        }
+        let mut plain_events = 0;
+        if !to_plain.is_empty() {
+            plain_events |= libc::POLLOUT;
+        }
+        if !plain_eof && !tls.is_handshaking() && !tls.wants_write() {
+            plain_events |= libc::POLLIN;
+        }
+        let mut fds = [
+            libc::pollfd { fd: stream.as_raw_fd(), events: stream_events, revents: 0 },
+            libc::pollfd { fd: plain.as_raw_fd(), events: plain_events, revents: 0 },
+        ];
+        if unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        let ready = |revents: libc::c_short| revents & (libc::POLLIN | libc::POLLHUP | libc::POLLERR) != 0;
+        if fds[0].revents & libc::POLLOUT != 0 {
+            match tls.write_tls(&mut stream) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if stream_events & libc::POLLIN != 0 && ready(fds[0].revents) {
+            match tls.read_tls(&mut stream) {
+                Ok(0) => stream_eof = true,
+                Ok(_) => {
+                    if let Err(e) = tls.process_new_packets() {
+                        let _ = tls.write_tls(&mut stream);
+                        return Err(invalid(e));
+                    }
+                    loop {
+                        match tls.reader().read(&mut buf) {
+                            Ok(0) => {
+                                stream_eof = true;
+                                break;
+                            }
+                            Ok(n) => to_plain.extend_from_slice(&buf[..n]),
+                            Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                            Err(e) => return Err(e),
+                        }
+                    }
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if fds[1].revents & (libc::POLLOUT | libc::POLLERR | libc::POLLHUP) != 0 && !to_plain.is_empty() {
+            match plain.write(&to_plain) {
+                Ok(n) => {
+                    to_plain.drain(..n);
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if plain_events & libc::POLLIN != 0 && ready(fds[1].revents) {
+            match plain.read(&mut buf) {
+                Ok(0) => {
+                    plain_eof = true;
+                    tls.send_close_notify();
+                }
+                Ok(n) => tls.writer().write_all(&buf[..n])?,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
@@ -0,0 +1,292 @@
+//! Storage for uploaded files, on top of the `multipart` parser.
+//!
+//! `Uploads::read` collects the parts of a form: text fields are kept as
+//! strings, files in memory while small and spooled to temporary files
+//! beyond that. Temporary files are removed when the `UploadedFile` is
+//! dropped, unless it was `persist`ed:
+//!
+//! ```ignore
+//! let form = Uploads::new().max_part_size(50 << 20).read(&mut exchange.multipart()?)?;
+//! if let Some(avatar) = form.file("avatar") {
+//!     avatar.persist(format!("/srv/avatars/{}.png", user_id))?;
+//! }
+//! ```
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::multipart::{Multipart, Part};
+
+/// Makes the names of temporary files unique within the process.
+static NEXT_FILE: AtomicUsize = AtomicUsize::new(0);
+
+fn too_large(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::FileTooLarge, message)
+}
+
+/// A temporary file, removed on drop.
+struct TempFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl TempFile {
+    /// Creates a file only the current user can read in `dir`.
+    fn create(dir: &Path) -> io::Result<TempFile> {
+        loop {
+            let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.subsec_nanos());
+            let name = format!("fcgi-upload-{}-{}-{}", process::id(), NEXT_FILE.fetch_add(1, Ordering::Relaxed), nanos);
+            let path = dir.join(name);
+            match OpenOptions::new().read(true).write(true).create_new(true).mode(0o600).open(&path) {
+                Ok(file) => return Ok(TempFile { path, file }),
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Where the data of an uploaded file is kept.
+enum Storage {
+    Memory(Vec<u8>),
+    File(TempFile),
+}
+
+/// A file of an uploaded form.
+pub struct UploadedFile {
+    name: String,
+    filename: Option<String>,
+    content_type: Option<String>,
+    size: u64,
+    storage: Storage,
+}
+
+impl UploadedFile {
+    /// The form field name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The file name sent by the client: do not use it as a path without
+    /// sanitizing it.
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    /// The Content-Type sent by the client.
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    /// The size of the data in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The temporary file holding the data, None if the data is held in
+    /// memory. The file is removed when the upload is dropped.
+    pub fn path(&self) -> Option<&Path> {
+        match self.storage {
+            Storage::Memory(_) => None,
+            Storage::File(ref temp) => Some(&temp.path),
+        }
+    }
+
+    /// Returns a reader over the data.
+    pub fn reader(&self) -> io::Result<Box<dyn Read + '_>> {
+        match self.storage {
+            Storage::Memory(ref data) => Ok(Box::new(Cursor::new(data.as_slice()))),
+            Storage::File(ref temp) => Ok(Box::new(File::open(&temp.path)?)),
+        }
+    }
+
+    /// Reads the whole data into memory.
+    pub fn bytes(&self) -> io::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(self.size as usize);
+        self.reader()?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Moves the data to `path`, renaming the temporary file if it is on
+    /// the same file system and copying it otherwise. Renamed files keep
+    /// the mode 0600 of the temporary file.
+    pub fn persist<P: AsRef<Path>>(self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        match self.storage {
+            Storage::Memory(ref data) => fs::write(path, data),
+            Storage::File(ref temp) => {
+                if fs::rename(&temp.path, path).is_err() {
+                    fs::copy(&temp.path, path)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The fields and files of a form read by `Uploads::read`.
+#[derive(Default)]
+pub struct Form {
+    fields: Vec<(String, String)>,
+    files: Vec<UploadedFile>,
+}
+
+impl Form {
+    /// Returns the first value of a text field.
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    /// All text fields in the order they were sent.
+    pub fn fields(&self) -> &[(String, String)] {
+        &self.fields
+    }
+
+    /// Returns the first file uploaded as the given field.
+    pub fn file(&self, name: &str) -> Option<&UploadedFile> {
+        self.files.iter().find(|file| file.name == name)
+    }
+
+    /// All uploaded files in the order they were sent.
+    pub fn files(&self) -> &[UploadedFile] {
+        &self.files
+    }
+
+    /// Takes the uploaded files, e.g. to persist them.
+    pub fn into_files(self) -> Vec<UploadedFile> {
+        self.files
+    }
+}
+
+/// Reads multipart forms with limits, spooling large files to disk.
+#[derive(Clone, Debug)]
+pub struct Uploads {
+    max_part_size: u64,
+    max_total_size: u64,
+    max_field_size: usize,
+    memory_threshold: usize,
+    temp_dir: Option<PathBuf>,
+}
+
+impl Default for Uploads {
+    fn default() -> Uploads {
+        Uploads::new()
+    }
+}
+
+impl Uploads {
+    /// Accepts files of up to 10 MiB, 32 MiB in total and text fields of
+    /// up to 64 KiB. Files over 64 KiB go to the temporary directory.
+    pub fn new() -> Uploads {
+        Uploads {
+            max_part_size: 10 << 20,
+            max_total_size: 32 << 20,
+            max_field_size: 64 << 10,
+            memory_threshold: 64 << 10,
+            temp_dir: None,
+        }
+    }
+
+    /// Sets the maximum size of a single file.
+    pub fn max_part_size(mut self, bytes: u64) -> Uploads {
+        self.max_part_size = bytes;
+        self
+    }
+
+    /// Sets the maximum size of all parts of a form together.
+    pub fn max_total_size(mut self, bytes: u64) -> Uploads {
+        self.max_total_size = bytes;
+        self
+    }
+
+    /// Sets the maximum size of a text field, which is held in memory.
+    pub fn max_field_size(mut self, bytes: usize) -> Uploads {
+        self.max_field_size = bytes;
+        self
+    }
+
+    /// Files larger than this are written to a temporary file.
+    pub fn memory_threshold(mut self, bytes: usize) -> Uploads {
+        self.memory_threshold = bytes;
+        self
+    }
+
+    /// Sets the directory for temporary files, `std::env::temp_dir()`
+    /// unless set.
+    pub fn temp_dir<P: Into<PathBuf>>(mut self, dir: P) -> Uploads {
+        self.temp_dir = Some(dir.into());
+        self
+    }
+
+    /// Reads all parts of `multipart`. Parts with a file name become
+    /// `UploadedFile`s, the others text fields. Fails with `FileTooLarge`
+    /// once a limit is exceeded, which handlers would usually answer with
+    /// 413; files stored so far are removed.
+    pub fn read<R: Read>(&self, multipart: &mut Multipart<R>) -> io::Result<Form> {
+        let mut form = Form::default();
+        let mut total = 0;
+        while let Some(mut part) = multipart.next_part()? {
+            let name = String::from(part.name().unwrap_or(""));
+            if part.filename().is_none() {
+                let limit = (self.max_field_size as u64).min(self.max_total_size - total);
+                let data = read_limited(&mut part, limit, "form field exceeds the size limit")?;
+                total += data.len() as u64;
+                form.fields.push((name, String::from_utf8_lossy(&data).into_owned()));
+                continue;
+            }
+            let file = self.store(&mut part, name, self.max_part_size.min(self.max_total_size - total))?;
+            total += file.size;
+            form.files.push(file);
+        }
+        Ok(form)
+    }
+
+    /// Stores the data of a file part of at most `limit` bytes, in memory
+    /// up to the threshold and in a temporary file beyond.
+    fn store<R: Read>(&self, part: &mut Part<'_, R>, name: String, limit: u64) -> io::Result<UploadedFile> {
+        let filename = part.filename().map(String::from);
+        let content_type = part.content_type().map(String::from);
+        let in_memory = (self.memory_threshold as u64).min(limit);
+        let mut data = Vec::new();
+        part.take(in_memory + 1).read_to_end(&mut data)?;
+        let mut size = data.len() as u64;
+        let storage = if size <= in_memory {
+            Storage::Memory(data)
+        } else if size > limit {
+            return Err(too_large("uploaded file exceeds the size limit"));
+        } else {
+            let dir = self.temp_dir.clone().unwrap_or_else(env::temp_dir);
+            let mut temp = TempFile::create(&dir)?;
+            temp.file.write_all(&data)?;
+            size += io::copy(&mut part.take(limit - size + 1), &mut temp.file)?;
+            if size > limit {
+                return Err(too_large("uploaded file exceeds the size limit"));
+            }
+            Storage::File(temp)
+        };
+        Ok(UploadedFile { name, filename, content_type, size, storage })
+    }
+}
+
+/// Reads at most `limit` bytes, failing with `FileTooLarge` if there are
+/// more.
+fn read_limited<R: Read>(reader: &mut R, limit: u64, message: &str) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    reader.take(limit.saturating_add(1)).read_to_end(&mut data)?;
+    if data.len() as u64 > limit {
+        return Err(too_large(message));
+    }
+    Ok(data)
+}
@@ -0,0 +1,104 @@
+//! Building links and redirect targets from untrusted parts.
+//!
+//! `UriBuilder` percent-encodes every path segment, query parameter and
+//! fragment it is given, so user data cannot break the link or smuggle a
+//! line break into a Location header. Built from a request, paths start at
+//! the SCRIPT_NAME the application is mounted at:
+//!
+//! ```ignore
+//! let uri = UriBuilder::for_request(exchange).segment("users").segment(&name).query("tab", "posts");
+//! exchange.redirect(303, &uri);
+//! ```
+
+use std::fmt;
+
+use crate::exchange::Exchange;
+use crate::urlencoding::{build_query, encode_path, encode_path_segment};
+
+/// Builds a path with query and fragment, see the module documentation.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UriBuilder {
+    /// The encoded path.
+    path: String,
+    query: Vec<(String, String)>,
+    fragment: Option<String>,
+}
+
+impl UriBuilder {
+    /// Starts at the root, `/`.
+    pub fn new() -> UriBuilder {
+        UriBuilder::default()
+    }
+
+    /// Starts at the path the application is mounted at: SCRIPT_NAME if
+    /// the web server passes the rest of the path as PATH_INFO, else the
+    /// root, matching what the router sees.
+    pub fn for_request(exchange: &Exchange) -> UriBuilder {
+        let mut builder = UriBuilder::new();
+        if exchange.param("PATH_INFO").is_some_and(|path| !path.is_empty()) {
+            builder = builder.path(&exchange.param("SCRIPT_NAME").unwrap_or_default());
+        }
+        builder
+    }
+
+    /// Appends a single segment, escaping any `/` in it, and the dots of
+    /// `.` and `..` which clients would resolve.
+    pub fn segment(mut self, segment: &str) -> UriBuilder {
+        if !self.path.ends_with('/') {
+            self.path.push('/');
+        }
+        match segment {
+            "." => self.path.push_str("%2E"),
+            ".." => self.path.push_str("%2E%2E"),
+            _ => self.path.push_str(&encode_path_segment(segment)),
+        }
+        self
+    }
+
+    /// Appends a path whose `/` separate segments, e.g. `docs/intro`.
+    pub fn path(mut self, path: &str) -> UriBuilder {
+        let path = path.strip_prefix('/').unwrap_or(path);
+        if !self.path.ends_with('/') {
+            self.path.push('/');
+        }
+        self.path.push_str(&encode_path(path));
+        self
+    }
+
+    /// Adds a query parameter.
+    pub fn query(mut self, name: &str, value: &str) -> UriBuilder {
+        self.query.push((String::from(name), String::from(value)));
+        self
+    }
+
+    /// Sets the fragment, without the `#`.
+    pub fn fragment(mut self, fragment: &str) -> UriBuilder {
+        self.fragment = Some(String::from(fragment));
+        self
+    }
+
+    /// Returns the URI, e.g. `/app/users/J%C3%B6rg?tab=posts`.
+    pub fn build(&self) -> String {
+        let mut uri = match self.path.as_str() {
+            "" => String::from("/"),
+            // A leading "//" would make a link to another host.
+            path if path.starts_with("//") => format!("/%2F{}", path.trim_start_matches('/')),
+            path => String::from(path),
+        };
+        if !self.query.is_empty() {
+            uri.push('?');
+            uri.push_str(&build_query(self.query.iter().map(|(name, value)| (name, value))));
+        }
+        if let Some(ref fragment) = self.fragment {
+            uri.push('#');
+            uri.push_str(&encode_path(fragment));
+        }
+        uri
+    }
+}
+
+impl fmt::Display for UriBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.build())
+    }
+}
@@ -0,0 +1,188 @@
+//! Percent-encoding and decoding of URL paths and query strings, as used by
+//! the router, static files and query parsing.
+//!
+//! Paths and queries differ in one point: in a query string, and in
+//! `application/x-www-form-urlencoded` forms, `+` stands for a space,
+//! while in a path it is a literal `+`.
+//!
+//! ```ignore
+//! assert_eq!(urlencoding::decode_path("/a+b%20c").as_deref(), Some("/a+b c"));
+//! assert_eq!(urlencoding::decode_query("a+b%20c").as_deref(), Some("a b c"));
+//! assert_eq!(urlencoding::encode_query("1 + 1 = 2"), "1+%2B+1+%3D+2");
+//! assert_eq!(urlencoding::encode_path("/files/my report.pdf"), "/files/my%20report.pdf");
+//! ```
+
+fn hex_value(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Decodes `%XX` escapes, and `+` as a space if `plus` is set. Malformed
+/// escapes are kept as they are if `strict` is not set, and make decoding
+/// fail otherwise.
+fn decode_bytes(input: &str, plus: bool, strict: bool) -> Option<Vec<u8>> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let escape = bytes.get(i + 1..i + 3)
+                    .and_then(|hex| Some(hex_value(hex[0])? << 4 | hex_value(hex[1])?));
+                match escape {
+                    Some(b) => {
+                        decoded.push(b);
+                        i += 3;
+                        continue;
+                    }
+                    None if strict => return None,
+                    None => decoded.push(b'%'),
+                }
+            }
+            b'+' if plus => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    Some(decoded)
+}
+
+/// Decodes `%XX` escapes in a path or path segment, leaving `+` alone.
+/// Returns None for malformed escapes or if the result is not valid UTF-8.
+pub fn decode_path(input: &str) -> Option<String> {
+    String::from_utf8(decode_bytes(input, false, true)?).ok()
+}
+
+/// Decodes a component of a query string or form, where `+` is a space.
+/// Returns None for malformed escapes or if the result is not valid UTF-8.
+pub fn decode_query(input: &str) -> Option<String> {
+    String::from_utf8(decode_bytes(input, true, true)?).ok()
+}
+
+/// Decodes a component of a query string like `decode_query`, but keeps
+/// malformed escapes as they are and replaces invalid UTF-8, the way
+/// browsers and `application/x-www-form-urlencoded` parsers do.
+pub fn decode_query_lossy(input: &str) -> String {
+    let decoded = decode_bytes(input, true, false).unwrap_or_default();
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Splits a query string into decoded name and value pairs, in order.
+/// Names without `=` get an empty value; empty components are skipped.
+pub fn parse_query(query: &str) -> Vec<(String, String)> {
+    query.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode_query_lossy(name), decode_query_lossy(value))
+        })
+        .collect()
+}
+
+/// Percent-encodes every byte of `input` except the unreserved characters
+/// and those in `keep`, and a space as `+` if `plus` is set.
+fn encode(input: &str, keep: &[u8], plus: bool) -> String {
+    let mut encoded = String::with_capacity(input.len());
+    for &b in input.as_bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(b as char),
+            b' ' if plus => encoded.push('+'),
+            _ if keep.contains(&b) => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+/// Encodes a single path segment: everything but the unreserved
+/// characters is escaped, including `/`.
+pub fn encode_path_segment(input: &str) -> String {
+    encode(input, b"", false)
+}
+
+/// Encodes a path, keeping its `/` separators.
+pub fn encode_path(input: &str) -> String {
+    encode(input, b"/", false)
+}
+
+/// Encodes a name or value for a query string or form, with spaces as
+/// `+`.
+pub fn encode_query(input: &str) -> String {
+    encode(input, b"", true)
+}
+
+/// Builds a query string from name and value pairs, without the leading
+/// `?`.
+pub fn build_query<I, N, V>(pairs: I) -> String
+    where I: IntoIterator<Item = (N, V)>, N: AsRef<str>, V: AsRef<str>
+{
+    let mut query = String::new();
+    for (name, value) in pairs {
+        if !query.is_empty() {
+            query.push('&');
+        }
+        query.push_str(&encode_query(name.as_ref()));
+        query.push('=');
+        query.push_str(&encode_query(value.as_ref()));
+    }
+    query
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_query, decode_path, decode_query, decode_query_lossy, encode_path, encode_path_segment,
+                encode_query, parse_query};
+
+    #[test]
+    fn decoding() {
+        assert_eq!(decode_path("/a+b%20c").as_deref(), Some("/a+b c"));
+        assert_eq!(decode_query("a+b%20c").as_deref(), Some("a b c"));
+        assert_eq!(decode_path("%C3%A4%c3%a4").as_deref(), Some("ää"));
+        assert_eq!(decode_path("%2F%00").as_deref(), Some("/\0"));
+        assert_eq!(decode_path(""), Some(String::new()));
+    }
+
+    #[test]
+    fn malformed() {
+        for input in ["%", "%2", "a%zz", "%%41", "%2x"] {
+            assert_eq!(decode_path(input), None, "{}", input);
+            assert_eq!(decode_query(input), None, "{}", input);
+        }
+        assert_eq!(decode_path("%ff"), None);
+        assert_eq!(decode_query_lossy("100%+sure%2"), "100% sure%2");
+        assert_eq!(decode_query_lossy("%ff%41"), "\u{fffd}A");
+    }
+
+    #[test]
+    fn queries() {
+        assert_eq!(parse_query("a=1&b=x+y&&c&d=%3D=&=e"), vec![
+            (String::from("a"), String::from("1")),
+            (String::from("b"), String::from("x y")),
+            (String::from("c"), String::new()),
+            (String::from("d"), String::from("==")),
+            (String::new(), String::from("e")),
+        ]);
+        assert!(parse_query("").is_empty());
+        let query = build_query(vec![("q", "1 + 1 = 2"), ("name", "ä&ö")]);
+        assert_eq!(query, "q=1+%2B+1+%3D+2&name=%C3%A4%26%C3%B6");
+        assert_eq!(parse_query(&query), vec![
+            (String::from("q"), String::from("1 + 1 = 2")),
+            (String::from("name"), String::from("ä&ö")),
+        ]);
+    }
+
+    #[test]
+    fn encoding() {
+        assert_eq!(encode_path("/files/my report.pdf"), "/files/my%20report.pdf");
+        assert_eq!(encode_path_segment("a/b c+d"), "a%2Fb%20c%2Bd");
+        assert_eq!(encode_query("a-z_0.9~ +/"), "a-z_0.9~+%2B%2F");
+        for input in ["a b+c/d?e#f%g", "ä€😀", ""] {
+            assert_eq!(decode_path(&encode_path(input)).as_deref(), Some(input));
+            assert_eq!(decode_query(&encode_query(input)).as_deref(), Some(input));
+        }
+    }
+}
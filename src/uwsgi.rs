@@ -0,0 +1,127 @@
+//! The uwsgi protocol, as an alternative transport to FastCGI.
+//!
+//! nginx (`uwsgi_pass`) and the uWSGI router open a connection per request
+//! and start it with a four byte header: `modifier1`, the size of the
+//! variables as a little-endian `u16`, and `modifier2`. The CGI parameters
+//! follow, each name and value prefixed with its little-endian `u16`
+//! length, and then `CONTENT_LENGTH` bytes of input.
+//!
+//! Only requests with `modifier1` 0, the standard variables nginx sends,
+//! are accepted. The response is written back in CGI form, with a `Status`
+//! header, and ends when the application closes the connection.
+//! `UwsgiRequest` implements the same `Request` trait as the FastCGI
+//! requests; the high-level server speaks uwsgi with the `protocol`
+//! setting. There is no error stream: `Request::error` writes to stderr.
+
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::os::unix::io::RawFd;
+
+use crate::conn::{self, Connection};
+use crate::{Request, StreamType};
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid uwsgi request: {}", message))
+}
+
+fn read_u16(data: &[u8], pos: &mut usize) -> io::Result<usize> {
+    let bytes = data.get(*pos..*pos + 2).ok_or_else(|| invalid("truncated variables"))?;
+    *pos += 2;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+}
+
+fn read_string(data: &[u8], pos: &mut usize) -> io::Result<String> {
+    let len = read_u16(data, pos)?;
+    let bytes = data.get(*pos..*pos + len).ok_or_else(|| invalid("truncated variables"))?;
+    *pos += len;
+    Ok(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Reads the header and the variables at the start of a uwsgi request.
+pub fn read_params<R: Read>(reader: &mut R) -> io::Result<Vec<(String, String)>> {
+    let mut header = [0; 4];
+    reader.read_exact(&mut header)?;
+    if header[0] != 0 {
+        return Err(invalid(&format!("unsupported modifier1 {}", header[0])));
+    }
+    let mut data = vec![0; u16::from_le_bytes([header[1], header[2]]) as usize];
+    reader.read_exact(&mut data)?;
+    let mut params = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let name = read_string(&data, &mut pos)?;
+        let value = read_string(&data, &mut pos)?;
+        params.push((name, value));
+    }
+    Ok(params)
+}
+
+/// A uwsgi request, accepted from a listen socket.
+pub struct UwsgiRequest {
+    listen_fd: RawFd,
+    current: Option<Connection>,
+}
+
+impl Request for UwsgiRequest {
+    /// Accepts requests from the socket passed as stdin.
+    fn new() -> Option<UwsgiRequest> {
+        UwsgiRequest::new_with_fd(0)
+    }
+
+    fn new_with_fd(fd: RawFd) -> Option<UwsgiRequest> {
+        Some(UwsgiRequest { listen_fd: fd, current: None })
+    }
+
+    /// Finishes the previous request and accepts the next. Connections
+    /// which do not start with valid parameters are closed.
+    fn accept(&mut self) -> bool {
+        self.finish();
+        self.current = conn::accept_request(self.listen_fd, read_params);
+        self.current.is_some()
+    }
+
+    /// Sends the remaining output and closes the connection.
+    fn finish(&mut self) {
+        if let Some(current) = self.current.take() {
+            current.close();
+        }
+    }
+
+    fn get_param(&self, name: &str) -> Option<String> {
+        self.current.as_ref()?.param(name)
+    }
+
+    fn params(&self) -> Vec<(String, String)> {
+        self.current.as_ref().map(|current| current.params().to_vec()).unwrap_or_default()
+    }
+
+    fn write(&mut self, msg: &str) -> i32 {
+        self.write_bytes(msg.as_bytes())
+    }
+
+    fn error(&mut self, msg: &str) -> i32 {
+        conn::write_stderr(msg)
+    }
+
+    fn write_bytes(&mut self, buf: &[u8]) -> i32 {
+        self.current.as_mut().map_or(-1, |current| current.write(buf))
+    }
+
+    fn read_bytes(&mut self, buf: &mut [u8]) -> i32 {
+        self.current.as_mut().map_or(-1, |current| current.read(buf))
+    }
+
+    fn read(&mut self, n: i32) -> (String, i32) {
+        crate::read_lossy(self, n)
+    }
+
+    fn flush(&mut self, stream_type: StreamType) {
+        if let (StreamType::OutStream, Some(current)) = (stream_type, self.current.as_mut()) {
+            current.flush();
+        }
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.current.as_ref().and_then(Connection::peer_addr)
+    }
+}
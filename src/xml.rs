@@ -0,0 +1,133 @@
+//! Writing XML responses, e.g. RSS and Atom feeds or SOAP replies,
+//! without escaping by hand.
+//!
+//! `XmlWriter` escapes all text and attribute values and closes elements
+//! in order. Characters which XML 1.0 does not allow at all, such as most
+//! control characters, are replaced by U+FFFD:
+//!
+//! ```ignore
+//! exchange.set_header("Content-Type", "application/rss+xml");
+//! let mut xml = exchange.xml()?;
+//! xml.start("rss", &[("version", "2.0")])?;
+//! xml.start("channel", &[])?;
+//! xml.element("title", &feed.title)?;
+//! xml.finish()?;
+//! ```
+
+use std::borrow::Cow;
+use std::io::{self, BufWriter, Write};
+
+/// The Content-Type of XML responses.
+pub const CONTENT_TYPE: &str = "application/xml; charset=utf-8";
+
+/// The XML declaration, with a line break.
+pub const DECLARATION: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n";
+
+/// Whether XML 1.0 allows `c` in a document.
+fn is_xml_char(c: char) -> bool {
+    matches!(c, '\t' | '\n' | '\r' | ' '..='\u{D7FF}' | '\u{E000}'..='\u{FFFD}' | '\u{10000}'..)
+}
+
+fn escape(input: &str, attribute: bool) -> Cow<'_, str> {
+    let needs_escape = |c: char| matches!(c, '&' | '<' | '>') || !is_xml_char(c)
+        || attribute && matches!(c, '"' | '\'' | '\t' | '\n' | '\r');
+    if !input.contains(needs_escape) {
+        return Cow::Borrowed(input);
+    }
+    let mut escaped = String::with_capacity(input.len() + 16);
+    for c in input.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            // Kept as characters, these would be normalized to spaces.
+            '"' if attribute => escaped.push_str("&quot;"),
+            '\'' if attribute => escaped.push_str("&apos;"),
+            '\t' if attribute => escaped.push_str("&#9;"),
+            '\n' if attribute => escaped.push_str("&#10;"),
+            '\r' if attribute => escaped.push_str("&#13;"),
+            c if !is_xml_char(c) => escaped.push('\u{FFFD}'),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// Escapes character data, for text between tags.
+pub fn escape_text(text: &str) -> Cow<'_, str> {
+    escape(text, false)
+}
+
+/// Escapes an attribute value, for use in double or single quotes.
+pub fn escape_attribute(value: &str) -> Cow<'_, str> {
+    escape(value, true)
+}
+
+/// Writes an XML document, see the module documentation.
+pub struct XmlWriter<W: Write> {
+    out: BufWriter<W>,
+    /// The names of the elements started and not ended yet.
+    open: Vec<String>,
+}
+
+impl<W: Write> XmlWriter<W> {
+    /// Writes to `out`, starting with the XML declaration.
+    pub fn new(out: W) -> io::Result<XmlWriter<W>> {
+        let mut out = BufWriter::new(out);
+        out.write_all(DECLARATION.as_bytes())?;
+        Ok(XmlWriter { out, open: Vec::new() })
+    }
+
+    fn write_tag(&mut self, name: &str, attributes: &[(&str, &str)], empty: bool) -> io::Result<()> {
+        write!(self.out, "<{}", name)?;
+        for &(name, value) in attributes {
+            write!(self.out, " {}=\"{}\"", name, escape_attribute(value))?;
+        }
+        self.out.write_all(if empty { b"/>" } else { b">" })
+    }
+
+    /// Starts an element with attributes, to be closed by `end`.
+    pub fn start(&mut self, name: &str, attributes: &[(&str, &str)]) -> io::Result<()> {
+        self.write_tag(name, attributes, false)?;
+        self.open.push(String::from(name));
+        Ok(())
+    }
+
+    /// Ends the innermost element started.
+    pub fn end(&mut self) -> io::Result<()> {
+        match self.open.pop() {
+            Some(name) => write!(self.out, "</{}>", name),
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "no XML element to end")),
+        }
+    }
+
+    /// Writes an empty element, `<name/>`.
+    pub fn empty(&mut self, name: &str, attributes: &[(&str, &str)]) -> io::Result<()> {
+        self.write_tag(name, attributes, true)
+    }
+
+    /// Writes an element containing only `text`.
+    pub fn element(&mut self, name: &str, text: &str) -> io::Result<()> {
+        write!(self.out, "<{}>{}</{}>", name, escape_text(text), name)
+    }
+
+    /// Writes text into the current element.
+    pub fn text(&mut self, text: &str) -> io::Result<()> {
+        self.out.write_all(escape_text(text).as_bytes())
+    }
+
+    /// Writes markup as it is, e.g. a fragment rendered before. The
+    /// caller is responsible for it being well-formed.
+    pub fn raw(&mut self, markup: &str) -> io::Result<()> {
+        self.out.write_all(markup.as_bytes())
+    }
+
+    /// Ends all elements still open and flushes the output.
+    pub fn finish(mut self) -> io::Result<()> {
+        while !self.open.is_empty() {
+            self.end()?;
+        }
+        self.out.write_all(b"\n")?;
+        self.out.flush()
+    }
+}
@@ -0,0 +1,464 @@
+//! Protocol conformance tests.
+//!
+//! Each backend, libfcgi with the `ffi` feature, the native transport with
+//! `pure` and its event loop with `evented`, is started on a TCP socket
+//! with a small handler and driven with scripted record sequences written
+//! by hand, checking the replies against the FastCGI specification. Run
+//! them with `cargo test --no-default-features --features pure,evented`
+//! for the native transport, and with the default features where libfcgi
+//! is installed.
+
+extern crate fcgi;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::{IntoRawFd, RawFd};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use fcgi::protocol::{self, BeginRequest, EndRequest, Header, ProtocolStatus, Record, RecordType, Role};
+use fcgi::Request;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Answers with the request URI, the length of the input and the lengths
+/// of the parameters `P` and `LONG`. The URI `/wait` waits for the request
+/// to be aborted first.
+fn handle(request: &mut dyn Request) {
+    let uri = request.get_param("REQUEST_URI").unwrap_or_default();
+    if uri == "/wait" {
+        let start = Instant::now();
+        while !request.abort_token().is_aborted() && start.elapsed() < TIMEOUT {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    let mut len = 0;
+    let mut buf = [0; 4096];
+    loop {
+        let n = request.read_bytes(&mut buf);
+        if n <= 0 {
+            break;
+        }
+        len += n as usize;
+    }
+    let long = request.get_param("LONG").map_or(0, |value| value.len());
+    let p = request.get_param("P").unwrap_or_default();
+    request.write(&format!("Content-Type: text/plain\r\n\r\nuri={};len={};p={};long={}", uri, len, p, long));
+}
+
+/// Starts a backend on a new socket, returning its address.
+fn start<R: Request + 'static>(new_request: fn(RawFd) -> R) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let fd = listener.into_raw_fd();
+    thread::spawn(move || {
+        let mut request = new_request(fd);
+        while request.accept() {
+            handle(&mut request);
+        }
+    });
+    addr
+}
+
+fn connect(addr: SocketAddr) -> TcpStream {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    stream.set_nodelay(true).unwrap();
+    stream
+}
+
+fn begin(id: u16, role: Role, keep_conn: bool) -> Vec<u8> {
+    let mut buf = Vec::new();
+    let flags = if keep_conn { protocol::KEEP_CONN } else { 0 };
+    BeginRequest { role, flags }.to_record(id).encode(&mut buf);
+    buf
+}
+
+fn record(record_type: RecordType, id: u16, content: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    protocol::encode_record(&mut buf, record_type, id, content);
+    buf
+}
+
+fn params(pairs: &[(&str, &str)]) -> Vec<u8> {
+    protocol::encode_name_values(pairs.iter().map(|&(name, value)| (name.as_bytes(), value.as_bytes())))
+}
+
+/// A complete responder request with the parameters in one record.
+fn request(id: u16, keep_conn: bool, pairs: &[(&str, &str)], stdin: &[u8]) -> Vec<u8> {
+    let mut buf = begin(id, Role::Responder, keep_conn);
+    buf.extend(record(RecordType::Params, id, &params(pairs)));
+    buf.extend(record(RecordType::Params, id, b""));
+    if !stdin.is_empty() {
+        buf.extend(record(RecordType::Stdin, id, stdin));
+    }
+    buf.extend(record(RecordType::Stdin, id, b""));
+    buf
+}
+
+/// Reads a record, checking that it is padded to a multiple of 8 bytes as
+/// the specification recommends. `None` once the connection is closed.
+fn read_record(stream: &mut TcpStream) -> Option<Record> {
+    let mut header = [0; protocol::HEADER_LEN];
+    match stream.read(&mut header[..1]).unwrap() {
+        0 => return None,
+        _ => stream.read_exact(&mut header[1..]).unwrap(),
+    }
+    let header = Header::decode(&header).unwrap().unwrap();
+    assert_eq!(header.record_len() % 8, 0, "record not padded: {:?}", header);
+    let mut content = vec![0; header.record_len() - protocol::HEADER_LEN];
+    stream.read_exact(&mut content).unwrap();
+    content.truncate(header.content_length as usize);
+    Some(Record { record_type: header.record_type, request_id: header.request_id, content })
+}
+
+/// The output of a request up to its `FCGI_END_REQUEST`.
+struct Response {
+    stdout: Vec<u8>,
+    stdout_records: usize,
+    stdout_ended: bool,
+    end: EndRequest,
+}
+
+impl Response {
+    fn body(&self) -> String {
+        let stdout = String::from_utf8_lossy(&self.stdout);
+        stdout.split("\r\n\r\n").nth(1).unwrap_or_default().to_string()
+    }
+}
+
+fn read_response(stream: &mut TcpStream, id: u16) -> Response {
+    let mut response = Response {
+        stdout: Vec::new(),
+        stdout_records: 0,
+        stdout_ended: false,
+        end: EndRequest { app_status: 0, protocol_status: ProtocolStatus::Other(255) },
+    };
+    loop {
+        let record = read_record(stream).expect("connection closed before FCGI_END_REQUEST");
+        assert_eq!(record.request_id, id, "record for another request: {:?}", record);
+        match record.record_type {
+            RecordType::Stdout => {
+                assert!(!response.stdout_ended, "output after the end of the STDOUT stream");
+                response.stdout_ended = record.content.is_empty();
+                response.stdout_records += 1;
+                response.stdout.extend(record.content);
+            }
+            RecordType::Stderr => {}
+            RecordType::EndRequest => {
+                response.end = record.end_request().unwrap();
+                return response;
+            }
+            other => panic!("unexpected {:?} record", other),
+        }
+    }
+}
+
+fn assert_complete(response: &Response) {
+    assert!(response.stdout_ended, "STDOUT stream not ended by an empty record");
+    assert_eq!(response.end, EndRequest { app_status: 0, protocol_status: ProtocolStatus::RequestComplete });
+}
+
+fn assert_closed(stream: &mut TcpStream) {
+    assert!(read_record(stream).is_none(), "connection not closed without FCGI_KEEP_CONN");
+}
+
+fn simple_request(addr: SocketAddr) {
+    let mut stream = connect(addr);
+    stream.write_all(&request(1, false, &[("REQUEST_URI", "/a"), ("P", "x")], b"hello")).unwrap();
+    let response = read_response(&mut stream, 1);
+    assert_complete(&response);
+    assert_eq!(response.body(), "uri=/a;len=5;p=x;long=0");
+    assert_closed(&mut stream);
+}
+
+fn fragmented_params(addr: SocketAddr) {
+    let long = "v".repeat(300);
+    let content = params(&[("REQUEST_URI", "/fragmented"), ("LONG", &long), ("P", "y")]);
+    let mut buf = begin(1, Role::Responder, false);
+    // Split inside the four byte length of LONG and everywhere else.
+    for chunk in content.chunks(3) {
+        buf.extend(record(RecordType::Params, 1, chunk));
+    }
+    buf.extend(record(RecordType::Params, 1, b""));
+    buf.extend(record(RecordType::Stdin, 1, b""));
+    let mut stream = connect(addr);
+    stream.write_all(&buf).unwrap();
+    let response = read_response(&mut stream, 1);
+    assert_complete(&response);
+    assert_eq!(response.body(), "uri=/fragmented;len=0;p=y;long=300");
+}
+
+fn zero_length_stdin(addr: SocketAddr) {
+    let mut stream = connect(addr);
+    stream.write_all(&request(1, false, &[("REQUEST_URI", "/empty")], b"")).unwrap();
+    let response = read_response(&mut stream, 1);
+    assert_complete(&response);
+    assert_eq!(response.body(), "uri=/empty;len=0;p=;long=0");
+}
+
+fn byte_at_a_time(addr: SocketAddr) {
+    let mut stream = connect(addr);
+    for byte in request(1, false, &[("REQUEST_URI", "/slow")], b"abc") {
+        stream.write_all(&[byte]).unwrap();
+    }
+    let response = read_response(&mut stream, 1);
+    assert_complete(&response);
+    assert_eq!(response.body(), "uri=/slow;len=3;p=;long=0");
+}
+
+fn large_stdin(addr: SocketAddr) {
+    let body = vec![b'a'; 200_000];
+    let mut buf = begin(1, Role::Responder, false);
+    buf.extend(record(RecordType::Params, 1, &params(&[("REQUEST_URI", "/large")])));
+    buf.extend(record(RecordType::Params, 1, b""));
+    let mut stdin = Vec::new();
+    protocol::encode_stream(&mut stdin, RecordType::Stdin, 1, &body);
+    buf.extend(stdin);
+    buf.extend(record(RecordType::Stdin, 1, b""));
+    let mut stream = connect(addr);
+    stream.write_all(&buf).unwrap();
+    let response = read_response(&mut stream, 1);
+    assert_complete(&response);
+    assert_eq!(response.body(), "uri=/large;len=200000;p=;long=0");
+}
+
+fn keep_conn(addr: SocketAddr) {
+    let mut stream = connect(addr);
+    for (id, uri) in [(1, "/first"), (2, "/second")] {
+        stream.write_all(&request(id, true, &[("REQUEST_URI", uri)], b"")).unwrap();
+        let response = read_response(&mut stream, id);
+        assert_complete(&response);
+        assert_eq!(response.body(), format!("uri={};len=0;p=;long=0", uri));
+    }
+}
+
+fn get_values(addr: SocketAddr) {
+    let mut stream = connect(addr);
+    let query = params(&[("FCGI_MAX_CONNS", ""), ("FCGI_MAX_REQS", ""), ("FCGI_MPXS_CONNS", "")]);
+    stream.write_all(&record(RecordType::GetValues, protocol::NULL_REQUEST_ID, &query)).unwrap();
+    let result = read_record(&mut stream).unwrap();
+    assert_eq!(result.record_type, RecordType::GetValuesResult);
+    assert_eq!(result.request_id, protocol::NULL_REQUEST_ID);
+    let names: Vec<&[u8]> = protocol::name_values(&result.content).map(|pair| pair.unwrap().0).collect();
+    assert_eq!(names, [&b"FCGI_MAX_CONNS"[..], b"FCGI_MAX_REQS", b"FCGI_MPXS_CONNS"]);
+}
+
+fn unknown_management_type(addr: SocketAddr) {
+    let mut stream = connect(addr);
+    stream.write_all(&record(RecordType::Other(42), protocol::NULL_REQUEST_ID, b"?")).unwrap();
+    let reply = read_record(&mut stream).unwrap();
+    assert_eq!(reply.record_type, RecordType::UnknownType);
+    assert_eq!(reply.request_id, protocol::NULL_REQUEST_ID);
+    assert_eq!(reply.content[0], 42);
+    // The connection remains usable.
+    stream.write_all(&request(1, false, &[("REQUEST_URI", "/after")], b"")).unwrap();
+    assert_complete(&read_response(&mut stream, 1));
+}
+
+fn unknown_role(addr: SocketAddr) {
+    let mut stream = connect(addr);
+    stream.write_all(&begin(1, Role::Other(99), false)).unwrap();
+    let response = read_response(&mut stream, 1);
+    assert_eq!(response.stdout_records, 0);
+    assert_eq!(response.end.protocol_status, ProtocolStatus::UnknownRole);
+}
+
+macro_rules! conformance_tests {
+    ($backend:ident, $new_request:expr, [$($case:ident),*]) => {
+        mod $backend {
+            $(
+                #[test]
+                fn $case() {
+                    super::$case(super::start($new_request));
+                }
+            )*
+        }
+    }
+}
+
+#[cfg(feature = "pure")]
+fn native(fd: RawFd) -> fcgi::NativeRequest {
+    fcgi::NativeRequest::new_with_fd(fd).unwrap()
+}
+
+#[cfg(feature = "pure")]
+conformance_tests!(native, super::native, [
+    simple_request, fragmented_params, zero_length_stdin, byte_at_a_time, large_stdin, keep_conn,
+    get_values, unknown_management_type, unknown_role,
+    abort_while_reading_params, abort_while_handling, oversized_params, cant_mpx_conn
+]);
+
+#[cfg(feature = "evented")]
+fn evented(fd: RawFd) -> fcgi::NativeRequest {
+    let listener = fcgi::Listener::new(fd);
+    listener.set_event_loop(true);
+    fcgi::NativeRequest::from_listener(listener)
+}
+
+#[cfg(feature = "evented")]
+conformance_tests!(evented, super::evented, [
+    simple_request, fragmented_params, zero_length_stdin, byte_at_a_time, large_stdin, keep_conn,
+    get_values, unknown_management_type, unknown_role,
+    abort_while_reading_params, abort_while_handling, oversized_params, cant_mpx_conn
+]);
+
+// Backends accepting several requests on a connection at once.
+
+#[cfg(feature = "pure")]
+fn mpx_listener(fd: RawFd) -> std::sync::Arc<fcgi::Listener> {
+    let listener = fcgi::Listener::new(fd);
+    listener.set_capabilities(fcgi::Capabilities { max_conns: 1, max_reqs: 100, mpxs_conns: true });
+    listener
+}
+
+#[cfg(feature = "pure")]
+fn native_mpx(fd: RawFd) -> fcgi::NativeRequest {
+    fcgi::NativeRequest::from_listener(mpx_listener(fd))
+}
+
+#[cfg(feature = "pure")]
+conformance_tests!(native_mpx, super::native_mpx, [multiplexed, too_many_params_pending]);
+
+#[cfg(feature = "evented")]
+fn evented_mpx(fd: RawFd) -> fcgi::NativeRequest {
+    let listener = mpx_listener(fd);
+    listener.set_event_loop(true);
+    fcgi::NativeRequest::from_listener(listener)
+}
+
+#[cfg(feature = "evented")]
+conformance_tests!(evented_mpx, super::evented_mpx, [multiplexed, too_many_params_pending]);
+
+#[cfg(feature = "ffi")]
+fn ffi(fd: RawFd) -> fcgi::DefaultRequest {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| assert!(fcgi::initialize_fcgi()));
+    fcgi::DefaultRequest::new_with_fd(fd).unwrap()
+}
+
+#[cfg(feature = "ffi")]
+conformance_tests!(ffi, super::ffi, [
+    simple_request, fragmented_params, zero_length_stdin, byte_at_a_time, large_stdin, keep_conn,
+    get_values, unknown_management_type, unknown_role
+]);
+
+// The following cases are only run against the native transport: libfcgi
+// does not act on FCGI_ABORT_REQUEST and does not report CANT_MPX_CONN.
+
+#[cfg(feature = "pure")]
+fn abort_while_reading_params(addr: SocketAddr) {
+    let mut buf = begin(1, Role::Responder, false);
+    buf.extend(record(RecordType::Params, 1, &params(&[("REQUEST_URI", "/never")])));
+    buf.extend(record(RecordType::AbortRequest, 1, b""));
+    let mut stream = connect(addr);
+    stream.write_all(&buf).unwrap();
+    let response = read_response(&mut stream, 1);
+    assert_eq!(response.stdout_records, 0, "output for a request aborted before it started");
+    assert_eq!(response.end.protocol_status, ProtocolStatus::RequestComplete);
+    assert_closed(&mut stream);
+}
+
+#[cfg(feature = "pure")]
+fn abort_while_handling(addr: SocketAddr) {
+    let mut stream = connect(addr);
+    stream.write_all(&request(1, false, &[("REQUEST_URI", "/wait")], b"")).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    stream.write_all(&record(RecordType::AbortRequest, 1, b"")).unwrap();
+    let response = read_response(&mut stream, 1);
+    assert_eq!(response.stdout_records, 0, "output sent after the request was aborted");
+    assert_eq!(response.end.protocol_status, ProtocolStatus::RequestComplete);
+}
+
+#[cfg(feature = "pure")]
+fn oversized_params(addr: SocketAddr) {
+    let long = "v".repeat(60_000);
+    let mut buf = begin(1, Role::Responder, false);
+    for _ in 0..5 {
+        buf.extend(record(RecordType::Params, 1, &params(&[("LONG", &long)])));
+    }
+    let mut stream = connect(addr);
+    stream.write_all(&buf).unwrap();
+    let response = read_response(&mut stream, 1);
+    assert_eq!(response.stdout_records, 0);
+    assert_eq!(response.end.protocol_status, ProtocolStatus::Overloaded);
+    assert_closed(&mut stream);
+}
+
+#[cfg(feature = "pure")]
+fn cant_mpx_conn(addr: SocketAddr) {
+    let mut stream = connect(addr);
+    let mut buf = begin(1, Role::Responder, true);
+    buf.extend(record(RecordType::Params, 1, &params(&[("REQUEST_URI", "/first")])));
+    buf.extend(record(RecordType::Params, 1, b""));
+    buf.extend(begin(2, Role::Responder, true));
+    stream.write_all(&buf).unwrap();
+    let rejected = read_response(&mut stream, 2);
+    assert_eq!(rejected.end.protocol_status, ProtocolStatus::CantMpxConn);
+    stream.write_all(&record(RecordType::Stdin, 1, b"")).unwrap();
+    let response = read_response(&mut stream, 1);
+    assert_complete(&response);
+    assert_eq!(response.body(), "uri=/first;len=0;p=;long=0");
+}
+
+#[cfg(feature = "pure")]
+fn multiplexed(addr: SocketAddr) {
+    let mut stream = connect(addr);
+    let mut buf = Vec::new();
+    for (id, uri) in [(1, "/one"), (2, "/two")] {
+        buf.extend(begin(id, Role::Responder, true));
+        buf.extend(record(RecordType::Params, id, &params(&[("REQUEST_URI", uri), ("P", &id.to_string())])));
+    }
+    buf.extend(record(RecordType::Params, 2, b""));
+    buf.extend(record(RecordType::Params, 1, b""));
+    for (id, chunk) in [(2, "bb"), (1, "a"), (2, "bb"), (1, "aa"), (2, "b")] {
+        buf.extend(record(RecordType::Stdin, id, chunk.as_bytes()));
+    }
+    buf.extend(record(RecordType::Stdin, 2, b""));
+    buf.extend(record(RecordType::Stdin, 1, b""));
+    stream.write_all(&buf).unwrap();
+
+    // The records of both requests may arrive interleaved.
+    let mut stdout = [Vec::new(), Vec::new()];
+    let mut ended = [false, false];
+    while ended != [true, true] {
+        let record = read_record(&mut stream).expect("connection closed before both requests ended");
+        let index = match record.request_id {
+            1 | 2 => record.request_id as usize - 1,
+            id => panic!("record for unknown request {}", id),
+        };
+        assert!(!ended[index], "record after FCGI_END_REQUEST: {:?}", record);
+        match record.record_type {
+            RecordType::Stdout => stdout[index].extend(record.content),
+            RecordType::Stderr => {}
+            RecordType::EndRequest => {
+                let end = record.end_request().unwrap();
+                assert_eq!(end, EndRequest { app_status: 0, protocol_status: ProtocolStatus::RequestComplete });
+                ended[index] = true;
+            }
+            other => panic!("unexpected {:?} record", other),
+        }
+    }
+    let body = |stdout: &[u8]| String::from_utf8_lossy(stdout).split("\r\n\r\n").nth(1).unwrap_or_default().to_string();
+    assert_eq!(body(&stdout[0]), "uri=/one;len=3;p=1;long=0");
+    assert_eq!(body(&stdout[1]), "uri=/two;len=5;p=2;long=0");
+}
+
+#[cfg(feature = "pure")]
+fn too_many_params_pending(addr: SocketAddr) {
+    let mut stream = connect(addr);
+    let mut buf = Vec::new();
+    for id in 1..=65 {
+        buf.extend(begin(id, Role::Responder, true));
+    }
+    stream.write_all(&buf).unwrap();
+    let rejected = read_response(&mut stream, 65);
+    assert_eq!(rejected.end.protocol_status, ProtocolStatus::Overloaded);
+    // The requests begun before are still served.
+    stream.write_all(&record(RecordType::Params, 1, &params(&[("REQUEST_URI", "/first")]))).unwrap();
+    stream.write_all(&record(RecordType::Params, 1, b"")).unwrap();
+    stream.write_all(&record(RecordType::Stdin, 1, b"")).unwrap();
+    let response = read_response(&mut stream, 1);
+    assert_complete(&response);
+    assert_eq!(response.body(), "uri=/first;len=0;p=;long=0");
+}